@@ -43,26 +43,22 @@ mod imp {
     /// Dump the profile to the `path`.
     ///
     /// If `path` is `None`, will dump it in the working directory with a auto-generated name.
-    pub fn dump_prof(path: Option<&str>) {
+    pub fn dump_prof(path: Option<&str>) -> Result<(), String> {
         unsafe {
             if let Err(e) = jemallocator::mallctl_set(PROFILE_ACTIVE, true) {
-                error!("failed to activate profiling: {}", e);
-                return;
+                return Err(format!("failed to activate profiling: {}", e));
             }
         }
         let mut c_path = DumpPathGuard::from_cstring(path.map(|p| CString::new(p).unwrap()));
         let res = unsafe { jemallocator::mallctl_set(PROFILE_DUMP, c_path.get_mut_ptr()) };
-        match res {
-            Err(e) => error!("failed to dump the profile to {:?}: {}", path, e),
-            Ok(_) => {
-                if let Some(p) = path {
-                    info!("dump profile to {}", p);
-                    return;
-                }
-
-                info!("dump profile to {}", env::current_dir().unwrap().display());
-            }
+        if let Err(e) = res {
+            return Err(format!("failed to dump the profile to {:?}: {}", path, e));
         }
+        match path {
+            Some(p) => info!("dump profile to {}", p),
+            None => info!("dump profile to {}", env::current_dir().unwrap().display()),
+        }
+        Ok(())
     }
 
     #[cfg(test)]
@@ -78,11 +74,11 @@ mod imp {
             let dir = TempDir::new("test_profiling").unwrap();
             let os_path = dir.path().to_path_buf().join("test1.dump").into_os_string();
             let path = os_path.into_string().unwrap();
-            super::dump_prof(Some(&path));
+            super::dump_prof(Some(&path)).unwrap();
 
             let os_path = dir.path().to_path_buf().join("test2.dump").into_os_string();
             let path = os_path.into_string().unwrap();
-            super::dump_prof(Some(&path));
+            super::dump_prof(Some(&path)).unwrap();
 
             let files = fs::read_dir(dir.path()).unwrap().count();
             assert_eq!(files, 2);
@@ -92,7 +88,9 @@ mod imp {
 
 #[cfg(not(feature = "mem-profiling"))]
 mod imp {
-    pub fn dump_prof(_: Option<&str>) {}
+    pub fn dump_prof(_: Option<&str>) -> Result<(), String> {
+        Err("tikv-server is built without the mem-profiling feature".to_owned())
+    }
 }
 
 pub use self::imp::*;
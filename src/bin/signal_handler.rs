@@ -77,10 +77,16 @@ mod imp {
                     info!("{}", String::from_utf8(buffer).unwrap());
 
                     print_rocksdb_stats(&engines.kv_engine);
-                    print_rocksdb_stats(&engines.raft_engine);
+                    if let Some(raft_db) = engines.raft_engine.rocksdb() {
+                        print_rocksdb_stats(&raft_db);
+                    }
                     print_malloc_stats();
                 }
-                SIGUSR2 => profiling::dump_prof(None),
+                SIGUSR2 => {
+                    if let Err(e) = profiling::dump_prof(None) {
+                        error!("{}", e);
+                    }
+                }
                 // TODO: handle more signal
                 _ => unreachable!(),
             }
@@ -25,6 +25,7 @@ extern crate tempdir;
 extern crate rustc_serialize;
 
 use std::{str, u64};
+use std::sync::Arc;
 use clap::{App, Arg, SubCommand};
 use rustc_serialize::hex::{FromHex, ToHex};
 use protobuf::Message;
@@ -34,7 +35,9 @@ use kvproto::eraftpb::Entry;
 use rocksdb::{ReadOptions, SeekKey, DB};
 use tikv::util::{self, escape, unescape};
 use tikv::util::codec::bytes::encode_bytes;
-use tikv::raftstore::store::keys;
+use tikv::raftstore::store::{keys, unsafe_recovery, Config as RaftstoreConfig, RaftEngine};
+use tikv::raftstore::store::raft_engine::{migrate_raft_engine, open_raft_engine,
+                                          RAFT_ENGINE_ROCKSDB};
 use tikv::raftstore::store::engine::{IterOption, Iterable, Peekable};
 use tikv::storage::{CfName, ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
 use tikv::storage::mvcc::{Lock, Write};
@@ -57,6 +60,13 @@ fn main() {
                 .takes_value(true)
                 .help("set raft rocksdb path"),
         )
+        .arg(
+            Arg::with_name("raft-engine")
+                .long("raft-engine")
+                .takes_value(true)
+                .default_value(RAFT_ENGINE_ROCKSDB)
+                .help("set the raft log engine, rocksdb or segment"),
+        )
         .arg(
             Arg::with_name("hex-to-escaped")
                 .short("h")
@@ -230,6 +240,46 @@ fn main() {
                         .takes_value(true)
                         .help("specify region id"),
                 ),
+        )
+        .subcommand(
+            SubCommand::with_name("unsafe-recover")
+                .about(
+                    "remove the peers on failed stores from all regions, \
+                     the store must be stopped",
+                )
+                .arg(
+                    Arg::with_name("stores")
+                        .short("s")
+                        .takes_value(true)
+                        .required(true)
+                        .help("failed store ids, separated by comma"),
+                )
+                .arg(
+                    Arg::with_name("apply")
+                        .long("apply")
+                        .help("write the changes, otherwise only print the dry-run report"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate-raft-engine")
+                .about(
+                    "copy the raft logs and states to another raft engine, \
+                     the store must be stopped",
+                )
+                .arg(
+                    Arg::with_name("to-engine")
+                        .long("to-engine")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the target raft log engine, rocksdb or segment"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .help("the target raft engine path"),
+                ),
         );
     let matches = app.clone().get_matches();
 
@@ -250,7 +300,11 @@ fn main() {
     let db_path = matches.value_of("db").unwrap();
     let db = util::rocksdb::open(db_path, ALL_CFS).unwrap();
     let raft_db_path = matches.value_of("raftdb").unwrap();
-    let raft_db = util::rocksdb::open(raft_db_path, &[]).unwrap();
+    let raft_engine = open_ctl_raft_engine(
+        matches.value_of("raft-engine").unwrap(),
+        raft_db_path,
+        false,
+    );
 
     if let Some(matches) = matches.subcommand_matches("print") {
         let cf_name = matches.value_of("cf").unwrap_or(CF_DEFAULT);
@@ -258,23 +312,23 @@ fn main() {
         dump_raw_value(db, cf_name, key);
     } else if let Some(matches) = matches.subcommand_matches("raft") {
         if let Some(matches) = matches.subcommand_matches("log") {
-            let key = match matches.value_of("key") {
+            let (region_id, index) = match matches.value_of("key") {
                 None => {
                     let region = String::from(matches.value_of("region").unwrap());
                     let index = String::from(matches.value_of("index").unwrap());
-                    keys::raft_log_key(region.parse().unwrap(), index.parse().unwrap())
+                    (region.parse().unwrap(), index.parse().unwrap())
                 }
-                Some(k) => unescape(k),
+                Some(k) => keys::decode_raft_log_key(&unescape(k)).unwrap(),
             };
-            dump_raft_log_entry(raft_db, &key);
+            dump_raft_log_entry(&*raft_engine, region_id, index);
         } else if let Some(matches) = matches.subcommand_matches("region") {
             let skip_tombstone = matches.is_present("skip-tombstone");
             match matches.value_of("region") {
                 Some(id) => {
-                    dump_region_info(&db, &raft_db, id.parse().unwrap(), skip_tombstone);
+                    dump_region_info(&db, &*raft_engine, id.parse().unwrap(), skip_tombstone);
                 }
                 None => {
-                    dump_all_region_info(&db, &raft_db, skip_tombstone);
+                    dump_all_region_info(&db, &*raft_engine, skip_tombstone);
                 }
             }
         } else {
@@ -333,6 +387,18 @@ fn main() {
         let db_path2 = matches.value_of("to").unwrap();
         let db2 = util::rocksdb::open(db_path2, ALL_CFS).unwrap();
         dump_diff(&db, &db2, region_id);
+    } else if let Some(matches) = matches.subcommand_matches("unsafe-recover") {
+        let stores: Vec<u64> = matches
+            .value_of("stores")
+            .unwrap()
+            .split(',')
+            .map(|s| s.trim().parse().unwrap())
+            .collect();
+        unsafe_recover(&db, &*raft_engine, &stores, matches.is_present("apply"));
+    } else if let Some(matches) = matches.subcommand_matches("migrate-raft-engine") {
+        let to_engine = matches.value_of("to-engine").unwrap();
+        let to = matches.value_of("to").unwrap();
+        migrate_raft_log(&db, &*raft_engine, to_engine, to);
     } else {
         let _ = app.print_help();
     }
@@ -468,12 +534,26 @@ fn dump_raw_value(db: DB, cf: &str, key: String) {
     println!("value: {}", value.map_or("None".to_owned(), |v| escape(&v)));
 }
 
-fn dump_raft_log_entry(raft_db: DB, idx_key: &[u8]) {
-    let (region_id, idx) = keys::decode_raft_log_key(idx_key).unwrap();
-    println!("idx_key: {}", escape(idx_key));
+fn open_ctl_raft_engine(engine: &str, path: &str, create: bool) -> Arc<RaftEngine> {
+    let mut cfg = RaftstoreConfig::default();
+    cfg.raft_log_engine = engine.to_owned();
+    cfg.raftdb_path = path.to_owned();
+    open_raft_engine(&cfg, |path| {
+        let db = if create {
+            util::rocksdb::new_engine(path, &[CF_DEFAULT])
+        } else {
+            util::rocksdb::open(path, &[])
+        };
+        db.map_err(Into::into)
+    }).unwrap()
+}
+
+fn dump_raft_log_entry(raft_engine: &RaftEngine, region_id: u64, idx: u64) {
+    let idx_key = keys::raft_log_key(region_id, idx);
+    println!("idx_key: {}", escape(&idx_key));
     println!("region: {}", region_id);
     println!("log index: {}", idx);
-    let mut ent: Entry = raft_db.get_msg(idx_key).unwrap().unwrap();
+    let mut ent: Entry = raft_engine.get_entry(region_id, idx).unwrap().unwrap();
     let data = ent.take_data();
     println!("entry {:?}", ent);
     let mut msg = RaftCmdRequest::new();
@@ -569,7 +649,28 @@ fn dump_diff(db: &DB, db2: &DB, region_id: u64) {
     }
 }
 
-fn dump_region_info(db: &DB, raft_db: &DB, region_id: u64, skip_tombstone: bool) {
+fn unsafe_recover(db: &DB, raft_engine: &RaftEngine, failed_stores: &[u64], apply: bool) {
+    let (store_id, regions) = unsafe_recovery::load_regions(db).unwrap();
+    let recoveries = unsafe_recovery::plan(store_id, &regions, failed_stores).unwrap();
+    println!(
+        "store {}: {} of {} regions have peers on stores {:?}",
+        store_id,
+        recoveries.len(),
+        regions.len(),
+        failed_stores
+    );
+    for r in &recoveries {
+        println!("{}", r);
+    }
+    if !apply {
+        println!("dry run, pass --apply to write the changes");
+        return;
+    }
+    unsafe_recovery::write_recoveries(db, raft_engine, &recoveries).unwrap();
+    println!("{} regions recovered", recoveries.len());
+}
+
+fn dump_region_info(db: &DB, raft_engine: &RaftEngine, region_id: u64, skip_tombstone: bool) {
     let region_state_key = keys::region_state_key(region_id);
     let region_state: Option<RegionLocalState> = db.get_msg_cf(CF_RAFT, &region_state_key).unwrap();
     if skip_tombstone &&
@@ -584,7 +685,7 @@ fn dump_region_info(db: &DB, raft_db: &DB, region_id: u64, skip_tombstone: bool)
 
     let raft_state_key = keys::raft_state_key(region_id);
     println!("raft state key: {}", escape(&raft_state_key));
-    let raft_state: Option<RaftLocalState> = raft_db.get_msg(&raft_state_key).unwrap();
+    let raft_state: Option<RaftLocalState> = raft_engine.get_raft_state(region_id).unwrap();
     println!("raft state: {:?}", raft_state);
 
     let apply_state_key = keys::apply_state_key(region_id);
@@ -622,13 +723,26 @@ fn dump_region_size(db: &DB, region_id: u64, cf: Option<&str>) {
     println!("region size: {}", convert_gbmb(size));
 }
 
-fn dump_all_region_info(db: &DB, raft_db: &DB, skip_tombstone: bool) {
+fn dump_all_region_info(db: &DB, raft_engine: &RaftEngine, skip_tombstone: bool) {
     let region_ids = get_all_region_ids(db);
     for region_id in region_ids {
-        dump_region_info(db, raft_db, region_id, skip_tombstone);
+        dump_region_info(db, raft_engine, region_id, skip_tombstone);
     }
 }
 
+fn migrate_raft_log(db: &DB, src: &RaftEngine, to_engine: &str, to: &str) {
+    let region_ids = get_all_region_ids(db);
+    let dst = open_ctl_raft_engine(to_engine, to, true);
+    migrate_raft_engine(src, &*dst, &region_ids).unwrap();
+    println!(
+        "migrated the raft logs of {} regions from {} to {} engine at {}",
+        region_ids.len(),
+        src.path(),
+        to_engine,
+        to
+    );
+}
+
 fn dump_all_region_size(db: &DB, cf: Option<&str>) {
     let mut region_ids = get_all_region_ids(db);
     let mut region_sizes: Vec<u64> = region_ids
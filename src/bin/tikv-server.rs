@@ -54,6 +54,7 @@ use std::process;
 use std::fs::File;
 use std::usize;
 use std::path::Path;
+use std::net::TcpStream;
 use std::sync::{mpsc, Arc};
 use std::io::Read;
 use std::env;
@@ -67,12 +68,15 @@ use tikv::util::{self, panic_hook, rocksdb as rocksdb_util};
 use tikv::util::collections::HashMap;
 use tikv::util::logger::{self, StderrLogger};
 use tikv::util::file_log::RotatingFileLogger;
+use tikv::util::security::SecurityManager;
 use tikv::util::transport::SendCh;
 use tikv::storage::DEFAULT_ROCKSDB_SUB_DIR;
-use tikv::server::{create_raft_storage, Node, Server, DEFAULT_CLUSTER_ID};
+use tikv::server::{create_raft_storage, Node, Server, StatusServer, DEFAULT_CLUSTER_ID};
 use tikv::server::transport::ServerRaftStoreRouter;
+use tikv::server::status_server::connectable_addr;
 use tikv::server::resolve;
-use tikv::raftstore::store::{self, Engines, SnapManager};
+use tikv::raftstore::store::{self, Engines, SnapManagerBuilder};
+use tikv::raftstore::store::raft_engine::open_raft_engine;
 use tikv::pd::{PdClient, RpcClient};
 use tikv::util::time::Monitor;
 use tikv::util::rocksdb::metrics_flusher::{MetricsFlusher, DEFAULT_FLUSER_INTERVAL};
@@ -131,12 +135,23 @@ fn check_system_config(config: &TiKvConfig) {
     }
 }
 
-fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
+#[cfg(unix)]
+fn new_status_server(cfg: &TiKvConfig) -> StatusServer {
+    let mut status_server = StatusServer::new(cfg);
+    status_server.set_heap_profiler(Box::new(|path: &str| profiling::dump_prof(Some(path))));
+    status_server
+}
+
+#[cfg(not(unix))]
+fn new_status_server(cfg: &TiKvConfig) -> StatusServer {
+    StatusServer::new(cfg)
+}
+
+fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig, security_mgr: Arc<SecurityManager>) {
     let store_path = Path::new(&cfg.storage.data_dir);
     let lock_path = store_path.join(Path::new("LOCK"));
     let db_path = store_path.join(Path::new(DEFAULT_ROCKSDB_SUB_DIR));
     let snap_path = store_path.join(Path::new("snap"));
-    let raft_db_path = Path::new(&cfg.raft_store.raftdb_path);
 
     let f = File::create(lock_path).unwrap_or_else(|e| exit_with_err(e));
     if f.try_lock_exclusive().is_err() {
@@ -167,31 +182,34 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     let pd_client = Arc::new(pd_client);
     let (mut worker, resolver) =
         resolve::new_resolver(pd_client.clone()).unwrap_or_else(|e| exit_with_err(e));
-    let snap_mgr = SnapManager::new(
-        snap_path.as_path().to_str().unwrap().to_owned(),
-        Some(store_sendch),
-    );
+    let snap_mgr = SnapManagerBuilder::default()
+        .max_send_bytes_per_sec(cfg.server.snap_max_send_bytes_per_sec.0)
+        .max_recv_bytes_per_sec(cfg.server.snap_max_recv_bytes_per_sec.0)
+        .max_send_snap_count(cfg.server.concurrent_send_snap_limit)
+        .max_recv_snap_count(cfg.server.concurrent_recv_snap_limit)
+        .max_file_size(cfg.raft_store.snap_max_file_size.0)
+        .build(
+            snap_path.as_path().to_str().unwrap().to_owned(),
+            Some(store_sendch),
+        );
     let mut server = Server::new(
         &cfg.server,
         cfg.raft_store.region_split_size.0 as usize,
         storage.clone(),
-        raft_router,
+        raft_router.clone(),
         snap_status_sender,
         resolver,
         snap_mgr.clone(),
+        security_mgr,
     ).unwrap_or_else(|e| exit_with_err(e));
     let trans = server.transport();
 
     // Create raft engine.
     let raft_db_opts = cfg.raftdb.build_opt();
     let raft_db_cf_opts = cfg.raftdb.build_cf_opts();
-    let raft_engine = Arc::new(
-        rocksdb_util::new_engine_opt(
-            raft_db_path.to_str().unwrap(),
-            raft_db_opts,
-            raft_db_cf_opts,
-        ).unwrap_or_else(|s| exit_with_msg(s)),
-    );
+    let raft_engine = open_raft_engine(&cfg.raft_store, |path| {
+        rocksdb_util::new_engine_opt(path, raft_db_opts, raft_db_cf_opts).map_err(Into::into)
+    }).unwrap_or_else(|e| exit_with_err(e));
     // Create node.
     let mut node = Node::new(&mut event_loop, &cfg.server, &cfg.raft_store, pd_client);
     let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
@@ -201,6 +219,7 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
         trans,
         snap_mgr,
         snap_status_receiver,
+        storage.get_read_stats(),
     ).unwrap_or_else(|e| exit_with_err(e));
     initial_metric(&cfg.metric, Some(node.id()));
 
@@ -224,9 +243,40 @@ fn run_raft_server(pd_client: RpcClient, cfg: &TiKvConfig) {
     server
         .start(&cfg.server)
         .unwrap_or_else(|e| exit_with_err(e));
+    let mut status_server = if cfg.server.status_addr.is_empty() {
+        None
+    } else {
+        let mut status_server = new_status_server(cfg);
+        status_server.set_raft_router(raft_router);
+        status_server.set_read_stats(storage.get_read_stats());
+        let grpc_addr = connectable_addr(server.listening_addr());
+        status_server.add_health_check(
+            "grpc",
+            Box::new(move || {
+                TcpStream::connect(grpc_addr)
+                    .map(|_| ())
+                    .map_err(|e| format!("failed to connect to {}: {}", grpc_addr, e))
+            }),
+        );
+        status_server
+            .start(&cfg.server.status_addr)
+            .unwrap_or_else(|e| exit_with_err(e));
+        Some(status_server)
+    };
     signal_handler::handle_signal(engines, &cfg.rocksdb.backup_dir);
 
+    // Transfer leaders away before stopping, so that the regions don't need
+    // to wait for an election timeout.
+    let drain_timeout = cfg.raft_store.leader_drain_timeout.0;
+    if drain_timeout > Duration::from_secs(0) {
+        node.drain_leaders(drain_timeout)
+            .unwrap_or_else(|e| exit_with_err(e));
+    }
+
     // Stop.
+    if let Some(ref mut status_server) = status_server {
+        status_server.stop();
+    }
     server.stop().unwrap_or_else(|e| exit_with_err(e));
 
     metrics_flusher.stop();
@@ -254,6 +304,10 @@ fn overwrite_config_with_cmd_args(config: &mut TiKvConfig, matches: &ArgMatches)
         config.server.advertise_addr = advertise_addr.to_owned();
     }
 
+    if let Some(status_addr) = matches.value_of("status-addr") {
+        config.server.status_addr = status_addr.to_owned();
+    }
+
     if let Some(data_dir) = matches.value_of("data-dir") {
         config.storage.data_dir = data_dir.to_owned();
     }
@@ -332,6 +386,13 @@ fn main() {
                 .value_name("IP:PORT")
                 .help("Sets advertise listening address for client communication"),
         )
+        .arg(
+            Arg::with_name("status-addr")
+                .long("status-addr")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .help("Sets the address of the HTTP status server"),
+        )
         .arg(
             Arg::with_name("log-level")
                 .short("L")
@@ -450,7 +511,12 @@ fn main() {
     // Before any startup, check system configuration.
     check_system_config(&config);
 
-    let pd_client = RpcClient::new(&config.pd.endpoints).unwrap_or_else(|e| exit_with_err(e));
+    let security_mgr = Arc::new(
+        SecurityManager::new(&config.security)
+            .unwrap_or_else(|e| exit_with_msg(format!("failed to create security manager: {}", e))),
+    );
+    let pd_client = RpcClient::new(&config.pd.endpoints, security_mgr.clone())
+        .unwrap_or_else(|e| exit_with_err(e));
     let cluster_id = pd_client
         .get_cluster_id()
         .unwrap_or_else(|e| exit_with_err(e));
@@ -461,5 +527,5 @@ fn main() {
     info!("connect to PD cluster {}", cluster_id);
 
     let _m = Monitor::default();
-    run_raft_server(pd_client, &config);
+    run_raft_server(pd_client, &config, security_mgr);
 }
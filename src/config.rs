@@ -27,6 +27,7 @@ use storage::{Config as StorageConfig, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE, D
               DEFAULT_ROCKSDB_SUB_DIR};
 use util::config::{self, compression_type_level_serde, ReadableDuration, ReadableSize, GB, KB, MB};
 use util::properties::{MvccPropertiesCollectorFactory, SizePropertiesCollectorFactory};
+use util::security::SecurityConfig;
 use util::rocksdb::{db_exist, CFOptions, EventListener, FixedPrefixSliceTransform,
                     FixedSuffixSliceTransform, NoopSliceTransform};
 
@@ -629,6 +630,7 @@ pub struct TiKvConfig {
     pub raft_store: RaftstoreConfig,
     pub rocksdb: DbConfig,
     pub raftdb: RaftDbConfig,
+    pub security: SecurityConfig,
 }
 
 impl Default for TiKvConfig {
@@ -643,6 +645,7 @@ impl Default for TiKvConfig {
             rocksdb: DbConfig::default(),
             raftdb: RaftDbConfig::default(),
             storage: StorageConfig::default(),
+            security: SecurityConfig::default(),
         }
     }
 }
@@ -687,6 +690,7 @@ impl TiKvConfig {
         try!(self.server.validate());
         try!(self.raft_store.validate());
         try!(self.pd.validate());
+        try!(self.security.validate());
         Ok(())
     }
 }
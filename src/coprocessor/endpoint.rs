@@ -14,6 +14,7 @@
 use std::usize;
 use std::time::{Duration, Instant};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::fmt::{self, Debug, Display, Formatter};
 
 use tipb::select::{self, Chunk, DAGRequest, SelectRequest};
@@ -28,7 +29,7 @@ use util::worker::{BatchRunnable, Scheduler};
 use util::collections::HashMap;
 use util::threadpool::{Context, ContextFactory, ThreadPool, DEFAULT_TASKS_PER_TICK};
 use server::OnResponse;
-use storage::{self, engine, Engine, Snapshot, SnapshotStore, Statistics};
+use storage::{self, engine, Engine, ReadStats, Snapshot, SnapshotStore, Statistics};
 use storage::engine::Error as EngineError;
 
 use super::codec::mysql;
@@ -65,6 +66,7 @@ const ENDPOINT_IS_BUSY: &'static str = "endpoint is busy";
 pub struct Host {
     engine: Box<Engine>,
     sched: Scheduler<Task>,
+    read_stats: Arc<ReadStats>,
     reqs: HashMap<u64, Vec<RequestTask>>,
     last_req_id: u64,
     pool: ThreadPool<DummyContext>,
@@ -94,11 +96,17 @@ impl ContextFactory<DummyContext> for DummyContextFactory {
 }
 
 impl Host {
-    pub fn new(engine: Box<Engine>, scheduler: Scheduler<Task>, concurrency: usize) -> Host {
+    pub fn new(
+        engine: Box<Engine>,
+        scheduler: Scheduler<Task>,
+        concurrency: usize,
+        read_stats: Arc<ReadStats>,
+    ) -> Host {
         // TODO: use true ContextFactory instead of DummyContextFactory
         Host {
             engine: engine,
             sched: scheduler,
+            read_stats: read_stats,
             reqs: HashMap::default(),
             last_req_id: 0,
             max_running_task_count: DEFAULT_MAX_RUNNING_TASK_COUNT,
@@ -151,7 +159,7 @@ impl Host {
             COPR_PENDING_REQS
                 .with_label_values(&[type_str, pri_str])
                 .add(1.0);
-            let end_point = TiDbEndPoint::new(snap.clone());
+            let end_point = TiDbEndPoint::new(snap.clone(), self.read_stats.clone());
 
             let pool = match pri {
                 CommandPri::Low => &mut self.low_priority_pool,
@@ -493,11 +501,15 @@ fn respond(resp: Response, mut t: RequestTask) {
 
 pub struct TiDbEndPoint {
     snap: Box<Snapshot>,
+    read_stats: Arc<ReadStats>,
 }
 
 impl TiDbEndPoint {
-    pub fn new(snap: Box<Snapshot>) -> TiDbEndPoint {
-        TiDbEndPoint { snap: snap }
+    pub fn new(snap: Box<Snapshot>, read_stats: Arc<ReadStats>) -> TiDbEndPoint {
+        TiDbEndPoint {
+            snap: snap,
+            read_stats: read_stats,
+        }
     }
 }
 
@@ -513,12 +525,24 @@ impl TiDbEndPoint {
             Ok(CopRequest::DAG(dag)) => self.handle_dag(dag, &mut t),
             Err(err) => Err(err),
         };
+        self.record_read_flow(&t);
         match resp {
             Ok(r) => respond(r, t),
             Err(e) => on_error(e, t),
         }
     }
 
+    // Only the traffic is recorded, the keys scanned by the executors are
+    // unknown here, and the start keys of the ranges are not the hot keys.
+    fn record_read_flow(&self, t: &RequestTask) {
+        let flow = t.statistics.total_read_flow();
+        if flow.read_keys == 0 {
+            return;
+        }
+        self.read_stats
+            .record(t.req.get_context().get_region_id(), &flow, vec![]);
+    }
+
     fn handle_select(&self, sel: SelectRequest, t: &mut RequestTask) -> Result<Response> {
         let snap = SnapshotStore::new(
             self.snap.as_ref(),
@@ -658,7 +682,12 @@ mod tests {
     fn test_req_outdated() {
         let mut worker = Worker::new("test-endpoint");
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
-        let end_point = Host::new(engine, worker.scheduler(), 1);
+        let end_point = Host::new(
+            engine,
+            worker.scheduler(),
+            1,
+            Arc::new(ReadStats::new()),
+        );
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
         let mut task = RequestTask::new(Request::new(), box move |msg| { tx.send(msg).unwrap(); });
@@ -673,7 +702,12 @@ mod tests {
     fn test_too_many_reqs() {
         let mut worker = Worker::new("test-endpoint");
         let engine = engine::new_local_engine(TEMP_DIR, &[]).unwrap();
-        let mut end_point = Host::new(engine, worker.scheduler(), 1);
+        let mut end_point = Host::new(
+            engine,
+            worker.scheduler(),
+            1,
+            Arc::new(ReadStats::new()),
+        );
         end_point.max_running_task_count = 3;
         worker.start_batch(end_point, 30).unwrap();
         let (tx, rx) = mpsc::channel();
@@ -74,6 +74,7 @@ extern crate serde;
 extern crate serde_derive;
 extern crate toml;
 extern crate sys_info;
+extern crate rustc_serialize;
 
 #[macro_use]
 pub mod util;
@@ -22,6 +22,7 @@ use kvproto::metapb;
 use kvproto::pdpb::{self, Member};
 
 use util::{Either, HandyRwLock};
+use util::security::SecurityManager;
 use pd::PdFuture;
 use super::{Error, PdClient, RegionStat, Result};
 use super::util::{check_resp_header, sync_request, validate_endpoints, Inner, LeaderClient};
@@ -35,18 +36,18 @@ pub struct RpcClient {
 }
 
 impl RpcClient {
-    pub fn new(endpoints: &[String]) -> Result<RpcClient> {
+    pub fn new(endpoints: &[String], security_mgr: Arc<SecurityManager>) -> Result<RpcClient> {
         let env = Arc::new(
             EnvBuilder::new()
                 .cq_count(CQ_COUNT)
                 .name_prefix(CLIENT_PREFIX)
                 .build(),
         );
-        let (client, members) = try!(validate_endpoints(env.clone(), endpoints));
+        let (client, members) = try!(validate_endpoints(env.clone(), &security_mgr, endpoints));
 
         Ok(RpcClient {
             cluster_id: members.get_header().get_cluster_id(),
-            leader_client: LeaderClient::new(env, client, members),
+            leader_client: LeaderClient::new(env, security_mgr, client, members),
         })
     }
 
@@ -150,6 +151,27 @@ impl PdClient for RpcClient {
         Ok(resp.take_store())
     }
 
+    fn get_store_async(&self, store_id: u64) -> PdFuture<metapb::Store> {
+        let mut req = pdpb::GetStoreRequest::new();
+        req.set_header(self.header());
+        req.set_store_id(store_id);
+
+        let executor = |client: &RwLock<Inner>, req: pdpb::GetStoreRequest| {
+            let handler = client.rl().client.get_store_async(req);
+            handler
+                .map_err(Error::Grpc)
+                .and_then(|mut resp| {
+                    try!(check_resp_header(resp.get_header()));
+                    Ok(resp.take_store())
+                })
+                .boxed()
+        };
+
+        self.leader_client
+            .request(req, executor, LEADER_CHANGE_RETRY)
+            .execute()
+    }
+
     fn get_cluster_config(&self) -> Result<metapb::Cluster> {
         let mut req = pdpb::GetClusterConfigRequest::new();
         req.set_header(self.header());
@@ -218,6 +240,8 @@ impl PdClient for RpcClient {
         req.set_pending_peers(RepeatedField::from_vec(region_stat.pending_peers));
         req.set_bytes_written(region_stat.written_bytes);
         req.set_keys_written(region_stat.written_keys);
+        req.set_bytes_read(region_stat.read_bytes);
+        req.set_keys_read(region_stat.read_keys);
         req.set_approximate_size(region_stat.approximate_size);
 
         let executor = |client: &RwLock<Inner>, req: pdpb::RegionHeartbeatRequest| {
@@ -325,4 +349,21 @@ impl PdClient for RpcClient {
             .request(req, executor, LEADER_CHANGE_RETRY)
             .execute()
     }
+
+    fn report_inconsistency(
+        &self,
+        region: metapb::Region,
+        peer: metapb::Peer,
+        index: u64,
+    ) -> PdFuture<()> {
+        // pdpb has no request for it yet, so the report is only logged until
+        // PD supports it.
+        warn!(
+            "PD can't handle inconsistency report of peer {:?} at index {}, region {:?}",
+            peer,
+            index,
+            region
+        );
+        future::ok(()).boxed()
+    }
 }
@@ -34,6 +34,8 @@ pub struct RegionStat {
     pub pending_peers: Vec<metapb::Peer>,
     pub written_bytes: u64,
     pub written_keys: u64,
+    pub read_bytes: u64,
+    pub read_keys: u64,
     pub approximate_size: u64,
 }
 
@@ -43,6 +45,8 @@ impl RegionStat {
         pending_peers: Vec<metapb::Peer>,
         written_bytes: u64,
         written_keys: u64,
+        read_bytes: u64,
+        read_keys: u64,
         approximate_size: u64,
     ) -> RegionStat {
         RegionStat {
@@ -50,6 +54,8 @@ impl RegionStat {
             pending_peers: pending_peers,
             written_bytes: written_bytes,
             written_keys: written_keys,
+            read_bytes: read_bytes,
+            read_keys: read_keys,
             approximate_size: approximate_size,
         }
     }
@@ -103,6 +109,9 @@ pub trait PdClient: Send + Sync {
     // Get store information.
     fn get_store(&self, store_id: u64) -> Result<metapb::Store>;
 
+    // Get store information without blocking.
+    fn get_store_async(&self, store_id: u64) -> PdFuture<metapb::Store>;
+
     // Get cluster meta information.
     fn get_cluster_config(&self) -> Result<metapb::Cluster>;
 
@@ -136,4 +145,13 @@ pub trait PdClient: Send + Sync {
 
     // Report pd the split region.
     fn report_split(&self, left: metapb::Region, right: metapb::Region) -> PdFuture<()>;
+
+    // Report pd that the data of the peer is inconsistent with the leader at
+    // the index.
+    fn report_inconsistency(
+        &self,
+        region: metapb::Region,
+        peer: metapb::Peer,
+        index: u64,
+    ) -> PdFuture<()>;
 }
@@ -32,11 +32,13 @@ use kvproto::pdpb_grpc::PdClient;
 use prometheus::HistogramTimer;
 
 use util::{Either, HandyRwLock};
+use util::security::SecurityManager;
 use pd::{Error, PdFuture, Result};
 use pd::metrics::PD_SEND_MSG_HISTOGRAM;
 
 pub struct Inner {
     env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
     pub hb_sender: Either<
         Option<ClientDuplexSender<RegionHeartbeatRequest>>,
         UnboundedSender<RegionHeartbeatRequest>,
@@ -94,6 +96,7 @@ pub struct LeaderClient {
 impl LeaderClient {
     pub fn new(
         env: Arc<Environment>,
+        security_mgr: Arc<SecurityManager>,
         client: PdClient,
         members: GetMembersResponse,
     ) -> LeaderClient {
@@ -102,6 +105,7 @@ impl LeaderClient {
             timer: Timer::default(),
             inner: Arc::new(RwLock::new(Inner {
                 env: env,
+                security_mgr: security_mgr,
                 hb_sender: Either::Left(Some(tx)),
                 hb_receiver: Either::Left(Some(rx)),
                 client: client,
@@ -161,7 +165,7 @@ impl LeaderClient {
 
             let start = Instant::now();
             (
-                try!(try_connect_leader(inner.env.clone(), &inner.members)),
+                try!(try_connect_leader(inner.env.clone(), &inner.security_mgr, &inner.members)),
                 start,
             )
         };
@@ -318,6 +322,7 @@ where
 
 pub fn validate_endpoints(
     env: Arc<Environment>,
+    security_mgr: &SecurityManager,
     endpoints: &[String],
 ) -> Result<(PdClient, GetMembersResponse)> {
     if endpoints.is_empty() {
@@ -334,7 +339,7 @@ pub fn validate_endpoints(
             return Err(box_err!("duplicate PD endpoint {}", ep));
         }
 
-        let (_, resp) = match connect(env.clone(), ep) {
+        let (_, resp) = match connect(env.clone(), security_mgr, ep) {
             Ok(resp) => resp,
             // Ignore failed PD node.
             Err(e) => {
@@ -365,7 +370,7 @@ pub fn validate_endpoints(
 
     match members {
         Some(members) => {
-            let (client, members) = try!(try_connect_leader(env.clone(), &members));
+            let (client, members) = try!(try_connect_leader(env.clone(), security_mgr, &members));
             info!("All PD endpoints are consistent: {:?}", endpoints);
             Ok((client, members))
         }
@@ -373,10 +378,14 @@ pub fn validate_endpoints(
     }
 }
 
-fn connect(env: Arc<Environment>, addr: &str) -> Result<(PdClient, GetMembersResponse)> {
+fn connect(
+    env: Arc<Environment>,
+    security_mgr: &SecurityManager,
+    addr: &str,
+) -> Result<(PdClient, GetMembersResponse)> {
     debug!("connect to PD endpoint: {:?}", addr);
-    let addr = addr.trim_left_matches("http://");
-    let channel = ChannelBuilder::new(env).connect(addr);
+    let addr = addr.trim_left_matches("http://").trim_left_matches("https://");
+    let channel = security_mgr.connect(ChannelBuilder::new(env), addr);
     let client = PdClient::new(channel);
     match client.get_members(GetMembersRequest::new()) {
         Ok(resp) => Ok((client, resp)),
@@ -386,6 +395,7 @@ fn connect(env: Arc<Environment>, addr: &str) -> Result<(PdClient, GetMembersRes
 
 pub fn try_connect_leader(
     env: Arc<Environment>,
+    security_mgr: &SecurityManager,
     previous: &GetMembersResponse,
 ) -> Result<(PdClient, GetMembersResponse)> {
     // Try to connect other members.
@@ -398,7 +408,7 @@ pub fn try_connect_leader(
     let mut resp = None;
     'outer: for i in indexes {
         for ep in members[i].get_client_urls() {
-            match connect(env.clone(), ep.as_str()) {
+            match connect(env.clone(), security_mgr, ep.as_str()) {
                 Ok((_, r)) => {
                     let new_cluster_id = r.get_header().get_cluster_id();
                     if new_cluster_id == cluster_id {
@@ -425,7 +435,7 @@ pub fn try_connect_leader(
     if let Some(resp) = resp {
         let leader = resp.get_leader().clone();
         for ep in leader.get_client_urls() {
-            if let Ok((client, _)) = connect(env.clone(), ep.as_str()) {
+            if let Ok((client, _)) = connect(env.clone(), security_mgr, ep.as_str()) {
                 info!("connect to PD leader {:?}", ep);
                 return Ok((client, resp));
             }
@@ -76,6 +76,9 @@ quick_error! {
         SnapshotTemporarilyUnavailable {
             description("snapshot is temporarily unavailable")
         }
+        LogTemporarilyUnavailable {
+            description("log is temporarily unavailable")
+        }
         Other(err: Box<error::Error + Sync + Send>) {
             from()
             cause(err.as_ref())
@@ -96,6 +99,10 @@ impl cmp::PartialEq for StorageError {
                 &StorageError::SnapshotTemporarilyUnavailable,
                 &StorageError::SnapshotTemporarilyUnavailable,
             ) => true,
+            (
+                &StorageError::LogTemporarilyUnavailable,
+                &StorageError::LogTemporarilyUnavailable,
+            ) => true,
             _ => false,
         }
     }
@@ -155,7 +162,15 @@ mod tests {
             StorageError::SnapshotTemporarilyUnavailable,
             StorageError::SnapshotTemporarilyUnavailable
         );
+        assert_eq!(
+            StorageError::LogTemporarilyUnavailable,
+            StorageError::LogTemporarilyUnavailable
+        );
         assert_ne!(StorageError::Compacted, StorageError::Unavailable);
+        assert_ne!(
+            StorageError::LogTemporarilyUnavailable,
+            StorageError::Unavailable
+        );
         assert_ne!(
             StorageError::Other(box StorageError::Unavailable),
             StorageError::Unavailable
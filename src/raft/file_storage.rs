@@ -0,0 +1,827 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple file-backed implementation of the `Storage` trait.
+//!
+//! All the files live in one directory:
+//!
+//! - `raft.log` is an append-only log of records in the format of
+//!   `| len: u32 | crc32: u32 | payload |`. The first record holds the index
+//!   and term of the entry before the first entry in the log, every following
+//!   record is a batch of entries written by one `append`. A batch with a
+//!   lower first index overwrites the conflicting entries of the previous
+//!   batches. The locations of the entries are indexed in memory, and the
+//!   index is rebuilt by replaying the log on open. A broken last record is
+//!   a partially written tail and is truncated, but a broken record followed
+//!   by others means the log is corrupted and it fails to open.
+//! - `hardstate` and `snapshot` hold the latest `HardState` and snapshot,
+//!   they're replaced atomically by writing a temporary file and renaming it.
+//! - `compact` holds the index and term of the last compacted entry, it's
+//!   replaced in the same atomic way.
+//!
+//! Compacting only replaces `compact`, the compacted entries are skipped when
+//! replaying the log. Once they take more than half of the log, the log is
+//! rewritten atomically with the remaining entries only, so compacting costs
+//! amortized constant time per entry. Applying a snapshot rewrites the log
+//! right away.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crc::crc32;
+use protobuf::{self, Message, MessageStatic};
+use kvproto::eraftpb::{ConfState, Entry, HardState, Snapshot};
+
+use raft::errors::{Error, Result, StorageError};
+use raft::storage::{RaftState, Storage};
+use util::HandyRwLock;
+use util::codec::number::{NumberDecoder, NumberEncoder};
+
+pub const LOG_FILE_NAME: &'static str = "raft.log";
+pub const HARD_STATE_FILE_NAME: &'static str = "hardstate";
+pub const SNAPSHOT_FILE_NAME: &'static str = "snapshot";
+pub const COMPACT_FILE_NAME: &'static str = "compact";
+const TMP_FILE_SUFFIX: &'static str = ".tmp";
+const RECORD_HEADER_SIZE: u64 = 8;
+// The index and term of the dummy entry.
+const BASE_PAYLOAD_SIZE: u64 = 16;
+const BASE_RECORD_SIZE: u64 = RECORD_HEADER_SIZE + BASE_PAYLOAD_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+struct EntryLocation {
+    term: u64,
+    offset: u64,
+    len: u64,
+}
+
+fn encode_record(payload: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+    box_try!(buf.encode_u32_le(payload.len() as u32));
+    box_try!(buf.encode_u32_le(crc32::checksum_ieee(payload)));
+    buf.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Decodes the record at the beginning of `data`, returns the payload, or
+/// `None` if the record is incomplete or broken.
+fn decode_record(data: &[u8]) -> Option<&[u8]> {
+    if (data.len() as u64) < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let mut header = &data[..RECORD_HEADER_SIZE as usize];
+    let len = header.decode_u32_le().unwrap() as u64;
+    let checksum = header.decode_u32_le().unwrap();
+    if RECORD_HEADER_SIZE + len > data.len() as u64 {
+        return None;
+    }
+    let payload = &data[RECORD_HEADER_SIZE as usize..(RECORD_HEADER_SIZE + len) as usize];
+    if crc32::checksum_ieee(payload) != checksum {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Returns true if the broken record at the beginning of `data` can be
+/// partially written when crashed, which means it's the last record. A broken
+/// record followed by others can't be caused by a crash.
+fn is_torn_record(data: &[u8]) -> bool {
+    if (data.len() as u64) < RECORD_HEADER_SIZE {
+        return true;
+    }
+    let len = (&data[..4]).decode_u32_le().unwrap() as u64;
+    RECORD_HEADER_SIZE + len >= data.len() as u64
+}
+
+/// Encodes the entries as a record, returns the offsets of the entries
+/// relative to the beginning of the record.
+fn encode_entries(ents: &[Entry], buf: &mut Vec<u8>) -> Result<Vec<(u64, u64)>> {
+    let mut payload = vec![];
+    let mut locations = Vec::with_capacity(ents.len());
+    box_try!(payload.encode_var_u64(ents.len() as u64));
+    for e in ents {
+        let data = box_try!(e.write_to_bytes());
+        box_try!(payload.encode_var_u64(data.len() as u64));
+        locations.push((payload.len() as u64, data.len() as u64));
+        payload.extend_from_slice(&data);
+    }
+    let base = buf.len() as u64 + RECORD_HEADER_SIZE;
+    try!(encode_record(&payload, buf));
+    Ok(
+        locations
+            .into_iter()
+            .map(|(offset, len)| (base + offset, len))
+            .collect(),
+    )
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    try!(try!(File::open(dir)).sync_all());
+    Ok(())
+}
+
+/// Replaces the file at `path` with `data` atomically.
+fn write_file_atomic(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+    let tmp_path = dir.join(format!("{}{}", name, TMP_FILE_SUFFIX));
+    {
+        let mut f = try!(File::create(&tmp_path));
+        try!(f.write_all(data));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&tmp_path, dir.join(name)));
+    sync_dir(dir)
+}
+
+fn write_msg_atomic<M: Message>(dir: &Path, name: &str, msg: &M) -> Result<()> {
+    let mut buf = vec![];
+    try!(encode_record(&box_try!(msg.write_to_bytes()), &mut buf));
+    write_file_atomic(dir, name, &buf)
+}
+
+fn read_msg<M: Message + MessageStatic>(dir: &Path, name: &str) -> Result<Option<M>> {
+    let path = dir.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    try!(try!(File::open(&path)).read_to_end(&mut data));
+    let payload = match decode_record(&data) {
+        Some(payload) => payload,
+        None => return Err(box_err!("{} is corrupted", path.display())),
+    };
+    let msg = box_try!(protobuf::parse_from_bytes(payload));
+    Ok(Some(msg))
+}
+
+pub struct FileStorageCore {
+    dir: PathBuf,
+    hard_state: HardState,
+    snapshot: Snapshot,
+    // The index and term of the entry before the first entry in the log, which
+    // is the last compacted entry, or the entry of the applied snapshot.
+    dummy_index: u64,
+    dummy_term: u64,
+    // locations[i] is the location of the entry at dummy_index + 1 + i.
+    locations: Vec<EntryLocation>,
+    log: File,
+    log_size: u64,
+}
+
+impl FileStorageCore {
+    fn open(dir: &Path) -> Result<FileStorageCore> {
+        try!(fs::create_dir_all(dir));
+        let hard_state = try!(read_msg(dir, HARD_STATE_FILE_NAME)).unwrap_or_else(HardState::new);
+        let snapshot = try!(read_msg(dir, SNAPSHOT_FILE_NAME)).unwrap_or_else(Snapshot::new);
+        let log_path = dir.join(LOG_FILE_NAME);
+        if !log_path.exists() {
+            // When starting from scratch the log only contains a dummy entry
+            // at term zero.
+            let mut buf = vec![];
+            try!(encode_base(0, 0, &mut buf));
+            try!(write_file_atomic(dir, LOG_FILE_NAME, &buf));
+        }
+        let mut log = try!(OpenOptions::new().read(true).append(true).open(&log_path));
+        let mut data = vec![];
+        try!(log.read_to_end(&mut data));
+
+        let (mut dummy_index, mut dummy_term) = match decode_record(&data) {
+            Some(payload) => try!(decode_base(payload)),
+            None => return Err(box_err!("{} has no valid base", log_path.display())),
+        };
+        let mut offset = BASE_RECORD_SIZE;
+        let mut locations = vec![];
+        while let Some(payload) = decode_record(&data[offset as usize..]) {
+            let base = offset + RECORD_HEADER_SIZE;
+            try!(replay_entries(payload, base, dummy_index, &mut locations));
+            offset = base + payload.len() as u64;
+        }
+        if offset < data.len() as u64 {
+            if !is_torn_record(&data[offset as usize..]) {
+                return Err(box_err!(
+                    "{} is corrupted, the record at {} is broken",
+                    log_path.display(),
+                    offset
+                ));
+            }
+            // The tail may be partially written when crashed.
+            warn!(
+                "{} has a broken tail at {}, truncate {} bytes",
+                log_path.display(),
+                offset,
+                data.len() as u64 - offset
+            );
+            try!(log.set_len(offset));
+            try!(log.sync_all());
+        }
+
+        // Rewriting the log removes the compact state first, so it's always
+        // ahead of the base of the log, and the log must contain the entry.
+        if let Some((index, term)) = try!(read_compact_state(dir)) {
+            if index > dummy_index {
+                let pos = (index - dummy_index) as usize;
+                if locations.get(pos - 1).map_or(true, |l| l.term != term) {
+                    return Err(box_err!(
+                        "{} doesn't contain the compacted entry {} at term {}",
+                        log_path.display(),
+                        index,
+                        term
+                    ));
+                }
+                locations.drain(..pos);
+                dummy_index = index;
+                dummy_term = term;
+            }
+        }
+
+        // Applying a snapshot writes the snapshot before rewriting the log, if
+        // it crashed in between, the log is stale and should be dropped unless
+        // it contains the entry of the snapshot.
+        let mut stale = false;
+        let snap_index = snapshot.get_metadata().get_index();
+        let snap_term = snapshot.get_metadata().get_term();
+        if snap_index > dummy_index {
+            let pos = snap_index - dummy_index - 1;
+            let matched = locations
+                .get(pos as usize)
+                .map_or(false, |l: &EntryLocation| l.term == snap_term);
+            if !matched {
+                dummy_index = snap_index;
+                dummy_term = snap_term;
+                locations.clear();
+                stale = true;
+            }
+        }
+
+        let mut core = FileStorageCore {
+            dir: dir.to_path_buf(),
+            hard_state: hard_state,
+            snapshot: snapshot,
+            dummy_index: dummy_index,
+            dummy_term: dummy_term,
+            locations: locations,
+            log: log,
+            log_size: offset,
+        };
+        if stale {
+            try!(core.rewrite_log());
+        }
+        Ok(core)
+    }
+
+    /// set_hardstate saves the current HardState.
+    pub fn set_hardstate(&mut self, hs: HardState) -> Result<()> {
+        try!(write_msg_atomic(&self.dir, HARD_STATE_FILE_NAME, &hs));
+        self.hard_state = hs;
+        Ok(())
+    }
+
+    fn inner_last_index(&self) -> u64 {
+        self.dummy_index + self.locations.len() as u64
+    }
+
+    fn read_entry(&self, idx: u64) -> Result<Entry> {
+        let loc = self.locations[(idx - self.dummy_index - 1) as usize];
+        self.read_location(loc)
+    }
+
+    fn read_location(&self, loc: EntryLocation) -> Result<Entry> {
+        let mut buf = vec![0; loc.len as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            let n = try!(self.log.read_at(&mut buf[read..], loc.offset + read as u64));
+            if n == 0 {
+                return Err(box_err!("unexpected eof when reading entry at {:?}", loc));
+            }
+            read += n;
+        }
+        let mut e = Entry::new();
+        box_try!(e.merge_from_bytes(&buf));
+        Ok(e)
+    }
+
+    // The size of the compacted entries which are still in the log.
+    fn compacted_size(&self) -> u64 {
+        let start = self.locations.first().map_or(self.log_size, |l| l.offset);
+        start - BASE_RECORD_SIZE
+    }
+
+    /// Rewrites the log with the current dummy entry and entries.
+    fn rewrite_log(&mut self) -> Result<()> {
+        // Otherwise a stale compact state may be ahead of the new base if it
+        // crashes after the rename.
+        let compact_path = self.dir.join(COMPACT_FILE_NAME);
+        if compact_path.exists() {
+            try!(fs::remove_file(&compact_path));
+            try!(sync_dir(&self.dir));
+        }
+
+        let mut buf = vec![];
+        try!(encode_base(self.dummy_index, self.dummy_term, &mut buf));
+        let mut locations = Vec::with_capacity(self.locations.len());
+        if !self.locations.is_empty() {
+            let mut ents = Vec::with_capacity(self.locations.len());
+            for loc in &self.locations {
+                ents.push(try!(self.read_location(*loc)));
+            }
+            for ((offset, len), e) in try!(encode_entries(&ents, &mut buf))
+                .into_iter()
+                .zip(&ents)
+            {
+                locations.push(EntryLocation {
+                    term: e.get_term(),
+                    offset: offset,
+                    len: len,
+                });
+            }
+        }
+        try!(write_file_atomic(&self.dir, LOG_FILE_NAME, &buf));
+        self.log = try!(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(self.dir.join(LOG_FILE_NAME))
+        );
+        self.log_size = buf.len() as u64;
+        self.locations = locations;
+        Ok(())
+    }
+
+    /// apply_snapshot overwrites the contents of this Storage object with
+    /// those of the given snapshot.
+    pub fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        // handle check for old snapshot being applied
+        let index = self.snapshot.get_metadata().get_index();
+        let snapshot_index = snapshot.get_metadata().get_index();
+        if index >= snapshot_index {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        try!(write_msg_atomic(&self.dir, SNAPSHOT_FILE_NAME, &snapshot));
+        self.dummy_index = snapshot_index;
+        self.dummy_term = snapshot.get_metadata().get_term();
+        self.snapshot = snapshot;
+        self.locations.clear();
+        self.rewrite_log()
+    }
+
+    /// create_snapshot makes a snapshot which can be retrieved with snapshot() and
+    /// can be used to reconstruct the state at that point.
+    /// If any configuration changes have been made since the last compaction,
+    /// the result of the last apply_conf_change must be passed in.
+    pub fn create_snapshot(
+        &mut self,
+        idx: u64,
+        cs: Option<ConfState>,
+        data: Vec<u8>,
+    ) -> Result<&Snapshot> {
+        if idx <= self.snapshot.get_metadata().get_index() {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+        if idx > self.inner_last_index() {
+            panic!(
+                "snapshot {} is out of bound lastindex({})",
+                idx,
+                self.inner_last_index()
+            )
+        }
+
+        let mut snapshot = self.snapshot.clone();
+        let term = try!(self.inner_term(idx));
+        snapshot.mut_metadata().set_index(idx);
+        snapshot.mut_metadata().set_term(term);
+        if let Some(cs) = cs {
+            snapshot.mut_metadata().set_conf_state(cs)
+        }
+        snapshot.set_data(data);
+        try!(write_msg_atomic(&self.dir, SNAPSHOT_FILE_NAME, &snapshot));
+        self.snapshot = snapshot;
+        Ok(&self.snapshot)
+    }
+
+    /// compact discards all log entries prior to compact_index.
+    /// It is the application's responsibility to not attempt to compact an index
+    /// greater than RaftLog.applied.
+    pub fn compact(&mut self, compact_index: u64) -> Result<()> {
+        if compact_index <= self.dummy_index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if compact_index > self.inner_last_index() {
+            panic!(
+                "compact {} is out of bound lastindex({})",
+                compact_index,
+                self.inner_last_index()
+            )
+        }
+
+        let skip = (compact_index - self.dummy_index) as usize;
+        let term = self.locations[skip - 1].term;
+        let mut buf = vec![];
+        try!(encode_base(compact_index, term, &mut buf));
+        try!(write_file_atomic(&self.dir, COMPACT_FILE_NAME, &buf));
+        self.dummy_index = compact_index;
+        self.dummy_term = term;
+        self.locations.drain(..skip);
+        if self.compacted_size() * 2 > self.log_size {
+            try!(self.rewrite_log());
+        }
+        Ok(())
+    }
+
+    /// Append the new entries to storage, the entries are synced to the
+    /// disk before returning.
+    pub fn append(&mut self, ents: &[Entry]) -> Result<()> {
+        if ents.is_empty() {
+            return Ok(());
+        }
+        let first = self.dummy_index + 1;
+        let last = ents[0].get_index() + ents.len() as u64 - 1;
+
+        if last < first {
+            return Ok(());
+        }
+        // truncate compacted entries
+        let te: &[Entry] = if first > ents[0].get_index() {
+            let start = (first - ents[0].get_index()) as usize;
+            &ents[start..ents.len()]
+        } else {
+            ents
+        };
+        if te[0].get_index() > self.inner_last_index() + 1 {
+            panic!(
+                "missing log entry [last: {}, append at: {}]",
+                self.inner_last_index(),
+                te[0].get_index()
+            )
+        }
+
+        let mut buf = vec![];
+        let offsets = try!(encode_entries(te, &mut buf));
+        if let Err(e) = self.log.write_all(&buf).and_then(|_| self.log.sync_data()) {
+            // Drop the partial record, otherwise the following records can't
+            // be recovered.
+            let _ = self.log.set_len(self.log_size);
+            return Err(e.into());
+        }
+        let pos = (te[0].get_index() - first) as usize;
+        self.locations.truncate(pos);
+        for ((offset, len), e) in offsets.into_iter().zip(te) {
+            self.locations.push(EntryLocation {
+                term: e.get_term(),
+                offset: self.log_size + offset,
+                len: len,
+            });
+        }
+        self.log_size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn inner_term(&self, idx: u64) -> Result<u64> {
+        if idx < self.dummy_index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if idx == self.dummy_index {
+            return Ok(self.dummy_term);
+        }
+        match self.locations.get((idx - self.dummy_index - 1) as usize) {
+            Some(loc) => Ok(loc.term),
+            None => Err(Error::Store(StorageError::Unavailable)),
+        }
+    }
+}
+
+fn encode_base(index: u64, term: u64, buf: &mut Vec<u8>) -> Result<()> {
+    let mut payload = vec![];
+    box_try!(payload.encode_u64(index));
+    box_try!(payload.encode_u64(term));
+    encode_record(&payload, buf)
+}
+
+fn decode_base(mut payload: &[u8]) -> Result<(u64, u64)> {
+    let index = box_try!(payload.decode_u64());
+    let term = box_try!(payload.decode_u64());
+    Ok((index, term))
+}
+
+/// Reads the index and term of the last compacted entry, which may be not
+/// rewritten out of the log yet.
+fn read_compact_state(dir: &Path) -> Result<Option<(u64, u64)>> {
+    let path = dir.join(COMPACT_FILE_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    try!(try!(File::open(&path)).read_to_end(&mut data));
+    match decode_record(&data) {
+        Some(payload) => decode_base(payload).map(Some),
+        None => Err(box_err!("{} is corrupted", path.display())),
+    }
+}
+
+/// Applies a batch of entries in the log to the index.
+fn replay_entries(
+    mut payload: &[u8],
+    base: u64,
+    dummy_index: u64,
+    locations: &mut Vec<EntryLocation>,
+) -> Result<()> {
+    let total = payload.len() as u64;
+    let count = box_try!(payload.decode_var_u64());
+    for i in 0..count {
+        let len = box_try!(payload.decode_var_u64());
+        if (payload.len() as u64) < len {
+            return Err(box_err!("entry is truncated"));
+        }
+        let mut e = Entry::new();
+        box_try!(e.merge_from_bytes(&payload[..len as usize]));
+        if i == 0 {
+            let last_index = dummy_index + locations.len() as u64;
+            if e.get_index() <= dummy_index || e.get_index() > last_index + 1 {
+                return Err(box_err!(
+                    "unexpected entry {} in log, dummy index {}, last index {}",
+                    e.get_index(),
+                    dummy_index,
+                    last_index
+                ));
+            }
+            locations.truncate((e.get_index() - dummy_index - 1) as usize);
+        }
+        locations.push(EntryLocation {
+            term: e.get_term(),
+            offset: base + total - payload.len() as u64,
+            len: len,
+        });
+        payload = &payload[len as usize..];
+    }
+    Ok(())
+}
+
+/// `FileStorage` is a thread-safe implementation of Storage trait which
+/// persists the raft logs and states in a directory, see the module
+/// document for details.
+#[derive(Clone)]
+pub struct FileStorage {
+    core: Arc<RwLock<FileStorageCore>>,
+}
+
+impl FileStorage {
+    /// Opens the storage in `dir`, creates an empty one if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileStorage> {
+        let core = try!(FileStorageCore::open(dir.as_ref()));
+        Ok(FileStorage {
+            core: Arc::new(RwLock::new(core)),
+        })
+    }
+
+    pub fn rl(&self) -> RwLockReadGuard<FileStorageCore> {
+        self.core.rl()
+    }
+
+    pub fn wl(&self) -> RwLockWriteGuard<FileStorageCore> {
+        self.core.wl()
+    }
+}
+
+impl Storage for FileStorage {
+    /// initial_state implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        let core = self.rl();
+        Ok(RaftState {
+            hard_state: core.hard_state.clone(),
+            conf_state: core.snapshot.get_metadata().get_conf_state().clone(),
+        })
+    }
+
+    /// entries implements the Storage trait.
+    fn entries(&self, low: u64, high: u64, max_size: u64) -> Result<Vec<Entry>> {
+        let core = self.rl();
+        if low <= core.dummy_index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+
+        if high > core.inner_last_index() + 1 {
+            panic!("index out of bound")
+        }
+        // only contains dummy entries.
+        if core.locations.is_empty() {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+
+        let mut ents = Vec::with_capacity(cmp::min(high - low, 64) as usize);
+        let mut size = 0;
+        for idx in low..high {
+            let e = try!(core.read_entry(idx));
+            size += e.compute_size() as u64;
+            if !ents.is_empty() && size > max_size {
+                break;
+            }
+            ents.push(e);
+        }
+        Ok(ents)
+    }
+
+    /// term implements the Storage trait.
+    fn term(&self, idx: u64) -> Result<u64> {
+        self.rl().inner_term(idx)
+    }
+
+    /// first_index implements the Storage trait.
+    fn first_index(&self) -> Result<u64> {
+        Ok(self.rl().dummy_index + 1)
+    }
+
+    /// last_index implements the Storage trait.
+    fn last_index(&self) -> Result<u64> {
+        Ok(self.rl().inner_last_index())
+    }
+
+    /// snapshot implements the Storage trait.
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(self.rl().snapshot.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempdir::TempDir;
+    use kvproto::eraftpb::{ConfState, Entry, HardState, Snapshot};
+    use raft::Result;
+    use raft::storage::Storage;
+    use raft::storage::test::*;
+    use super::*;
+
+    impl TestStorage for FileStorage {
+        fn append(&self, ents: &[Entry]) -> Result<()> {
+            self.wl().append(ents)
+        }
+
+        fn compact(&self, compact_index: u64) -> Result<()> {
+            self.wl().compact(compact_index)
+        }
+
+        fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+            self.wl().apply_snapshot(snapshot)
+        }
+
+        fn create_snapshot(&self, idx: u64, cs: Option<ConfState>, data: Vec<u8>) -> Result<()> {
+            self.wl().create_snapshot(idx, cs, data).map(|_| ())
+        }
+
+        fn all_entries(&self) -> Vec<Entry> {
+            let core = self.rl();
+            let mut ents = vec![new_entry(core.dummy_index, core.dummy_term)];
+            for idx in core.dummy_index + 1..core.inner_last_index() + 1 {
+                ents.push(core.read_entry(idx).unwrap());
+            }
+            ents
+        }
+
+        fn reload(&self) -> FileStorage {
+            FileStorage::open(&self.rl().dir).unwrap()
+        }
+    }
+
+    // Creates every storage in its own sub directory of a temporary directory.
+    struct Storages {
+        path: TempDir,
+        count: usize,
+    }
+
+    impl Storages {
+        fn new(prefix: &str) -> Storages {
+            Storages {
+                path: TempDir::new(prefix).unwrap(),
+                count: 0,
+            }
+        }
+
+        // Creates a storage whose dummy entry is ents[0] and contains the rest.
+        fn create(&mut self, ents: &[Entry]) -> FileStorage {
+            self.count += 1;
+            let storage = FileStorage::open(self.path.path().join(self.count.to_string())).unwrap();
+            if ents[0].get_index() > 0 {
+                let snap = new_snapshot(ents[0].get_index(), ents[0].get_term(), vec![], vec![]);
+                storage.wl().apply_snapshot(snap).unwrap();
+            }
+            storage.wl().append(&ents[1..]).unwrap();
+            storage
+        }
+    }
+
+    #[test]
+    fn test_storage_term() {
+        let mut storages = Storages::new("test-file-storage-term");
+        check_term(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_entries() {
+        let mut storages = Storages::new("test-file-storage-entries");
+        check_entries(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_last_index() {
+        let mut storages = Storages::new("test-file-storage-last-index");
+        check_last_index(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_first_index() {
+        let mut storages = Storages::new("test-file-storage-first-index");
+        check_first_index(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_compact() {
+        let mut storages = Storages::new("test-file-storage-compact");
+        check_compact(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_create_snapshot() {
+        let mut storages = Storages::new("test-file-storage-create-snapshot");
+        check_create_snapshot(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_append() {
+        let mut storages = Storages::new("test-file-storage-append");
+        check_append(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_apply_snapshot() {
+        let mut storages = Storages::new("test-file-storage-apply-snapshot");
+        check_apply_snapshot(|ents| storages.create(ents));
+    }
+
+    #[test]
+    fn test_storage_compact_lazily() {
+        let mut storages = Storages::new("test-file-storage-compact-lazily");
+        let storage = storages.create(&[new_entry(0, 0)]);
+        for i in 1..11 {
+            storage.wl().append(&[new_entry(i, 1)]).unwrap();
+        }
+        let dir = storage.rl().dir.clone();
+        let log_size = |dir: &Path| fs::metadata(dir.join(LOG_FILE_NAME)).unwrap().len();
+        let size = log_size(&dir);
+
+        // Only the compact state is written as less than half of the log is
+        // compacted.
+        storage.wl().compact(3).unwrap();
+        assert_eq!(log_size(&dir), size);
+        assert_eq!(read_compact_state(&dir).unwrap(), Some((3, 1)));
+        let storage = storage.reload();
+        assert_eq!(storage.first_index(), Ok(4));
+        assert_eq!(storage.term(3), Ok(1));
+        assert_eq!(storage.all_entries().len(), 8);
+
+        // The log is rewritten once most of it is compacted.
+        storage.wl().compact(8).unwrap();
+        assert!(log_size(&dir) < size);
+        assert_eq!(read_compact_state(&dir).unwrap(), None);
+        let storage = storage.reload();
+        assert_eq!(storage.first_index(), Ok(9));
+        assert_eq!(storage.term(8), Ok(1));
+        assert_eq!(
+            storage.all_entries(),
+            vec![new_entry(8, 1), new_entry(9, 1), new_entry(10, 1)]
+        );
+
+        // A compact state which doesn't match the log means it's corrupted.
+        let mut buf = vec![];
+        encode_base(9, 2, &mut buf).unwrap();
+        write_file_atomic(&dir, COMPACT_FILE_NAME, &buf).unwrap();
+        assert!(FileStorage::open(&dir).is_err());
+    }
+
+    #[test]
+    fn test_storage_hard_state() {
+        let path = TempDir::new("test-file-storage-hard-state").unwrap();
+        let storage = FileStorage::open(path.path()).unwrap();
+        assert_eq!(storage.initial_state().unwrap().hard_state, HardState::new());
+
+        let mut hs = HardState::new();
+        hs.set_term(3);
+        hs.set_vote(2);
+        hs.set_commit(5);
+        storage.wl().set_hardstate(hs.clone()).unwrap();
+        drop(storage);
+        let storage = FileStorage::open(path.path()).unwrap();
+        assert_eq!(storage.initial_state().unwrap().hard_state, hs);
+    }
+}
@@ -27,6 +27,7 @@
 
 mod raft_log;
 pub mod storage;
+pub mod file_storage;
 mod raft;
 mod progress;
 mod errors;
@@ -34,14 +35,17 @@ mod log_unstable;
 mod status;
 pub mod raw_node;
 mod read_only;
+pub mod observer;
 
 pub use self::storage::{RaftState, Storage};
+pub use self::file_storage::FileStorage;
 pub use self::errors::{Error, Result, StorageError};
 pub use self::raft::{quorum, vote_resp_msg_type, Config, Raft, SoftState, StateRole, INVALID_ID,
                      INVALID_INDEX};
 pub use self::raft_log::{RaftLog, NO_LIMIT};
 pub use self::raw_node::{is_empty_snap, Peer, RawNode, Ready, SnapshotStatus};
-pub use self::status::Status;
+pub use self::status::{FollowerStatus, Status};
+pub use self::observer::{LogConflict, RaftObserver, VoteRejectReason};
 pub use self::log_unstable::Unstable;
 pub use self::progress::{Inflights, Progress, ProgressState};
 pub use self::read_only::{ReadOnlyOption, ReadState};
@@ -0,0 +1,75 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use kvproto::eraftpb::MessageType;
+
+use raft::raft::StateRole;
+
+/// The reasons why a vote request is rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoteRejectReason {
+    /// The request is ignored because the lease of the current leader is not
+    /// expired yet.
+    LeaseNotExpired,
+    /// The request is from a lower term.
+    StaleTerm,
+    /// Already voted for another candidate in the term.
+    AlreadyVoted,
+    /// The log of the candidate is not as up-to-date as the local one.
+    StaleLog,
+    /// The local node has a higher election priority than the candidate.
+    LowerPriority,
+}
+
+/// The kinds of log conflicts found when appending entries from the leader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogConflict {
+    /// The local log doesn't contain the entry before the appended entries,
+    /// the append is rejected.
+    Mismatch,
+    /// The local entries from the index conflict with the appended ones, and
+    /// are overwritten.
+    Truncated,
+}
+
+/// `RaftObserver` is notified of the events of a raft instance for debugging.
+/// All the callbacks are called synchronously in the raft state machine, so
+/// they should be cheap. The term passed to the callbacks is the term of the
+/// local raft after the event.
+pub trait RaftObserver {
+    /// Called when the role of the raft changes, or it becomes a follower
+    /// at a new term.
+    fn on_role_changed(&mut self, _term: u64, _from: StateRole, _to: StateRole) {}
+
+    /// Called when a vote or pre-vote is granted to the candidate.
+    fn on_vote_granted(&mut self, _term: u64, _msg_type: MessageType, _candidate: u64) {}
+
+    /// Called when a vote or pre-vote request from the candidate is rejected
+    /// or ignored.
+    fn on_vote_rejected(
+        &mut self,
+        _term: u64,
+        _msg_type: MessageType,
+        _candidate: u64,
+        _reason: VoteRejectReason,
+    ) {
+    }
+
+    /// Called when the entries appended by the leader conflict with the
+    /// local log at the index.
+    fn on_log_conflict(&mut self, _term: u64, _leader: u64, _index: u64, _conflict: LogConflict) {
+    }
+
+    /// Called when the leader sends a snapshot to the follower.
+    fn on_snapshot_sent(&mut self, _term: u64, _to: u64, _snap_index: u64, _snap_term: u64) {}
+}
@@ -27,6 +27,7 @@
 
 
 use std::cmp;
+use std::time::Instant;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ProgressState {
@@ -70,6 +71,14 @@ pub struct Progress {
     // from the corresponding follower indicates the progress is active.
     // RecentActive can be reset to false after an election timeout.
     pub recent_active: bool,
+    // last_active is the last time the leader received a response from the
+    // corresponding follower, it's only used for debugging. It's accurate to
+    // a tick, as it's the time of the tick before the response.
+    pub last_active: Option<Instant>,
+
+    // pending_fetch is true if the entries to send are being fetched from
+    // the storage asynchronously, they are sent after the fetch is done.
+    pub pending_fetch: bool,
 
     // Inflights is a sliding window for the inflight messages.
     // When inflights is full, no more message should be sent.
@@ -78,6 +87,9 @@ pub struct Progress {
     // into inflights in order.
     // When a leader receives a reply, the previous inflights should
     // be freed by calling inflights.freeTo.
+    // The inflights is also full when the total size of the entries
+    // in flight exceeds its max bytes, so a few huge entries can't
+    // flood a slow follower.
     pub ins: Inflights,
 }
 
@@ -198,27 +210,56 @@ pub struct Inflights {
 
     // ring buffer
     buffer: Vec<u64>,
+    // the sizes of the inflights, indexed the same as buffer
+    sizes: Vec<u64>,
+
+    // total size of the inflights
+    bytes: u64,
+    // max total size of the inflights, 0 means no limit
+    max_bytes: u64,
 }
 
 impl Inflights {
     pub fn new(cap: usize) -> Inflights {
+        Inflights::with_max_bytes(cap, 0)
+    }
+
+    pub fn with_max_bytes(cap: usize, max_bytes: u64) -> Inflights {
         Inflights {
             buffer: Vec::with_capacity(cap),
+            sizes: Vec::with_capacity(cap),
+            max_bytes: max_bytes,
             ..Default::default()
         }
     }
 
-    // full returns true if the inflights is full.
+    // full returns true if the inflights is full, either by the count or
+    // by the total size.
     pub fn full(&self) -> bool {
-        self.count == self.cap()
+        self.count == self.cap() || (self.max_bytes > 0 && self.bytes >= self.max_bytes)
+    }
+
+    // count returns the number of the inflights.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    // bytes returns the total size of the inflights.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
     }
 
     pub fn cap(&self) -> usize {
         self.buffer.capacity()
     }
 
-    // add adds an inflight into inflights
-    pub fn add(&mut self, inflight: u64) {
+    // add adds an inflight of the given size into inflights. The inflight
+    // that makes the total size exceed max bytes is still accepted.
+    pub fn add(&mut self, inflight: u64, size: u64) {
         if self.full() {
             panic!("cannot add into a full inflights")
         }
@@ -230,10 +271,13 @@ impl Inflights {
         assert!(next <= self.buffer.len());
         if next == self.buffer.len() {
             self.buffer.push(inflight);
+            self.sizes.push(size);
         } else {
             self.buffer[next] = inflight;
+            self.sizes[next] = size;
         }
         self.count += 1;
+        self.bytes += size;
     }
 
     // free_to frees the inflights smaller or equal to the given `to` flight.
@@ -250,6 +294,7 @@ impl Inflights {
                 // found the first large inflight
                 break;
             }
+            self.bytes -= self.sizes[idx];
 
 
             // increase index and maybe rotate
@@ -276,5 +321,6 @@ impl Inflights {
     pub fn reset(&mut self) {
         self.count = 0;
         self.start = 0;
+        self.bytes = 0;
     }
 }
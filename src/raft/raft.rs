@@ -27,12 +27,14 @@
 
 
 use std::cmp;
+use std::time::Instant;
 
-use rand::{self, Rng};
+use rand::{self, Rng, SeedableRng, XorShiftRng};
 use kvproto::eraftpb::{Entry, EntryType, HardState, Message, MessageType, Snapshot};
-use protobuf::repeated::RepeatedField;
+use protobuf::{self, RepeatedField};
 
 use raft::storage::Storage;
+use raft::observer::{LogConflict, RaftObserver, VoteRejectReason};
 use raft::progress::{Inflights, Progress, ProgressState};
 use raft::errors::{Error, Result, StorageError};
 use raft::raft_log::{self, RaftLog};
@@ -110,6 +112,14 @@ pub struct Config {
     /// buffer over TCP/UDP. Setting MaxInflightMsgs to avoid overflowing that sending buffer.
     /// TODO: feedback to application to limit the proposal rate?
     pub max_inflight_msgs: usize,
+    /// max_inflight_bytes limits the max total size of the entries in the in-flight
+    /// append messages to each peer during optimistic replication phase, so a few huge
+    /// entries can't flood a slow follower. 0 for no limit.
+    pub max_inflight_bytes: u64,
+    /// max_probe_size_per_msg limits the max size of each append message sent to the
+    /// peers in probe state, which are likely to reject the entries. 0 to use
+    /// max_size_per_msg.
+    pub max_probe_size_per_msg: u64,
 
     /// check_quorum specifies if the leader should check quorum activity. Leader steps down when
     /// quorum is not active for an electionTimeout.
@@ -128,6 +138,17 @@ pub struct Config {
     // May affect proposal forwarding and follower read.
     pub skip_bcast_commit: bool,
 
+    /// witness specifies if the local raft is a witness. A witness votes and
+    /// persists logs like other voters, but never campaigns to be the leader.
+    pub witness: bool,
+
+    /// priority is the election priority of the local raft. The nodes with
+    /// lower priorities wait longer before campaigning, and a node rejects
+    /// the votes for candidates with lower priorities if its log is at least
+    /// as up-to-date as the candidates'. The priorities of other nodes are
+    /// set by `Raft::set_peer_priority`.
+    pub priority: u64,
+
     /// tag is only used for logging
     pub tag: String,
 }
@@ -181,7 +202,9 @@ pub struct Raft<T: Storage> {
     pub raft_log: RaftLog<T>,
 
     pub max_inflight: usize,
+    pub max_inflight_bytes: u64,
     pub max_msg_size: u64,
+    pub max_probe_msg_size: u64,
     pub prs: FlatMap<u64, Progress>,
 
     pub state: StateRole,
@@ -215,27 +238,43 @@ pub struct Raft<T: Storage> {
     pub check_quorum: bool,
     pre_vote: bool,
     skip_bcast_commit: bool,
+    witness: bool,
+
+    priority: u64,
+    // The known election priorities of other nodes.
+    peer_priorities: FlatMap<u64, u64>,
 
     heartbeat_timeout: usize,
     election_timeout: usize,
 
     // randomized_election_timeout is a random number between
-    // [election_timeout, 2 * election_timeout - 1]. It gets reset
-    // when raft changes its state to follower or candidate.
+    // [election_timeout, 2 * election_timeout - 1], plus an extra
+    // election_timeout for each known priority higher than ours. It gets
+    // reset when raft changes its state to follower or candidate.
     randomized_election_timeout: usize,
+    // If set, the randomized election timeout is generated by it instead of
+    // the thread rng, so the elections are deterministic.
+    rng: Option<XorShiftRng>,
+
+    // The time of the last tick. The responses are stamped with it to set
+    // `Progress::last_active`, so the clock isn't read for every message.
+    last_tick: Option<Instant>,
 
     /// Will be called when step** is about to be called.
     /// return false will skip step**.
     pub before_step_state: Option<Box<FnMut(&Message) -> bool>>,
 
+    // Notified of the events for debugging, see `set_observer`.
+    observer: Option<Box<RaftObserver>>,
+
     /// tag is only used for logging
     tag: String,
 }
 
-fn new_progress(next_idx: u64, ins_size: usize) -> Progress {
+fn new_progress(next_idx: u64, ins_size: usize, ins_bytes: u64) -> Progress {
     Progress {
         next_idx: next_idx,
-        ins: Inflights::new(ins_size),
+        ins: Inflights::with_max_bytes(ins_size, ins_bytes),
         ..Default::default()
     }
 }
@@ -287,7 +326,13 @@ impl<T: Storage> Raft<T> {
             read_states: Default::default(),
             raft_log: raft_log,
             max_inflight: c.max_inflight_msgs,
+            max_inflight_bytes: c.max_inflight_bytes,
             max_msg_size: c.max_size_per_msg,
+            max_probe_msg_size: if c.max_probe_size_per_msg == 0 {
+                c.max_size_per_msg
+            } else {
+                c.max_probe_size_per_msg
+            },
             prs: FlatMap::with_capacity(peers.len()),
             state: StateRole::Follower,
             check_quorum: c.check_quorum,
@@ -303,14 +348,21 @@ impl<T: Storage> Raft<T> {
             election_elapsed: Default::default(),
             pending_conf: Default::default(),
             before_step_state: None,
+            observer: None,
             vote: Default::default(),
             heartbeat_elapsed: Default::default(),
             randomized_election_timeout: 0,
+            rng: None,
+            last_tick: Some(Instant::now()),
             skip_bcast_commit: c.skip_bcast_commit,
+            witness: c.witness,
+            priority: c.priority,
+            peer_priorities: Default::default(),
             tag: c.tag.to_owned(),
         };
         for p in peers {
-            r.prs.insert(*p, new_progress(1, r.max_inflight));
+            r.prs
+                .insert(*p, new_progress(1, r.max_inflight, r.max_inflight_bytes));
         }
         if rs.hard_state != HardState::new() {
             r.load_state(rs.hard_state);
@@ -495,6 +547,9 @@ impl<T: Storage> Raft<T> {
             to,
             pr
         );
+        if let Some(ref mut o) = self.observer {
+            o.on_snapshot_sent(self.term, to, sindex, sterm);
+        }
         true
     }
 
@@ -509,8 +564,11 @@ impl<T: Storage> Raft<T> {
             match pr.state {
                 ProgressState::Replicate => {
                     let last = m.get_entries().last().unwrap().get_index();
+                    let size = m.get_entries().iter().fold(0, |size, e| {
+                        size + u64::from(protobuf::Message::compute_size(e))
+                    });
                     pr.optimistic_update(last);
-                    pr.ins.add(last);
+                    pr.ins.add(last, size);
                 }
                 ProgressState::Probe => pr.pause(),
                 _ => panic!(
@@ -529,11 +587,27 @@ impl<T: Storage> Raft<T> {
             if pr.is_paused() {
                 return;
             }
+            // The peer in probe state may reject the entries, don't send too many.
+            let max_size = if pr.state == ProgressState::Probe {
+                self.max_probe_msg_size
+            } else {
+                self.max_msg_size
+            };
             (
                 self.raft_log.term(pr.next_idx - 1),
-                self.raft_log.entries(pr.next_idx, self.max_msg_size),
+                self.raft_log.fetch_entries(pr.next_idx, max_size),
             )
         };
+        if let Err(Error::Store(StorageError::LogTemporarilyUnavailable)) = ents {
+            debug!(
+                "{} entries from {} to {} are being fetched",
+                self.tag,
+                self.prs[&to].next_idx,
+                to
+            );
+            self.prs.get_mut(&to).unwrap().pending_fetch = true;
+            return;
+        }
         let mut m = Message::new();
         m.set_to(to);
         if term.is_err() || ents.is_err() {
@@ -547,6 +621,22 @@ impl<T: Storage> Raft<T> {
         self.send(m);
     }
 
+    /// on_entries_fetched is called when the entries fetched from the storage
+    /// asynchronously are ready, it sends them to the peers waiting for them.
+    pub fn on_entries_fetched(&mut self) {
+        let waiting: Vec<u64> = self.prs
+            .iter()
+            .filter(|&(_, pr)| pr.pending_fetch)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in waiting {
+            self.prs.get_mut(&id).unwrap().pending_fetch = false;
+            if self.state == StateRole::Leader {
+                self.send_append(id);
+            }
+        }
+    }
+
     // send_heartbeat sends an empty MsgAppend
     fn send_heartbeat(&mut self, to: u64, ctx: Option<Vec<u8>>) {
         // Attach the commit as min(to.matched, self.raft_log.committed).
@@ -625,10 +715,14 @@ impl<T: Storage> Raft<T> {
         self.abort_leader_transfer();
 
         self.votes = FlatMap::default();
-        let (last_index, max_inflight) = (self.raft_log.last_index(), self.max_inflight);
+        let (last_index, max_inflight, max_inflight_bytes) = (
+            self.raft_log.last_index(),
+            self.max_inflight,
+            self.max_inflight_bytes,
+        );
         let self_id = self.id;
         for (id, p) in &mut self.prs {
-            *p = new_progress(last_index + 1, max_inflight);
+            *p = new_progress(last_index + 1, max_inflight, max_inflight_bytes);
             if id == &self_id {
                 p.matched = last_index;
             }
@@ -654,6 +748,7 @@ impl<T: Storage> Raft<T> {
 
     /// Returns true to indicate that there will probably be some readiness need to be handled.
     pub fn tick(&mut self) -> bool {
+        self.last_tick = Some(Instant::now());
         match self.state {
             StateRole::Follower | StateRole::PreCandidate | StateRole::Candidate => {
                 self.tick_election()
@@ -710,10 +805,12 @@ impl<T: Storage> Raft<T> {
     }
 
     pub fn become_follower(&mut self, term: u64, leader_id: u64) {
+        let prev = self.state;
         self.reset(term);
         self.leader_id = leader_id;
         self.state = StateRole::Follower;
         info!("{} became follower at term {}", self.tag, self.term);
+        self.notify_role_changed(prev);
     }
 
     // TODO: revoke pub when there is a better way to test.
@@ -723,12 +820,14 @@ impl<T: Storage> Raft<T> {
             StateRole::Leader,
             "invalid transition [leader -> candidate]"
         );
+        let prev = self.state;
         let term = self.term + 1;
         self.reset(term);
         let id = self.id;
         self.vote = id;
         self.state = StateRole::Candidate;
         info!("{} became candidate at term {}", self.tag, self.term);
+        self.notify_role_changed(prev);
     }
 
     pub fn become_pre_candidate(&mut self) {
@@ -740,8 +839,10 @@ impl<T: Storage> Raft<T> {
         // Becoming a pre-candidate changes our state.
         // but doesn't change anything else. In particular it does not increase
         // self.term or change self.vote.
+        let prev = self.state;
         self.state = StateRole::PreCandidate;
         info!("{} became pre-candidate at term {}", self.tag, self.term);
+        self.notify_role_changed(prev);
     }
 
     // TODO: revoke pub when there is a better way to test.
@@ -751,6 +852,7 @@ impl<T: Storage> Raft<T> {
             StateRole::Follower,
             "invalid transition [follower -> leader]"
         );
+        let prev = self.state;
         let term = self.term;
         self.reset(term);
         self.leader_id = self.id;
@@ -769,6 +871,45 @@ impl<T: Storage> Raft<T> {
         }
         self.append_entry(&mut [Entry::new()]);
         info!("{} became leader at term {}", self.tag, self.term);
+        self.notify_role_changed(prev);
+    }
+
+    /// Sets the observer to be notified of the events of the raft, it
+    /// replaces the previous one if any.
+    pub fn set_observer(&mut self, observer: Box<RaftObserver>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn take_observer(&mut self) -> Option<Box<RaftObserver>> {
+        self.observer.take()
+    }
+
+    fn notify_role_changed(&mut self, prev: StateRole) {
+        let (term, state) = (self.term, self.state);
+        if let Some(ref mut o) = self.observer {
+            o.on_role_changed(term, prev, state);
+        }
+    }
+
+    fn notify_vote_granted(&mut self, m: &Message) {
+        let term = self.term;
+        if let Some(ref mut o) = self.observer {
+            o.on_vote_granted(term, m.get_msg_type(), m.get_from());
+        }
+    }
+
+    fn notify_vote_rejected(&mut self, m: &Message, reason: VoteRejectReason) {
+        let term = self.term;
+        if let Some(ref mut o) = self.observer {
+            o.on_vote_rejected(term, m.get_msg_type(), m.get_from(), reason);
+        }
+    }
+
+    fn notify_log_conflict(&mut self, leader: u64, index: u64, conflict: LogConflict) {
+        let term = self.term;
+        if let Some(ref mut o) = self.observer {
+            o.on_log_conflict(term, leader, index, conflict);
+        }
     }
 
     fn num_pending_conf(&self, ents: &[Entry]) -> usize {
@@ -875,6 +1016,7 @@ impl<T: Storage> Raft<T> {
                         self.term,
                         self.election_timeout - self.election_elapsed
                     );
+                    self.notify_vote_rejected(&m, VoteRejectReason::LeaseNotExpired);
 
                     return Ok(());
                 }
@@ -935,6 +1077,11 @@ impl<T: Storage> Raft<T> {
                     m.get_from(),
                     m.get_term()
                 );
+                if m.get_msg_type() == MessageType::MsgRequestVote ||
+                    m.get_msg_type() == MessageType::MsgRequestPreVote
+                {
+                    self.notify_vote_rejected(&m, VoteRejectReason::StaleTerm);
+                }
             }
             return Ok(());
         }
@@ -948,7 +1095,9 @@ impl<T: Storage> Raft<T> {
 
 
         match m.get_msg_type() {
-            MessageType::MsgHup => if self.state != StateRole::Leader {
+            MessageType::MsgHup => if self.witness {
+                debug!("{} ignoring MsgHup because it's a witness", self.tag);
+            } else if self.state != StateRole::Leader {
                 let ents = self.raft_log
                     .slice(
                         self.raft_log.applied + 1,
@@ -983,11 +1132,13 @@ impl<T: Storage> Raft<T> {
             MessageType::MsgRequestVote | MessageType::MsgRequestPreVote => {
                 // The m.get_term() > self.term clause is for MsgRequestPreVote. For MsgRequestVote
                 // m.get_term() should always equal self.term
-                if (self.vote == INVALID_ID || m.get_term() > self.term ||
-                    self.vote == m.get_from()) &&
-                    self.raft_log.is_up_to_date(m.get_index(), m.get_log_term())
-                {
+                let not_voted = self.vote == INVALID_ID || m.get_term() > self.term ||
+                    self.vote == m.get_from();
+                let up_to_date = self.raft_log.is_up_to_date(m.get_index(), m.get_log_term());
+                let can_vote = not_voted && up_to_date;
+                if can_vote && !self.prefer_self_to(&m) {
                     self.log_vote_approve(&m);
+                    self.notify_vote_granted(&m);
                     let mut to_send =
                         new_message(m.get_from(), vote_resp_msg_type(m.get_msg_type()), None);
                     to_send.set_reject(false);
@@ -997,12 +1148,51 @@ impl<T: Storage> Raft<T> {
                         self.election_elapsed = 0;
                         self.vote = m.get_from();
                     }
+                } else if can_vote {
+                    info!(
+                        "{} [logterm: {}, index: {}, priority: {}] rejected {:?} from {} \
+                         [logterm: {}, index: {}] with lower priority at term {}, campaign \
+                         at next tick",
+                        self.tag,
+                        self.raft_log.last_term(),
+                        self.raft_log.last_index(),
+                        self.priority,
+                        m.get_msg_type(),
+                        m.get_from(),
+                        m.get_log_term(),
+                        m.get_index(),
+                        self.term
+                    );
+                    let mut to_send =
+                        new_message(m.get_from(), vote_resp_msg_type(m.get_msg_type()), None);
+                    to_send.set_reject(true);
+                    self.send(to_send);
+                    self.notify_vote_rejected(&m, VoteRejectReason::LowerPriority);
+                    // Campaign soon, so the election is not delayed by the
+                    // rejection if we are alive.
+                    if self.state != StateRole::Leader {
+                        self.election_elapsed = self.randomized_election_timeout;
+                    }
                 } else {
                     self.log_vote_reject(&m);
                     let mut to_send =
                         new_message(m.get_from(), vote_resp_msg_type(m.get_msg_type()), None);
                     to_send.set_reject(true);
                     self.send(to_send);
+                    let reason = if not_voted {
+                        VoteRejectReason::StaleLog
+                    } else {
+                        VoteRejectReason::AlreadyVoted
+                    };
+                    self.notify_vote_rejected(&m, reason);
+                    // A candidate with a higher priority but a stale log can't be
+                    // elected, so don't wait for it.
+                    let higher = self.peer_priorities
+                        .get(&m.get_from())
+                        .map_or(false, |p| *p > self.priority);
+                    if higher && !up_to_date {
+                        self.randomize_election_timeout(0);
+                    }
                 }
             }
             _ => match self.state {
@@ -1054,7 +1244,11 @@ impl<T: Storage> Raft<T> {
         send_append: &mut bool,
         maybe_commit: &mut bool,
     ) {
-        self.prs.get_mut(&m.get_from()).unwrap().recent_active = true;
+        {
+            let pr = self.prs.get_mut(&m.get_from()).unwrap();
+            pr.recent_active = true;
+            pr.last_active = self.last_tick;
+        }
         if m.get_reject() {
             let pr = self.prs.get_mut(&m.get_from()).unwrap();
             debug!(
@@ -1223,6 +1417,7 @@ impl<T: Storage> Raft<T> {
                 {
                     let pr = self.prs.get_mut(&m.get_from()).unwrap();
                     pr.recent_active = true;
+                    pr.last_active = self.last_tick;
                     pr.resume();
 
                     // free one slot for the full inflights window to allow progress.
@@ -1598,6 +1793,20 @@ impl<T: Storage> Raft<T> {
             self.send(to_send);
             return;
         }
+        if self.observer.is_some() {
+            // The conflicting entries are overwritten by maybe_append.
+            let last_index = self.raft_log.last_index();
+            let conflict = m.get_entries()
+                .iter()
+                .take_while(|e| e.get_index() <= last_index)
+                .find(|e| !self.raft_log.match_term(e.get_index(), e.get_term()))
+                .map(|e| e.get_index());
+            if let Some(index) = conflict {
+                if self.raft_log.match_term(m.get_index(), m.get_log_term()) {
+                    self.notify_log_conflict(m.get_from(), index, LogConflict::Truncated);
+                }
+            }
+        }
         let mut to_send = Message::new();
         to_send.set_to(m.get_from());
         to_send.set_msg_type(MessageType::MsgAppendResponse);
@@ -1626,6 +1835,7 @@ impl<T: Storage> Raft<T> {
                 to_send.set_reject(true);
                 to_send.set_reject_hint(self.raft_log.last_index());
                 self.send(to_send);
+                self.notify_log_conflict(m.get_from(), m.get_index(), LogConflict::Mismatch);
             }
         }
     }
@@ -1736,7 +1946,65 @@ impl<T: Storage> Raft<T> {
     // promotable indicates whether state machine can be promoted to leader,
     // which is true when its own id is in progress list.
     pub fn promotable(&self) -> bool {
-        self.prs.contains_key(&self.id)
+        !self.witness && self.prs.contains_key(&self.id)
+    }
+
+    pub fn is_witness(&self) -> bool {
+        self.witness
+    }
+
+    pub fn get_priority(&self) -> u64 {
+        self.priority
+    }
+
+    /// Sets the election priority of the node `id`, the priorities of the
+    /// nodes that are never set are unknown and ignored in elections.
+    pub fn set_peer_priority(&mut self, id: u64, priority: u64) {
+        if id == self.id || self.peer_priorities.get(&id) == Some(&priority) {
+            return;
+        }
+        self.peer_priorities.insert(id, priority);
+        // The higher priorities may change the election timeout.
+        self.reset_randomized_election_timeout();
+    }
+
+    pub fn get_peer_priority(&self, id: u64) -> Option<u64> {
+        if id == self.id {
+            return Some(self.priority);
+        }
+        self.peer_priorities.get(&id).cloned()
+    }
+
+    // priority_delay returns the extra ticks to wait before campaigning, which
+    // is an election timeout for each distinct higher priority of the peers, so
+    // the nodes with higher priorities campaign first. If all of them are down,
+    // the lower ones still campaign after the delay.
+    fn priority_delay(&self) -> usize {
+        let mut higher: Vec<u64> = self.prs
+            .keys()
+            .filter_map(|id| self.peer_priorities.get(id))
+            .filter(|p| **p > self.priority)
+            .cloned()
+            .collect();
+        higher.sort();
+        higher.dedup();
+        higher.len() * self.election_timeout
+    }
+
+    // prefer_self_to returns true if the local node should be elected instead
+    // of the candidate of the vote request m: it has a higher priority than
+    // the candidate and its log is at least as up-to-date as the candidate's.
+    fn prefer_self_to(&self, m: &Message) -> bool {
+        if !self.promotable() || m.get_context() == CAMPAIGN_TRANSFER {
+            return false;
+        }
+        match self.peer_priorities.get(&m.get_from()) {
+            Some(p) if *p < self.priority => {}
+            _ => return false,
+        }
+        let last_term = self.raft_log.last_term();
+        m.get_log_term() < last_term ||
+            (m.get_log_term() == last_term && m.get_index() <= self.raft_log.last_index())
     }
 
     pub fn add_node(&mut self, id: u64) {
@@ -1775,7 +2043,7 @@ impl<T: Storage> Raft<T> {
     }
 
     pub fn set_progress(&mut self, id: u64, matched: u64, next_idx: u64) {
-        let mut p = new_progress(next_idx, self.max_inflight);
+        let mut p = new_progress(next_idx, self.max_inflight, self.max_inflight_bytes);
         p.matched = matched;
         self.prs.insert(id, p);
     }
@@ -1810,9 +2078,25 @@ impl<T: Storage> Raft<T> {
     }
 
     pub fn reset_randomized_election_timeout(&mut self) {
+        let delay = self.priority_delay();
+        self.randomize_election_timeout(delay);
+    }
+
+    /// Makes the randomized election timeouts generated from the seed, which
+    /// must not be all zeros, instead of the thread rng. It's used to run raft
+    /// deterministically in tests.
+    pub fn set_rng_seed(&mut self, seed: [u32; 4]) {
+        self.rng = Some(XorShiftRng::from_seed(seed));
+        self.reset_randomized_election_timeout();
+    }
+
+    fn randomize_election_timeout(&mut self, delay: usize) {
         let prev_timeout = self.randomized_election_timeout;
-        let timeout =
-            self.election_timeout + rand::thread_rng().gen_range(0, self.election_timeout);
+        let jitter = match self.rng {
+            Some(ref mut rng) => rng.gen_range(0, self.election_timeout),
+            None => rand::thread_rng().gen_range(0, self.election_timeout),
+        };
+        let timeout = self.election_timeout + jitter + delay;
         debug!(
             "{} reset election timeout {} -> {} at {}",
             self.tag,
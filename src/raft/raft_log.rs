@@ -287,6 +287,16 @@ impl<T: Storage> RaftLog<T> {
         self.slice(idx, last + 1, max_size)
     }
 
+    /// fetch_entries is like entries, but it may return LogTemporarilyUnavailable
+    /// if the entries are being fetched from the storage asynchronously.
+    pub fn fetch_entries(&self, idx: u64, max_size: u64) -> Result<Vec<Entry>> {
+        let last = self.last_index();
+        if idx > last {
+            return Ok(Vec::new());
+        }
+        self.slice_impl(idx, last + 1, max_size, true)
+    }
+
     pub fn all_entries(&self) -> Vec<Entry> {
         let first_index = self.first_index();
         match self.entries(first_index, NO_LIMIT) {
@@ -379,6 +389,10 @@ impl<T: Storage> RaftLog<T> {
     }
 
     pub fn slice(&self, low: u64, high: u64, max_size: u64) -> Result<Vec<Entry>> {
+        self.slice_impl(low, high, max_size, false)
+    }
+
+    fn slice_impl(&self, low: u64, high: u64, max_size: u64, fetch: bool) -> Result<Vec<Entry>> {
         let err = self.must_check_outofbounds(low, high);
         if err.is_some() {
             return Err(err.unwrap());
@@ -391,12 +405,19 @@ impl<T: Storage> RaftLog<T> {
         }
 
         if low < self.unstable.offset {
-            let stored_entries = self.store
-                .entries(low, cmp::min(high, self.unstable.offset), max_size);
+            let stored_high = cmp::min(high, self.unstable.offset);
+            let stored_entries = if fetch {
+                self.store.fetch_entries(low, stored_high, max_size)
+            } else {
+                self.store.entries(low, stored_high, max_size)
+            };
             if stored_entries.is_err() {
                 let e = stored_entries.unwrap_err();
                 match e {
                     Error::Store(StorageError::Compacted) => return Err(e),
+                    Error::Store(StorageError::LogTemporarilyUnavailable) if fetch => {
+                        return Err(e)
+                    }
                     Error::Store(StorageError::Unavailable) => panic!(
                         "{} entries[{}:{}] is unavailable from storage",
                         self.tag,
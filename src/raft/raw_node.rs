@@ -32,7 +32,7 @@ use protobuf::{self, RepeatedField};
 use kvproto::eraftpb::{ConfChange, ConfChangeType, ConfState, Entry, EntryType, HardState,
                        Message, MessageType, Snapshot};
 use raft::raft::{Config, Raft, SoftState, INVALID_ID};
-use raft::Status;
+use raft::{RaftObserver, Status};
 use raft::read_only::ReadState;
 
 #[derive(Debug, Default)]
@@ -246,11 +246,17 @@ impl<T: Storage> RawNode<T> {
 
     // Propose proposes data be appended to the raft log.
     pub fn propose(&mut self, data: Vec<u8>) -> Result<()> {
+        self.propose_with_context(vec![], data)
+    }
+
+    // ProposeWithContext proposes data along with the context of the entry.
+    pub fn propose_with_context(&mut self, context: Vec<u8>, data: Vec<u8>) -> Result<()> {
         let mut m = Message::new();
         m.set_msg_type(MessageType::MsgPropose);
         m.set_from(self.raft.id);
         let mut e = Entry::new();
         e.set_data(data);
+        e.set_context(context);
         m.set_entries(RepeatedField::from_vec(vec![e]));
         self.raft.step(m)
     }
@@ -383,6 +389,11 @@ impl<T: Storage> RawNode<T> {
         Status::new(&self.raft)
     }
 
+    /// SetObserver sets the observer to be notified of the raft events.
+    pub fn set_observer(&mut self, observer: Box<RaftObserver>) {
+        self.raft.set_observer(observer);
+    }
+
     // ReportUnreachable reports the given node is not reachable for the last send.
     pub fn report_unreachable(&mut self, id: u64) {
         let mut m = Message::new();
@@ -392,6 +403,12 @@ impl<T: Storage> RawNode<T> {
         self.raft.step(m).is_ok();
     }
 
+    /// OnEntriesFetched notifies raft that the entries being fetched by
+    /// `Storage::fetch_entries` are ready, raft sends them again.
+    pub fn on_entries_fetched(&mut self) {
+        self.raft.on_entries_fetched();
+    }
+
     // ReportSnapshot reports the status of the sent snapshot.
     pub fn report_snapshot(&mut self, id: u64, status: SnapshotStatus) {
         let rej = status == SnapshotStatus::Failure;
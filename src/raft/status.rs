@@ -25,6 +25,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::Instant;
+
 use kvproto::eraftpb::HardState;
 
 use raft::raft::{Raft, SoftState, StateRole};
@@ -33,6 +35,19 @@ use raft::progress::Progress;
 
 use super::FlatMap;
 
+// FollowerStatus is the recent activity of a follower seen by the leader.
+#[derive(Debug, Default, Clone)]
+pub struct FollowerStatus {
+    // The last time the leader received a response from the follower, None
+    // if it hasn't responded since the leader was elected. It's accurate to a
+    // tick.
+    pub last_active: Option<Instant>,
+    pub recent_active: bool,
+    // The number and total size of the inflight append messages.
+    pub inflight_count: usize,
+    pub inflight_bytes: u64,
+}
+
 #[derive(Default)]
 pub struct Status {
     pub id: u64,
@@ -40,6 +55,7 @@ pub struct Status {
     pub ss: SoftState,
     pub applied: u64,
     pub progress: FlatMap<u64, Progress>,
+    pub followers: FlatMap<u64, FollowerStatus>,
 }
 
 impl Status {
@@ -54,6 +70,20 @@ impl Status {
         s.applied = raft.raft_log.get_applied();
         if s.ss.raft_state == StateRole::Leader {
             s.progress = raft.prs.clone();
+            for (id, pr) in &raft.prs {
+                if *id == raft.id {
+                    continue;
+                }
+                s.followers.insert(
+                    *id,
+                    FollowerStatus {
+                        last_active: pr.last_active,
+                        recent_active: pr.recent_active,
+                        inflight_count: pr.ins.count(),
+                        inflight_bytes: pr.ins.bytes(),
+                    },
+                );
+            }
         }
         s
     }
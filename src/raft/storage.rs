@@ -51,6 +51,15 @@ pub trait Storage {
     /// max_size limits the total size of the log entries returned, but
     /// entries returns at least one entry if any.
     fn entries(&self, low: u64, high: u64, max_size: u64) -> Result<Vec<Entry>>;
+    /// fetch_entries is like entries, but it's only called when the leader sends
+    /// the entries to followers, so it doesn't need to return them right away.
+    /// If the entries are being fetched asynchronously, it should return
+    /// LogTemporarilyUnavailable, and the application should call
+    /// `RawNode::on_entries_fetched` after the fetch is done, even if it failed,
+    /// so raft could send the entries again.
+    fn fetch_entries(&self, low: u64, high: u64, max_size: u64) -> Result<Vec<Entry>> {
+        self.entries(low, high, max_size)
+    }
     /// term returns the term of entry idx, which must be in the range
     /// [first_index()-1, last_index()]. The term of the entry before
     /// first_index is retained for matching purpose even though the
@@ -76,6 +85,9 @@ pub struct MemStorageCore {
     // TODO: maybe vec_deque
     // entries[i] has raft log position i+snapshot.get_metadata().get_index()
     entries: Vec<Entry>,
+    // If true, fetch_entries returns LogTemporarilyUnavailable to simulate
+    // the entries being fetched asynchronously.
+    log_unavailable: bool,
 }
 
 impl Default for MemStorageCore {
@@ -85,6 +97,7 @@ impl Default for MemStorageCore {
             entries: vec![Entry::new()],
             hard_state: HardState::new(),
             snapshot: Snapshot::new(),
+            log_unavailable: false,
         }
     }
 }
@@ -95,6 +108,12 @@ impl MemStorageCore {
         self.hard_state = hs;
     }
 
+    /// trigger_log_unavailable makes fetch_entries return LogTemporarilyUnavailable
+    /// if unavailable is true.
+    pub fn trigger_log_unavailable(&mut self, unavailable: bool) {
+        self.log_unavailable = unavailable;
+    }
+
     fn inner_last_index(&self) -> u64 {
         self.entries[0].get_index() + self.entries.len() as u64 - 1
     }
@@ -270,6 +289,14 @@ impl Storage for MemStorage {
         Ok(ents)
     }
 
+    /// fetch_entries implements the Storage trait.
+    fn fetch_entries(&self, low: u64, high: u64, max_size: u64) -> Result<Vec<Entry>> {
+        if self.rl().log_unavailable {
+            return Err(Error::Store(StorageError::LogTemporarilyUnavailable));
+        }
+        self.entries(low, high, max_size)
+    }
+
     /// term implements the Storage trait.
     fn term(&self, idx: u64) -> Result<u64> {
         let core = self.rl();
@@ -302,16 +329,29 @@ impl Storage for MemStorage {
     }
 }
 
+/// The contract tests of the `Storage` implementations, the same cases run
+/// against `MemStorage` here and `FileStorage` in its own module.
 #[cfg(test)]
-mod test {
+pub mod test {
     use protobuf;
     use kvproto::eraftpb::{ConfState, Entry, Snapshot};
-    use raft::{Error as RaftError, StorageError};
+    use raft::{Error as RaftError, Result, StorageError};
     use raft::storage::{MemStorage, Storage};
 
-    // TODO extract these duplicated utility functions for tests
+    /// The operations the contract tests need besides the `Storage` trait.
+    pub trait TestStorage: Storage + Sized {
+        fn append(&self, ents: &[Entry]) -> Result<()>;
+        fn compact(&self, compact_index: u64) -> Result<()>;
+        fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()>;
+        fn create_snapshot(&self, idx: u64, cs: Option<ConfState>, data: Vec<u8>) -> Result<()>;
+        /// Returns the dummy entry followed by all the entries in the log.
+        fn all_entries(&self) -> Vec<Entry>;
+        /// Loads the storage again from where it's persisted, so the cases can
+        /// check the changes survive restart.
+        fn reload(&self) -> Self;
+    }
 
-    fn new_entry(index: u64, term: u64) -> Entry {
+    pub fn new_entry(index: u64, term: u64) -> Entry {
         let mut e = Entry::new();
         e.set_term(term);
         e.set_index(index);
@@ -322,7 +362,7 @@ mod test {
         m.compute_size()
     }
 
-    fn new_snapshot(index: u64, term: u64, nodes: Vec<u64>, data: Vec<u8>) -> Snapshot {
+    pub fn new_snapshot(index: u64, term: u64, nodes: Vec<u64>, data: Vec<u8>) -> Snapshot {
         let mut s = Snapshot::new();
         s.mut_metadata().set_index(index);
         s.mut_metadata().set_term(term);
@@ -331,8 +371,10 @@ mod test {
         s
     }
 
-    #[test]
-    fn test_storage_term() {
+    // All the check_* functions take a function that creates a storage whose
+    // dummy entry is ents[0] and which contains the rest of ents.
+
+    pub fn check_term<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
         let mut tests = vec![
             (2, Err(RaftError::Store(StorageError::Compacted))),
@@ -343,8 +385,7 @@ mod test {
         ];
 
         for (i, (idx, wterm)) in tests.drain(..).enumerate() {
-            let storage = MemStorage::new();
-            storage.wl().entries = ents.clone();
+            let storage = new_storage(&ents);
 
             let t = storage.term(idx);
             if t != wterm {
@@ -353,8 +394,7 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_storage_entries() {
+    pub fn check_entries<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![
             new_entry(3, 3),
             new_entry(4, 4),
@@ -413,8 +453,7 @@ mod test {
             ),
         ];
         for (i, (lo, hi, maxsize, wentries)) in tests.drain(..).enumerate() {
-            let storage = MemStorage::new();
-            storage.wl().entries = ents.clone();
+            let storage = new_storage(&ents);
             let e = storage.entries(lo, hi, maxsize);
             if e != wentries {
                 panic!("#{}: expect entries {:?}, got {:?}", i, wentries, e);
@@ -422,11 +461,9 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_storage_last_index() {
+    pub fn check_last_index<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
-        let storage = MemStorage::new();
-        storage.wl().entries = ents;
+        let storage = new_storage(&ents);
 
         let wresult = Ok(5);
         let result = storage.last_index();
@@ -434,22 +471,21 @@ mod test {
             panic!("want {:?}, got {:?}", wresult, result);
         }
 
-        storage
-            .wl()
-            .append(&[new_entry(6, 5)])
-            .expect("append failed");
+        storage.append(&[new_entry(6, 5)]).expect("append failed");
         let wresult = Ok(6);
         let result = storage.last_index();
         if result != wresult {
             panic!("want {:?}, got {:?}", wresult, result);
         }
+        let result = storage.reload().last_index();
+        if result != wresult {
+            panic!("want {:?}, got {:?} after reload", wresult, result);
+        }
     }
 
-    #[test]
-    fn test_storage_first_index() {
+    pub fn check_first_index<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
-        let storage = MemStorage::new();
-        storage.wl().entries = ents;
+        let storage = new_storage(&ents);
 
         let wresult = Ok(4);
         let result = storage.first_index();
@@ -457,16 +493,19 @@ mod test {
             panic!("want {:?}, got {:?}", wresult, result);
         }
 
-        storage.wl().compact(4).expect("compact failed");
+        storage.compact(4).expect("compact failed");
         let wresult = Ok(5);
         let result = storage.first_index();
         if result != wresult {
             panic!("want {:?}, got {:?}", wresult, result);
         }
+        let result = storage.reload().first_index();
+        if result != wresult {
+            panic!("want {:?}, got {:?} after reload", wresult, result);
+        }
     }
 
-    #[test]
-    fn test_storage_compact() {
+    pub fn check_compact<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
         let mut tests = vec![
             (2, Err(RaftError::Store(StorageError::Compacted)), 3, 3, 3),
@@ -475,30 +514,31 @@ mod test {
             (5, Ok(()), 5, 5, 1),
         ];
         for (i, (idx, wresult, windex, wterm, wlen)) in tests.drain(..).enumerate() {
-            let storage = MemStorage::new();
-            storage.wl().entries = ents.clone();
+            let storage = new_storage(&ents);
 
-            let result = storage.wl().compact(idx);
+            let result = storage.compact(idx);
             if result != wresult {
                 panic!("#{}: want {:?}, got {:?}", i, wresult, result);
             }
-            let index = storage.wl().entries[0].get_index();
-            if index != windex {
-                panic!("#{}: want {}, index {}", i, windex, index);
-            }
-            let term = storage.wl().entries[0].get_term();
-            if term != wterm {
-                panic!("#{}: want {}, term {}", i, wterm, term);
-            }
-            let len = storage.wl().entries.len();
-            if len != wlen {
-                panic!("#{}: want {}, term {}", i, wlen, len);
+            // The compaction survives restart.
+            for (j, storage) in vec![storage.reload(), storage].into_iter().enumerate() {
+                let entries = storage.all_entries();
+                let index = entries[0].get_index();
+                if index != windex {
+                    panic!("#{}.{}: want {}, index {}", i, j, windex, index);
+                }
+                let term = entries[0].get_term();
+                if term != wterm {
+                    panic!("#{}.{}: want {}, term {}", i, j, wterm, term);
+                }
+                if entries.len() != wlen {
+                    panic!("#{}.{}: want {}, len {}", i, j, wlen, entries.len());
+                }
             }
         }
     }
 
-    #[test]
-    fn test_storage_create_snapshot() {
+    pub fn check_create_snapshot<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
         let nodes = vec![1, 2, 3];
         let mut cs = ConfState::new();
@@ -510,22 +550,26 @@ mod test {
             (5, Ok(new_snapshot(5, 5, nodes.clone(), data.clone()))),
         ];
         for (i, (idx, wresult)) in tests.drain(..).enumerate() {
-            let storage = MemStorage::new();
-            storage.wl().entries = ents.clone();
+            let storage = new_storage(&ents);
 
             storage
-                .wl()
                 .create_snapshot(idx, Some(cs.clone()), data.clone())
                 .expect("create snapshot failed");
             let result = storage.snapshot();
             if result != wresult {
                 panic!("#{}: want {:?}, got {:?}", i, wresult, result);
             }
+            // Creating a snapshot doesn't compact the log.
+            let storage = storage.reload();
+            let result = storage.snapshot();
+            if result != wresult {
+                panic!("#{}: want {:?}, got {:?} after reload", i, wresult, result);
+            }
+            assert_eq!(storage.all_entries(), ents);
         }
     }
 
-    #[test]
-    fn test_storage_append() {
+    pub fn check_append<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
         let mut tests = vec![
             (
@@ -578,22 +622,25 @@ mod test {
             ),
         ];
         for (i, (entries, wresult, wentries)) in tests.drain(..).enumerate() {
-            let storage = MemStorage::new();
-            storage.wl().entries = ents.clone();
+            let storage = new_storage(&ents);
 
-            let result = storage.wl().append(&entries);
+            let result = storage.append(&entries);
             if result != wresult {
                 panic!("#{}: want {:?}, got {:?}", i, wresult, result);
             }
-            let e = &storage.wl().entries;
-            if *e != wentries {
+            let e = storage.all_entries();
+            if e != wentries {
                 panic!("#{}: want {:?}, entries {:?}", i, wentries, e);
             }
+            // The overwritten entries are dropped after restart too.
+            let e = storage.reload().all_entries();
+            if e != wentries {
+                panic!("#{}: want {:?}, entries {:?} after reload", i, wentries, e);
+            }
         }
     }
 
-    #[test]
-    fn test_storage_apply_snapshot() {
+    pub fn check_apply_snapshot<S: TestStorage, F: FnMut(&[Entry]) -> S>(mut new_storage: F) {
         let nodes = vec![1, 2, 3];
         let data = b"data".to_vec();
 
@@ -602,22 +649,97 @@ mod test {
             new_snapshot(3, 3, nodes.clone(), data.clone()),
         ];
 
-        let storage = MemStorage::new();
+        let storage = new_storage(&[Entry::new()]);
 
         // Apply snapshot successfully
         let i = 0;
         let wresult = Ok(());
-        let r = storage.wl().apply_snapshot(snapshots[i].clone());
+        let r = storage.apply_snapshot(snapshots[i].clone());
         if r != wresult {
             panic!("#{}: want {:?}, got {:?}", i, wresult, r);
         }
+        let storage = storage.reload();
+        assert_eq!(storage.snapshot().unwrap(), snapshots[i]);
+        assert_eq!(storage.all_entries(), vec![new_entry(4, 4)]);
 
         // Apply snapshot fails due to StorageError::SnapshotOutOfDate
         let i = 1;
         let wresult = Err(RaftError::Store(StorageError::SnapshotOutOfDate));
-        let r = storage.wl().apply_snapshot(snapshots[i].clone());
+        let r = storage.apply_snapshot(snapshots[i].clone());
         if r != wresult {
             panic!("#{}: want {:?}, got {:?}", i, wresult, r);
         }
     }
+
+    impl TestStorage for MemStorage {
+        fn append(&self, ents: &[Entry]) -> Result<()> {
+            self.wl().append(ents)
+        }
+
+        fn compact(&self, compact_index: u64) -> Result<()> {
+            self.wl().compact(compact_index)
+        }
+
+        fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+            self.wl().apply_snapshot(snapshot)
+        }
+
+        fn create_snapshot(&self, idx: u64, cs: Option<ConfState>, data: Vec<u8>) -> Result<()> {
+            self.wl().create_snapshot(idx, cs, data).map(|_| ())
+        }
+
+        fn all_entries(&self) -> Vec<Entry> {
+            self.rl().entries.clone()
+        }
+
+        fn reload(&self) -> MemStorage {
+            self.clone()
+        }
+    }
+
+    fn new_storage(ents: &[Entry]) -> MemStorage {
+        let storage = MemStorage::new();
+        storage.wl().entries = ents.to_vec();
+        storage
+    }
+
+    #[test]
+    fn test_storage_term() {
+        check_term(new_storage);
+    }
+
+    #[test]
+    fn test_storage_entries() {
+        check_entries(new_storage);
+    }
+
+    #[test]
+    fn test_storage_last_index() {
+        check_last_index(new_storage);
+    }
+
+    #[test]
+    fn test_storage_first_index() {
+        check_first_index(new_storage);
+    }
+
+    #[test]
+    fn test_storage_compact() {
+        check_compact(new_storage);
+    }
+
+    #[test]
+    fn test_storage_create_snapshot() {
+        check_create_snapshot(new_storage);
+    }
+
+    #[test]
+    fn test_storage_append() {
+        check_append(new_storage);
+    }
+
+    #[test]
+    fn test_storage_apply_snapshot() {
+        check_apply_snapshot(new_storage);
+    }
 }
@@ -11,10 +11,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{ObserverContext, RegionObserver, Result};
+use super::{ObserverContext, RegionChangeEvent, RegionObserver, Result};
 
-use kvproto::raft_cmdpb::RaftCmdRequest;
+use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse};
 use kvproto::metapb::Region;
+use raft::StateRole;
 
 struct ObserverEntry {
     priority: u32,
@@ -29,6 +30,9 @@ pub struct Registry {
 
 impl Registry {
     /// register an Observer to dispatcher.
+    ///
+    /// Observers are called in ascending order of priority, the ones with the
+    /// same priority are called in the order of registration.
     pub fn register_observer(&mut self, priority: u32, ro: Box<RegionObserver + Send + Sync>) {
         ro.start();
         let r = ObserverEntry {
@@ -98,6 +102,58 @@ impl CoprocessorHost {
         }
     }
 
+    /// Call all post apply hook until bypass is set to true.
+    pub fn post_apply(
+        &self,
+        region: &Region,
+        index: u64,
+        req: &RaftCmdRequest,
+        resp: &RaftCmdResponse,
+    ) {
+        if resp.get_header().has_error() {
+            return;
+        }
+        let ctx = ObserverContext::new(region);
+        if req.has_admin_request() {
+            self.execute_hook(ctx, |o, ctx| {
+                o.post_apply_admin(
+                    ctx,
+                    index,
+                    req.get_admin_request(),
+                    resp.get_admin_response(),
+                )
+            });
+        } else {
+            self.execute_hook(ctx, |o, ctx| {
+                o.post_apply_query(ctx, index, req.get_requests(), resp.get_responses())
+            });
+        }
+    }
+
+    /// Call all role change hook until bypass is set to true.
+    pub fn on_role_change(&self, region: &Region, role: StateRole) {
+        let ctx = ObserverContext::new(region);
+        self.execute_hook(ctx, |o, ctx| o.on_role_change(ctx, role));
+    }
+
+    /// Call all region change hook until bypass is set to true.
+    pub fn on_region_changed(&self, region: &Region, event: RegionChangeEvent) {
+        let ctx = ObserverContext::new(region);
+        self.execute_hook(ctx, |o, ctx| o.on_region_changed(ctx, event));
+    }
+
+    fn execute_hook<H>(&self, mut ctx: ObserverContext, mut hook: H)
+    where
+        H: FnMut(&RegionObserver, &mut ObserverContext),
+    {
+        for entry in &self.registry.observers {
+            hook(entry.observer.as_ref(), &mut ctx);
+            if ctx.bypass {
+                break;
+            }
+        }
+    }
+
     pub fn shutdown(&self) {
         for entry in &self.registry.observers {
             entry.observer.stop();
@@ -113,7 +169,9 @@ mod test {
     use protobuf::RepeatedField;
 
     use kvproto::metapb::Region;
-    use kvproto::raft_cmdpb::{AdminRequest, RaftCmdRequest, Request};
+    use kvproto::raft_cmdpb::{AdminRequest, AdminResponse, RaftCmdRequest, RaftCmdResponse,
+                              Request, Response};
+    use raft::StateRole;
 
     struct TestCoprocessor {
         bypass: Arc<AtomicBool>,
@@ -165,6 +223,51 @@ mod test {
             self.called.fetch_add(3, Ordering::SeqCst);
             ctx.bypass = self.bypass.load(Ordering::SeqCst);
         }
+
+        fn post_apply_admin(
+            &self,
+            ctx: &mut ObserverContext,
+            _: u64,
+            _: &AdminRequest,
+            _: &AdminResponse,
+        ) {
+            self.called.fetch_add(4, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn post_apply_query(
+            &self,
+            ctx: &mut ObserverContext,
+            _: u64,
+            _: &[Request],
+            _: &[Response],
+        ) {
+            self.called.fetch_add(5, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn on_role_change(&self, ctx: &mut ObserverContext, _: StateRole) {
+            self.called.fetch_add(6, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+
+        fn on_region_changed(&self, ctx: &mut ObserverContext, _: RegionChangeEvent) {
+            self.called.fetch_add(7, Ordering::SeqCst);
+            ctx.bypass = self.bypass.load(Ordering::SeqCst);
+        }
+    }
+
+    struct OrderObserver {
+        id: u32,
+        order: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Coprocessor for OrderObserver {}
+
+    impl RegionObserver for OrderObserver {
+        fn on_role_change(&self, _: &mut ObserverContext, _: StateRole) {
+            self.order.lock().unwrap().push(self.id);
+        }
     }
 
     fn share_bool() -> Arc<AtomicBool> {
@@ -247,4 +350,58 @@ mod test {
         assert!(host.pre_propose(&region, &mut admin_req).is_err());
         assert_all!(&[&called1, &called2], &[0, 1]);
     }
+
+    #[test]
+    fn test_coprocessor_host_notify() {
+        let (bypass1, called1, r1) = (share_bool(), share_usize(), share_bool());
+        let observer1 = TestCoprocessor::new(bypass1.clone(), called1.clone(), r1.clone());
+        let (bypass2, called2, r2) = (share_bool(), share_usize(), share_bool());
+        let observer2 = TestCoprocessor::new(bypass2.clone(), called2.clone(), r2.clone());
+        let mut host = CoprocessorHost::default();
+        host.registry.register_observer(3, Box::new(observer1));
+        host.registry.register_observer(2, Box::new(observer2));
+        let region = Region::new();
+        let mut admin_req = RaftCmdRequest::new();
+        admin_req.set_admin_request(AdminRequest::new());
+        let mut query_req = RaftCmdRequest::new();
+        query_req.set_requests(RepeatedField::from_vec(vec![Request::new()]));
+        let resp = RaftCmdResponse::new();
+
+        host.post_apply(&region, 1, &admin_req, &resp);
+        assert_all!(&[&called1, &called2], &[4, 4]);
+        host.post_apply(&region, 2, &query_req, &resp);
+        assert_all!(&[&called1, &called2], &[9, 9]);
+
+        // post apply hooks are skipped for failed commands.
+        let mut err_resp = RaftCmdResponse::new();
+        err_resp.mut_header().mut_error().set_message("error".to_owned());
+        host.post_apply(&region, 3, &query_req, &err_resp);
+        assert_all!(&[&called1, &called2], &[9, 9]);
+
+        set_all!(&[&called1, &called2], 0);
+        set_all!(&[&bypass2], true);
+        host.on_role_change(&region, StateRole::Leader);
+        assert_all!(&[&called1, &called2], &[0, 6]);
+        host.on_region_changed(&region, RegionChangeEvent::Split);
+        assert_all!(&[&called1, &called2], &[0, 13]);
+
+        set_all!(&[&bypass2], false);
+        host.on_region_changed(&region, RegionChangeEvent::Destroy);
+        assert_all!(&[&called1, &called2], &[7, 20]);
+    }
+
+    #[test]
+    fn test_coprocessor_host_priority() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut host = CoprocessorHost::default();
+        for &(id, priority) in &[(1, 3), (2, 1), (3, 3), (4, 2)] {
+            let observer = OrderObserver {
+                id: id,
+                order: order.clone(),
+            };
+            host.registry.register_observer(priority, Box::new(observer));
+        }
+        host.on_role_change(&Region::new(), StateRole::Follower);
+        assert_eq!(*order.lock().unwrap(), vec![2, 4, 1, 3]);
+    }
 }
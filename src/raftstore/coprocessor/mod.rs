@@ -19,9 +19,10 @@ mod error;
 pub use self::region_snapshot::{RegionIterator, RegionSnapshot};
 pub use self::dispatcher::{CoprocessorHost, Registry};
 
-use kvproto::raft_cmdpb::{AdminRequest, Request};
+use kvproto::raft_cmdpb::{AdminRequest, AdminResponse, Request, Response};
 use kvproto::metapb::Region;
 use protobuf::RepeatedField;
+use raft::StateRole;
 
 pub use self::error::{Error, Result};
 
@@ -53,6 +54,17 @@ impl<'a> ObserverContext<'a> {
     }
 }
 
+/// The event that changes the region of a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionChangeEvent {
+    /// The region is split, it's triggered for every region after the split.
+    Split,
+    /// The peers of the region are changed.
+    ConfChange,
+    /// The peer of the region on this store is destroyed.
+    Destroy,
+}
+
 /// Observer hook of region level.
 pub trait RegionObserver: Coprocessor {
     /// Hook to call before execute admin request.
@@ -69,4 +81,28 @@ pub trait RegionObserver: Coprocessor {
     ///
     /// Please note that improper implementation can lead to data inconsistency.
     fn pre_apply_query(&self, _: &mut ObserverContext, _: &mut RepeatedField<Request>) {}
+
+    /// Hook to call after admin request is applied successfully at `index`.
+    ///
+    /// Please note that the result may not be persisted yet when it's called.
+    fn post_apply_admin(
+        &self,
+        _: &mut ObserverContext,
+        _: u64,
+        _: &AdminRequest,
+        _: &AdminResponse,
+    ) {
+    }
+
+    /// Hook to call after read/write request is applied successfully at `index`.
+    ///
+    /// Please note that the result may not be persisted yet when it's called.
+    fn post_apply_query(&self, _: &mut ObserverContext, _: u64, _: &[Request], _: &[Response]) {}
+
+    /// Hook to call when the peer becomes leader or follower. It may be called
+    /// with the same role again when the leader of the region changes.
+    fn on_role_change(&self, _: &mut ObserverContext, _: StateRole) {}
+
+    /// Hook to call after the region of the peer is changed.
+    fn on_region_changed(&self, _: &mut ObserverContext, _: RegionChangeEvent) {}
 }
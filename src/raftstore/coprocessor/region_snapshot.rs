@@ -307,7 +307,8 @@ mod tests {
     use raftstore::Result;
     use raftstore::store::engine::*;
     use raftstore::store::keys::*;
-    use raftstore::store::{CacheQueryStats, PeerStorage};
+    use raftstore::store::{CacheQueryStats, PeerStorage, RaftEngine};
+    use raftstore::store::raft_engine::RocksRaftEngine;
     use storage::{CFStatistics, Cursor, Key, ScanMode, ALL_CFS, CF_DEFAULT};
     use util::{escape, rocksdb, worker};
 
@@ -315,19 +316,18 @@ mod tests {
 
     type DataSet = Vec<(Vec<u8>, Vec<u8>)>;
 
-    fn new_temp_engine(path: &TempDir) -> (Arc<DB>, Arc<DB>) {
+    fn new_temp_engine(path: &TempDir) -> (Arc<DB>, Arc<RaftEngine>) {
         let raft_path = path.path().join(Path::new("raft"));
+        let raft_db = rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap();
         (
             Arc::new(
                 rocksdb::new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap(),
             ),
-            Arc::new(
-                rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap(),
-            ),
+            Arc::new(RocksRaftEngine::new(Arc::new(raft_db))),
         )
     }
 
-    fn new_peer_storage(engine: Arc<DB>, raft_engine: Arc<DB>, r: &Region) -> PeerStorage {
+    fn new_peer_storage(engine: Arc<DB>, raft_engine: Arc<RaftEngine>, r: &Region) -> PeerStorage {
         let metrics = Rc::new(RefCell::new(CacheQueryStats::default()));
         PeerStorage::new(
             engine,
@@ -339,7 +339,10 @@ mod tests {
         ).unwrap()
     }
 
-    fn load_default_dataset(engine: Arc<DB>, raft_engine: Arc<DB>) -> (PeerStorage, DataSet) {
+    fn load_default_dataset(
+        engine: Arc<DB>,
+        raft_engine: Arc<RaftEngine>,
+    ) -> (PeerStorage, DataSet) {
         let mut r = Region::new();
         r.mut_peers().push(Peer::new());
         r.set_id(10);
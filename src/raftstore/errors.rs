@@ -133,6 +133,10 @@ quick_error!{
             description(err.description())
             display("Transport {}", err)
         }
+        ServerIsBusy(reason: String) {
+            description("server is busy")
+            display("server is busy, {}", reason)
+        }
     }
 }
 
@@ -186,6 +190,11 @@ impl Into<errorpb::Error> for Error {
                 server_is_busy_err.set_reason(RAFTSTORE_IS_BUSY.to_owned());
                 errorpb.set_server_is_busy(server_is_busy_err);
             }
+            Error::ServerIsBusy(reason) => {
+                let mut server_is_busy_err = errorpb::ServerIsBusy::new();
+                server_is_busy_err.set_reason(reason);
+                errorpb.set_server_is_busy(server_is_busy_err);
+            }
             _ => {}
         };
 
@@ -0,0 +1,271 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Batch split splits a region at many keys in one raft command.
+//!
+//! kvproto has no batch split admin command, so a batch split is proposed
+//! as a `Split` admin request for the first key, and the context of the raft
+//! entry carries all the split requests. The appliers split the region at
+//! all the keys at once, and the response only holds the first and the last
+//! regions, the others are rebuilt from the request.
+//!
+//! The stores which don't know the context would split the region at the
+//! first key only, so it's used only if `use-batch-split` is enabled, which
+//! should be done after all the stores are upgraded. A context which can't be
+//! decoded fails the split on every store.
+
+use std::io::Read;
+
+use protobuf::{self, Message};
+use kvproto::metapb::Region;
+use kvproto::raft_cmdpb::{SplitRequest, SplitResponse};
+
+use raftstore::Result;
+use util::codec::number::{NumberDecoder, NumberEncoder};
+use super::util;
+
+// The first byte of the entry context of a batch split.
+const BATCH_SPLIT_FLAG: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSplitRequest {
+    // Ordered by split key, the new region and peer ids of the region on the
+    // left of the key, or the right one if `right_derive` is false.
+    pub requests: Vec<SplitRequest>,
+    // If true, the rightmost region keeps the origin region id.
+    pub right_derive: bool,
+}
+
+impl BatchSplitRequest {
+    pub fn new(requests: Vec<SplitRequest>, right_derive: bool) -> BatchSplitRequest {
+        BatchSplitRequest {
+            requests: requests,
+            right_derive: right_derive,
+        }
+    }
+
+    /// Encodes the request as the context of a raft entry.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![BATCH_SPLIT_FLAG, self.right_derive as u8];
+        for req in &self.requests {
+            let data = try!(req.write_to_bytes());
+            try!(buf.encode_u64(data.len() as u64));
+            buf.extend_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    /// Decodes the context of a raft entry, returns `None` if it's not a
+    /// batch split.
+    pub fn decode(mut context: &[u8]) -> Result<Option<BatchSplitRequest>> {
+        if context.len() < 2 || context[0] != BATCH_SPLIT_FLAG {
+            return Ok(None);
+        }
+        let right_derive = context[1] != 0;
+        context = &context[2..];
+        let mut requests = vec![];
+        while !context.is_empty() {
+            let len = try!(context.decode_u64()) as usize;
+            let mut data = vec![0; len];
+            try!(context.read_exact(&mut data));
+            requests.push(try!(protobuf::parse_from_bytes(&data)));
+        }
+        Ok(Some(BatchSplitRequest::new(requests, right_derive)))
+    }
+
+    /// Splits the region, returns all the regions ordered by start key and
+    /// the index of the one which keeps the origin region id.
+    pub fn split(&self, region: &Region) -> Result<(Vec<Region>, usize)> {
+        if self.requests.is_empty() {
+            return Err(box_err!("missing split requests"));
+        }
+        let mut last_key = region.get_start_key();
+        for req in &self.requests {
+            let key = req.get_split_key();
+            if key.is_empty() || key <= last_key {
+                return Err(box_err!("invalid split request: {:?}", req));
+            }
+            try!(util::check_key_in_region(key, region));
+            if req.get_new_peer_ids().len() != region.get_peers().len() {
+                return Err(box_err!(
+                    "invalid new peer id count, need {}, but got {}",
+                    region.get_peers().len(),
+                    req.get_new_peer_ids().len()
+                ));
+            }
+            last_key = key;
+        }
+
+        // Every region gets the same version as if it were split one by one.
+        let mut derived = region.clone();
+        let version = region.get_region_epoch().get_version() + self.requests.len() as u64;
+        derived.mut_region_epoch().set_version(version);
+
+        let mut regions = Vec::with_capacity(self.requests.len() + 1);
+        let mut start_key = region.get_start_key().to_vec();
+        if !self.right_derive {
+            derived.set_end_key(self.requests[0].get_split_key().to_vec());
+            start_key = derived.get_end_key().to_vec();
+            regions.push(derived.clone());
+        }
+        for (i, req) in self.requests.iter().enumerate() {
+            let mut new_region = derived.clone();
+            new_region.set_id(req.get_new_region_id());
+            for (peer, &peer_id) in new_region.mut_peers().iter_mut().zip(req.get_new_peer_ids()) {
+                peer.set_id(peer_id);
+            }
+            let end_key = if self.right_derive {
+                req.get_split_key().to_vec()
+            } else if i + 1 < self.requests.len() {
+                self.requests[i + 1].get_split_key().to_vec()
+            } else {
+                region.get_end_key().to_vec()
+            };
+            new_region.set_start_key(start_key);
+            new_region.set_end_key(end_key);
+            start_key = new_region.get_end_key().to_vec();
+            regions.push(new_region);
+        }
+        let derived_index = if self.right_derive {
+            derived.set_start_key(start_key);
+            derived.set_end_key(region.get_end_key().to_vec());
+            regions.push(derived);
+            regions.len() - 1
+        } else {
+            0
+        };
+        Ok((regions, derived_index))
+    }
+
+    /// Rebuilds all the regions from the response, which holds the first
+    /// and the last regions.
+    pub fn regions_from_response(&self, resp: &SplitResponse) -> Result<Vec<Region>> {
+        let mut region = if self.right_derive {
+            resp.get_right().clone()
+        } else {
+            resp.get_left().clone()
+        };
+        region.set_start_key(resp.get_left().get_start_key().to_vec());
+        region.set_end_key(resp.get_right().get_end_key().to_vec());
+        let version = region.get_region_epoch().get_version() - self.requests.len() as u64;
+        region.mut_region_epoch().set_version(version);
+        self.split(&region).map(|(regions, _)| regions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb::Region;
+    use kvproto::raft_cmdpb::{SplitRequest, SplitResponse};
+    use raftstore::store::util::new_peer;
+
+    use super::*;
+
+    fn new_split_request(key: &[u8], region_id: u64, peer_ids: Vec<u64>) -> SplitRequest {
+        let mut req = SplitRequest::new();
+        req.set_split_key(key.to_vec());
+        req.set_new_region_id(region_id);
+        req.set_new_peer_ids(peer_ids);
+        req
+    }
+
+    fn new_region() -> Region {
+        let mut region = Region::new();
+        region.set_id(1);
+        region.set_start_key(b"a".to_vec());
+        region.set_end_key(b"z".to_vec());
+        region.mut_peers().push(new_peer(1, 2));
+        region.mut_peers().push(new_peer(2, 3));
+        region.mut_region_epoch().set_version(5);
+        region.mut_region_epoch().set_conf_ver(3);
+        region
+    }
+
+    fn check_regions(regions: &[Region], ids: &[u64], keys: &[&[u8]]) {
+        assert_eq!(regions.len(), ids.len());
+        for (i, r) in regions.iter().enumerate() {
+            assert_eq!(r.get_id(), ids[i]);
+            assert_eq!(r.get_start_key(), keys[i]);
+            assert_eq!(r.get_end_key(), keys[i + 1]);
+            assert_eq!(r.get_region_epoch().get_version(), 7);
+            assert_eq!(r.get_region_epoch().get_conf_ver(), 3);
+            let stores: Vec<_> = r.get_peers().iter().map(|p| p.get_store_id()).collect();
+            assert_eq!(stores, vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_batch_split() {
+        let region = new_region();
+        let requests = vec![
+            new_split_request(b"k", 10, vec![11, 12]),
+            new_split_request(b"p", 20, vec![21, 22]),
+        ];
+        let keys: Vec<&[u8]> = vec![b"a", b"k", b"p", b"z"];
+
+        let req = BatchSplitRequest::new(requests.clone(), false);
+        let (regions, derived) = req.split(&region).unwrap();
+        assert_eq!(derived, 0);
+        check_regions(&regions, &[1, 10, 20], &keys);
+        assert_eq!(regions[0].get_peers(), region.get_peers());
+        assert_eq!(regions[2].get_peers(), &[new_peer(1, 21), new_peer(2, 22)]);
+
+        let mut resp = SplitResponse::new();
+        resp.set_left(regions[0].clone());
+        resp.set_right(regions[2].clone());
+        assert_eq!(req.regions_from_response(&resp).unwrap(), regions);
+
+        let req = BatchSplitRequest::new(requests.clone(), true);
+        let (regions, derived) = req.split(&region).unwrap();
+        assert_eq!(derived, 2);
+        check_regions(&regions, &[10, 20, 1], &keys);
+        assert_eq!(regions[0].get_peers(), &[new_peer(1, 11), new_peer(2, 12)]);
+        assert_eq!(regions[2].get_peers(), region.get_peers());
+
+        let mut resp = SplitResponse::new();
+        resp.set_left(regions[0].clone());
+        resp.set_right(regions[2].clone());
+        assert_eq!(req.regions_from_response(&resp).unwrap(), regions);
+
+        // The keys must be ordered and in the region.
+        let invalid = vec![
+            vec![],
+            vec![new_split_request(b"p", 10, vec![11, 12]), requests[0].clone()],
+            vec![new_split_request(b"a", 10, vec![11, 12])],
+            vec![new_split_request(b"zz", 10, vec![11, 12])],
+            vec![new_split_request(b"k", 10, vec![11])],
+        ];
+        for requests in invalid {
+            let req = BatchSplitRequest::new(requests, false);
+            assert!(req.split(&region).is_err(), "{:?}", req);
+        }
+    }
+
+    #[test]
+    fn test_batch_split_codec() {
+        let requests = vec![
+            new_split_request(b"k", 10, vec![11, 12]),
+            new_split_request(b"p", 20, vec![21, 22]),
+        ];
+        for &right_derive in &[false, true] {
+            let req = BatchSplitRequest::new(requests.clone(), right_derive);
+            let context = req.encode().unwrap();
+            assert_eq!(BatchSplitRequest::decode(&context).unwrap(), Some(req));
+        }
+
+        assert_eq!(BatchSplitRequest::decode(b"").unwrap(), None);
+        assert_eq!(BatchSplitRequest::decode(b"\x00\x01").unwrap(), None);
+        let context = BatchSplitRequest::new(requests, false).encode().unwrap();
+        assert!(BatchSplitRequest::decode(&context[..context.len() - 1]).is_err());
+    }
+}
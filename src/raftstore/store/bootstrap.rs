@@ -16,8 +16,10 @@ use kvproto::raft_serverpb::{RegionLocalState, StoreIdent};
 use kvproto::metapb;
 use raftstore::Result;
 use super::keys;
-use super::engine::{Iterable, Mutable};
-use super::peer_storage::{write_initial_apply_state, write_initial_raft_state};
+use super::engine::{Iterable, Mutable, Peekable};
+use super::peer_storage::{write_initial_apply_state, write_initial_raft_state,
+                          RAFT_INIT_LOG_INDEX};
+use super::raft_engine::RaftLogBatch;
 use super::store::Engines;
 use util::rocksdb;
 use storage::{CF_DEFAULT, CF_RAFT};
@@ -49,12 +51,7 @@ pub fn bootstrap_store(engines: &Engines, cluster_id: u64, store_id: u64) -> Res
         return Err(box_err!("kv store is not empty and has already had data."));
     }
 
-    if !try!(is_range_empty(
-        &engines.raft_engine,
-        CF_DEFAULT,
-        keys::MIN_KEY,
-        keys::MAX_KEY
-    )) {
+    if !try!(engines.raft_engine.is_empty()) {
         return Err(box_err!(
             "raft store is not empty and has already had data."
         ));
@@ -68,6 +65,36 @@ pub fn bootstrap_store(engines: &Engines, cluster_id: u64, store_id: u64) -> Res
     engines.kv_engine.put_msg(&ident_key, &ident)
 }
 
+// Check the witness label of the store against the persisted flag. A witness
+// has no data, so the peers on the store can't be changed to or from
+// witnesses by relabeling the store. The flag is persisted if it's not set
+// yet or the store has no peers.
+pub fn check_store_witness(kv_engine: &DB, witness: bool) -> Result<()> {
+    let key = keys::store_witness_key();
+    if let Some(v) = try!(kv_engine.get_value_cf(CF_RAFT, &key)) {
+        let persisted = v.first() == Some(&1);
+        if persisted == witness {
+            return Ok(());
+        }
+        if !try!(is_range_empty(
+            kv_engine,
+            CF_RAFT,
+            keys::REGION_META_MIN_KEY,
+            keys::REGION_META_MAX_KEY
+        )) {
+            return Err(box_err!(
+                "the store is labeled witness = {}, but its peers were created with \
+                 witness = {}, a store with peers can't change the witness label",
+                witness,
+                persisted
+            ));
+        }
+    }
+    let handle = try!(rocksdb::get_cf_handle(kv_engine, CF_RAFT));
+    try!(kv_engine.put_cf(handle, &key, &[witness as u8]));
+    Ok(())
+}
+
 // Write first region meta and prepare state.
 pub fn write_prepare_bootstrap(engines: &Engines, region: &metapb::Region) -> Result<()> {
     let mut state = RegionLocalState::new();
@@ -87,10 +114,10 @@ pub fn write_prepare_bootstrap(engines: &Engines, region: &metapb::Region) -> Re
         region.get_id()
     ));
 
-    let raft_wb = WriteBatch::new();
-    try!(write_initial_raft_state(&raft_wb, region.get_id()));
+    let mut raft_wb = RaftLogBatch::new();
+    try!(write_initial_raft_state(&mut raft_wb, region.get_id()));
     try!(engines.kv_engine.write(wb));
-    try!(engines.raft_engine.write(raft_wb));
+    try!(engines.raft_engine.write(raft_wb, false));
     Ok(())
 }
 
@@ -104,7 +131,9 @@ pub fn clear_prepare_bootstrap(engines: &Engines, region_id: u64) -> Result<()>
     try!(wb.delete_cf(handle, &keys::region_state_key(region_id)));
     try!(wb.delete_cf(handle, &keys::apply_state_key(region_id)));
 
-    try!(engines.raft_engine.delete(&keys::raft_state_key(region_id)));
+    let mut raft_wb = RaftLogBatch::new();
+    raft_wb.clean(region_id, RAFT_INIT_LOG_INDEX);
+    try!(engines.raft_engine.write(raft_wb, false));
     try!(engines.kv_engine.write(wb));
     Ok(())
 }
@@ -149,6 +178,7 @@ mod tests {
     use util::rocksdb;
     use raftstore::store::engine::Peekable;
     use raftstore::store::{keys, Engines};
+    use raftstore::store::raft_engine::{RaftEngine, RocksRaftEngine};
     use storage::CF_DEFAULT;
 
     #[test]
@@ -158,9 +188,8 @@ mod tests {
         let kv_engine = Arc::new(
             rocksdb::new_engine(path.path().to_str().unwrap(), &[CF_DEFAULT, CF_RAFT]).unwrap(),
         );
-        let raft_engine = Arc::new(
-            rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap(),
-        );
+        let raft_db = rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let raft_engine: Arc<RaftEngine> = Arc::new(RocksRaftEngine::new(Arc::new(raft_db)));
         let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
 
         assert!(bootstrap_store(&engines, 1, 1).is_ok());
@@ -185,12 +214,7 @@ mod tests {
                 .unwrap()
                 .is_some()
         );
-        assert!(
-            raft_engine
-                .get_value(&keys::raft_state_key(1))
-                .unwrap()
-                .is_some()
-        );
+        assert!(raft_engine.get_raft_state(1).unwrap().is_some());
 
         assert!(clear_prepare_bootstrap_state(&engines).is_ok());
         assert!(clear_prepare_bootstrap(&engines, 1).is_ok());
@@ -202,13 +226,35 @@ mod tests {
                 &keys::region_meta_prefix(2)
             ).unwrap()
         );
-        assert!(
-            is_range_empty(
-                &raft_engine,
-                CF_DEFAULT,
-                &keys::region_raft_prefix(1),
-                &keys::region_raft_prefix(2)
-            ).unwrap()
+        assert!(raft_engine.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_check_store_witness() {
+        let path = TempDir::new("var").unwrap();
+        let raft_path = path.path().join("raft");
+        let kv_engine = Arc::new(
+            rocksdb::new_engine(path.path().to_str().unwrap(), &[CF_DEFAULT, CF_RAFT]).unwrap(),
+        );
+        let raft_db = rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let raft_engine: Arc<RaftEngine> = Arc::new(RocksRaftEngine::new(Arc::new(raft_db)));
+        let engines = Engines::new(kv_engine.clone(), raft_engine.clone());
+
+        // The label can be changed before the store has any peer.
+        check_store_witness(&kv_engine, false).unwrap();
+        check_store_witness(&kv_engine, true).unwrap();
+        check_store_witness(&kv_engine, true).unwrap();
+
+        prepare_bootstrap(&engines, 1, 1, 1).unwrap();
+        check_store_witness(&kv_engine, true).unwrap();
+        assert!(check_store_witness(&kv_engine, false).is_err());
+        assert_eq!(
+            kv_engine
+                .get_value_cf(CF_RAFT, &keys::store_witness_key())
+                .unwrap()
+                .unwrap()
+                .to_vec(),
+            vec![1]
         );
     }
 }
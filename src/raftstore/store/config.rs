@@ -17,6 +17,8 @@ use time::Duration as TimeDuration;
 
 use raftstore::Result;
 use util::config::{ReadableDuration, ReadableSize};
+use super::consistency::ConsistencyCheckRule;
+use super::raft_engine::{RAFT_ENGINE_ROCKSDB, RAFT_ENGINE_SEGMENT};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -25,6 +27,17 @@ pub struct Config {
     // true for high reliability, prevent data loss when power failure.
     pub sync_log: bool,
     pub raftdb_path: String,
+    // The engine storing the raft logs in raftdb_path, "rocksdb" or "segment".
+    pub raft_log_engine: String,
+    // The segment engine switches to a new file when the current one
+    // exceeds the size.
+    pub raft_log_segment_size: ReadableSize,
+    // The segment engine syncs the files after so many bytes are written
+    // even if sync_log is false.
+    pub raft_log_bytes_per_sync: ReadableSize,
+    // A segment file expires once there are so many newer files, the live
+    // logs in it are rewritten to the newest file so it can be purged.
+    pub raft_log_segment_expire_count: u64,
 
     // store capacity. 0 means no limit.
     pub capacity: ReadableSize,
@@ -35,8 +48,16 @@ pub struct Config {
     pub raft_election_timeout_ticks: usize,
     pub raft_max_size_per_msg: ReadableSize,
     pub raft_max_inflight_msgs: usize,
+    // The max total size of the in-flight entries to each follower, 0 means no limit.
+    pub raft_max_inflight_bytes: ReadableSize,
+    // The max size of each append message to the followers being probed, 0 means
+    // using raft_max_size_per_msg.
+    pub raft_max_probe_size_per_msg: ReadableSize,
     // When the entry exceed the max size, reject to propose it.
     pub raft_entry_max_size: ReadableSize,
+    // The memory budget of the raft entry caches of all peers on the store,
+    // entries are evicted from the caches when it's exceeded.
+    pub raft_entry_cache_limit: ReadableSize,
 
     // Interval to gc unnecessary raft log (ms).
     pub raft_log_gc_tick_interval: ReadableDuration,
@@ -83,9 +104,29 @@ pub struct Config {
     pub max_leader_missing_duration: ReadableDuration,
 
     pub snap_apply_batch_size: ReadableSize,
+    /// The max number of snapshots applied at the same time.
+    pub concurrent_apply_snap_limit: usize,
+    /// A cf larger than the size is split into several files when
+    /// generating snapshot, 0 means never split. Stores older than snapshot
+    /// version 3 can't receive split snapshots, so only enable it after all
+    /// the stores are upgraded.
+    pub snap_max_file_size: ReadableSize,
+    /// Let the leader ask an up-to-date follower to generate and send snapshots.
+    pub delegate_snap_generation: bool,
+    /// The leader falls back to generating the snapshot itself if the follower
+    /// doesn't respond within the duration.
+    pub delegate_snap_generation_timeout: ReadableDuration,
 
     // Interval (ms) to check region whether the data is consistent.
     pub consistency_check_interval: ReadableDuration,
+    // A region is checked again only when it hasn't been checked within the
+    // duration, 0 means the region checked least recently is always picked.
+    pub consistency_check_region_interval: ReadableDuration,
+    // The speed of scanning data to compute region hashes, 0 means no limit.
+    pub consistency_check_bytes_per_sec: ReadableSize,
+    // Stop serving the regions whose data is inconsistent with two different
+    // leaders, otherwise the inconsistency is only reported.
+    pub consistency_check_quarantine: bool,
 
     pub report_region_flow_interval: ReadableDuration,
 
@@ -94,8 +135,40 @@ pub struct Config {
 
     // Right region derive origin region id when split.
     pub right_derive_when_split: bool,
+    // Split a region at many keys in one raft command. Stores older than the
+    // batch split ignore all the keys but the first one and diverge, so it
+    // must be enabled only after all the stores are upgraded. If disabled,
+    // the region is split at the keys one by one.
+    pub use_batch_split: bool,
 
     pub allow_remove_leader: bool,
+
+    /// Before shutdown, the store transfers its leaders to other stores and
+    /// waits at most the duration, 0 means shutdown immediately.
+    pub leader_drain_timeout: ReadableDuration,
+
+    /// An online unsafe recovery gives up removing the failed peers of a
+    /// region if it isn't finished within the duration.
+    pub unsafe_recovery_timeout: ReadableDuration,
+
+    /// New writes are delayed when the committed but not yet applied entries
+    /// of all regions exceed the count, 0 means no limit.
+    pub apply_backlog_limit: u64,
+    /// New writes are delayed when the pending compaction bytes of the kv
+    /// engine exceed the size, 0 means no limit.
+    pub pending_compaction_bytes_limit: ReadableSize,
+    /// New writes are delayed when a column family of the kv engine has more
+    /// level 0 files than the count, 0 means no limit.
+    pub level0_files_limit: u64,
+    /// The interval to refresh the compaction pressure of the kv engine.
+    pub compaction_pressure_check_interval: ReadableDuration,
+    /// The delayed writes are rejected with `ServerIsBusy` if they wait
+    /// longer than the duration, 0 means rejecting them at once.
+    pub write_stall_max_delay: ReadableDuration,
+
+    // Overrides the consistency check of the regions in some tables or key
+    // ranges, the first matched rule takes effect.
+    pub consistency_check_rules: Vec<ConsistencyCheckRule>,
 }
 
 impl Default for Config {
@@ -104,13 +177,20 @@ impl Default for Config {
         Config {
             sync_log: true,
             raftdb_path: String::new(),
+            raft_log_engine: RAFT_ENGINE_ROCKSDB.to_owned(),
+            raft_log_segment_size: ReadableSize::mb(128),
+            raft_log_bytes_per_sync: ReadableSize::mb(1),
+            raft_log_segment_expire_count: 8,
             capacity: ReadableSize(0),
             raft_base_tick_interval: ReadableDuration::secs(1),
             raft_heartbeat_ticks: 2,
             raft_election_timeout_ticks: 10,
             raft_max_size_per_msg: ReadableSize::mb(1),
             raft_max_inflight_msgs: 256,
+            raft_max_inflight_bytes: ReadableSize(0),
+            raft_max_probe_size_per_msg: ReadableSize(0),
             raft_entry_max_size: ReadableSize::mb(8),
+            raft_entry_cache_limit: ReadableSize::gb(1),
             raft_log_gc_tick_interval: ReadableDuration::secs(10),
             raft_log_gc_threshold: 50,
             // Assume the average size of entries is 1k.
@@ -132,15 +212,32 @@ impl Default for Config {
             max_peer_down_duration: ReadableDuration::minutes(5),
             max_leader_missing_duration: ReadableDuration::hours(2),
             snap_apply_batch_size: ReadableSize::mb(10),
+            concurrent_apply_snap_limit: 1,
+            snap_max_file_size: ReadableSize(0),
+            delegate_snap_generation: false,
+            delegate_snap_generation_timeout: ReadableDuration::minutes(5),
             lock_cf_compact_interval: ReadableDuration::minutes(10),
             lock_cf_compact_bytes_threshold: ReadableSize::mb(256),
             // Disable consistency check by default as it will hurt performance.
             // We should turn on this only in our tests.
             consistency_check_interval: ReadableDuration::secs(0),
+            consistency_check_region_interval: ReadableDuration::secs(0),
+            consistency_check_bytes_per_sec: ReadableSize::mb(64),
+            consistency_check_quarantine: false,
             report_region_flow_interval: ReadableDuration::minutes(1),
             raft_store_max_leader_lease: ReadableDuration::secs(9),
             right_derive_when_split: true,
+            use_batch_split: false,
             allow_remove_leader: false,
+            leader_drain_timeout: ReadableDuration::secs(30),
+            unsafe_recovery_timeout: ReadableDuration::minutes(10),
+            apply_backlog_limit: 200_000,
+            pending_compaction_bytes_limit: ReadableSize::gb(192),
+            // A little smaller than the default level0-stop-writes-trigger.
+            level0_files_limit: 32,
+            compaction_pressure_check_interval: ReadableDuration::secs(5),
+            write_stall_max_delay: ReadableDuration::secs(3),
+            consistency_check_rules: vec![],
         }
     }
 }
@@ -172,6 +269,23 @@ impl Config {
             ));
         }
 
+        if self.raft_log_engine != RAFT_ENGINE_ROCKSDB && self.raft_log_engine != RAFT_ENGINE_SEGMENT
+        {
+            return Err(box_err!(
+                "unknown raft log engine {}",
+                self.raft_log_engine
+            ));
+        }
+
+        if self.raft_log_segment_size.0 == 0 {
+            return Err(box_err!("raft log segment size should large than 0."));
+        }
+        if self.raft_log_segment_expire_count == 0 {
+            return Err(box_err!(
+                "raft log segment expire count should large than 0."
+            ));
+        }
+
         if self.raft_log_gc_threshold < 1 {
             return Err(box_err!(
                 "raft log gc threshold must >= 1, not {}",
@@ -183,6 +297,10 @@ impl Config {
             return Err(box_err!("raft log gc size limit should large than 0."));
         }
 
+        if self.concurrent_apply_snap_limit == 0 {
+            return Err(box_err!("concurrent apply snap limit should large than 0."));
+        }
+
         if self.region_max_size.0 < self.region_split_size.0 {
             return Err(box_err!(
                 "region max size {} must >= split size {}",
@@ -202,6 +320,10 @@ impl Config {
             ));
         }
 
+        for rule in &self.consistency_check_rules {
+            try!(rule.key_range());
+        }
+
         Ok(())
     }
 }
@@ -236,6 +358,21 @@ mod tests {
         cfg.raft_log_gc_size_limit = ReadableSize(0);
         assert!(cfg.validate().is_err());
 
+        cfg = Config::new();
+        cfg.raft_log_engine = "unknown".to_owned();
+        assert!(cfg.validate().is_err());
+        cfg.raft_log_engine = "segment".to_owned();
+        assert!(cfg.validate().is_ok());
+        cfg.raft_log_segment_size = ReadableSize(0);
+        assert!(cfg.validate().is_err());
+        cfg.raft_log_segment_size = ReadableSize::mb(1);
+        cfg.raft_log_segment_expire_count = 0;
+        assert!(cfg.validate().is_err());
+
+        cfg = Config::new();
+        cfg.concurrent_apply_snap_limit = 0;
+        assert!(cfg.validate().is_err());
+
         cfg = Config::new();
         cfg.region_max_size = ReadableSize(10);
         cfg.region_split_size = ReadableSize(20);
@@ -246,5 +383,12 @@ mod tests {
         cfg.raft_election_timeout_ticks = 10;
         cfg.raft_store_max_leader_lease = ReadableDuration::secs(20);
         assert!(cfg.validate().is_err());
+
+        cfg = Config::new();
+        let mut rule = ConsistencyCheckRule::default();
+        rule.start_key = "b".to_owned();
+        rule.end_key = "a".to_owned();
+        cfg.consistency_check_rules.push(rule);
+        assert!(cfg.validate().is_err());
     }
 }
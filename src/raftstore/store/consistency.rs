@@ -0,0 +1,304 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use kvproto::metapb::Region;
+use rocksdb::{Writable, WriteBatch, DB};
+use rocksdb::rocksdb_options::WriteOptions;
+
+use coprocessor::codec::table::TABLE_PREFIX;
+use raftstore::Result;
+use storage::{Key, CF_RAFT};
+use util::codec::number::NumberEncoder;
+use util::config::ReadableDuration;
+use util::rocksdb;
+
+use super::Config;
+use super::engine::Peekable;
+use super::keys;
+
+/// ConsistencyCheckRule overrides how often the regions of a table or a key
+/// range are checked.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConsistencyCheckRule {
+    // The records and indices of the table are matched if it's not 0,
+    // otherwise the key range [start_key, end_key) is matched.
+    pub table_id: i64,
+    pub start_key: String,
+    // Empty means no upper bound.
+    pub end_key: String,
+    // The keys are written by the raw KV API, which are used as the region
+    // boundaries directly. Otherwise they are transactional keys, which are
+    // encoded in the region boundaries.
+    pub raw_kv: bool,
+    // A matched region is checked again only when it hasn't been checked
+    // within the duration.
+    pub interval: ReadableDuration,
+    // Never check the matched regions.
+    pub disabled: bool,
+}
+
+impl Default for ConsistencyCheckRule {
+    fn default() -> ConsistencyCheckRule {
+        ConsistencyCheckRule {
+            table_id: 0,
+            start_key: String::new(),
+            end_key: String::new(),
+            raw_kv: false,
+            interval: ReadableDuration::secs(0),
+            disabled: false,
+        }
+    }
+}
+
+impl ConsistencyCheckRule {
+    /// Returns the encoded key range of the rule, which is comparable with
+    /// the region keys.
+    pub fn key_range(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (start, end) = if self.table_id != 0 {
+            (
+                table_prefix(self.table_id),
+                self.table_id
+                    .checked_add(1)
+                    .map(table_prefix)
+                    .unwrap_or_default(),
+            )
+        } else {
+            (
+                self.start_key.clone().into_bytes(),
+                self.end_key.clone().into_bytes(),
+            )
+        };
+        if !end.is_empty() && start >= end {
+            return Err(box_err!("invalid consistency check rule {:?}", self));
+        }
+        if self.raw_kv && self.table_id == 0 {
+            return Ok((start, end));
+        }
+        let end = if end.is_empty() {
+            end
+        } else {
+            Key::from_raw(&end).encoded().clone()
+        };
+        Ok((Key::from_raw(&start).encoded().clone(), end))
+    }
+}
+
+fn table_prefix(table_id: i64) -> Vec<u8> {
+    let mut prefix = TABLE_PREFIX.to_vec();
+    prefix.encode_i64(table_id).unwrap();
+    prefix
+}
+
+struct RuleRange {
+    start_key: Vec<u8>,
+    end_key: Vec<u8>,
+    // None means the check is disabled.
+    interval: Option<Duration>,
+}
+
+impl RuleRange {
+    fn overlaps(&self, region: &Region) -> bool {
+        (self.end_key.is_empty() || region.get_start_key() < self.end_key.as_slice()) &&
+            (region.get_end_key().is_empty() || self.start_key.as_slice() < region.get_end_key())
+    }
+}
+
+/// ConsistencyCheckPolicy decides which regions are due for a consistency
+/// check.
+pub struct ConsistencyCheckPolicy {
+    region_interval: Duration,
+    rules: Vec<RuleRange>,
+}
+
+impl ConsistencyCheckPolicy {
+    pub fn new(cfg: &Config) -> Result<ConsistencyCheckPolicy> {
+        let mut rules = Vec::with_capacity(cfg.consistency_check_rules.len());
+        for rule in &cfg.consistency_check_rules {
+            let (start_key, end_key) = try!(rule.key_range());
+            rules.push(RuleRange {
+                start_key: start_key,
+                end_key: end_key,
+                interval: if rule.disabled {
+                    None
+                } else {
+                    Some(rule.interval.0)
+                },
+            });
+        }
+        Ok(ConsistencyCheckPolicy {
+            region_interval: cfg.consistency_check_region_interval.0,
+            rules: rules,
+        })
+    }
+
+    /// Returns the check interval of the region, the first matched rule
+    /// takes effect. None means the region should never be checked.
+    pub fn interval(&self, region: &Region) -> Option<Duration> {
+        match self.rules.iter().find(|r| r.overlaps(region)) {
+            Some(rule) => rule.interval,
+            None => Some(self.region_interval),
+        }
+    }
+
+    /// Checks whether the region last checked at `last_check_time` is due.
+    pub fn is_due(&self, region: &Region, last_check_time: Instant, now: Instant) -> bool {
+        match self.interval(region) {
+            Some(interval) => now.duration_since(last_check_time) >= interval,
+            None => false,
+        }
+    }
+}
+
+/// Persists that the data of the region is inconsistent at `index`, so that
+/// the peer is still quarantined after restart. The state is cleared with
+/// the other meta of the peer when the peer is destroyed or a snapshot is
+/// applied.
+pub fn write_quarantine_state(kv_engine: &DB, region_id: u64, index: u64) -> Result<()> {
+    let handle = try!(rocksdb::get_cf_handle(kv_engine, CF_RAFT));
+    let mut value = Vec::with_capacity(8);
+    value.write_u64::<BigEndian>(index).unwrap();
+    let wb = WriteBatch::new();
+    try!(wb.put_cf(handle, &keys::quarantine_state_key(region_id), &value));
+    let mut write_opts = WriteOptions::new();
+    write_opts.set_sync(true);
+    try!(kv_engine.write_opt(wb, &write_opts));
+    Ok(())
+}
+
+/// Returns the index at which the data of the region was found inconsistent,
+/// if the peer is quarantined.
+pub fn load_quarantine_state(kv_engine: &DB, region_id: u64) -> Result<Option<u64>> {
+    let value = try!(kv_engine.get_value_cf(CF_RAFT, &keys::quarantine_state_key(region_id)));
+    match value {
+        Some(v) => {
+            let index = try!((&*v as &[u8]).read_u64::<BigEndian>());
+            Ok(Some(index))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use kvproto::metapb::Region;
+    use tempdir::TempDir;
+    use util::config::ReadableDuration;
+    use storage::{Key, ALL_CFS};
+
+    use super::*;
+
+    fn new_region(start: &[u8], end: &[u8]) -> Region {
+        let mut region = Region::new();
+        region.set_start_key(Key::from_raw(start).encoded().clone());
+        if !end.is_empty() {
+            region.set_end_key(Key::from_raw(end).encoded().clone());
+        }
+        region
+    }
+
+    #[test]
+    fn test_consistency_check_rule() {
+        let mut rule = ConsistencyCheckRule::default();
+        rule.start_key = "b".to_owned();
+        rule.end_key = "a".to_owned();
+        assert!(rule.key_range().is_err());
+
+        rule.end_key = String::new();
+        let (start, end) = rule.key_range().unwrap();
+        assert_eq!(start, Key::from_raw(b"b").encoded().clone());
+        assert!(end.is_empty());
+
+        // The table id takes precedence over the key range.
+        rule.table_id = 10;
+        let (start, end) = rule.key_range().unwrap();
+        assert_eq!(start, Key::from_raw(&table_prefix(10)).encoded().clone());
+        assert_eq!(end, Key::from_raw(&table_prefix(11)).encoded().clone());
+        assert!(start < end);
+
+        // The raw KV keys are not encoded.
+        rule.raw_kv = true;
+        assert_eq!(rule.key_range().unwrap(), (start, end));
+        rule.table_id = 0;
+        rule.end_key = "c".to_owned();
+        assert_eq!(
+            rule.key_range().unwrap(),
+            (b"b".to_vec(), b"c".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_quarantine_state() {
+        let path = TempDir::new("test-quarantine").unwrap();
+        let engine = rocksdb::new_engine(path.path().to_str().unwrap(), ALL_CFS).unwrap();
+        assert_eq!(load_quarantine_state(&engine, 1).unwrap(), None);
+        write_quarantine_state(&engine, 1, 10).unwrap();
+        assert_eq!(load_quarantine_state(&engine, 1).unwrap(), Some(10));
+        assert_eq!(load_quarantine_state(&engine, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_consistency_check_policy() {
+        let mut cfg = Config::new();
+        cfg.consistency_check_region_interval = ReadableDuration::secs(100);
+        let mut disabled = ConsistencyCheckRule::default();
+        disabled.table_id = 10;
+        disabled.disabled = true;
+        let mut frequent = ConsistencyCheckRule::default();
+        frequent.start_key = "a".to_owned();
+        frequent.end_key = "c".to_owned();
+        frequent.interval = ReadableDuration::secs(1);
+        cfg.consistency_check_rules = vec![disabled, frequent];
+        let policy = ConsistencyCheckPolicy::new(&cfg).unwrap();
+
+        let table_region = new_region(&table_prefix(10), &table_prefix(11));
+        assert_eq!(policy.interval(&table_region), None);
+        let mut index_key = table_prefix(10);
+        index_key.extend_from_slice(b"_i");
+        assert_eq!(
+            policy.interval(&new_region(&table_prefix(9), &index_key)),
+            None
+        );
+        let other_table = new_region(&table_prefix(11), b"");
+        assert_eq!(
+            policy.interval(&other_table),
+            Some(Duration::from_secs(100))
+        );
+
+        assert_eq!(
+            policy.interval(&new_region(b"b", b"d")),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.interval(&new_region(b"", b"a")),
+            Some(Duration::from_secs(100))
+        );
+        assert_eq!(
+            policy.interval(&new_region(b"c", b"d")),
+            Some(Duration::from_secs(100))
+        );
+
+        let region = new_region(b"a", b"b");
+        let now = Instant::now();
+        let last_check = now - Duration::from_secs(2);
+        assert!(policy.is_due(&region, last_check, now));
+        assert!(!policy.is_due(&region, now, now));
+        assert!(!policy.is_due(&table_region, last_check, now));
+    }
+}
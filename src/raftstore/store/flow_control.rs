@@ -0,0 +1,291 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use kvproto::raft_cmdpb::{CmdType, RaftCmdRequest};
+
+use super::{Callback, Config};
+
+// The writes are rejected at once if too many of them are delayed.
+const MAX_DELAYED_WRITES: usize = 4096;
+
+/// The reason why the store delays new writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteStall {
+    ApplyBacklog(u64),
+    PendingCompactionBytes(u64),
+    Level0Files(u64),
+}
+
+impl WriteStall {
+    pub fn tag(&self) -> &'static str {
+        match *self {
+            WriteStall::ApplyBacklog(_) => "apply_backlog",
+            WriteStall::PendingCompactionBytes(_) => "pending_compaction_bytes",
+            WriteStall::Level0Files(_) => "level0_files",
+        }
+    }
+}
+
+impl Display for WriteStall {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            WriteStall::ApplyBacklog(n) => write!(f, "{} committed logs are not applied", n),
+            WriteStall::PendingCompactionBytes(n) => {
+                write!(f, "{} bytes are pending for compaction", n)
+            }
+            WriteStall::Level0Files(n) => write!(f, "{} files at level 0", n),
+        }
+    }
+}
+
+/// A write which is held back by the flow control, it's proposed again
+/// after the stall is over, or rejected if it waits too long.
+pub struct DelayedWrite {
+    pub req: RaftCmdRequest,
+    pub context: Vec<u8>,
+    pub cb: Callback,
+    start: Instant,
+}
+
+impl DelayedWrite {
+    #[inline]
+    pub fn delay(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Decides whether the store should stop accepting writes, so that the
+/// apply worker and the compaction of the kv engine can catch up.
+pub struct WriteFlowControl {
+    apply_backlog_limit: u64,
+    pending_compaction_bytes_limit: u64,
+    level0_files_limit: u64,
+    max_delay: Duration,
+    // Reading the properties of RocksDB is not cheap, they are cached and
+    // refreshed on a slower tick than the raft base tick.
+    pending_compaction_bytes: u64,
+    level0_files: u64,
+    stall: Option<WriteStall>,
+    delayed: VecDeque<DelayedWrite>,
+}
+
+fn exceeds(value: u64, limit: u64) -> bool {
+    limit > 0 && value > limit
+}
+
+impl WriteFlowControl {
+    pub fn new(cfg: &Config) -> WriteFlowControl {
+        WriteFlowControl {
+            apply_backlog_limit: cfg.apply_backlog_limit,
+            pending_compaction_bytes_limit: cfg.pending_compaction_bytes_limit.0,
+            level0_files_limit: cfg.level0_files_limit,
+            max_delay: cfg.write_stall_max_delay.0,
+            pending_compaction_bytes: 0,
+            level0_files: 0,
+            stall: None,
+            delayed: VecDeque::new(),
+        }
+    }
+
+    /// Caches the compaction pressure of the kv engine, it takes effect on
+    /// the next `update`.
+    pub fn update_compaction_pressure(&mut self, pending_compaction_bytes: u64, level0_files: u64) {
+        self.pending_compaction_bytes = pending_compaction_bytes;
+        self.level0_files = level0_files;
+    }
+
+    /// Updates the state with the latest apply backlog of the store and the
+    /// cached compaction pressure, returns whether the reason of the stall
+    /// is changed.
+    pub fn update(&mut self, apply_backlog: u64) -> bool {
+        let (pending_compaction_bytes, level0_files) =
+            (self.pending_compaction_bytes, self.level0_files);
+        let stall = if exceeds(apply_backlog, self.apply_backlog_limit) {
+            Some(WriteStall::ApplyBacklog(apply_backlog))
+        } else if exceeds(pending_compaction_bytes, self.pending_compaction_bytes_limit) {
+            Some(WriteStall::PendingCompactionBytes(
+                pending_compaction_bytes,
+            ))
+        } else if exceeds(level0_files, self.level0_files_limit) {
+            Some(WriteStall::Level0Files(level0_files))
+        } else {
+            None
+        };
+        let changed = stall.map(|s| s.tag()) != self.stall.map(|s| s.tag());
+        self.stall = stall;
+        changed
+    }
+
+    #[inline]
+    pub fn stall(&self) -> Option<WriteStall> {
+        self.stall
+    }
+
+    /// Holds back the write until the stall is over. Returns the callback
+    /// back if the write can't be delayed, the caller should reject it.
+    pub fn delay(
+        &mut self,
+        req: RaftCmdRequest,
+        context: Vec<u8>,
+        cb: Callback,
+    ) -> Option<Callback> {
+        if self.max_delay == Duration::from_secs(0) || self.delayed.len() >= MAX_DELAYED_WRITES {
+            return Some(cb);
+        }
+        self.delayed.push_back(DelayedWrite {
+            req: req,
+            context: context,
+            cb: cb,
+            start: Instant::now(),
+        });
+        None
+    }
+
+    #[inline]
+    pub fn delayed_count(&self) -> usize {
+        self.delayed.len()
+    }
+
+    /// Returns the delayed writes which can be proposed now, and the ones
+    /// which have waited too long and should be rejected.
+    pub fn take_delayed(&mut self) -> (Vec<DelayedWrite>, Vec<DelayedWrite>) {
+        if self.stall.is_none() {
+            return (self.delayed.drain(..).collect(), vec![]);
+        }
+        let mut timed_out = vec![];
+        while self.delayed
+            .front()
+            .map_or(false, |w| w.delay() >= self.max_delay)
+        {
+            timed_out.push(self.delayed.pop_front().unwrap());
+        }
+        (vec![], timed_out)
+    }
+}
+
+/// Checks whether the request writes data. Admin requests are never
+/// rejected, as they may be required to get out of the stall.
+pub fn is_write_request(req: &RaftCmdRequest) -> bool {
+    req.get_requests().iter().any(|r| match r.get_cmd_type() {
+        CmdType::Put | CmdType::Delete | CmdType::DeleteRange => true,
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use kvproto::raft_cmdpb::{AdminCmdType, RaftCmdResponse, Request};
+    use util::config::{ReadableDuration, ReadableSize};
+
+    use super::*;
+
+    fn new_request(cmd_types: &[CmdType]) -> RaftCmdRequest {
+        let mut req = RaftCmdRequest::new();
+        for &t in cmd_types {
+            let mut r = Request::new();
+            r.set_cmd_type(t);
+            req.mut_requests().push(r);
+        }
+        req
+    }
+
+    #[test]
+    fn test_write_flow_control() {
+        let mut cfg = Config::new();
+        cfg.apply_backlog_limit = 100;
+        cfg.pending_compaction_bytes_limit = ReadableSize::kb(1);
+        cfg.level0_files_limit = 0;
+        let mut fc = WriteFlowControl::new(&cfg);
+        // 0 means no limit.
+        fc.update_compaction_pressure(1024, 1000);
+        assert!(!fc.update(100));
+        assert_eq!(fc.stall(), None);
+
+        fc.update_compaction_pressure(0, 0);
+        assert!(fc.update(101));
+        assert_eq!(fc.stall(), Some(WriteStall::ApplyBacklog(101)));
+        // The reason is unchanged.
+        fc.update_compaction_pressure(2048, 0);
+        assert!(!fc.update(200));
+        assert_eq!(fc.stall(), Some(WriteStall::ApplyBacklog(200)));
+        // The cached pressure is used until it's updated again.
+        assert!(fc.update(0));
+        assert_eq!(fc.stall(), Some(WriteStall::PendingCompactionBytes(2048)));
+        assert!(!fc.update(0));
+        fc.update_compaction_pressure(0, 0);
+        assert!(fc.update(0));
+        assert_eq!(fc.stall(), None);
+    }
+
+    #[test]
+    fn test_delay_writes() {
+        let mut cfg = Config::new();
+        cfg.apply_backlog_limit = 100;
+        cfg.write_stall_max_delay = ReadableDuration::millis(100);
+        let mut fc = WriteFlowControl::new(&cfg);
+        assert!(fc.update(101));
+
+        let new_cb = || -> Callback { box |_: RaftCmdResponse| {} };
+        assert!(fc.delay(new_request(&[CmdType::Put]), vec![], new_cb()).is_none());
+        let (ready, timed_out) = fc.take_delayed();
+        assert!(ready.is_empty() && timed_out.is_empty());
+        assert_eq!(fc.delayed_count(), 1);
+
+        // The writes are released once the stall is over.
+        assert!(fc.update(0));
+        let (ready, timed_out) = fc.take_delayed();
+        assert_eq!(ready.len(), 1);
+        assert!(timed_out.is_empty());
+        assert_eq!(ready[0].req, new_request(&[CmdType::Put]));
+
+        // Or rejected after waiting too long.
+        assert!(fc.update(101));
+        assert!(fc.delay(new_request(&[CmdType::Put]), vec![], new_cb()).is_none());
+        thread::sleep(Duration::from_millis(100));
+        assert!(fc.delay(new_request(&[CmdType::Delete]), vec![], new_cb()).is_none());
+        let (ready, timed_out) = fc.take_delayed();
+        assert!(ready.is_empty());
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].req, new_request(&[CmdType::Put]));
+        assert_eq!(fc.delayed_count(), 1);
+
+        // 0 means rejecting at once.
+        cfg.write_stall_max_delay = ReadableDuration::secs(0);
+        let mut fc = WriteFlowControl::new(&cfg);
+        assert!(fc.update(101));
+        assert!(fc.delay(new_request(&[CmdType::Put]), vec![], new_cb()).is_some());
+        assert_eq!(fc.delayed_count(), 0);
+    }
+
+    #[test]
+    fn test_is_write_request() {
+        assert!(!is_write_request(&new_request(&[])));
+        assert!(!is_write_request(&new_request(&[CmdType::Get, CmdType::Snap])));
+        assert!(is_write_request(&new_request(&[CmdType::Put])));
+        assert!(is_write_request(
+            &new_request(&[CmdType::Delete, CmdType::DeleteRange])
+        ));
+
+        let mut req = RaftCmdRequest::new();
+        req.mut_admin_request()
+            .set_cmd_type(AdminCmdType::CompactLog);
+        assert!(!is_write_request(&req));
+    }
+}
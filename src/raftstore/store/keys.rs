@@ -36,6 +36,8 @@ pub const DATA_MAX_KEY: &'static [u8] = &[DATA_PREFIX + 1];
 // Following keys are all local keys, so the first byte must be 0x01.
 pub const STORE_IDENT_KEY: &'static [u8] = &[LOCAL_PREFIX, 0x01];
 pub const PREPARE_BOOTSTRAP_KEY: &'static [u8] = &[LOCAL_PREFIX, 0x02];
+// Whether the peers on the store are witnesses, it's in CF_RAFT.
+pub const STORE_WITNESS_KEY: &'static [u8] = &[LOCAL_PREFIX, 0x04];
 // We save two types region data in DB, for raft and other meta data.
 // When the store starts, we should iterate all region meta data to
 // construct peer, no need to travel large raft data, so we separate them
@@ -53,6 +55,7 @@ pub const RAFT_LOG_SUFFIX: u8 = 0x01;
 pub const RAFT_STATE_SUFFIX: u8 = 0x02;
 pub const APPLY_STATE_SUFFIX: u8 = 0x03;
 pub const SNAPSHOT_RAFT_STATE_SUFFIX: u8 = 0x04;
+pub const QUARANTINE_STATE_SUFFIX: u8 = 0x05;
 
 // For region meta
 pub const REGION_STATE_SUFFIX: u8 = 0x01;
@@ -65,6 +68,10 @@ pub fn prepare_bootstrap_key() -> Vec<u8> {
     PREPARE_BOOTSTRAP_KEY.to_vec()
 }
 
+pub fn store_witness_key() -> Vec<u8> {
+    STORE_WITNESS_KEY.to_vec()
+}
+
 fn make_region_id_key(region_id: u64, suffix: u8, extra_cap: usize) -> Vec<u8> {
     let mut key = Vec::with_capacity(
         REGION_RAFT_PREFIX_KEY.len() + mem::size_of::<u64>() + mem::size_of::<u8>() + extra_cap,
@@ -108,6 +115,10 @@ pub fn apply_state_key(region_id: u64) -> Vec<u8> {
     make_region_id_key(region_id, APPLY_STATE_SUFFIX, 0)
 }
 
+pub fn quarantine_state_key(region_id: u64) -> Vec<u8> {
+    make_region_id_key(region_id, QUARANTINE_STATE_SUFFIX, 0)
+}
+
 /// Get the log index from raft log key generated by `raft_log_key`.
 pub fn raft_log_index(key: &[u8]) -> Result<u64> {
     let expect_key_len = REGION_RAFT_PREFIX_KEY.len() + mem::size_of::<u64>() +
@@ -241,6 +252,7 @@ mod tests {
             assert!(raft_log_key(region_id, 1).starts_with(&prefix));
             assert!(raft_state_key(region_id).starts_with(&prefix));
             assert!(apply_state_key(region_id).starts_with(&prefix));
+            assert!(quarantine_state_key(region_id).starts_with(&prefix));
         }
 
         // test sort.
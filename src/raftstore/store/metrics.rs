@@ -129,6 +129,20 @@ lazy_static! {
              exponential_buckets(1.0, 2.0, 20).unwrap()
         ).unwrap();
 
+    pub static ref REGION_READ_BYTES_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_region_read_bytes",
+            "Histogram of bytes read for regions",
+             exponential_buckets(256.0, 2.0, 20).unwrap()
+        ).unwrap();
+
+    pub static ref REGION_READ_KEYS_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_region_read_keys",
+            "Histogram of keys read for regions",
+             exponential_buckets(1.0, 2.0, 20).unwrap()
+        ).unwrap();
+
     pub static ref REQUEST_WAIT_TIME_HISTOGRAM: Histogram =
         register_histogram!(
             "tikv_raftstore_request_wait_time_duration_secs",
@@ -179,6 +193,13 @@ lazy_static! {
              exponential_buckets(1024.0, 2.0, 22).unwrap() // 1024,1024*2^1,..,4G
         ).unwrap();
 
+    pub static ref SNAPSHOT_THROTTLED_DURATION_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_snapshot_throttled_duration_seconds_total",
+            "Total duration of snapshots throttled by the rate and concurrency limits",
+            &["type"]
+        ).unwrap();
+
     pub static ref RAFT_ENTRY_FETCHES: CounterVec =
         register_counter_vec!(
             "tikv_raftstore_entry_fetches",
@@ -186,6 +207,45 @@ lazy_static! {
             &["type"]
         ).unwrap();
 
+    pub static ref RAFT_ENTRY_CACHE_MEM_SIZE_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_raftstore_entry_cache_mem_size",
+            "Memory taken by the raft entry caches of the store"
+        ).unwrap();
+
+    pub static ref RAFT_ENTRY_CACHE_EVICT_COUNTER: CounterVec =
+        register_counter_vec!(
+            "tikv_raftstore_entry_cache_evict_bytes",
+            "Total bytes of raft entries evicted from the caches",
+            &["type"]
+        ).unwrap();
+
+    pub static ref APPLY_BACKLOG_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_raftstore_apply_backlog",
+            "Number of committed but not applied raft logs"
+        ).unwrap();
+
+    pub static ref WRITE_STALL_REJECTED_COUNTER: CounterVec =
+        register_counter_vec!(
+            "tikv_raftstore_write_stall_rejected_total",
+            "Total number of writes rejected by the store flow control",
+            &["reason"]
+        ).unwrap();
+
+    pub static ref WRITE_STALL_DELAYED_GAUGE: Gauge =
+        register_gauge!(
+            "tikv_raftstore_write_stall_delayed",
+            "Number of writes delayed by the store flow control"
+        ).unwrap();
+
+    pub static ref WRITE_STALL_DELAY_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_raftstore_write_stall_delay_duration_seconds",
+            "Bucketed histogram of the delay of writes by the store flow control",
+            exponential_buckets(0.001, 2.0, 16).unwrap()
+        ).unwrap();
+
     pub static ref BATCH_SNAPSHOT_COMMANDS: Histogram =
         register_histogram!(
             "tikv_raftstore_batch_snapshot_commands_total",
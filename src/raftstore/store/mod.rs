@@ -19,6 +19,9 @@ pub mod transport;
 pub mod bootstrap;
 pub mod cmd_resp;
 pub mod util;
+pub mod unsafe_recovery;
+pub mod batch_split;
+pub mod raft_engine;
 
 mod store;
 mod peer;
@@ -27,16 +30,23 @@ mod snap;
 mod worker;
 mod metrics;
 mod local_metrics;
+mod flow_control;
+mod consistency;
 
-pub use self::msg::{BatchCallback, Callback, Msg, SnapshotStatusMsg, Tick};
+pub use self::msg::{BatchCallback, Callback, DrainCallback, HealthCheckCallback, Msg,
+                    RecoveryCallback, SnapshotStatusMsg, SplitCallback, Tick};
 pub use self::store::{create_event_loop, Engines, Store, StoreChannel};
 pub use self::config::Config;
+pub use self::consistency::ConsistencyCheckRule;
 pub use self::transport::Transport;
 pub use self::peer::Peer;
-pub use self::bootstrap::{bootstrap_store, clear_prepare_bootstrap, clear_prepare_bootstrap_state,
-                          prepare_bootstrap, write_prepare_bootstrap};
+pub use self::raft_engine::{RaftEngine, RaftLogBatch};
+pub use self::bootstrap::{bootstrap_store, check_store_witness, clear_prepare_bootstrap,
+                          clear_prepare_bootstrap_state, prepare_bootstrap,
+                          write_prepare_bootstrap};
 pub use self::engine::{Iterable, Mutable, Peekable};
 pub use self::peer_storage::{do_snapshot, CacheQueryStats, PeerStorage, SnapState,
                              RAFT_INIT_LOG_INDEX, RAFT_INIT_LOG_TERM};
-pub use self::snap::{check_abort, copy_snapshot, ApplyOptions, SnapEntry, SnapKey, SnapManager,
-                     Snapshot, SnapshotDeleter, SnapshotStatistics};
+pub use self::snap::{check_abort, copy_snapshot, get_snapshot_file_size, strip_snapshot_files,
+                     ApplyOptions, SnapEntry, SnapKey, SnapManager, SnapManagerBuilder, Snapshot,
+                     SnapshotDeleter, SnapshotStatistics};
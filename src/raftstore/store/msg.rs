@@ -16,14 +16,26 @@ use std::boxed::FnBox;
 use std::fmt;
 
 use kvproto::raft_serverpb::RaftMessage;
+use kvproto::eraftpb::Entry;
 use kvproto::raft_cmdpb::{RaftCmdRequest, RaftCmdResponse};
-use kvproto::metapb::RegionEpoch;
+use kvproto::metapb::{Region, RegionEpoch};
 use raft::SnapshotStatus;
+use raftstore::Result;
 
 use util::escape;
+use super::batch_split::BatchSplitRequest;
+use super::unsafe_recovery::RegionRecovery;
 
 pub type Callback = Box<FnBox(RaftCmdResponse) + Send>;
 pub type BatchCallback = Box<FnBox(Vec<Option<RaftCmdResponse>>) + Send>;
+// Receives all the regions produced by a manual split, ordered by start key.
+pub type SplitCallback = Box<FnBox(Result<Vec<Region>>) + Send>;
+// Receives the regions changed, or to be changed in a dry run, by unsafe recovery.
+pub type RecoveryCallback = Box<FnBox(Result<Vec<RegionRecovery>>) + Send>;
+// Called when there are no leaders left to transfer away.
+pub type DrainCallback = Box<FnBox() + Send>;
+// Called when the store handles the health check.
+pub type HealthCheckCallback = Box<FnBox() + Send>;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Tick {
@@ -37,6 +49,8 @@ pub enum Tick {
     CompactLockCf,
     ConsistencyCheck,
     ReportRegionFlow,
+    DrainLeaders,
+    CompactionPressure,
 }
 
 pub struct SnapshotStatusMsg {
@@ -70,6 +84,22 @@ pub enum Msg {
         split_key: Vec<u8>,
     },
 
+    // For manual split with explicit split keys.
+    SplitRegion {
+        region_id: u64,
+        region_epoch: RegionEpoch,
+        // It's an origin key, not an encoded data key.
+        split_keys: Vec<Vec<u8>>,
+        callback: SplitCallback,
+    },
+
+    // Propose the request along with all the splits as one raft command.
+    BatchSplit {
+        request: RaftCmdRequest,
+        splits: BatchSplitRequest,
+        callback: Callback,
+    },
+
     ReportUnreachable { region_id: u64, to_peer_id: u64 },
 
     // For snapshot stats.
@@ -81,6 +111,26 @@ pub enum Msg {
         index: u64,
         hash: Vec<u8>,
     },
+
+    // For unsafe recovery, remove the peers on failed stores from all regions.
+    RemoveFailedStores {
+        store_ids: Vec<u64>,
+        dry_run: bool,
+        callback: RecoveryCallback,
+    },
+
+    // Transfer all the leaders away before shutdown.
+    DrainLeaders { callback: DrainCallback },
+
+    // Checks the store is still handling messages.
+    HealthCheck { callback: HealthCheckCallback },
+
+    // The raft logs read by the raftlog fetch worker.
+    RaftLogFetched {
+        region_id: u64,
+        seq: u64,
+        entries: Vec<Entry>,
+    },
 }
 
 impl fmt::Debug for Msg {
@@ -91,6 +141,26 @@ impl fmt::Debug for Msg {
             Msg::RaftCmd { .. } => write!(fmt, "Raft Command"),
             Msg::BatchRaftSnapCmds { .. } => write!(fmt, "Batch Raft Commands"),
             Msg::SplitCheckResult { .. } => write!(fmt, "Split Check Result"),
+            Msg::SplitRegion {
+                ref region_id,
+                ref split_keys,
+                ..
+            } => write!(
+                fmt,
+                "Split region {} at {} keys",
+                region_id,
+                split_keys.len()
+            ),
+            Msg::BatchSplit {
+                ref request,
+                ref splits,
+                ..
+            } => write!(
+                fmt,
+                "Batch split region {} at {} keys",
+                request.get_header().get_region_id(),
+                splits.requests.len()
+            ),
             Msg::ReportUnreachable {
                 ref region_id,
                 ref to_peer_id,
@@ -112,6 +182,28 @@ impl fmt::Debug for Msg {
                 index,
                 escape(hash)
             ),
+            Msg::RemoveFailedStores {
+                ref store_ids,
+                dry_run,
+                ..
+            } => write!(
+                fmt,
+                "Remove failed stores {:?}, dry run: {}",
+                store_ids,
+                dry_run
+            ),
+            Msg::DrainLeaders { .. } => write!(fmt, "Drain leaders"),
+            Msg::HealthCheck { .. } => write!(fmt, "Health check"),
+            Msg::RaftLogFetched {
+                region_id,
+                ref entries,
+                ..
+            } => write!(
+                fmt,
+                "[region {}] fetched {} raft logs",
+                region_id,
+                entries.len()
+            ),
         }
     }
 }
@@ -135,6 +227,20 @@ impl Msg {
             on_finished: on_finished,
         }
     }
+
+    pub fn new_split_region(
+        region_id: u64,
+        region_epoch: RegionEpoch,
+        split_keys: Vec<Vec<u8>>,
+        callback: SplitCallback,
+    ) -> Msg {
+        Msg::SplitRegion {
+            region_id: region_id,
+            region_epoch: region_epoch,
+            split_keys: split_keys,
+            callback: callback,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -11,10 +11,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::{cmp, mem, slice};
 use std::time::{Duration, Instant};
 
@@ -32,29 +33,41 @@ use raft::{self, Progress, ProgressState, RawNode, Ready, SnapshotStatus, StateR
 use raftstore::{Error, Result};
 use raftstore::coprocessor::CoprocessorHost;
 use raftstore::store::Config;
-use raftstore::store::worker::{apply, PdTask, Proposal, RegionProposal};
+use raftstore::store::worker::{apply, PdTask, Proposal, RaftlogFetchTask, RegionProposal};
 use raftstore::store::worker::apply::ExecResult;
 
 use util::worker::{FutureWorker, Scheduler};
 use raftstore::store::worker::{Apply, ApplyRes, ApplyTask};
-use util::Either;
+use util::{Either, HandyRwLock};
 use util::time::monotonic_raw_now;
-use util::collections::{FlatMap, FlatMapValues as Values, HashSet};
+use util::collections::{FlatMap, FlatMapValues as Values, HashMap, HashSet};
 
 use pd::INVALID_ID;
 
 use super::store::Store;
-use super::peer_storage::{write_peer_state, ApplySnapResult, InvokeContext, PeerStorage};
+use super::peer_storage::{write_peer_state, ApplySnapResult, GenSnapTask, InvokeContext,
+                          PeerStorage};
 use super::util;
+use super::consistency;
 use super::msg::Callback;
 use super::cmd_resp;
 use super::transport::Transport;
 use super::engine::Snapshot;
+use super::snap::{strip_snapshot_files, SnapKey};
 use super::metrics::*;
+use super::raft_engine::{RaftEngine, RaftLogBatch};
 use super::local_metrics::{RaftMessageMetrics, RaftMetrics, RaftProposeMetrics, RaftReadyMetrics};
 
 const TRANSFER_LEADER_ALLOW_LOG_LAG: u64 = 10;
-const DEFAULT_APPEND_WB_SIZE: usize = 4 * 1024;
+
+// Markers in `eraftpb::Message.context` of the messages used to delegate snapshot
+// generation to a follower. kvproto has no dedicated types for them, so they are sent
+// as `MsgSnapStatus`, which raft never sends to other peers, and are handled by the
+// store without stepping raft.
+pub const DELEGATE_SNAP_GEN: &'static [u8] = b"DelegateSnapGen";
+pub const DELEGATE_SNAP_GEN_RESP: &'static [u8] = b"DelegateSnapGenResp";
+pub const DELEGATE_SNAP_SEND: &'static [u8] = b"DelegateSnapSend";
+pub const DELEGATE_SNAP_STATUS: &'static [u8] = b"DelegateSnapStatus";
 
 struct ReadIndexRequest {
     id: u64,
@@ -142,7 +155,7 @@ impl ProposalQueue {
 
 pub struct ReadyContext<'a, T: 'a> {
     pub kv_wb: WriteBatch,
-    pub raft_wb: WriteBatch,
+    pub raft_wb: RaftLogBatch,
     pub metrics: &'a mut RaftMetrics,
     pub trans: &'a T,
     pub ready_res: Vec<(Ready, InvokeContext)>,
@@ -152,7 +165,7 @@ impl<'a, T> ReadyContext<'a, T> {
     pub fn new(metrics: &'a mut RaftMetrics, t: &'a T, cap: usize) -> ReadyContext<'a, T> {
         ReadyContext {
             kv_wb: WriteBatch::new(),
-            raft_wb: WriteBatch::with_capacity(DEFAULT_APPEND_WB_SIZE),
+            raft_wb: RaftLogBatch::with_capacity(cap),
             metrics: metrics,
             trans: t,
             ready_res: Vec::with_capacity(cap),
@@ -169,11 +182,73 @@ pub fn parse_data_at<T: Message + MessageStatic>(data: &[u8], index: u64, tag: &
     })
 }
 
+pub fn is_delegate_snap_msg(msg: &eraftpb::Message) -> bool {
+    if msg.get_msg_type() != MessageType::MsgSnapStatus {
+        return false;
+    }
+    let ctx = msg.get_context();
+    ctx == DELEGATE_SNAP_GEN || ctx == DELEGATE_SNAP_GEN_RESP || ctx == DELEGATE_SNAP_SEND ||
+        ctx == DELEGATE_SNAP_STATUS
+}
+
+fn new_delegate_snap_msg(context: &[u8]) -> eraftpb::Message {
+    let mut msg = eraftpb::Message::new();
+    msg.set_msg_type(MessageType::MsgSnapStatus);
+    msg.set_context(context.to_vec());
+    msg
+}
+
+// The payload of a delegate snapshot message is carried in its only entry.
+fn set_delegate_snap_payload<M: Message>(msg: &mut eraftpb::Message, payload: &M) -> Result<()> {
+    let mut entry = eraftpb::Entry::new();
+    entry.set_data(try!(payload.write_to_bytes()));
+    msg.mut_entries().push(entry);
+    Ok(())
+}
+
+fn get_delegate_snap_payload<M: Message + MessageStatic>(msg: &eraftpb::Message) -> Result<M> {
+    match msg.get_entries().first() {
+        Some(entry) => Ok(try!(protobuf::parse_from_bytes::<M>(entry.get_data()))),
+        None => Err(box_err!("missing payload in {:?}", msg.get_context())),
+    }
+}
+
+// A snapshot generation the leader has delegated to a follower.
+struct PendingSnapGen {
+    delegate: metapb::Peer,
+    term: u64,
+    task: GenSnapTask,
+    start: Instant,
+}
+
+// A snapshot generated by a follower and the follower holding its files.
+struct DelegatedSnap {
+    key: SnapKey,
+    delegate: metapb::Peer,
+    // The peers the delegate is sending the snapshot to.
+    sending: HashSet<u64>,
+    start: Instant,
+}
+
+// A snapshot a follower is generating for the leader.
+struct DelegatedSnapGen {
+    leader: metapb::Peer,
+    receiver: Receiver<eraftpb::Snapshot>,
+}
+
 pub struct ConsistencyState {
     pub last_check_time: Instant,
     // (computed_result_or_to_be_verified, index, hash)
     pub index: u64,
     pub hash: Vec<u8>,
+    // The leader whose hash mismatched the local one last time. The local
+    // data is only blamed when the hash of another leader mismatches too,
+    // as the leader itself may be the inconsistent one.
+    pub mismatched_leader: u64,
+    // The data is inconsistent with the leaders, so the peer stops serving
+    // requests and never becomes leader. It's persisted until the peer is
+    // destroyed or a snapshot is applied.
+    pub quarantined: bool,
 }
 
 enum RequestPolicy {
@@ -192,11 +267,13 @@ pub struct PeerStat {
     pub written_keys: u64,
     pub last_written_bytes: u64,
     pub last_written_keys: u64,
+    pub last_read_bytes: u64,
+    pub last_read_keys: u64,
 }
 
 pub struct Peer {
     kv_engine: Arc<DB>,
-    raft_engine: Arc<DB>,
+    raft_engine: Arc<RaftEngine>,
     cfg: Rc<Config>,
     peer_cache: RefCell<FlatMap<u64, metapb::Peer>>,
     pub peer: metapb::Peer,
@@ -252,6 +329,22 @@ pub struct Peer {
     leader_lease_expired_time: Option<Either<Timespec, Timespec>>,
 
     pub peer_stat: PeerStat,
+
+    // The snapshot generation delegated to a follower, only set on the leader.
+    pending_snap_gen: Option<PendingSnapGen>,
+    // The latest snapshot generated by a follower, cleared when all its sends are
+    // reported, on timeout or when the peer is no longer leader.
+    delegated_snap: Option<DelegatedSnap>,
+    // The snapshot generating on behalf of the leader, only set on followers.
+    delegated_snap_gen: Option<DelegatedSnapGen>,
+    // Snapshots sent on behalf of the leader, target peer id -> leader.
+    delegated_snap_sends: FlatMap<u64, metapb::Peer>,
+
+    // A witness votes and persists logs, but stores no data and never
+    // becomes leader.
+    witness: bool,
+    witness_stores: Arc<RwLock<HashMap<u64, bool>>>,
+    store_priorities: Arc<RwLock<HashMap<u64, u64>>>,
 }
 
 impl Peer {
@@ -303,7 +396,7 @@ impl Peer {
         let peer_cache = FlatMap::default();
         let tag = format!("[region {}] {}", region.get_id(), peer_id);
 
-        let ps = try!(PeerStorage::new(
+        let mut ps = try!(PeerStorage::new(
             store.kv_engine(),
             store.raft_engine(),
             region,
@@ -312,7 +405,20 @@ impl Peer {
             store.entry_cache_metries.clone()
         ));
 
+        ps.set_delegate_snap_gen(cfg.delegate_snap_generation);
+        ps.set_raftlog_fetch_scheduler(store.raftlog_fetch_scheduler());
         let applied_index = ps.applied_index();
+        let quarantined_index = try!(consistency::load_quarantine_state(
+            &store.kv_engine(),
+            region.get_id()
+        ));
+        if let Some(index) = quarantined_index {
+            warn!(
+                "{} is quarantined as the data is inconsistent at index {}",
+                tag,
+                index
+            );
+        }
 
         let raft_cfg = raft::Config {
             id: peer_id,
@@ -321,10 +427,14 @@ impl Peer {
             heartbeat_tick: cfg.raft_heartbeat_ticks,
             max_size_per_msg: cfg.raft_max_size_per_msg.0,
             max_inflight_msgs: cfg.raft_max_inflight_msgs,
+            max_inflight_bytes: cfg.raft_max_inflight_bytes.0,
+            max_probe_size_per_msg: cfg.raft_max_probe_size_per_msg.0,
             applied: applied_index,
             check_quorum: true,
             tag: tag.clone(),
             skip_bcast_commit: true,
+            witness: store.is_witness(),
+            priority: store.election_priority(),
             ..Default::default()
         };
 
@@ -355,12 +465,21 @@ impl Peer {
                 last_check_time: Instant::now(),
                 index: INVALID_INDEX,
                 hash: vec![],
+                mismatched_leader: raft::INVALID_ID,
+                quarantined: quarantined_index.is_some(),
             },
             raft_log_size_hint: 0,
             raft_entry_max_size: cfg.raft_entry_max_size.0,
             cfg: cfg,
             leader_lease_expired_time: None,
             peer_stat: PeerStat::default(),
+            pending_snap_gen: None,
+            delegated_snap: None,
+            delegated_snap_gen: None,
+            delegated_snap_sends: FlatMap::default(),
+            witness: store.is_witness(),
+            witness_stores: store.witness_stores(),
+            store_priorities: store.store_priorities(),
         };
 
         // If this region has only one peer and I am the one, campaign directly.
@@ -391,8 +510,8 @@ impl Peer {
 
         // Set Tombstone state explicitly
         let kv_wb = WriteBatch::new();
-        let raft_wb = WriteBatch::new();
-        try!(self.mut_store().clear_meta(&kv_wb, &raft_wb));
+        let mut raft_wb = RaftLogBatch::new();
+        try!(self.mut_store().clear_meta(&kv_wb, &mut raft_wb));
         try!(write_peer_state(
             &self.kv_engine,
             &kv_wb,
@@ -401,7 +520,7 @@ impl Peer {
         ));
         // write kv rocksdb first in case of restart happen between two write
         try!(self.kv_engine.write(kv_wb));
-        try!(self.raft_engine.write(raft_wb));
+        try!(self.raft_engine.write(raft_wb, false));
 
         if self.get_store().is_initialized() {
             // If we meet panic when deleting data and raft log, the dirty data
@@ -434,7 +553,7 @@ impl Peer {
         self.kv_engine.clone()
     }
 
-    pub fn raft_engine(&self) -> Arc<DB> {
+    pub fn raft_engine(&self) -> Arc<RaftEngine> {
         self.raft_engine.clone()
     }
 
@@ -458,6 +577,16 @@ impl Peer {
         self.raft_group.raft.state == StateRole::Leader
     }
 
+    pub fn is_witness(&self) -> bool {
+        self.witness
+    }
+
+    /// Checks whether the peer is a witness, None if the store of the peer is
+    /// not resolved from pd yet.
+    pub fn is_witness_peer(&self, peer: &metapb::Peer) -> Option<bool> {
+        self.witness_stores.rl().get(&peer.get_store_id()).cloned()
+    }
+
     #[inline]
     pub fn get_store(&self) -> &PeerStorage {
         self.raft_group.get_store()
@@ -500,6 +629,13 @@ impl Peer {
         for msg in msgs {
             let msg_type = msg.get_msg_type();
 
+            if msg_type == MessageType::MsgSnapshot && self.is_delegated_snap(&msg) {
+                // The status is reported by the follower, so it's not counted as
+                // a snapshot sent by this store.
+                self.forward_delegated_snap(msg, trans);
+                continue;
+            }
+
             try!(self.send_raft_message(msg, trans));
 
             match msg_type {
@@ -632,6 +768,9 @@ impl Peer {
     fn on_role_changed(&mut self, ready: &Ready, worker: &FutureWorker<PdTask>) {
         // Update leader lease when the Raft state changes.
         if let Some(ref ss) = ready.ss {
+            if ss.raft_state != StateRole::Leader {
+                self.clear_delegated_snap();
+            }
             match ss.raft_state {
                 StateRole::Leader => {
                     // The local read can only be performed after a new leader has applied
@@ -649,10 +788,14 @@ impl Peer {
                         self.tag,
                         next_expired_time
                     );
-                    self.heartbeat_pd(worker)
+                    self.heartbeat_pd(worker);
+                    self.coprocessor_host
+                        .on_role_change(self.region(), ss.raft_state);
                 }
                 StateRole::Follower => {
                     self.leader_lease_expired_time = None;
+                    self.coprocessor_host
+                        .on_role_change(self.region(), ss.raft_state);
                 }
                 _ => {}
             }
@@ -696,6 +839,7 @@ impl Peer {
         if self.pending_remove {
             return;
         }
+        self.handle_gen_snap_task(ctx.trans);
         if self.mut_store().check_applying_snap() {
             // If we continue to handle all the messages, it may cause too many messages because
             // leader will send all the remaining messages to this follower, which can lead
@@ -990,13 +1134,15 @@ impl Peer {
         None
     }
 
-    /// Propose a request.
+    /// Propose a request, the context is kept in the raft entry if the request
+    /// is proposed to the raft log.
     ///
     /// Return true means the request has been proposed successfully.
     pub fn propose(
         &mut self,
         cb: Callback,
         req: RaftCmdRequest,
+        context: Vec<u8>,
         mut err_resp: RaftCmdResponse,
         metrics: &mut RaftProposeMetrics,
     ) -> bool {
@@ -1014,7 +1160,7 @@ impl Peer {
                 return false;
             }
             Ok(RequestPolicy::ReadIndex) => return self.read_index(req, cb, metrics),
-            Ok(RequestPolicy::ProposeNormal) => self.propose_normal(req, metrics),
+            Ok(RequestPolicy::ProposeNormal) => self.propose_normal(req, context, metrics),
             Ok(RequestPolicy::ProposeTransferLeader) => {
                 return self.propose_transfer_leader(req, cb, metrics)
             }
@@ -1200,6 +1346,30 @@ impl Peer {
             return Err(box_err!("ignore remove leader"));
         }
 
+        // Witnesses store no data, at least one peer with data must be left. The
+        // peers on the stores not resolved yet may be witnesses, so they don't
+        // count until the stores are resolved.
+        let is_data_peer = |p: &metapb::Peer| self.is_witness_peer(p) == Some(false);
+        let has_data_peer = (change_type == ConfChangeType::AddNode && is_data_peer(peer)) ||
+            self.region()
+                .get_peers()
+                .iter()
+                .any(|p| p.get_id() != peer.get_id() && is_data_peer(p));
+        if !has_data_peer {
+            PEER_ADMIN_CMD_COUNTER_VEC
+                .with_label_values(&["conf_change", "reject_unsafe"])
+                .inc();
+            info!(
+                "{} rejects conf change request {:?} leaving no known peer with data",
+                self.tag,
+                change_peer
+            );
+            return Err(box_err!(
+                "unsafe to perform conf change {:?}, no known peer with data is left",
+                change_peer
+            ));
+        }
+
         let mut status = self.raft_group.status();
         let total = status.progress.len();
         if total == 1 {
@@ -1254,6 +1424,11 @@ impl Peer {
     }
 
     fn is_transfer_leader_allowed(&self, peer: &metapb::Peer) -> bool {
+        // A witness on a store not resolved yet rejects MsgTimeoutNow itself.
+        if self.is_witness_peer(peer) == Some(true) {
+            return false;
+        }
+
         let peer_id = peer.get_id();
         let status = self.raft_group.status();
 
@@ -1271,6 +1446,15 @@ impl Peer {
         last_index <= status.progress[&peer_id].matched + TRANSFER_LEADER_ALLOW_LOG_LAG
     }
 
+    /// Picks a peer that the leadership can be transferred to right now.
+    pub fn pick_transfer_leader_target(&self) -> Option<metapb::Peer> {
+        self.region()
+            .get_peers()
+            .iter()
+            .find(|p| p.get_id() != self.peer_id() && self.is_transfer_leader_allowed(p))
+            .cloned()
+    }
+
     fn read_local(&mut self, req: RaftCmdRequest, cb: Callback, metrics: &mut RaftProposeMetrics) {
         metrics.local_read += 1;
         cb(self.handle_read(req));
@@ -1341,6 +1525,7 @@ impl Peer {
     fn propose_normal(
         &mut self,
         mut req: RaftCmdRequest,
+        context: Vec<u8>,
         metrics: &mut RaftProposeMetrics,
     ) -> Result<u64> {
         metrics.normal += 1;
@@ -1352,13 +1537,14 @@ impl Peer {
         // TODO: use local histogram metrics
         PEER_PROPOSE_LOG_SIZE_HISTOGRAM.observe(data.len() as f64);
 
-        if data.len() as u64 > self.raft_entry_max_size {
-            error!("entry is too large, entry size {}", data.len());
-            return Err(Error::RaftEntryTooLarge(self.region_id, data.len() as u64));
+        let size = data.len() + context.len();
+        if size as u64 > self.raft_entry_max_size {
+            error!("entry is too large, entry size {}", size);
+            return Err(Error::RaftEntryTooLarge(self.region_id, size as u64));
         }
 
         let propose_index = self.next_proposal_index();
-        try!(self.raft_group.propose(data));
+        try!(self.raft_group.propose_with_context(context, data));
         if self.next_proposal_index() == propose_index {
             // The message is dropped silently, this usually due to leader absence
             // or transferring leader. Both cases can be considered as NotLeader error.
@@ -1564,6 +1750,8 @@ impl Peer {
             pending_peers: self.collect_pending_peers(),
             written_bytes: self.peer_stat.last_written_bytes,
             written_keys: self.peer_stat.last_written_keys,
+            read_bytes: self.peer_stat.last_read_bytes,
+            read_keys: self.peer_stat.last_read_keys,
             approximate_size: self.approximate_size().unwrap_or(0),
         };
         if let Err(e) = worker.schedule(task) {
@@ -1571,7 +1759,62 @@ impl Peer {
         }
     }
 
+    /// Updates the election priorities of the other peers in raft, the stores
+    /// which are not resolved yet are resolved by the pd worker.
+    pub fn update_election_priorities(&mut self, worker: &FutureWorker<PdTask>) {
+        let peers = self.region().get_peers().to_vec();
+        let mut unresolved = vec![];
+        {
+            let priorities = self.store_priorities.rl();
+            for peer in &peers {
+                match priorities.get(&peer.get_store_id()) {
+                    Some(priority) => {
+                        self.raft_group
+                            .raft
+                            .set_peer_priority(peer.get_id(), *priority)
+                    }
+                    None => unresolved.push(peer.get_store_id()),
+                }
+            }
+        }
+        if unresolved.is_empty() {
+            return;
+        }
+        let task = PdTask::ResolveStores {
+            store_ids: unresolved,
+        };
+        if let Err(e) = worker.schedule(task) {
+            error!("{} failed to notify pd: {}", self.tag, e);
+        }
+    }
+
     fn send_raft_message<T: Transport>(&mut self, msg: eraftpb::Message, trans: &T) -> Result<()> {
+        let send_msg = try!(self.build_raft_message(msg));
+        let to_peer_id = send_msg.get_to_peer().get_id();
+        let to_store_id = send_msg.get_to_peer().get_store_id();
+        let msg_type = send_msg.get_message().get_msg_type();
+
+        if let Err(e) = trans.send(send_msg) {
+            warn!(
+                "{} failed to send msg to {} in store {}, err: {:?}",
+                self.tag,
+                to_peer_id,
+                to_store_id,
+                e
+            );
+
+            // unreachable store
+            self.raft_group.report_unreachable(to_peer_id);
+            if msg_type == eraftpb::MessageType::MsgSnapshot {
+                self.raft_group
+                    .report_snapshot(to_peer_id, SnapshotStatus::Failure);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_raft_message(&self, mut msg: eraftpb::Message) -> Result<RaftMessage> {
         let mut send_msg = RaftMessage::new();
         send_msg.set_region_id(self.region_id);
         // set current epoch
@@ -1591,7 +1834,6 @@ impl Peer {
         };
 
         let to_peer_id = to_peer.get_id();
-        let to_store_id = to_peer.get_store_id();
         let msg_type = msg.get_msg_type();
         debug!(
             "{} send raft msg {:?}[size: {}] from {} to {}",
@@ -1602,6 +1844,11 @@ impl Peer {
             to_peer_id
         );
 
+        // A witness stores no data, only the meta of the snapshot is sent.
+        if msg_type == MessageType::MsgSnapshot && self.is_witness_peer(&to_peer) == Some(true) {
+            try!(strip_snapshot_files(msg.mut_snapshot()));
+        }
+
         send_msg.set_from_peer(from_peer);
         send_msg.set_to_peer(to_peer);
 
@@ -1624,25 +1871,536 @@ impl Peer {
         }
 
         send_msg.set_message(msg);
+        Ok(send_msg)
+    }
+
+    fn send_delegate_snap_msg<T: Transport>(
+        &self,
+        to: metapb::Peer,
+        mut msg: eraftpb::Message,
+        trans: &T,
+    ) -> Result<()> {
+        msg.set_from(self.peer_id());
+        msg.set_to(to.get_id());
+        msg.set_term(self.term());
+
+        let mut send_msg = RaftMessage::new();
+        send_msg.set_region_id(self.region_id);
+        send_msg.set_region_epoch(self.region().get_region_epoch().clone());
+        send_msg.set_from_peer(self.peer.clone());
+        send_msg.set_to_peer(to);
+        send_msg.set_message(msg);
+        trans.send(send_msg)
+    }
+
+    /// Handles a message of snapshot generation delegation, returns true if a
+    /// snapshot is sent on behalf of the leader.
+    pub fn on_delegate_snap_msg<T: Transport>(
+        &mut self,
+        from: metapb::Peer,
+        msg: eraftpb::Message,
+        trans: &T,
+    ) -> bool {
+        if msg.get_context() == DELEGATE_SNAP_GEN {
+            self.on_delegate_snap_gen(from, msg, trans);
+        } else if msg.get_context() == DELEGATE_SNAP_GEN_RESP {
+            self.on_delegate_snap_gen_resp(from.get_id(), msg);
+        } else if msg.get_context() == DELEGATE_SNAP_SEND {
+            return self.on_delegate_snap_send(from, msg, trans);
+        } else if msg.get_context() == DELEGATE_SNAP_STATUS {
+            self.on_delegate_snap_status(msg);
+        }
+        false
+    }
+
+    /// Loads the logs that lagging followers are going to need into the
+    /// entry cache in the background, so sending them doesn't block the
+    /// raftstore thread on reading the raft engine.
+    pub fn prefetch_entries(&mut self, sched: &Scheduler<RaftlogFetchTask>) {
+        if !self.is_leader() {
+            return;
+        }
+        let low = self.raft_group
+            .raft
+            .prs
+            .iter()
+            .filter(|&(_, pr)| pr.state != ProgressState::Snapshot)
+            .map(|(_, pr)| pr.next_idx)
+            .min();
+        if let Some(low) = low {
+            if low < self.get_store().cache_first_index() {
+                self.get_store().prefetch_entries(low, sched);
+            }
+        }
+    }
 
+    /// Returns the first index of the cached entries that may still be
+    /// needed, the entries before it can be evicted from the cache freely.
+    pub fn cache_evictable_index(&self) -> u64 {
+        let mut idx = self.get_store().applied_index() + 1;
+        if self.is_leader() {
+            for pr in self.raft_group.raft.prs.values() {
+                idx = cmp::min(idx, pr.matched + 1);
+            }
+        }
+        idx
+    }
+
+    pub fn on_snap_gen_delegation_tick<T: Transport>(&mut self, trans: &T) {
+        self.handle_gen_snap_task(trans);
+
+        let expired = match self.delegated_snap {
+            Some(ref snap) => {
+                !self.is_leader() ||
+                    snap.start.elapsed() >= self.cfg.delegate_snap_generation_timeout.0
+            }
+            None => false,
+        };
+        if expired {
+            warn!(
+                "{} snapshot sent by {:?} timeout",
+                self.tag,
+                self.delegated_snap.as_ref().unwrap().delegate
+            );
+            self.clear_delegated_snap();
+        }
+
+        let expired = match self.pending_snap_gen {
+            Some(ref pending) => {
+                !self.is_leader() || pending.term != self.term() ||
+                    pending.start.elapsed() >= self.cfg.delegate_snap_generation_timeout.0
+            }
+            None => false,
+        };
+        if expired {
+            let pending = self.pending_snap_gen.take().unwrap();
+            if self.is_leader() {
+                warn!(
+                    "{} snapshot generation delegated to {:?} timeout, generate locally",
+                    self.tag,
+                    pending.delegate
+                );
+                self.get_store().schedule_gen_snap_task(pending.task);
+            }
+        }
+
+        let res = match self.delegated_snap_gen.as_ref().map(|g| g.receiver.try_recv()) {
+            None | Some(Err(TryRecvError::Empty)) => return,
+            Some(Ok(snap)) => Some(snap),
+            Some(Err(TryRecvError::Disconnected)) => None,
+        };
+        let gen = self.delegated_snap_gen.take().unwrap();
+        let mut resp = new_delegate_snap_msg(DELEGATE_SNAP_GEN_RESP);
+        let res = match res {
+            Some(snap) => {
+                info!(
+                    "{} snapshot {:?} generated for leader {}",
+                    self.tag,
+                    SnapKey::from_snap(&snap),
+                    gen.leader.get_id()
+                );
+                set_delegate_snap_payload(&mut resp, &snap)
+            }
+            None => {
+                warn!(
+                    "{} failed to generate snapshot for leader {}",
+                    self.tag,
+                    gen.leader.get_id()
+                );
+                resp.set_reject(true);
+                Ok(())
+            }
+        };
+        if let Err(e) = res.and_then(|_| self.send_delegate_snap_msg(gen.leader, resp, trans)) {
+            warn!("{} failed to respond snapshot generation: {:?}", self.tag, e);
+        }
+    }
+
+    // Generates the snapshot requested by raft on an up-to-date follower if
+    // possible, or in the local region worker.
+    fn handle_gen_snap_task<T: Transport>(&mut self, trans: &T) {
+        let task = match self.get_store().take_gen_snap_task() {
+            Some(task) => task,
+            None => return,
+        };
+        if self.is_leader() && self.pending_snap_gen.is_none() {
+            if let Some(delegate) = self.pick_snap_gen_delegate() {
+                let mut msg = new_delegate_snap_msg(DELEGATE_SNAP_GEN);
+                // The snapshot is useless unless it covers the compacted logs.
+                msg.set_index(self.get_store().truncated_index());
+                match self.send_delegate_snap_msg(delegate.clone(), msg, trans) {
+                    Ok(()) => {
+                        info!(
+                            "{} delegate snapshot generation to {:?}",
+                            self.tag,
+                            delegate
+                        );
+                        self.pending_snap_gen = Some(PendingSnapGen {
+                            delegate: delegate,
+                            term: self.term(),
+                            task: task,
+                            start: Instant::now(),
+                        });
+                        return;
+                    }
+                    Err(e) => warn!(
+                        "{} failed to delegate snapshot generation to {:?}: {:?}",
+                        self.tag,
+                        delegate,
+                        e
+                    ),
+                }
+            }
+        }
+        self.get_store().schedule_gen_snap_task(task);
+    }
+
+    // Picks the active follower with the largest matched index that covers the
+    // compacted logs.
+    fn pick_snap_gen_delegate(&self) -> Option<metapb::Peer> {
+        let active_duration =
+            self.cfg.raft_base_tick_interval.0 * self.cfg.raft_election_timeout_ticks as u32;
+        let mut matched = self.get_store().truncated_index();
+        let mut delegate = None;
+        for (id, pr) in self.raft_group.raft.prs.iter() {
+            if *id == self.peer_id() || pr.state != ProgressState::Replicate ||
+                pr.matched < matched
+            {
+                continue;
+            }
+            match self.peer_heartbeats.get(id) {
+                Some(t) if t.elapsed() < active_duration => {}
+                _ => continue,
+            }
+            if let Some(peer) = self.get_peer_from_cache(*id) {
+                if self.is_witness_peer(&peer) != Some(false) {
+                    continue;
+                }
+                matched = pr.matched;
+                delegate = Some(peer);
+            }
+        }
+        delegate
+    }
+
+    fn on_delegate_snap_gen<T: Transport>(
+        &mut self,
+        leader: metapb::Peer,
+        msg: eraftpb::Message,
+        trans: &T,
+    ) {
+        let index = msg.get_index();
+        let reject_reason = if self.witness {
+            Some("witness has no data")
+        } else if self.is_leader() || self.leader_id() != leader.get_id() {
+            Some("not a follower of the leader")
+        } else if msg.get_term() != self.term() {
+            Some("term mismatch")
+        } else if self.delegated_snap_gen.is_some() {
+            Some("another snapshot is generating")
+        } else if self.is_applying_snapshot() || self.has_pending_snapshot() {
+            Some("applying snapshot")
+        } else if self.get_store().applied_index() < index {
+            Some("applied index is too small")
+        } else {
+            None
+        };
+        if let Some(reason) = reject_reason {
+            info!(
+                "{} reject to generate snapshot at {} for leader {}: {}",
+                self.tag,
+                index,
+                leader.get_id(),
+                reason
+            );
+            let mut resp = new_delegate_snap_msg(DELEGATE_SNAP_GEN_RESP);
+            resp.set_reject(true);
+            if let Err(e) = self.send_delegate_snap_msg(leader, resp, trans) {
+                warn!("{} failed to respond snapshot generation: {:?}", self.tag, e);
+            }
+            return;
+        }
+
+        info!(
+            "{} generate snapshot at {} for leader {}",
+            self.tag,
+            index,
+            leader.get_id()
+        );
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.get_store()
+            .schedule_gen_snap_task(GenSnapTask::new(self.region_id, tx));
+        self.delegated_snap_gen = Some(DelegatedSnapGen {
+            leader: leader,
+            receiver: rx,
+        });
+    }
+
+    fn on_delegate_snap_gen_resp(&mut self, from: u64, msg: eraftpb::Message) {
+        let is_pending = match self.pending_snap_gen {
+            Some(ref pending) => {
+                pending.delegate.get_id() == from && pending.term == msg.get_term()
+            }
+            None => false,
+        };
+        if !is_pending {
+            info!(
+                "{} ignore stale snapshot generation response from {}",
+                self.tag,
+                from
+            );
+            return;
+        }
+        let pending = self.pending_snap_gen.take().unwrap();
+        if !self.is_leader() || pending.term != self.term() {
+            // Dropping the task makes the storage try again later.
+            return;
+        }
+
+        let res = if msg.get_reject() {
+            Err(box_err!("rejected by {}", from))
+        } else {
+            get_delegate_snap_payload::<eraftpb::Snapshot>(&msg).and_then(|snap| {
+                try!(self.check_delegated_snap(&snap));
+                let key = try!(SnapKey::from_snap(&snap));
+                Ok((snap, key))
+            })
+        };
+        match res {
+            Ok((snap, key)) => {
+                info!(
+                    "{} accept snapshot {} generated by {:?}",
+                    self.tag,
+                    key,
+                    pending.delegate
+                );
+                // The sends of the previous snapshot are not waited anymore.
+                self.clear_delegated_snap();
+                self.delegated_snap = Some(DelegatedSnap {
+                    key: key,
+                    delegate: pending.delegate,
+                    sending: HashSet::default(),
+                    start: Instant::now(),
+                });
+                pending.task.notify(snap);
+            }
+            Err(e) => {
+                warn!(
+                    "{} snapshot generation delegated to {:?} failed: {:?}, generate locally",
+                    self.tag,
+                    pending.delegate,
+                    e
+                );
+                self.get_store().schedule_gen_snap_task(pending.task);
+            }
+        }
+    }
+
+    // A snapshot generated by a follower is accepted only if it covers the
+    // compacted logs and matches the log of the leader.
+    fn check_delegated_snap(&self, snap: &eraftpb::Snapshot) -> Result<()> {
+        let index = snap.get_metadata().get_index();
+        let term = snap.get_metadata().get_term();
+        let truncated_index = self.get_store().truncated_index();
+        if index < truncated_index {
+            return Err(box_err!(
+                "snapshot index {} < truncated index {}",
+                index,
+                truncated_index
+            ));
+        }
+        let local_term = try!(self.raft_group.raft.raft_log.term(index));
+        if local_term != term {
+            return Err(box_err!(
+                "snapshot term {} at {} doesn't match local term {}",
+                term,
+                index,
+                local_term
+            ));
+        }
+        Ok(())
+    }
+
+    fn is_delegated_snap(&self, msg: &eraftpb::Message) -> bool {
+        match self.delegated_snap {
+            Some(ref snap) => SnapKey::from_snap(msg.get_snapshot())
+                .map(|k| k == snap.key)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    // Forgets the delegated snapshot, the sends not reported yet are treated
+    // as failed so raft can retry them.
+    fn clear_delegated_snap(&mut self) {
+        let snap = match self.delegated_snap.take() {
+            Some(snap) => snap,
+            None => return,
+        };
+        if !self.is_leader() {
+            return;
+        }
+        for to in snap.sending {
+            self.raft_group.report_snapshot(to, SnapshotStatus::Failure);
+        }
+    }
+
+    // Asks the follower holding the snapshot files to send the snapshot.
+    fn forward_delegated_snap<T: Transport>(&mut self, msg: eraftpb::Message, trans: &T) {
+        let to = msg.get_to();
+        let delegate = self.delegated_snap.as_ref().unwrap().delegate.clone();
+        let res = self.build_raft_message(msg).and_then(|send_msg| {
+            let mut m = new_delegate_snap_msg(DELEGATE_SNAP_SEND);
+            try!(set_delegate_snap_payload(&mut m, &send_msg));
+            self.send_delegate_snap_msg(delegate.clone(), m, trans)
+        });
+        match res {
+            Ok(()) => {
+                info!(
+                    "{} ask {:?} to send snapshot to {}",
+                    self.tag,
+                    delegate,
+                    to
+                );
+                self.delegated_snap.as_mut().unwrap().sending.insert(to);
+            }
+            Err(e) => {
+                warn!(
+                    "{} failed to ask {:?} to send snapshot to {}: {:?}",
+                    self.tag,
+                    delegate,
+                    to,
+                    e
+                );
+                self.raft_group.report_snapshot(to, SnapshotStatus::Failure);
+            }
+        }
+    }
+
+    fn on_delegate_snap_send<T: Transport>(
+        &mut self,
+        leader: metapb::Peer,
+        msg: eraftpb::Message,
+        trans: &T,
+    ) -> bool {
+        let send_msg: RaftMessage = match get_delegate_snap_payload(&msg) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("{} invalid snapshot send request: {:?}", self.tag, e);
+                return false;
+            }
+        };
+        let to_peer_id = send_msg.get_to_peer().get_id();
+        if send_msg.get_region_id() != self.region_id || !send_msg.get_message().has_snapshot() {
+            warn!(
+                "{} invalid snapshot send request to {} from leader {}",
+                self.tag,
+                to_peer_id,
+                leader.get_id()
+            );
+            return false;
+        }
+        info!(
+            "{} send snapshot to {} on behalf of leader {}",
+            self.tag,
+            to_peer_id,
+            leader.get_id()
+        );
         if let Err(e) = trans.send(send_msg) {
             warn!(
-                "{} failed to send msg to {} in store {}, err: {:?}",
+                "{} failed to send snapshot to {}: {:?}",
                 self.tag,
                 to_peer_id,
-                to_store_id,
                 e
             );
+            self.send_delegated_snap_status(leader, to_peer_id, SnapshotStatus::Failure, trans);
+            return false;
+        }
+        self.delegated_snap_sends.insert(to_peer_id, leader);
+        true
+    }
 
-            // unreachable store
-            self.raft_group.report_unreachable(to_peer_id);
-            if msg_type == eraftpb::MessageType::MsgSnapshot {
-                self.raft_group
-                    .report_snapshot(to_peer_id, SnapshotStatus::Failure);
+    /// Forwards the status of a snapshot sent on behalf of the leader, returns
+    /// false if the snapshot is sent by the peer itself.
+    pub fn report_delegated_snap_status<T: Transport>(
+        &mut self,
+        to_peer_id: u64,
+        status: SnapshotStatus,
+        trans: &T,
+    ) -> bool {
+        if self.is_leader() {
+            self.delegated_snap_sends.clear();
+            return false;
+        }
+        match self.delegated_snap_sends.remove(&to_peer_id) {
+            Some(leader) => {
+                self.send_delegated_snap_status(leader, to_peer_id, status, trans);
+                true
             }
+            None => false,
         }
+    }
 
-        Ok(())
+    fn send_delegated_snap_status<T: Transport>(
+        &self,
+        leader: metapb::Peer,
+        to_peer_id: u64,
+        status: SnapshotStatus,
+        trans: &T,
+    ) {
+        let mut status_msg = eraftpb::Message::new();
+        status_msg.set_msg_type(MessageType::MsgSnapStatus);
+        status_msg.set_from(to_peer_id);
+        status_msg.set_reject(status == SnapshotStatus::Failure);
+        let mut msg = new_delegate_snap_msg(DELEGATE_SNAP_STATUS);
+        if let Err(e) = set_delegate_snap_payload(&mut msg, &status_msg)
+            .and_then(|_| self.send_delegate_snap_msg(leader, msg, trans))
+        {
+            warn!(
+                "{} failed to report snapshot status of {}: {:?}",
+                self.tag,
+                to_peer_id,
+                e
+            );
+        }
+    }
+
+    fn on_delegate_snap_status(&mut self, msg: eraftpb::Message) {
+        if !self.is_leader() {
+            return;
+        }
+        let status_msg: eraftpb::Message = match get_delegate_snap_payload(&msg) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("{} invalid snapshot status: {:?}", self.tag, e);
+                return;
+            }
+        };
+        let status = if status_msg.get_reject() {
+            SnapshotStatus::Failure
+        } else {
+            SnapshotStatus::Finish
+        };
+        info!(
+            "{} snapshot sent by {} to {} is {:?}",
+            self.tag,
+            msg.get_from(),
+            status_msg.get_from(),
+            status
+        );
+        let to = status_msg.get_from();
+        let done = match self.delegated_snap {
+            Some(ref mut snap) if snap.delegate.get_id() == msg.get_from() => {
+                snap.sending.remove(&to);
+                // The delegate may have lost the files, don't ask it again.
+                status == SnapshotStatus::Failure || snap.sending.is_empty()
+            }
+            _ => false,
+        };
+        if done {
+            self.clear_delegated_snap();
+        }
+        self.raft_group.report_snapshot(to, status);
     }
 
     fn exec_read(&mut self, req: &RaftCmdRequest) -> Result<RaftCmdResponse> {
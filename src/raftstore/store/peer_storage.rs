@@ -12,8 +12,8 @@
 // limitations under the License.
 
 use std::sync::{self, Arc};
-use std::sync::mpsc::{self, Receiver, TryRecvError};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::{cmp, error, u64};
@@ -31,10 +31,11 @@ use util::worker::Scheduler;
 use util::{self, rocksdb};
 use raft::{self, Error as RaftError, RaftState, Ready, Storage, StorageError};
 use raftstore::{Error, Result};
-use super::worker::RegionTask;
+use super::worker::{RaftlogFetchTask, RegionTask};
 use super::keys::{self, enc_end_key, enc_start_key};
-use super::engine::{Iterable, Mutable, Peekable, Snapshot as DbSnapshot};
+use super::engine::{Mutable, Peekable, Snapshot as DbSnapshot, SyncSnapshot};
 use super::peer::ReadyContext;
+use super::raft_engine::{RaftEngine, RaftLogBatch};
 use super::metrics::*;
 use super::{SnapEntry, SnapKey, SnapManager, SnapshotStatistics};
 use storage::CF_RAFT;
@@ -44,7 +45,6 @@ use storage::CF_RAFT;
 pub const RAFT_INIT_LOG_TERM: u64 = 5;
 pub const RAFT_INIT_LOG_INDEX: u64 = 5;
 const MAX_SNAP_TRY_CNT: usize = 5;
-const RAFT_LOG_MULTI_GET_CNT: u64 = 8;
 
 // One extra slot for VecDeque internal usage.
 const MAX_CACHE_CAPACITY: usize = 1024 - 1;
@@ -118,9 +118,17 @@ pub fn last_index(state: &RaftLocalState) -> u64 {
     state.get_last_index()
 }
 
+fn entries_size<'a, I: IntoIterator<Item = &'a Entry>>(entries: I) -> u64 {
+    entries
+        .into_iter()
+        .fold(0, |size, e| size + e.compute_size() as u64)
+}
+
 #[derive(Default)]
 struct EntryCache {
     cache: VecDeque<Entry>,
+    // The memory taken by the cached entries.
+    mem_size: u64,
 }
 
 impl EntryCache {
@@ -180,9 +188,10 @@ impl EntryCache {
             let first_index = entries[0].get_index();
             if cache_last_index >= first_index {
                 if self.cache.front().unwrap().get_index() >= first_index {
-                    self.cache.clear();
+                    self.clear();
                 } else {
                     let left = self.cache.len() - (cache_last_index - first_index + 1) as usize;
+                    self.mem_size -= entries_size(self.cache.iter().skip(left));
                     self.cache.truncate(left);
                 }
                 if self.cache.len() + entries.len() < SHRINK_CACHE_CAPACITY &&
@@ -202,17 +211,38 @@ impl EntryCache {
         let mut start_idx = 0;
         if let Some(len) = (self.cache.len() + entries.len()).checked_sub(MAX_CACHE_CAPACITY) {
             if len < self.cache.len() {
+                self.mem_size -= entries_size(self.cache.iter().take(len));
                 self.cache.drain(..len);
             } else {
                 start_idx = len - self.cache.len();
-                self.cache.clear();
+                self.clear();
             }
         }
         for e in &entries[start_idx..] {
+            self.mem_size += e.compute_size() as u64;
             self.cache.push_back(e.to_owned());
         }
     }
 
+    // Puts the entries fetched from the raft engine before the cached ones,
+    // returns false if they don't connect to the cache.
+    fn prepend(&mut self, entries: Vec<Entry>) -> bool {
+        match (entries.last(), self.cache.front()) {
+            (Some(last), Some(first)) if last.get_index() + 1 == first.get_index() => {}
+            _ => return false,
+        }
+        for e in entries.into_iter().rev() {
+            self.mem_size += e.compute_size() as u64;
+            self.cache.push_front(e);
+        }
+        true
+    }
+
+    fn clear(&mut self) {
+        self.cache.clear();
+        self.mem_size = 0;
+    }
+
     pub fn compact_to(&mut self, idx: u64) {
         let cache_first_idx = match self.cache.front() {
             None => return,
@@ -223,8 +253,9 @@ impl EntryCache {
             return;
         }
         let cache_last_idx = self.cache.back().unwrap().get_index();
-        self.cache
-            .drain(..(cmp::min(cache_last_idx, idx) - cache_first_idx) as usize);
+        let len = (cmp::min(cache_last_idx, idx) - cache_first_idx) as usize;
+        self.mem_size -= entries_size(self.cache.iter().take(len));
+        self.cache.drain(..len);
         if self.cache.len() < SHRINK_CACHE_CAPACITY &&
             self.cache.capacity() > SHRINK_CACHE_CAPACITY
         {
@@ -235,13 +266,31 @@ impl EntryCache {
     }
 }
 
-#[derive(Default)]
 pub struct CacheQueryStats {
     pub hit: u64,
     pub miss: u64,
+    // The memory taken by the entry caches of all peers on the store.
+    pub mem_size: u64,
+    // The limit of `mem_size`, prefetching never exceeds it.
+    pub limit: u64,
+}
+
+impl Default for CacheQueryStats {
+    fn default() -> CacheQueryStats {
+        CacheQueryStats::new(u64::MAX)
+    }
 }
 
 impl CacheQueryStats {
+    pub fn new(limit: u64) -> CacheQueryStats {
+        CacheQueryStats {
+            hit: 0,
+            miss: 0,
+            mem_size: 0,
+            limit: limit,
+        }
+    }
+
     pub fn flush(&mut self) {
         RAFT_ENTRY_FETCHES
             .with_label_values(&["hit"])
@@ -253,12 +302,41 @@ impl CacheQueryStats {
             .unwrap();
         self.hit = 0;
         self.miss = 0;
+        RAFT_ENTRY_CACHE_MEM_SIZE_GAUGE.set(self.mem_size as f64);
+    }
+}
+
+/// A snapshot generation task that is held by `PeerStorage` until the peer
+/// decides where to generate it, see `Peer::handle_gen_snap_task`.
+pub struct GenSnapTask {
+    region_id: u64,
+    notifier: SyncSender<Snapshot>,
+}
+
+impl GenSnapTask {
+    pub fn new(region_id: u64, notifier: SyncSender<Snapshot>) -> GenSnapTask {
+        GenSnapTask {
+            region_id: region_id,
+            notifier: notifier,
+        }
+    }
+
+    /// Hands over a snapshot generated elsewhere, it's returned by the
+    /// next `PeerStorage::snapshot` call.
+    pub fn notify(self, snap: Snapshot) {
+        if let Err(e) = self.notifier.try_send(snap) {
+            info!(
+                "[region {}] failed to notify snap result, ignore: {:?}",
+                self.region_id,
+                e
+            );
+        }
     }
 }
 
 pub struct PeerStorage {
     pub kv_engine: Arc<DB>,
-    pub raft_engine: Arc<DB>,
+    pub raft_engine: Arc<RaftEngine>,
 
     pub region: metapb::Region,
     pub raft_state: RaftLocalState,
@@ -269,13 +347,31 @@ pub struct PeerStorage {
     snap_state: RefCell<SnapState>,
     region_sched: Scheduler<RegionTask>,
     snap_tried_cnt: RefCell<usize>,
+    // If set, the generation task is kept in `gen_snap_task` instead of being
+    // scheduled to the region worker directly.
+    delegate_snap_gen: bool,
+    gen_snap_task: RefCell<Option<GenSnapTask>>,
 
     cache: EntryCache,
     stats: Rc<RefCell<CacheQueryStats>>,
+    // There is at most one prefetch running, its result is dropped if the
+    // seq is changed, which means the log may be overwritten. It's shared
+    // with the fetch task, which resets it if the result can't be sent back.
+    prefetching: Arc<AtomicBool>,
+    prefetch_seq: u64,
+    // If set, the entries to send which are not in the cache are fetched
+    // asynchronously.
+    raftlog_fetch_sched: Option<Scheduler<RaftlogFetchTask>>,
 
     pub tag: String,
 }
 
+impl Drop for PeerStorage {
+    fn drop(&mut self) {
+        self.stats.borrow_mut().mem_size -= self.cache.mem_size;
+    }
+}
+
 fn storage_error<E>(error: E) -> raft::Error
 where
     E: Into<Box<error::Error + Send + Sync>>,
@@ -326,8 +422,8 @@ impl InvokeContext {
     }
 
     #[inline]
-    pub fn save_raft_state_to(&self, raft_wb: &mut WriteBatch) -> Result<()> {
-        try!(raft_wb.put_msg(&keys::raft_state_key(self.region_id), &self.raft_state));
+    pub fn save_raft_state_to(&self, raft_wb: &mut RaftLogBatch) -> Result<()> {
+        raft_wb.put_state(self.region_id, &self.raft_state);
         Ok(())
     }
 
@@ -365,7 +461,11 @@ impl InvokeContext {
     }
 }
 
-pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id: u64) -> Result<()> {
+pub fn recover_from_applying_state(
+    kv_engine: &DB,
+    raft_engine: &RaftEngine,
+    region_id: u64,
+) -> Result<()> {
     let snapshot_raft_state_key = keys::snapshot_raft_state_key(region_id);
     let snapshot_raft_state: RaftLocalState =
         match box_try!(kv_engine.get_msg_cf(CF_RAFT, &snapshot_raft_state_key)) {
@@ -379,8 +479,7 @@ pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id:
             }
         };
 
-    let raft_state_key = keys::raft_state_key(region_id);
-    let raft_state: RaftLocalState = match box_try!(raft_engine.get_msg(&raft_state_key)) {
+    let raft_state = match box_try!(raft_engine.get_raft_state(region_id)) {
         Some(state) => state,
         None => RaftLocalState::new(),
     };
@@ -392,14 +491,15 @@ pub fn recover_from_applying_state(kv_engine: &DB, raft_engine: &DB, region_id:
     // (snapshot_raft_state), and set snapshot_raft_state.last_index = snapshot_index.
     // after restart, we need check last_index.
     if last_index(&snapshot_raft_state) > last_index(&raft_state) {
-        try!(raft_engine.put_msg(&raft_state_key, &snapshot_raft_state));
+        let mut raft_wb = RaftLogBatch::new();
+        raft_wb.put_state(region_id, &snapshot_raft_state);
+        try!(raft_engine.write(raft_wb, false));
     }
     Ok(())
 }
 
-fn init_raft_state(raft_engine: &DB, region: &Region) -> Result<RaftLocalState> {
-    let state_key = keys::raft_state_key(region.get_id());
-    Ok(match try!(raft_engine.get_msg(&state_key)) {
+fn init_raft_state(raft_engine: &RaftEngine, region: &Region) -> Result<RaftLocalState> {
+    Ok(match try!(raft_engine.get_raft_state(region.get_id())) {
         Some(s) => s,
         None => {
             let mut raft_state = RaftLocalState::new();
@@ -408,7 +508,9 @@ fn init_raft_state(raft_engine: &DB, region: &Region) -> Result<RaftLocalState>
                 raft_state.set_last_index(RAFT_INIT_LOG_INDEX);
                 raft_state.mut_hard_state().set_term(RAFT_INIT_LOG_TERM);
                 raft_state.mut_hard_state().set_commit(RAFT_INIT_LOG_INDEX);
-                try!(raft_engine.put_msg(&state_key, &raft_state));
+                let mut raft_wb = RaftLogBatch::new();
+                raft_wb.put_state(region.get_id(), &raft_state);
+                try!(raft_engine.write(raft_wb, false));
             }
             raft_state
         }
@@ -434,7 +536,7 @@ fn init_apply_state(kv_engine: &DB, region: &Region) -> Result<RaftApplyState> {
 }
 
 fn init_last_term(
-    raft_engine: &DB,
+    raft_engine: &RaftEngine,
     region: &Region,
     raft_state: &RaftLocalState,
     apply_state: &RaftApplyState,
@@ -449,8 +551,7 @@ fn init_last_term(
     } else {
         assert!(last_idx > RAFT_INIT_LOG_INDEX);
     }
-    let last_log_key = keys::raft_log_key(region.get_id(), last_idx);
-    Ok(match try!(raft_engine.get_msg::<Entry>(&last_log_key)) {
+    Ok(match try!(raft_engine.get_entry(region.get_id(), last_idx)) {
         None => {
             return Err(box_err!(
                 "[region {}] entry at {} doesn't exist, may lose data.",
@@ -465,14 +566,14 @@ fn init_last_term(
 impl PeerStorage {
     pub fn new(
         kv_engine: Arc<DB>,
-        raft_engine: Arc<DB>,
+        raft_engine: Arc<RaftEngine>,
         region: &metapb::Region,
         region_sched: Scheduler<RegionTask>,
         tag: String,
         stats: Rc<RefCell<CacheQueryStats>>,
     ) -> Result<PeerStorage> {
         debug!("creating storage on {} for {:?}", kv_engine.path(), region);
-        let raft_state = try!(init_raft_state(&raft_engine, region));
+        let raft_state = try!(init_raft_state(raft_engine.as_ref(), region));
         let apply_state = try!(init_apply_state(&kv_engine, region));
         if raft_state.get_last_index() < apply_state.get_applied_index() {
             panic!(
@@ -483,7 +584,7 @@ impl PeerStorage {
             );
         }
         let last_term = try!(init_last_term(
-            &raft_engine,
+            raft_engine.as_ref(),
             region,
             &raft_state,
             &apply_state
@@ -498,11 +599,16 @@ impl PeerStorage {
             snap_state: RefCell::new(SnapState::Relax),
             region_sched: region_sched,
             snap_tried_cnt: RefCell::new(0),
+            delegate_snap_gen: false,
+            gen_snap_task: RefCell::new(None),
             tag: tag,
             applied_index_term: RAFT_INIT_LOG_TERM,
             last_term: last_term,
             cache: EntryCache::default(),
             stats: stats,
+            prefetching: Arc::new(AtomicBool::new(false)),
+            prefetch_seq: 0,
+            raftlog_fetch_sched: None,
         })
     }
 
@@ -587,6 +693,18 @@ impl PeerStorage {
         Ok(ents)
     }
 
+    /// Like `entries`, but if the entries are not in the cache, they are read
+    /// from the raft engine asynchronously and `LogTemporarilyUnavailable` is
+    /// returned. The cache is filled when the fetch is done.
+    pub fn fetch_entries(&self, low: u64, high: u64, max_size: u64) -> raft::Result<Vec<Entry>> {
+        if let Some(ref sched) = self.raftlog_fetch_sched {
+            if low < self.cache.first_index() && self.prefetch_entries(low, sched) {
+                return Err(RaftError::Store(StorageError::LogTemporarilyUnavailable));
+            }
+        }
+        self.entries(low, high, max_size)
+    }
+
     fn fetch_entries_to(
         &self,
         low: u64,
@@ -594,66 +712,8 @@ impl PeerStorage {
         max_size: u64,
         buf: &mut Vec<Entry>,
     ) -> raft::Result<u64> {
-        let mut total_size: u64 = 0;
-        let mut next_index = low;
-        let mut exceeded_max_size = false;
-        if high - low <= RAFT_LOG_MULTI_GET_CNT {
-            // If election happens in inactive regions, they will just try
-            // to fetch one empty log.
-            for i in low..high {
-                let key = keys::raft_log_key(self.get_region_id(), i);
-                match box_try!(self.raft_engine.get(&key)) {
-                    None => return Err(RaftError::Store(StorageError::Unavailable)),
-                    Some(v) => {
-                        let mut entry = Entry::new();
-                        box_try!(entry.merge_from_bytes(&v));
-                        assert_eq!(entry.get_index(), i);
-                        total_size += v.len() as u64;
-                        if buf.is_empty() || total_size <= max_size {
-                            buf.push(entry);
-                        }
-                        if total_size > max_size {
-                            break;
-                        }
-                    }
-                }
-            }
-            return Ok(total_size);
-        }
-
-        let start_key = keys::raft_log_key(self.get_region_id(), low);
-        let end_key = keys::raft_log_key(self.get_region_id(), high);
-        try!(self.raft_engine.scan(
-            &start_key,
-            &end_key,
-            true, // fill_cache
-            &mut |_, value| {
-                let mut entry = Entry::new();
-                try!(entry.merge_from_bytes(value));
-
-                // May meet gap or has been compacted.
-                if entry.get_index() != next_index {
-                    return Ok(false);
-                }
-                next_index += 1;
-
-                total_size += value.len() as u64;
-                exceeded_max_size = total_size > max_size;
-                if !exceeded_max_size || buf.is_empty() {
-                    buf.push(entry);
-                }
-                Ok(!exceeded_max_size)
-            }
-        ));
-
-        // If we get the correct number of entries, returns,
-        // or the total size almost exceeds max_size, returns.
-        if buf.len() == (high - low) as usize || exceeded_max_size {
-            return Ok(total_size);
-        }
-
-        // Here means we don't fetch enough entries.
-        Err(RaftError::Store(StorageError::Unavailable))
+        self.raft_engine
+            .fetch_entries_to(self.get_region_id(), low, high, max_size, buf)
     }
 
     pub fn term(&self, idx: u64) -> raft::Result<u64> {
@@ -806,9 +866,35 @@ impl PeerStorage {
         let (tx, rx) = mpsc::sync_channel(1);
         *snap_state = SnapState::Generating(rx);
 
+        let task = GenSnapTask::new(self.get_region_id(), tx);
+        if self.delegate_snap_gen {
+            *self.gen_snap_task.borrow_mut() = Some(task);
+        } else {
+            self.schedule_gen_snap_task(task);
+        }
+        Err(raft::Error::Store(
+            raft::StorageError::SnapshotTemporarilyUnavailable,
+        ))
+    }
+
+    pub fn set_delegate_snap_gen(&mut self, delegate: bool) {
+        self.delegate_snap_gen = delegate;
+    }
+
+    pub fn set_raftlog_fetch_scheduler(&mut self, sched: Scheduler<RaftlogFetchTask>) {
+        self.raftlog_fetch_sched = Some(sched);
+    }
+
+    /// Takes the generation task requested by the last `snapshot` call, if any.
+    pub fn take_gen_snap_task(&self) -> Option<GenSnapTask> {
+        self.gen_snap_task.borrow_mut().take()
+    }
+
+    /// Generates the snapshot of the task in the region worker.
+    pub fn schedule_gen_snap_task(&self, task: GenSnapTask) {
         let task = RegionTask::Gen {
-            region_id: self.get_region_id(),
-            notifier: tx,
+            region_id: task.region_id,
+            notifier: task.notifier,
         };
         if let Err(e) = self.region_sched.schedule(task) {
             error!(
@@ -818,9 +904,6 @@ impl PeerStorage {
             );
             // update the status next time the function is called, also backoff for retry.
         }
-        Err(raft::Error::Store(
-            raft::StorageError::SnapshotTemporarilyUnavailable,
-        ))
     }
 
     // Append the given entries to the raft log using previous last index or self.last_index.
@@ -830,9 +913,12 @@ impl PeerStorage {
         &mut self,
         ctx: &mut InvokeContext,
         entries: &[Entry],
-        raft_wb: &mut WriteBatch,
+        raft_wb: &mut RaftLogBatch,
     ) -> Result<u64> {
         debug!("{} append {} entries", self.tag, entries.len());
+        fail_point!("peer_storage_append", |_| {
+            Err(box_err!("{} failed to append entries by fail point", self.tag))
+        });
         let prev_last_index = ctx.raft_state.get_last_index();
         if entries.is_empty() {
             return Ok(prev_last_index);
@@ -843,28 +929,142 @@ impl PeerStorage {
             (e.get_index(), e.get_term())
         };
 
-        for entry in entries {
-            try!(raft_wb.put_msg(
-                &keys::raft_log_key(self.get_region_id(), entry.get_index()),
-                entry
-            ));
-        }
+        raft_wb.append(self.get_region_id(), entries);
 
         // Delete any previously appended log entries which never committed.
-        for i in (last_index + 1)..(prev_last_index + 1) {
-            try!(raft_wb.delete(&keys::raft_log_key(self.get_region_id(), i)));
-        }
+        raft_wb.cut_logs(self.get_region_id(), last_index + 1, prev_last_index + 1);
 
         ctx.raft_state.set_last_index(last_index);
         ctx.last_term = last_term;
 
+        if entries[0].get_index() <= prev_last_index {
+            // The log is overwritten, a running prefetch may read the old one.
+            self.prefetch_seq += 1;
+        }
+
         // TODO: if the writebatch is failed to commit, the cache will be wrong.
+        let old_size = self.cache.mem_size;
         self.cache.append(&self.tag, entries);
+        self.update_cache_mem_size(old_size);
         Ok(last_index)
     }
 
     pub fn compact_to(&mut self, idx: u64) {
+        let old_size = self.cache.mem_size;
         self.cache.compact_to(idx);
+        self.update_cache_mem_size(old_size);
+    }
+
+    /// Drops all the cached entries, returns the memory released.
+    pub fn evict_cache(&mut self) -> u64 {
+        let old_size = self.cache.mem_size;
+        self.cache.clear();
+        self.update_cache_mem_size(old_size);
+        old_size
+    }
+
+    #[inline]
+    pub fn cache_mem_size(&self) -> u64 {
+        self.cache.mem_size
+    }
+
+    #[inline]
+    pub fn cache_first_index(&self) -> u64 {
+        self.cache.first_index()
+    }
+
+    fn update_cache_mem_size(&self, old_size: u64) {
+        let mut stats = self.stats.borrow_mut();
+        stats.mem_size = stats.mem_size + self.cache.mem_size - old_size;
+    }
+
+    /// Reads the entries in `[low, cache first index)` from the raft engine
+    /// asynchronously, so that they are in the cache when raft needs them.
+    /// Returns true if the entry at `low` is being fetched, it may not if the
+    /// range is too large for the cache, the caches of the store are over the
+    /// limit or a prefetch is already running.
+    pub fn prefetch_entries(&self, low: u64, sched: &Scheduler<RaftlogFetchTask>) -> bool {
+        if self.prefetching.load(Ordering::SeqCst) {
+            // Wait for the running one, the range is extended after it's done.
+            return true;
+        }
+        {
+            let stats = self.stats.borrow();
+            if stats.mem_size >= stats.limit {
+                // The entries would be evicted at once, read them directly.
+                return false;
+            }
+        }
+        let high = self.cache.first_index();
+        if high == u64::MAX {
+            return false;
+        }
+        let fetch_low = cmp::max(
+            low,
+            cmp::max(
+                self.truncated_index() + 1,
+                high.saturating_sub(MAX_CACHE_CAPACITY as u64),
+            ),
+        );
+        if fetch_low >= high {
+            return false;
+        }
+
+        let task = RaftlogFetchTask {
+            raft_engine: self.raft_engine.clone(),
+            region_id: self.get_region_id(),
+            seq: self.prefetch_seq,
+            prefetching: self.prefetching.clone(),
+            low: fetch_low,
+            high: high,
+        };
+        // Set it before scheduling, the task may fail and reset it at once.
+        self.prefetching.store(true, Ordering::SeqCst);
+        if let Err(e) = sched.schedule(task) {
+            error!("{} failed to schedule raftlog fetch: {:?}", self.tag, e);
+            self.prefetching.store(false, Ordering::SeqCst);
+            return false;
+        }
+        fetch_low == low
+    }
+
+    /// Fills the cache with the prefetched entries, returns true if the cache
+    /// is extended. The entries are counted against the limit of the caches,
+    /// only the ones next to the cache that fit in it are kept.
+    pub fn on_entries_prefetched(&mut self, seq: u64, mut entries: Vec<Entry>) -> bool {
+        self.prefetching.store(false, Ordering::SeqCst);
+        if seq != self.prefetch_seq {
+            debug!("{} drop stale prefetched entries", self.tag);
+            return false;
+        }
+        let budget = {
+            let stats = self.stats.borrow();
+            stats.limit.saturating_sub(stats.mem_size)
+        };
+        let mut size = 0;
+        let mut skip = entries.len();
+        for e in entries.iter().rev() {
+            size += e.compute_size() as u64;
+            if size > budget {
+                break;
+            }
+            skip -= 1;
+        }
+        if skip > 0 {
+            debug!(
+                "{} drop {} prefetched entries over the cache limit",
+                self.tag,
+                skip
+            );
+            entries.drain(..skip);
+        }
+        let old_size = self.cache.mem_size;
+        let prepended = self.cache.prepend(entries);
+        if !prepended {
+            debug!("{} prefetched entries don't connect to the cache", self.tag);
+        }
+        self.update_cache_mem_size(old_size);
+        prepended
     }
 
     // Apply the peer with given snapshot.
@@ -873,7 +1073,7 @@ impl PeerStorage {
         ctx: &mut InvokeContext,
         snap: &Snapshot,
         kv_wb: &WriteBatch,
-        raft_wb: &WriteBatch,
+        raft_wb: &mut RaftLogBatch,
     ) -> Result<()> {
         info!("{} begin to apply snapshot", self.tag);
 
@@ -928,17 +1128,17 @@ impl PeerStorage {
     }
 
     /// Delete all meta belong to the region. Results are stored in `wb`.
-    pub fn clear_meta(&mut self, kv_wb: &WriteBatch, raft_wb: &WriteBatch) -> Result<()> {
+    pub fn clear_meta(&mut self, kv_wb: &WriteBatch, raft_wb: &mut RaftLogBatch) -> Result<()> {
         let region_id = self.get_region_id();
         try!(clear_meta(
             &self.kv_engine,
-            &self.raft_engine,
             kv_wb,
             raft_wb,
             region_id,
             &self.raft_state
         ));
-        self.cache = EntryCache::default();
+        self.evict_cache();
+        self.prefetch_seq += 1;
         Ok(())
     }
 
@@ -980,7 +1180,7 @@ impl PeerStorage {
         Ok(())
     }
 
-    pub fn get_raft_engine(&self) -> Arc<DB> {
+    pub fn get_raft_engine(&self) -> Arc<RaftEngine> {
         self.raft_engine.clone()
     }
 
@@ -1100,7 +1300,7 @@ impl PeerStorage {
                 &mut ctx,
                 &ready.snapshot,
                 &ready_ctx.kv_wb,
-                &ready_ctx.raft_wb
+                &mut ready_ctx.raft_wb
             ));
             last_index(&ctx.raft_state)
         };
@@ -1186,9 +1386,8 @@ impl PeerStorage {
 /// Delete all meta belong to the region. Results are stored in `wb`.
 pub fn clear_meta(
     kv_engine: &DB,
-    raft_engine: &DB,
     kv_wb: &WriteBatch,
-    raft_wb: &WriteBatch,
+    raft_wb: &mut RaftLogBatch,
     region_id: u64,
     raft_state: &RaftLocalState,
 ) -> Result<()> {
@@ -1196,29 +1395,16 @@ pub fn clear_meta(
     let handle = try!(rocksdb::get_cf_handle(kv_engine, CF_RAFT));
     try!(kv_wb.delete_cf(handle, &keys::region_state_key(region_id)));
     try!(kv_wb.delete_cf(handle, &keys::apply_state_key(region_id)));
+    try!(kv_wb.delete_cf(handle, &keys::quarantine_state_key(region_id)));
 
     let last_index = last_index(raft_state);
-    let mut first_index = last_index + 1;
-    let begin_log_key = keys::raft_log_key(region_id, 0);
-    let end_log_key = keys::raft_log_key(region_id, first_index);
-    try!(raft_engine.scan(
-        &begin_log_key,
-        &end_log_key,
-        false,
-        &mut |key, _| {
-            first_index = keys::raft_log_index(key).unwrap();
-            Ok(false)
-        }
-    ));
-    for id in first_index..last_index + 1 {
-        try!(raft_wb.delete(&keys::raft_log_key(region_id, id)));
-    }
-    try!(raft_wb.delete(&keys::raft_state_key(region_id)));
+    raft_wb.clean(region_id, last_index);
 
     info!(
-        "[region {}] clear peer 1 meta key, 1 apply key, 1 raft key and {} raft logs, takes {:?}",
+        "[region {}] clear peer 1 meta key, 1 apply key, 1 raft key and raft logs to {}, \
+         takes {:?}",
         region_id,
-        last_index + 1 - first_index,
+        last_index,
         t.elapsed()
     );
     Ok(())
@@ -1226,11 +1412,17 @@ pub fn clear_meta(
 
 pub fn do_snapshot(
     mgr: SnapManager,
-    raft_db: &DB,
-    snap: &DbSnapshot,
+    raft_engine: &RaftEngine,
+    snap: &SyncSnapshot,
     region_id: u64,
 ) -> raft::Result<Snapshot> {
     debug!("[region {}] begin to generate a snapshot", region_id);
+    fail_point!("peer_storage_do_snapshot", |_| {
+        Err(box_err!(
+            "[region {}] failed to generate snapshot by fail point",
+            region_id
+        ))
+    });
 
     let apply_state: RaftApplyState =
         match try!(snap.get_msg_cf(CF_RAFT, &keys::apply_state_key(region_id))) {
@@ -1247,7 +1439,7 @@ pub fn do_snapshot(
     let term = if idx == apply_state.get_truncated_state().get_index() {
         apply_state.get_truncated_state().get_term()
     } else {
-        match try!(raft_db.get_msg::<Entry>(&keys::raft_log_key(region_id, idx))) {
+        match try!(raft_engine.get_entry(region_id, idx)) {
             None => return Err(box_err!("entry {} of {} not found.", idx, region_id)),
             Some(entry) => entry.get_term(),
         }
@@ -1308,13 +1500,13 @@ pub fn do_snapshot(
 }
 
 // When we bootstrap the region we must call this to initialize region local state first.
-pub fn write_initial_raft_state<T: Mutable>(raft_wb: &T, region_id: u64) -> Result<()> {
+pub fn write_initial_raft_state(raft_wb: &mut RaftLogBatch, region_id: u64) -> Result<()> {
     let mut raft_state = RaftLocalState::new();
     raft_state.set_last_index(RAFT_INIT_LOG_INDEX);
     raft_state.mut_hard_state().set_term(RAFT_INIT_LOG_TERM);
     raft_state.mut_hard_state().set_commit(RAFT_INIT_LOG_INDEX);
 
-    try!(raft_wb.put_msg(&keys::raft_state_key(region_id), &raft_state));
+    raft_wb.put_state(region_id, &raft_state);
     Ok(())
 }
 
@@ -1363,6 +1555,10 @@ impl Storage for PeerStorage {
         self.entries(low, high, max_size)
     }
 
+    fn fetch_entries(&self, low: u64, high: u64, max_size: u64) -> raft::Result<Vec<Entry>> {
+        self.fetch_entries(low, high, max_size)
+    }
+
     fn term(&self, idx: u64) -> raft::Result<u64> {
         self.term(idx)
     }
@@ -1395,13 +1591,16 @@ mod test {
     use tempdir::*;
     use protobuf;
     use raftstore::store::{bootstrap, Engines};
-    use raftstore::store::worker::RegionRunner;
+    use raftstore::store::Msg;
+    use raftstore::store::worker::{RaftlogFetchRunner, RegionRunner};
     use raftstore::store::worker::RegionTask;
     use util::worker::{Scheduler, Worker};
     use util::rocksdb::new_engine;
     use storage::{ALL_CFS, CF_DEFAULT};
     use kvproto::eraftpb::HardState;
     use rocksdb::WriteBatch;
+    use raftstore::store::engine::Iterable;
+    use raftstore::store::raft_engine::RocksRaftEngine;
 
     use super::*;
 
@@ -1411,11 +1610,12 @@ mod test {
         let raft_db = Arc::new(
             new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap(),
         );
-        let engines = Engines::new(kv_db.clone(), raft_db.clone());
+        let raft_engine: Arc<RaftEngine> = Arc::new(RocksRaftEngine::new(raft_db));
+        let engines = Engines::new(kv_db.clone(), raft_engine.clone());
         bootstrap::bootstrap_store(&engines, 1, 1).expect("");
         let region = bootstrap::prepare_bootstrap(&engines, 1, 1, 1).expect("");
         let metrics = Rc::new(RefCell::new(CacheQueryStats::default()));
-        PeerStorage::new(kv_db, raft_db, &region, sched, "".to_owned(), metrics).unwrap()
+        PeerStorage::new(kv_db, raft_engine, &region, sched, "".to_owned(), metrics).unwrap()
     }
 
     fn new_storage_from_ents(
@@ -1425,7 +1625,7 @@ mod test {
     ) -> PeerStorage {
         let mut store = new_storage(sched, path);
         let mut kv_wb = WriteBatch::new();
-        let mut raft_wb = WriteBatch::new();
+        let mut raft_wb = RaftLogBatch::new();
         let mut ctx = InvokeContext::new(&store);
         store.append(&mut ctx, &ents[1..], &mut raft_wb).expect("");
         ctx.apply_state
@@ -1438,7 +1638,7 @@ mod test {
             .set_applied_index(ents.last().unwrap().get_index());
         ctx.save_apply_state_to(&store.kv_engine, &mut kv_wb)
             .unwrap();
-        store.raft_engine.write(raft_wb).expect("");
+        store.raft_engine.write(raft_wb, false).expect("");
         store.kv_engine.write(kv_wb).expect("");
         store.raft_state = ctx.raft_state;
         store.apply_state = ctx.apply_state;
@@ -1447,20 +1647,23 @@ mod test {
 
     fn append_ents(store: &mut PeerStorage, ents: &[Entry]) {
         let mut ctx = InvokeContext::new(store);
-        let mut raft_wb = WriteBatch::new();
+        let mut raft_wb = RaftLogBatch::new();
         store.append(&mut ctx, ents, &mut raft_wb).unwrap();
         ctx.save_raft_state_to(&mut raft_wb).unwrap();
-        store.raft_engine.write(raft_wb).expect("");
+        store.raft_engine.write(raft_wb, false).expect("");
         store.raft_state = ctx.raft_state;
     }
 
     fn validate_cache(store: &PeerStorage, exp_ents: &[Entry]) {
         assert_eq!(store.cache.cache, exp_ents);
+        assert_eq!(store.cache_mem_size(), entries_size(exp_ents));
+        assert_eq!(store.stats.borrow().mem_size, store.cache_mem_size());
         for e in exp_ents {
-            let key = keys::raft_log_key(store.get_region_id(), e.get_index());
-            let bytes = store.raft_engine.get(&key).unwrap().unwrap();
-            let mut entry = Entry::new();
-            entry.merge_from_bytes(&bytes).unwrap();
+            let entry = store
+                .raft_engine
+                .get_entry(store.get_region_id(), e.get_index())
+                .unwrap()
+                .unwrap();
             assert_eq!(entry, *e);
         }
     }
@@ -1525,13 +1728,23 @@ mod test {
             })
             .unwrap();
 
-        store
-            .raft_engine
-            .scan(&raft_start, &raft_end, false, &mut |_, _| {
-                count += 1;
-                Ok(true)
-            })
-            .unwrap();
+        let raft_engine = &store.raft_engine;
+        if raft_engine.get_raft_state(region_id).unwrap().is_some() {
+            count += 1;
+        }
+        if let Some(first_index) = raft_engine.first_index(region_id).unwrap() {
+            let mut entries = vec![];
+            raft_engine
+                .fetch_entries_to(
+                    region_id,
+                    first_index,
+                    store.last_index() + 1,
+                    u64::MAX,
+                    &mut entries,
+                )
+                .unwrap();
+            count += entries.len();
+        }
 
         count
     }
@@ -1547,10 +1760,10 @@ mod test {
         assert_eq!(6, get_meta_key_count(&store));
 
         let kv_wb = WriteBatch::new();
-        let raft_wb = WriteBatch::new();
-        store.clear_meta(&kv_wb, &raft_wb).unwrap();
+        let mut raft_wb = RaftLogBatch::new();
+        store.clear_meta(&kv_wb, &mut raft_wb).unwrap();
         store.kv_engine.write(kv_wb).unwrap();
-        store.raft_engine.write(raft_wb).unwrap();
+        store.raft_engine.write(raft_wb, false).unwrap();
 
         assert_eq!(0, get_meta_key_count(&store));
     }
@@ -1673,7 +1886,8 @@ mod test {
         let mut worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let mut s = new_storage_from_ents(sched, &td, &ents);
-        let runner = RegionRunner::new(s.kv_engine.clone(), s.raft_engine.clone(), mgr, 0);
+        let runner =
+            RegionRunner::new(s.kv_engine.clone(), s.raft_engine.clone(), mgr, 0, false, 1);
         worker.start(runner).unwrap();
         let snap = s.snapshot();
         let unavailable = RaftError::Store(StorageError::SnapshotTemporarilyUnavailable);
@@ -1705,7 +1919,7 @@ mod test {
 
         let mut ctx = InvokeContext::new(&s);
         let mut kv_wb = WriteBatch::new();
-        let mut raft_wb = WriteBatch::new();
+        let mut raft_wb = RaftLogBatch::new();
         s.append(&mut ctx, &[new_entry(6, 5), new_entry(7, 5)], &mut raft_wb)
             .unwrap();
         let mut hs = HardState::new();
@@ -1717,7 +1931,7 @@ mod test {
         ctx.save_raft_state_to(&mut raft_wb).unwrap();
         ctx.save_apply_state_to(&s.kv_engine, &mut kv_wb).unwrap();
         s.kv_engine.write(kv_wb).unwrap();
-        s.raft_engine.write(raft_wb).unwrap();
+        s.raft_engine.write(raft_wb, false).unwrap();
         s.apply_state = ctx.apply_state;
         s.raft_state = ctx.raft_state;
         ctx = InvokeContext::new(&s);
@@ -1763,6 +1977,45 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_storage_delegate_snapshot() {
+        let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        let td = TempDir::new("tikv-store-test").unwrap();
+        let snap_dir = TempDir::new("snap_dir").unwrap();
+        let mgr = SnapManager::new(snap_dir.path().to_str().unwrap(), None);
+        let mut worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let mut s = new_storage_from_ents(sched, &td, &ents);
+        s.set_delegate_snap_gen(true);
+        let runner =
+            RegionRunner::new(s.kv_engine.clone(), s.raft_engine.clone(), mgr, 0, false, 1);
+        worker.start(runner).unwrap();
+
+        let unavailable = RaftError::Store(StorageError::SnapshotTemporarilyUnavailable);
+        assert_eq!(s.snapshot().unwrap_err(), unavailable);
+        // the task is held until the peer picks a place to generate it.
+        let task = s.take_gen_snap_task().unwrap();
+        assert!(s.take_gen_snap_task().is_none());
+        assert_eq!(s.snapshot().unwrap_err(), unavailable);
+
+        // generate the snapshot somewhere else and notify the storage.
+        let (tx, rx) = mpsc::sync_channel(1);
+        s.schedule_gen_snap_task(GenSnapTask::new(1, tx));
+        let snap = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+        task.notify(snap.clone());
+        assert_eq!(s.snapshot(), Ok(snap));
+        assert_eq!(*s.snap_tried_cnt.borrow(), 0);
+
+        // a dropped task should trigger another try.
+        assert_eq!(s.snapshot().unwrap_err(), unavailable);
+        drop(s.take_gen_snap_task().unwrap());
+        assert_eq!(s.snapshot().unwrap_err(), unavailable);
+        assert_eq!(*s.snap_tried_cnt.borrow(), 2);
+        assert!(s.take_gen_snap_task().is_some());
+
+        worker.stop().unwrap().join().unwrap();
+    }
+
     #[test]
     fn test_storage_append() {
         let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
@@ -1818,7 +2071,7 @@ mod test {
         let worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let mut store = new_storage_from_ents(sched, &td, &ents);
-        store.cache.cache.clear();
+        store.evict_cache();
         // empty cache should fetch data from rocksdb directly.
         let mut res = store.entries(4, 6, u64::max_value()).unwrap();
         assert_eq!(*res, ents[1..]);
@@ -1861,7 +2114,7 @@ mod test {
         let worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let mut store = new_storage_from_ents(sched, &td, &ents);
-        store.cache.cache.clear();
+        store.evict_cache();
 
         // initial cache
         let mut entries = vec![new_entry(6, 5), new_entry(7, 5)];
@@ -1934,6 +2187,103 @@ mod test {
         assert!(store.cache.cache.capacity() < cap as usize);
     }
 
+    #[test]
+    fn test_storage_prefetch_entries() {
+        let ents: Vec<_> = (3..10).map(|i| new_entry(i, i)).collect();
+        let td = TempDir::new("tikv-store-test").unwrap();
+        let worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let mut store = new_storage_from_ents(sched, &td, &ents);
+        store.compact_to(7);
+        validate_cache(&store, &ents[4..]);
+
+        let mut fetched = vec![];
+        store
+            .raft_engine
+            .fetch_entries_to(1, 4, 7, u64::MAX, &mut fetched)
+            .unwrap();
+        assert_eq!(fetched, &ents[1..4]);
+
+        // entries not connecting to the cache are dropped.
+        store.on_entries_prefetched(0, fetched[..2].to_vec());
+        validate_cache(&store, &ents[4..]);
+        // a stale fetch is dropped, but the prefetching is finished anyway.
+        store.prefetching.store(true, Ordering::SeqCst);
+        assert!(!store.on_entries_prefetched(1, fetched.clone()));
+        assert!(!store.prefetching.load(Ordering::SeqCst));
+        validate_cache(&store, &ents[4..]);
+        store.on_entries_prefetched(0, fetched);
+        validate_cache(&store, &ents[1..]);
+
+        // overwriting the log makes running fetches stale.
+        append_ents(&mut store, &[new_entry(9, 10)]);
+        assert_eq!(store.prefetch_seq, 1);
+        let size = store.cache_mem_size();
+        assert_eq!(store.evict_cache(), size);
+        assert_eq!(store.stats.borrow().mem_size, 0);
+    }
+
+    #[test]
+    fn test_storage_prefetch_entries_limit() {
+        let ents: Vec<_> = (3..10).map(|i| new_entry(i, i)).collect();
+        let td = TempDir::new("tikv-store-test").unwrap();
+        let worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let mut store = new_storage_from_ents(sched, &td, &ents);
+        store.compact_to(7);
+        validate_cache(&store, &ents[4..]);
+
+        // only the entries next to the cache that fit in the limit are kept.
+        let size = store.cache_mem_size();
+        let entry_size = ents[3].compute_size() as u64;
+        store.stats.borrow_mut().limit = size + entry_size * 2;
+        assert!(store.on_entries_prefetched(0, ents[1..4].to_vec()));
+        validate_cache(&store, &ents[2..]);
+        assert_eq!(store.stats.borrow().mem_size, size + entry_size * 2);
+
+        // prefetching is skipped once the caches are over the limit.
+        let fetch_worker = Worker::new("raftlog fetcher");
+        assert!(!store.prefetch_entries(4, &fetch_worker.scheduler()));
+        assert!(!store.prefetching.load(Ordering::SeqCst));
+        assert!(!store.on_entries_prefetched(0, ents[1..2].to_vec()));
+        validate_cache(&store, &ents[2..]);
+    }
+
+    #[test]
+    fn test_storage_fetch_entries_async() {
+        let ents: Vec<_> = (3..10).map(|i| new_entry(i, i)).collect();
+        let td = TempDir::new("tikv-store-test").unwrap();
+        let worker = Worker::new("snap_manager");
+        let sched = worker.scheduler();
+        let mut store = new_storage_from_ents(sched, &td, &ents);
+        store.compact_to(7);
+        validate_cache(&store, &ents[4..]);
+
+        let (tx, rx) = channel();
+        let mut fetch_worker = Worker::new("raftlog fetcher");
+        fetch_worker.start(RaftlogFetchRunner::new(tx)).unwrap();
+        store.set_raftlog_fetch_scheduler(fetch_worker.scheduler());
+
+        // the cached entries are returned directly.
+        assert_eq!(store.fetch_entries(7, 10, u64::MAX).unwrap(), &ents[4..]);
+        let unavailable = RaftError::Store(StorageError::LogTemporarilyUnavailable);
+        assert_eq!(store.fetch_entries(4, 10, u64::MAX).unwrap_err(), unavailable);
+        // waits for the running fetch.
+        assert_eq!(store.fetch_entries(5, 10, u64::MAX).unwrap_err(), unavailable);
+        match rx.recv_timeout(Duration::from_secs(3)).unwrap() {
+            Msg::RaftLogFetched { seq, entries, .. } => {
+                assert!(store.on_entries_prefetched(seq, entries))
+            }
+            _ => panic!("unexpected msg"),
+        }
+        assert_eq!(store.fetch_entries(4, 10, u64::MAX).unwrap(), &ents[1..]);
+
+        // the compacted entries can't be fetched.
+        let compacted = RaftError::Store(StorageError::Compacted);
+        assert_eq!(store.fetch_entries(3, 10, u64::MAX).unwrap_err(), compacted);
+        fetch_worker.stop().unwrap().join().unwrap();
+    }
+
     #[test]
     fn test_storage_apply_snapshot() {
         let ents = vec![
@@ -1951,8 +2301,14 @@ mod test {
         let mut worker = Worker::new("snap_manager");
         let sched = worker.scheduler();
         let s1 = new_storage_from_ents(sched.clone(), &td1, &ents);
-        let runner =
-            RegionRunner::new(s1.kv_engine.clone(), s1.raft_engine.clone(), mgr.clone(), 0);
+        let runner = RegionRunner::new(
+            s1.kv_engine.clone(),
+            s1.raft_engine.clone(),
+            mgr.clone(),
+            0,
+            false,
+            1,
+        );
         worker.start(runner).unwrap();
         assert!(s1.snapshot().is_err());
         let snap1 = match *s1.snap_state.borrow() {
@@ -1968,8 +2324,8 @@ mod test {
         let mut ctx = InvokeContext::new(&s2);
         assert_ne!(ctx.last_term, snap1.get_metadata().get_term());
         let kv_wb = WriteBatch::new();
-        let raft_wb = WriteBatch::new();
-        s2.apply_snapshot(&mut ctx, &snap1, &kv_wb, &raft_wb)
+        let mut raft_wb = RaftLogBatch::new();
+        s2.apply_snapshot(&mut ctx, &snap1, &kv_wb, &mut raft_wb)
             .unwrap();
         assert_eq!(ctx.last_term, snap1.get_metadata().get_term());
         assert_eq!(ctx.apply_state.get_applied_index(), 6);
@@ -1986,8 +2342,8 @@ mod test {
         let mut ctx = InvokeContext::new(&s3);
         assert_ne!(ctx.last_term, snap1.get_metadata().get_term());
         let kv_wb = WriteBatch::new();
-        let raft_wb = WriteBatch::new();
-        s3.apply_snapshot(&mut ctx, &snap1, &kv_wb, &raft_wb)
+        let mut raft_wb = RaftLogBatch::new();
+        s3.apply_snapshot(&mut ctx, &snap1, &kv_wb, &mut raft_wb)
             .unwrap();
         assert_eq!(ctx.last_term, snap1.get_metadata().get_term());
         assert_eq!(ctx.apply_state.get_applied_index(), 6);
@@ -0,0 +1,436 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod rocks;
+mod segment;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rocksdb::DB;
+use kvproto::eraftpb::Entry;
+use kvproto::raft_serverpb::RaftLocalState;
+
+use raft;
+use raftstore::Result;
+use super::Config;
+
+pub use self::rocks::RocksRaftEngine;
+pub use self::segment::SegmentRaftEngine;
+
+pub const RAFT_ENGINE_ROCKSDB: &'static str = "rocksdb";
+pub const RAFT_ENGINE_SEGMENT: &'static str = "segment";
+
+const MIGRATE_BATCH_SIZE: u64 = 4 * 1024 * 1024;
+
+// The file in the raft engine directory which records the engine kind.
+const ENGINE_MARKER_FILE: &'static str = "RAFT_LOG_ENGINE";
+// Every RocksDB has it, used to detect the raft db created before the marker.
+const ROCKSDB_CURRENT_FILE: &'static str = "CURRENT";
+
+/// `RaftEngine` persists the raft logs and the `RaftLocalState` of all the
+/// regions on a store.
+pub trait RaftEngine: Send + Sync {
+    fn path(&self) -> &str;
+
+    /// Checks whether there is no raft log or raft state of any region.
+    fn is_empty(&self) -> Result<bool>;
+
+    fn get_raft_state(&self, region_id: u64) -> Result<Option<RaftLocalState>>;
+
+    fn get_entry(&self, region_id: u64, index: u64) -> Result<Option<Entry>>;
+
+    /// Returns the index of the first raft log of the region, None if the
+    /// region has no log.
+    fn first_index(&self, region_id: u64) -> Result<Option<u64>>;
+
+    /// Reads the raft logs in `[low, high)` of the region, stops once the
+    /// total size exceeds `max_size`, returns the size read.
+    fn fetch_entries_to(
+        &self,
+        region_id: u64,
+        low: u64,
+        high: u64,
+        max_size: u64,
+        buf: &mut Vec<Entry>,
+    ) -> raft::Result<u64>;
+
+    /// Writes the batch atomically, the batch is durable when it returns if
+    /// `sync` is true.
+    fn write(&self, batch: RaftLogBatch, sync: bool) -> Result<()>;
+
+    /// Deletes the raft logs in `[from, to)` of the region, `from` being 0
+    /// means from the first log. Returns the count of the logs deleted.
+    fn gc(&self, region_id: u64, from: u64, to: u64) -> Result<u64>;
+
+    /// Flushes all the written data to disk.
+    fn flush(&self, sync: bool) -> Result<()>;
+
+    /// Returns the underlying RocksDB for statistics if there is one.
+    fn rocksdb(&self) -> Option<Arc<DB>> {
+        None
+    }
+}
+
+pub enum LogOp {
+    // region id, consecutive entries.
+    Append(u64, Vec<Entry>),
+    // region id, delete the logs in [from, to).
+    Cut(u64, u64, u64),
+    PutState(u64, RaftLocalState),
+    // region id, last index. Deletes all the logs and the raft state.
+    Clean(u64, u64),
+}
+
+/// `RaftLogBatch` collects the changes to a raft engine, which are applied
+/// in order.
+#[derive(Default)]
+pub struct RaftLogBatch {
+    ops: Vec<LogOp>,
+    data_size: usize,
+}
+
+impl RaftLogBatch {
+    pub fn new() -> RaftLogBatch {
+        RaftLogBatch::default()
+    }
+
+    pub fn with_capacity(cap: usize) -> RaftLogBatch {
+        RaftLogBatch {
+            ops: Vec::with_capacity(cap),
+            data_size: 0,
+        }
+    }
+
+    pub fn append(&mut self, region_id: u64, entries: &[Entry]) {
+        if entries.is_empty() {
+            return;
+        }
+        for e in entries {
+            self.data_size += e.get_data().len() + e.get_context().len();
+        }
+        self.ops.push(LogOp::Append(region_id, entries.to_vec()));
+    }
+
+    pub fn cut_logs(&mut self, region_id: u64, from: u64, to: u64) {
+        if from >= to {
+            return;
+        }
+        self.ops.push(LogOp::Cut(region_id, from, to));
+    }
+
+    pub fn put_state(&mut self, region_id: u64, state: &RaftLocalState) {
+        self.ops.push(LogOp::PutState(region_id, state.clone()));
+    }
+
+    pub fn clean(&mut self, region_id: u64, last_index: u64) {
+        self.ops.push(LogOp::Clean(region_id, last_index));
+    }
+
+    pub fn ops(&self) -> &[LogOp] {
+        &self.ops
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the approximate size of the entries in the batch.
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+}
+
+/// Returns the kind of the raft engine whose data is in `dir`, None if there
+/// is no data.
+fn detect_raft_engine(dir: &str) -> Result<Option<String>> {
+    let dir = Path::new(dir);
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let marker = dir.join(ENGINE_MARKER_FILE);
+    if marker.exists() {
+        let mut engine = String::new();
+        try!(try!(File::open(&marker)).read_to_string(&mut engine));
+        return Ok(Some(engine.trim().to_owned()));
+    }
+    if dir.join(ROCKSDB_CURRENT_FILE).exists() {
+        return Ok(Some(RAFT_ENGINE_ROCKSDB.to_owned()));
+    }
+    if try!(segment::has_log_files(dir.to_str().unwrap())) {
+        return Ok(Some(RAFT_ENGINE_SEGMENT.to_owned()));
+    }
+    Ok(None)
+}
+
+fn write_engine_marker(dir: &str, engine: &str) -> Result<()> {
+    let marker = Path::new(dir).join(ENGINE_MARKER_FILE);
+    if marker.exists() {
+        return Ok(());
+    }
+    let tmp = Path::new(dir).join(format!("{}.tmp", ENGINE_MARKER_FILE));
+    {
+        let mut f = try!(File::create(&tmp));
+        try!(f.write_all(engine.as_bytes()));
+        try!(f.sync_all());
+    }
+    try!(fs::rename(&tmp, &marker));
+    try!(try!(File::open(dir)).sync_all());
+    Ok(())
+}
+
+/// Opens the raft engine configured in `cfg.raft_log_engine` at
+/// `cfg.raftdb_path`, `open_rocksdb` is used if it's RocksDB. Fails if the
+/// directory holds the data of another engine.
+pub fn open_raft_engine<F>(cfg: &Config, open_rocksdb: F) -> Result<Arc<RaftEngine>>
+where
+    F: FnOnce(&str) -> Result<DB>,
+{
+    let path = &cfg.raftdb_path;
+    let engine = cfg.raft_log_engine.as_str();
+    if let Some(existing) = try!(detect_raft_engine(path)) {
+        if existing != engine {
+            return Err(box_err!(
+                "{} holds the data of raft log engine {}, but {} is configured, \
+                 migrate it by `tikv-ctl migrate-raft-engine` first",
+                path,
+                existing,
+                engine
+            ));
+        }
+    }
+    let raft_engine: Arc<RaftEngine> = match engine {
+        RAFT_ENGINE_ROCKSDB => {
+            let db = try!(open_rocksdb(path));
+            Arc::new(RocksRaftEngine::new(Arc::new(db)))
+        }
+        RAFT_ENGINE_SEGMENT => Arc::new(try!(SegmentRaftEngine::new(
+            path,
+            cfg.raft_log_segment_size.0,
+            cfg.raft_log_bytes_per_sync.0,
+            cfg.raft_log_segment_expire_count
+        ))),
+        e => return Err(box_err!("unknown raft log engine {}", e)),
+    };
+    try!(write_engine_marker(path, engine));
+    Ok(raft_engine)
+}
+
+/// Copies the raft states and logs of the regions from `src` to `dst`.
+pub fn migrate_raft_engine(src: &RaftEngine, dst: &RaftEngine, region_ids: &[u64]) -> Result<()> {
+    for &region_id in region_ids {
+        let state = match try!(src.get_raft_state(region_id)) {
+            Some(s) => s,
+            None => continue,
+        };
+        let mut batch = RaftLogBatch::new();
+        // Clean the stale data left by a former migration.
+        batch.clean(region_id, state.get_last_index());
+        try!(dst.write(batch, false));
+        if let Some(first_index) = try!(src.first_index(region_id)) {
+            let last_index = state.get_last_index();
+            let mut low = first_index;
+            while low <= last_index {
+                let mut entries = vec![];
+                try!(src.fetch_entries_to(
+                    region_id,
+                    low,
+                    last_index + 1,
+                    MIGRATE_BATCH_SIZE,
+                    &mut entries
+                ));
+                low += entries.len() as u64;
+                let mut batch = RaftLogBatch::new();
+                batch.append(region_id, &entries);
+                try!(dst.write(batch, false));
+            }
+        }
+        // Write the state at last, so an interrupted migration can be told.
+        let mut batch = RaftLogBatch::new();
+        batch.put_state(region_id, &state);
+        try!(dst.write(batch, false));
+    }
+    dst.flush(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+    use kvproto::eraftpb::Entry;
+    use kvproto::raft_serverpb::RaftLocalState;
+
+    use raft::{Error as RaftError, StorageError};
+    use storage::CF_DEFAULT;
+    use util::rocksdb::new_engine;
+    use super::*;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::new();
+        e.set_index(index);
+        e.set_term(term);
+        e.set_data(vec![index as u8; 16]);
+        e
+    }
+
+    fn new_entries(low: u64, high: u64, term: u64) -> Vec<Entry> {
+        (low..high).map(|i| new_entry(i, term)).collect()
+    }
+
+    fn new_state(last_index: u64) -> RaftLocalState {
+        let mut state = RaftLocalState::new();
+        state.set_last_index(last_index);
+        state.mut_hard_state().set_commit(last_index);
+        state
+    }
+
+    fn must_fetch(engine: &RaftEngine, region_id: u64, low: u64, high: u64) -> Vec<Entry> {
+        let mut entries = vec![];
+        engine
+            .fetch_entries_to(region_id, low, high, u64::max_value(), &mut entries)
+            .unwrap();
+        entries
+    }
+
+    fn test_raft_engine(engine: &RaftEngine) {
+        assert!(engine.is_empty().unwrap());
+
+        let mut batch = RaftLogBatch::new();
+        batch.append(1, &new_entries(1, 11, 1));
+        batch.put_state(1, &new_state(10));
+        batch.append(2, &new_entries(5, 8, 2));
+        batch.put_state(2, &new_state(7));
+        engine.write(batch, true).unwrap();
+        assert!(!engine.is_empty().unwrap());
+
+        assert_eq!(engine.get_raft_state(1).unwrap(), Some(new_state(10)));
+        assert_eq!(engine.get_raft_state(3).unwrap(), None);
+        assert_eq!(engine.first_index(1).unwrap(), Some(1));
+        assert_eq!(engine.first_index(2).unwrap(), Some(5));
+        assert_eq!(engine.first_index(3).unwrap(), None);
+        assert_eq!(engine.get_entry(2, 6).unwrap(), Some(new_entry(6, 2)));
+        assert_eq!(engine.get_entry(2, 8).unwrap(), None);
+        assert_eq!(must_fetch(engine, 1, 3, 11), new_entries(3, 11, 1));
+
+        // Stop once the size is exceeded, but at least one entry is returned.
+        let mut entries = vec![];
+        engine.fetch_entries_to(1, 1, 11, 0, &mut entries).unwrap();
+        assert_eq!(entries, new_entries(1, 2, 1));
+
+        let mut entries = vec![];
+        match engine.fetch_entries_to(1, 5, 12, u64::max_value(), &mut entries) {
+            Err(RaftError::Store(StorageError::Unavailable)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Overwrite the uncommitted logs.
+        let mut batch = RaftLogBatch::new();
+        batch.append(1, &new_entries(8, 10, 2));
+        batch.cut_logs(1, 10, 11);
+        batch.put_state(1, &new_state(9));
+        engine.write(batch, false).unwrap();
+        assert_eq!(engine.get_entry(1, 10).unwrap(), None);
+        let mut expect = new_entries(6, 8, 1);
+        expect.extend(new_entries(8, 10, 2));
+        assert_eq!(must_fetch(engine, 1, 6, 10), expect);
+
+        assert_eq!(engine.gc(1, 0, 4).unwrap(), 3);
+        assert_eq!(engine.first_index(1).unwrap(), Some(4));
+        assert_eq!(engine.get_entry(1, 3).unwrap(), None);
+        assert_eq!(engine.gc(1, 0, 4).unwrap(), 0);
+
+        let mut batch = RaftLogBatch::new();
+        batch.clean(2, 7);
+        engine.write(batch, true).unwrap();
+        assert_eq!(engine.get_raft_state(2).unwrap(), None);
+        assert_eq!(engine.first_index(2).unwrap(), None);
+        assert_eq!(engine.get_raft_state(1).unwrap(), Some(new_state(9)));
+    }
+
+    #[test]
+    fn test_rocks_raft_engine() {
+        let path = TempDir::new("test-rocks-raft-engine").unwrap();
+        let db = new_engine(path.path().to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        test_raft_engine(&RocksRaftEngine::new(Arc::new(db)));
+    }
+
+    #[test]
+    fn test_segment_raft_engine() {
+        let path = TempDir::new("test-segment-raft-engine").unwrap();
+        let engine = SegmentRaftEngine::new(path.path().to_str().unwrap(), 1024, 0, 8).unwrap();
+        test_raft_engine(&engine);
+    }
+
+    #[test]
+    fn test_open_raft_engine_mismatch() {
+        let path = TempDir::new("test-open-raft-engine-mismatch").unwrap();
+        let open_rocksdb =
+            |path: &str| -> Result<DB> { new_engine(path, &[CF_DEFAULT]).map_err(Into::into) };
+        let mut cfg = Config::default();
+        cfg.raftdb_path = path.path().join("raft").to_str().unwrap().to_owned();
+        cfg.raft_log_engine = RAFT_ENGINE_ROCKSDB.to_owned();
+        {
+            let engine = open_raft_engine(&cfg, &open_rocksdb).unwrap();
+            let mut batch = RaftLogBatch::new();
+            batch.put_state(1, &new_state(5));
+            engine.write(batch, true).unwrap();
+        }
+        cfg.raft_log_engine = RAFT_ENGINE_SEGMENT.to_owned();
+        open_raft_engine(&cfg, &open_rocksdb).unwrap_err();
+
+        // The raft db created before the marker is detected by RocksDB's files.
+        fs::remove_file(Path::new(&cfg.raftdb_path).join(ENGINE_MARKER_FILE)).unwrap();
+        open_raft_engine(&cfg, &open_rocksdb).unwrap_err();
+        cfg.raft_log_engine = RAFT_ENGINE_ROCKSDB.to_owned();
+        let engine = open_raft_engine(&cfg, &open_rocksdb).unwrap();
+        assert_eq!(engine.get_raft_state(1).unwrap(), Some(new_state(5)));
+        drop(engine);
+
+        cfg.raftdb_path = path.path().join("segment").to_str().unwrap().to_owned();
+        cfg.raft_log_engine = RAFT_ENGINE_SEGMENT.to_owned();
+        drop(open_raft_engine(&cfg, &open_rocksdb).unwrap());
+        cfg.raft_log_engine = RAFT_ENGINE_ROCKSDB.to_owned();
+        open_raft_engine(&cfg, &open_rocksdb).unwrap_err();
+        fs::remove_file(Path::new(&cfg.raftdb_path).join(ENGINE_MARKER_FILE)).unwrap();
+        open_raft_engine(&cfg, &open_rocksdb).unwrap_err();
+    }
+
+    #[test]
+    fn test_migrate_raft_engine() {
+        let path = TempDir::new("test-migrate-raft-engine").unwrap();
+        let db_path = path.path().join(Path::new("raftdb"));
+        let db = new_engine(db_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let src = RocksRaftEngine::new(Arc::new(db));
+        let mut batch = RaftLogBatch::new();
+        batch.append(1, &new_entries(3, 100, 1));
+        batch.put_state(1, &new_state(99));
+        batch.put_state(2, &new_state(5));
+        src.write(batch, true).unwrap();
+        src.gc(1, 0, 10).unwrap();
+
+        let segment_path = path.path().join(Path::new("segment"));
+        let dst = SegmentRaftEngine::new(segment_path.to_str().unwrap(), 512, 0, 8).unwrap();
+        migrate_raft_engine(&src, &dst, &[1, 2, 3]).unwrap();
+        assert_eq!(dst.get_raft_state(1).unwrap(), Some(new_state(99)));
+        assert_eq!(dst.get_raft_state(2).unwrap(), Some(new_state(5)));
+        assert_eq!(dst.get_raft_state(3).unwrap(), None);
+        assert_eq!(dst.first_index(1).unwrap(), Some(10));
+        assert_eq!(dst.first_index(2).unwrap(), None);
+        assert_eq!(must_fetch(&dst, 1, 10, 100), new_entries(10, 100, 1));
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use rocksdb::{Writable, WriteBatch, WriteOptions, DB};
+use protobuf::Message;
+use kvproto::eraftpb::Entry;
+use kvproto::raft_serverpb::RaftLocalState;
+
+use raft::{self, Error as RaftError, StorageError};
+use raftstore::Result;
+use raftstore::store::keys;
+use raftstore::store::engine::{Iterable, Mutable, Peekable};
+use super::{LogOp, RaftEngine, RaftLogBatch};
+
+const RAFT_LOG_MULTI_GET_CNT: u64 = 8;
+
+/// `RocksRaftEngine` stores the raft logs and states as keys in a RocksDB.
+pub struct RocksRaftEngine {
+    db: Arc<DB>,
+}
+
+impl RocksRaftEngine {
+    pub fn new(db: Arc<DB>) -> RocksRaftEngine {
+        RocksRaftEngine { db: db }
+    }
+
+    pub fn get_db(&self) -> &Arc<DB> {
+        &self.db
+    }
+}
+
+impl RaftEngine for RocksRaftEngine {
+    fn path(&self) -> &str {
+        self.db.path()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let mut count = 0;
+        try!(self.db.scan(
+            keys::MIN_KEY,
+            keys::MAX_KEY,
+            false,
+            &mut |_, _| {
+                count += 1;
+                Ok(false)
+            }
+        ));
+        Ok(count == 0)
+    }
+
+    fn get_raft_state(&self, region_id: u64) -> Result<Option<RaftLocalState>> {
+        self.db.get_msg(&keys::raft_state_key(region_id))
+    }
+
+    fn get_entry(&self, region_id: u64, index: u64) -> Result<Option<Entry>> {
+        self.db.get_msg(&keys::raft_log_key(region_id, index))
+    }
+
+    fn first_index(&self, region_id: u64) -> Result<Option<u64>> {
+        let mut first_index = None;
+        try!(self.db.scan(
+            &keys::raft_log_key(region_id, 0),
+            &keys::raft_state_key(region_id),
+            false,
+            &mut |key, _| {
+                first_index = Some(try!(keys::raft_log_index(key)));
+                Ok(false)
+            }
+        ));
+        Ok(first_index)
+    }
+
+    fn fetch_entries_to(
+        &self,
+        region_id: u64,
+        low: u64,
+        high: u64,
+        max_size: u64,
+        buf: &mut Vec<Entry>,
+    ) -> raft::Result<u64> {
+        let mut total_size: u64 = 0;
+        let mut next_index = low;
+        let mut exceeded_max_size = false;
+        if high - low <= RAFT_LOG_MULTI_GET_CNT {
+            // If election happens in inactive regions, they will just try
+            // to fetch one empty log.
+            for i in low..high {
+                let key = keys::raft_log_key(region_id, i);
+                match box_try!(self.db.get(&key)) {
+                    None => return Err(RaftError::Store(StorageError::Unavailable)),
+                    Some(v) => {
+                        let mut entry = Entry::new();
+                        box_try!(entry.merge_from_bytes(&v));
+                        assert_eq!(entry.get_index(), i);
+                        total_size += v.len() as u64;
+                        if buf.is_empty() || total_size <= max_size {
+                            buf.push(entry);
+                        }
+                        if total_size > max_size {
+                            break;
+                        }
+                    }
+                }
+            }
+            return Ok(total_size);
+        }
+
+        let start_key = keys::raft_log_key(region_id, low);
+        let end_key = keys::raft_log_key(region_id, high);
+        try!(self.db.scan(
+            &start_key,
+            &end_key,
+            true, // fill_cache
+            &mut |_, value| {
+                let mut entry = Entry::new();
+                try!(entry.merge_from_bytes(value));
+
+                // May meet gap or has been compacted.
+                if entry.get_index() != next_index {
+                    return Ok(false);
+                }
+                next_index += 1;
+
+                total_size += value.len() as u64;
+                exceeded_max_size = total_size > max_size;
+                if !exceeded_max_size || buf.is_empty() {
+                    buf.push(entry);
+                }
+                Ok(!exceeded_max_size)
+            }
+        ));
+
+        // If we get the correct number of entries, returns,
+        // or the total size almost exceeds max_size, returns.
+        if buf.len() == (high - low) as usize || exceeded_max_size {
+            return Ok(total_size);
+        }
+
+        // Here means we don't fetch enough entries.
+        Err(RaftError::Store(StorageError::Unavailable))
+    }
+
+    fn write(&self, batch: RaftLogBatch, sync: bool) -> Result<()> {
+        let wb = WriteBatch::new();
+        for op in batch.ops() {
+            match *op {
+                LogOp::Append(region_id, ref entries) => {
+                    for e in entries {
+                        try!(wb.put_msg(&keys::raft_log_key(region_id, e.get_index()), e));
+                    }
+                }
+                LogOp::Cut(region_id, from, to) => {
+                    for i in from..to {
+                        try!(wb.delete(&keys::raft_log_key(region_id, i)));
+                    }
+                }
+                LogOp::PutState(region_id, ref state) => {
+                    try!(wb.put_msg(&keys::raft_state_key(region_id), state));
+                }
+                LogOp::Clean(region_id, last_index) => {
+                    let first_index = try!(self.first_index(region_id)).unwrap_or(last_index + 1);
+                    for i in first_index..last_index + 1 {
+                        try!(wb.delete(&keys::raft_log_key(region_id, i)));
+                    }
+                    try!(wb.delete(&keys::raft_state_key(region_id)));
+                }
+            }
+        }
+        let mut write_opts = WriteOptions::new();
+        write_opts.set_sync(sync);
+        try!(self.db.write_opt(wb, &write_opts));
+        Ok(())
+    }
+
+    fn gc(&self, region_id: u64, from: u64, to: u64) -> Result<u64> {
+        let mut first_index = from;
+        if first_index == 0 {
+            first_index = try!(self.first_index(region_id)).unwrap_or(to);
+        }
+        if first_index >= to {
+            return Ok(0);
+        }
+        let wb = WriteBatch::new();
+        for idx in first_index..to {
+            try!(wb.delete(&keys::raft_log_key(region_id, idx)));
+        }
+        // TODO: disable WAL here.
+        try!(self.db.write(wb));
+        Ok(to - first_index)
+    }
+
+    fn flush(&self, sync: bool) -> Result<()> {
+        try!(self.db.flush(sync));
+        Ok(())
+    }
+
+    fn rocksdb(&self) -> Option<Arc<DB>> {
+        Some(self.db.clone())
+    }
+}
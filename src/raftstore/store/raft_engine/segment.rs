@@ -0,0 +1,872 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A raft engine which appends the raft logs and states of all the regions
+//! to a sequence of segment files.
+//!
+//! Every write is a record of `| len: u32 | crc32: u32 | payload |`, the
+//! payload is a list of operations. The locations of the logs are indexed in
+//! memory per region, and the index is rebuilt by replaying the files on
+//! start. Compacting the logs only updates the index, a file is purged once
+//! no region references it any more. The logs of a region that stay in an
+//! expired file, which has too many newer files, are rewritten to the active
+//! file, so an idle region doesn't keep all the files after its logs.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::cmp;
+
+use crc::crc32;
+use protobuf::Message;
+use kvproto::eraftpb::Entry;
+use kvproto::raft_serverpb::RaftLocalState;
+
+use raft::{self, Error as RaftError, StorageError};
+use raftstore::Result;
+use util::codec::number::{NumberDecoder, NumberEncoder};
+use super::{LogOp, RaftEngine, RaftLogBatch};
+
+const LOG_FILE_SUFFIX: &'static str = ".raftlog";
+const RECORD_HEADER_SIZE: u64 = 8;
+
+const TAG_ENTRIES: u8 = 1;
+const TAG_CUT: u8 = 2;
+const TAG_STATE: u8 = 3;
+const TAG_CLEAN: u8 = 4;
+const TAG_COMPACT: u8 = 5;
+
+enum Record {
+    // region id, first index, (offset in payload, length) of the entries.
+    Entries(u64, u64, Vec<(u64, u64)>),
+    // region id, delete the logs from the index.
+    Cut(u64, u64),
+    State(u64, RaftLocalState),
+    Clean(u64),
+    // region id, delete the logs before the index.
+    Compact(u64, u64),
+}
+
+fn encode_batch(batch: &RaftLogBatch, buf: &mut Vec<u8>) -> Result<Vec<Record>> {
+    let mut records = Vec::with_capacity(batch.ops().len());
+    for op in batch.ops() {
+        match *op {
+            LogOp::Append(region_id, ref entries) => {
+                let first_index = entries[0].get_index();
+                buf.push(TAG_ENTRIES);
+                try!(buf.encode_var_u64(region_id));
+                try!(buf.encode_var_u64(first_index));
+                try!(buf.encode_var_u64(entries.len() as u64));
+                let mut locations = Vec::with_capacity(entries.len());
+                for (i, e) in entries.iter().enumerate() {
+                    assert_eq!(e.get_index(), first_index + i as u64);
+                    let data = try!(e.write_to_bytes());
+                    try!(buf.encode_var_u64(data.len() as u64));
+                    locations.push((buf.len() as u64, data.len() as u64));
+                    buf.extend_from_slice(&data);
+                }
+                records.push(Record::Entries(region_id, first_index, locations));
+            }
+            LogOp::Cut(region_id, from, _) => {
+                buf.push(TAG_CUT);
+                try!(buf.encode_var_u64(region_id));
+                try!(buf.encode_var_u64(from));
+                records.push(Record::Cut(region_id, from));
+            }
+            LogOp::PutState(region_id, ref state) => {
+                let data = try!(state.write_to_bytes());
+                buf.push(TAG_STATE);
+                try!(buf.encode_var_u64(region_id));
+                try!(buf.encode_var_u64(data.len() as u64));
+                buf.extend_from_slice(&data);
+                records.push(Record::State(region_id, state.clone()));
+            }
+            LogOp::Clean(region_id, _) => {
+                buf.push(TAG_CLEAN);
+                try!(buf.encode_var_u64(region_id));
+                records.push(Record::Clean(region_id));
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn encode_compact(region_id: u64, to: u64, buf: &mut Vec<u8>) -> Result<Record> {
+    buf.push(TAG_COMPACT);
+    try!(buf.encode_var_u64(region_id));
+    try!(buf.encode_var_u64(to));
+    Ok(Record::Compact(region_id, to))
+}
+
+fn decode_records(payload: &[u8]) -> Result<Vec<Record>> {
+    let total = payload.len() as u64;
+    let mut data = payload;
+    let mut records = vec![];
+    while !data.is_empty() {
+        let tag = data[0];
+        data = &data[1..];
+        let region_id = try!(data.decode_var_u64());
+        let record = match tag {
+            TAG_ENTRIES => {
+                let first_index = try!(data.decode_var_u64());
+                let count = try!(data.decode_var_u64());
+                let mut locations = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let len = try!(data.decode_var_u64());
+                    if (data.len() as u64) < len {
+                        return Err(box_err!("entry of region {} is truncated", region_id));
+                    }
+                    locations.push((total - data.len() as u64, len));
+                    data = &data[len as usize..];
+                }
+                Record::Entries(region_id, first_index, locations)
+            }
+            TAG_CUT => Record::Cut(region_id, try!(data.decode_var_u64())),
+            TAG_STATE => {
+                let len = try!(data.decode_var_u64()) as usize;
+                if data.len() < len {
+                    return Err(box_err!("state of region {} is truncated", region_id));
+                }
+                let mut state = RaftLocalState::new();
+                try!(state.merge_from_bytes(&data[..len]));
+                data = &data[len..];
+                Record::State(region_id, state)
+            }
+            TAG_CLEAN => Record::Clean(region_id),
+            TAG_COMPACT => Record::Compact(region_id, try!(data.decode_var_u64())),
+            _ => return Err(box_err!("unknown record tag {}", tag)),
+        };
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct EntryLocation {
+    file_num: u64,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Default)]
+struct RegionLogs {
+    first_index: u64,
+    entries: VecDeque<EntryLocation>,
+    state: Option<RaftLocalState>,
+    state_file_num: u64,
+}
+
+impl RegionLogs {
+    fn append<I: Iterator<Item = EntryLocation>>(&mut self, first_index: u64, locations: I) {
+        let next_index = self.first_index + self.entries.len() as u64;
+        if self.entries.is_empty() || first_index < self.first_index || first_index > next_index {
+            self.entries.clear();
+            self.first_index = first_index;
+        } else {
+            self.entries
+                .truncate((first_index - self.first_index) as usize);
+        }
+        self.entries.extend(locations);
+    }
+
+    fn cut(&mut self, from: u64) {
+        if from <= self.first_index {
+            self.entries.clear();
+        } else {
+            let len = (from - self.first_index) as usize;
+            self.entries.truncate(len);
+        }
+    }
+
+    fn compact_to(&mut self, to: u64) -> u64 {
+        if to <= self.first_index {
+            return 0;
+        }
+        let count = cmp::min(to - self.first_index, self.entries.len() as u64);
+        self.entries.drain(..count as usize);
+        self.first_index = if self.entries.is_empty() {
+            to
+        } else {
+            self.first_index + count
+        };
+        count
+    }
+
+    fn get(&self, index: u64) -> Option<EntryLocation> {
+        if index < self.first_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.first_index) as usize)
+            .cloned()
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    regions: HashMap<u64, RegionLogs>,
+    // file number -> the handle for reading.
+    files: BTreeMap<u64, Arc<File>>,
+}
+
+impl Index {
+    fn apply(&mut self, records: Vec<Record>, file_num: u64, base: u64) -> u64 {
+        let mut compacted = 0;
+        for record in records {
+            match record {
+                Record::Entries(region_id, first_index, locations) => {
+                    let logs = self.regions
+                        .entry(region_id)
+                        .or_insert_with(RegionLogs::default);
+                    logs.append(
+                        first_index,
+                        locations.into_iter().map(|(offset, len)| {
+                            EntryLocation {
+                                file_num: file_num,
+                                offset: base + offset,
+                                len: len,
+                            }
+                        }),
+                    );
+                }
+                Record::Cut(region_id, from) => {
+                    if let Some(logs) = self.regions.get_mut(&region_id) {
+                        logs.cut(from);
+                    }
+                }
+                Record::State(region_id, state) => {
+                    let logs = self.regions
+                        .entry(region_id)
+                        .or_insert_with(RegionLogs::default);
+                    logs.state = Some(state);
+                    logs.state_file_num = file_num;
+                }
+                Record::Clean(region_id) => {
+                    self.regions.remove(&region_id);
+                }
+                Record::Compact(region_id, to) => {
+                    if let Some(logs) = self.regions.get_mut(&region_id) {
+                        compacted += logs.compact_to(to);
+                    }
+                }
+            }
+        }
+        compacted
+    }
+
+    fn locate(&self, region_id: u64, index: u64) -> Option<(EntryLocation, Arc<File>)> {
+        let loc = match self.regions.get(&region_id).and_then(|r| r.get(index)) {
+            Some(loc) => loc,
+            None => return None,
+        };
+        Some((loc, self.files[&loc.file_num].clone()))
+    }
+}
+
+struct Writer {
+    file_num: u64,
+    file: File,
+    offset: u64,
+    unsynced: u64,
+}
+
+impl Writer {
+    /// Appends a record, returns the offset of the payload in the file.
+    fn append(&mut self, payload: &[u8], sync: bool, bytes_per_sync: u64) -> Result<u64> {
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+        try!(buf.encode_u32_le(payload.len() as u32));
+        try!(buf.encode_u32_le(crc32::checksum_ieee(payload)));
+        buf.extend_from_slice(payload);
+        if let Err(e) = self.file.write_all(&buf) {
+            // Drop the partial record, otherwise the following records can't
+            // be recovered.
+            let _ = self.file.set_len(self.offset);
+            let _ = self.file.seek(SeekFrom::Start(self.offset));
+            return Err(e.into());
+        }
+        let base = self.offset + RECORD_HEADER_SIZE;
+        self.offset += buf.len() as u64;
+        self.unsynced += buf.len() as u64;
+        if sync || self.unsynced >= bytes_per_sync {
+            try!(self.sync());
+        }
+        Ok(base)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            try!(self.file.sync_data());
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+/// `SegmentRaftEngine` stores the raft logs and states in segment files,
+/// see the module document for details.
+pub struct SegmentRaftEngine {
+    dir: String,
+    segment_size: u64,
+    // Sync the files every `bytes_per_sync` bytes written even if it's not
+    // required by the writes, 0 means sync on every write.
+    bytes_per_sync: u64,
+    // A file expires once there are so many newer files.
+    expire_count: u64,
+    writer: Mutex<Writer>,
+    index: RwLock<Index>,
+}
+
+fn file_path(dir: &str, file_num: u64) -> PathBuf {
+    PathBuf::from(dir).join(format!("{:016}{}", file_num, LOG_FILE_SUFFIX))
+}
+
+fn parse_file_num(name: &str) -> Option<u64> {
+    if !name.ends_with(LOG_FILE_SUFFIX) {
+        return None;
+    }
+    name[..name.len() - LOG_FILE_SUFFIX.len()].parse().ok()
+}
+
+fn sync_dir(dir: &str) -> Result<()> {
+    try!(try!(File::open(dir)).sync_all());
+    Ok(())
+}
+
+/// Replays the records in the file, returns the size of the valid records.
+fn recover_file(path: &PathBuf, file_num: u64, index: &mut Index, is_last: bool) -> Result<u64> {
+    let mut data = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    let mut offset = 0;
+    while offset + RECORD_HEADER_SIZE <= data.len() as u64 {
+        let mut header = &data[offset as usize..(offset + RECORD_HEADER_SIZE) as usize];
+        let len = try!(header.decode_u32_le()) as u64;
+        let checksum = try!(header.decode_u32_le());
+        let begin = offset + RECORD_HEADER_SIZE;
+        if begin + len > data.len() as u64 {
+            break;
+        }
+        let payload = &data[begin as usize..(begin + len) as usize];
+        if crc32::checksum_ieee(payload) != checksum {
+            break;
+        }
+        let records = try!(decode_records(payload));
+        index.apply(records, file_num, begin);
+        offset = begin + len;
+    }
+    if offset < data.len() as u64 {
+        if !is_last {
+            return Err(box_err!(
+                "raft log file {} is corrupted at {}",
+                path.display(),
+                offset
+            ));
+        }
+        // The tail of the last file may be partially written when crashed.
+        warn!(
+            "raft log file {} has a broken tail at {}, truncate {} bytes",
+            path.display(),
+            offset,
+            data.len() as u64 - offset
+        );
+    }
+    Ok(offset)
+}
+
+impl SegmentRaftEngine {
+    pub fn new(
+        dir: &str,
+        segment_size: u64,
+        bytes_per_sync: u64,
+        expire_count: u64,
+    ) -> Result<SegmentRaftEngine> {
+        try!(fs::create_dir_all(dir));
+        let mut file_nums = vec![];
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            if let Some(num) = entry.file_name().to_str().and_then(parse_file_num) {
+                file_nums.push(num);
+            }
+        }
+        file_nums.sort();
+
+        let mut index = Index::default();
+        let mut last_offset = 0;
+        for (i, &num) in file_nums.iter().enumerate() {
+            let path = file_path(dir, num);
+            last_offset = try!(recover_file(
+                &path,
+                num,
+                &mut index,
+                i + 1 == file_nums.len()
+            ));
+            index.files.insert(num, Arc::new(try!(File::open(&path))));
+        }
+
+        let file_num = match file_nums.last() {
+            Some(&num) => num,
+            None => {
+                let path = file_path(dir, 1);
+                try!(OpenOptions::new().write(true).create_new(true).open(&path));
+                try!(sync_dir(dir));
+                index.files.insert(1, Arc::new(try!(File::open(&path))));
+                1
+            }
+        };
+        let mut file = try!(OpenOptions::new().write(true).open(file_path(dir, file_num)));
+        try!(file.set_len(last_offset));
+        try!(file.seek(SeekFrom::Start(last_offset)));
+
+        info!(
+            "open raft log files in {}, {} files, {} regions",
+            dir,
+            index.files.len(),
+            index.regions.len()
+        );
+        Ok(SegmentRaftEngine {
+            dir: dir.to_owned(),
+            segment_size: segment_size,
+            bytes_per_sync: bytes_per_sync,
+            expire_count: expire_count,
+            writer: Mutex::new(Writer {
+                file_num: file_num,
+                file: file,
+                offset: last_offset,
+                unsynced: 0,
+            }),
+            index: RwLock::new(index),
+        })
+    }
+
+    fn append_records(
+        &self,
+        writer: &mut Writer,
+        payload: &[u8],
+        records: Vec<Record>,
+        sync: bool,
+    ) -> Result<u64> {
+        let base = try!(writer.append(payload, sync, self.bytes_per_sync));
+        let compacted = self.index
+            .write()
+            .unwrap()
+            .apply(records, writer.file_num, base);
+        if writer.offset >= self.segment_size {
+            try!(self.rotate(writer));
+        }
+        Ok(compacted)
+    }
+
+    fn rotate(&self, writer: &mut Writer) -> Result<()> {
+        try!(writer.file.sync_data());
+        let file_num = writer.file_num + 1;
+        let path = file_path(&self.dir, file_num);
+        let file = try!(OpenOptions::new().write(true).create_new(true).open(&path));
+        try!(sync_dir(&self.dir));
+        let reader = Arc::new(try!(File::open(&path)));
+        self.index.write().unwrap().files.insert(file_num, reader);
+        *writer = Writer {
+            file_num: file_num,
+            file: file,
+            offset: 0,
+            unsynced: 0,
+        };
+        Ok(())
+    }
+
+    /// Collects the files which are not referenced by any region or are
+    /// expired and removes them from the index, the logs and the states in
+    /// them are rewritten to the active file first. Returns the active file,
+    /// which must be synced before the collected files are deleted.
+    fn collect_expired_files(&self, writer: &mut Writer) -> Result<Option<(File, Vec<u64>)>> {
+        let expired_file_num = writer.file_num.saturating_sub(self.expire_count);
+        let (min_file_num, expired_logs, stale_states) = {
+            let index = self.index.read().unwrap();
+            let mut min_file_num = writer.file_num;
+            // (region id, the locations of all the logs of the region).
+            let mut expired_logs = vec![];
+            for (&region_id, logs) in &index.regions {
+                let file_num = match logs.entries.front() {
+                    Some(loc) => loc.file_num,
+                    None => continue,
+                };
+                if file_num < expired_file_num {
+                    // Appending logs drops the ones after them, so all the
+                    // logs of the region are rewritten.
+                    let located: Vec<_> = logs.entries
+                        .iter()
+                        .map(|loc| (*loc, index.files[&loc.file_num].clone()))
+                        .collect();
+                    expired_logs.push((region_id, located));
+                    min_file_num = cmp::min(min_file_num, expired_file_num);
+                } else {
+                    min_file_num = cmp::min(min_file_num, file_num);
+                }
+            }
+            match index.files.keys().next() {
+                Some(&num) if num < min_file_num => {}
+                _ => return Ok(None),
+            }
+            let mut stale_states = vec![];
+            for (&region_id, logs) in &index.regions {
+                if let Some(ref state) = logs.state {
+                    if logs.state_file_num < min_file_num {
+                        stale_states.push((region_id, state.clone()));
+                    }
+                }
+            }
+            (min_file_num, expired_logs, stale_states)
+        };
+
+        // The index can't be changed by others as the writer is locked, so
+        // the files are read without the lock.
+        let mut rewrites = RaftLogBatch::with_capacity(expired_logs.len() + stale_states.len());
+        let mut rewritten = 0;
+        for (region_id, located) in expired_logs {
+            let mut entries = Vec::with_capacity(located.len());
+            for (loc, file) in located {
+                entries.push(try!(read_entry(loc, &file)));
+            }
+            rewritten += entries.len();
+            rewrites.append(region_id, &entries);
+        }
+        for (region_id, state) in stale_states {
+            rewrites.put_state(region_id, &state);
+        }
+        if !rewrites.is_empty() {
+            let mut payload = Vec::with_capacity(rewrites.data_size() + 64);
+            let records = try!(encode_batch(&rewrites, &mut payload));
+            try!(self.append_records(writer, &payload, records, false));
+        }
+        if rewritten > 0 {
+            info!(
+                "rewrite {} raft logs in expired files before {} in {}",
+                rewritten,
+                expired_file_num,
+                self.dir
+            );
+        }
+        // Files before the active one are synced when rotated.
+        let active = try!(writer.file.try_clone());
+
+        let mut index = self.index.write().unwrap();
+        let expired: Vec<u64> = index
+            .files
+            .keys()
+            .take_while(|&&num| num < min_file_num)
+            .cloned()
+            .collect();
+        for num in &expired {
+            index.files.remove(num);
+        }
+        Ok(Some((active, expired)))
+    }
+
+    fn purge_files(&self, active: File, expired: Vec<u64>) -> Result<()> {
+        // The compact marks and the rewritten states must be persisted before
+        // the files are deleted.
+        try!(active.sync_data());
+        for num in &expired {
+            try!(fs::remove_file(file_path(&self.dir, *num)));
+        }
+        info!("purge {} raft log files in {}", expired.len(), self.dir);
+        Ok(())
+    }
+}
+
+/// Checks whether there is any raft log file in the directory.
+pub fn has_log_files(dir: &str) -> Result<bool> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        if entry.file_name().to_str().and_then(parse_file_num).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn read_entry(loc: EntryLocation, file: &File) -> Result<Entry> {
+    let mut buf = vec![0; loc.len as usize];
+    let mut read = 0;
+    while read < buf.len() {
+        let n = try!(file.read_at(&mut buf[read..], loc.offset + read as u64));
+        if n == 0 {
+            return Err(box_err!("unexpected eof when reading entry at {:?}", loc));
+        }
+        read += n;
+    }
+    let mut entry = Entry::new();
+    try!(entry.merge_from_bytes(&buf));
+    Ok(entry)
+}
+
+impl RaftEngine for SegmentRaftEngine {
+    fn path(&self) -> &str {
+        &self.dir
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.index.read().unwrap().regions.is_empty())
+    }
+
+    fn get_raft_state(&self, region_id: u64) -> Result<Option<RaftLocalState>> {
+        let index = self.index.read().unwrap();
+        Ok(index
+            .regions
+            .get(&region_id)
+            .and_then(|logs| logs.state.clone()))
+    }
+
+    fn get_entry(&self, region_id: u64, idx: u64) -> Result<Option<Entry>> {
+        let located = self.index.read().unwrap().locate(region_id, idx);
+        match located {
+            None => Ok(None),
+            Some((loc, file)) => read_entry(loc, &file).map(Some),
+        }
+    }
+
+    fn first_index(&self, region_id: u64) -> Result<Option<u64>> {
+        let index = self.index.read().unwrap();
+        Ok(match index.regions.get(&region_id) {
+            Some(logs) if !logs.entries.is_empty() => Some(logs.first_index),
+            _ => None,
+        })
+    }
+
+    fn fetch_entries_to(
+        &self,
+        region_id: u64,
+        low: u64,
+        high: u64,
+        max_size: u64,
+        buf: &mut Vec<Entry>,
+    ) -> raft::Result<u64> {
+        let mut located = Vec::with_capacity((high - low) as usize);
+        {
+            let index = self.index.read().unwrap();
+            for i in low..high {
+                match index.locate(region_id, i) {
+                    Some(l) => located.push(l),
+                    None => return Err(RaftError::Store(StorageError::Unavailable)),
+                }
+            }
+        }
+        // The files are read without the lock, a purged file is still
+        // readable through the handle.
+        let mut total_size = 0;
+        for (loc, file) in located {
+            let entry = box_try!(read_entry(loc, &file));
+            total_size += loc.len;
+            if buf.is_empty() || total_size <= max_size {
+                buf.push(entry);
+            }
+            if total_size > max_size {
+                break;
+            }
+        }
+        Ok(total_size)
+    }
+
+    fn write(&self, batch: RaftLogBatch, sync: bool) -> Result<()> {
+        if batch.is_empty() {
+            if sync {
+                try!(self.writer.lock().unwrap().sync());
+            }
+            return Ok(());
+        }
+        let mut payload = Vec::with_capacity(batch.data_size() + 64);
+        let records = try!(encode_batch(&batch, &mut payload));
+        let mut writer = self.writer.lock().unwrap();
+        try!(self.append_records(&mut writer, &payload, records, sync));
+        Ok(())
+    }
+
+    fn gc(&self, region_id: u64, _: u64, to: u64) -> Result<u64> {
+        let mut payload = vec![];
+        let record = try!(encode_compact(region_id, to, &mut payload));
+        let (compacted, expired) = {
+            let mut writer = self.writer.lock().unwrap();
+            let compacted = try!(self.append_records(&mut writer, &payload, vec![record], false));
+            if compacted == 0 {
+                return Ok(0);
+            }
+            (compacted, try!(self.collect_expired_files(&mut writer)))
+        };
+        // Don't block the appends when syncing and deleting the files.
+        if let Some((active, expired)) = expired {
+            try!(self.purge_files(active, expired));
+        }
+        Ok(compacted)
+    }
+
+    fn flush(&self, _: bool) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use tempdir::TempDir;
+    use kvproto::eraftpb::Entry;
+    use kvproto::raft_serverpb::RaftLocalState;
+
+    use super::*;
+
+    fn new_entry(index: u64) -> Entry {
+        let mut e = Entry::new();
+        e.set_index(index);
+        e.set_term(1);
+        e.set_data(vec![0; 64]);
+        e
+    }
+
+    // Appends the logs in [low, high) one by one.
+    fn append(engine: &SegmentRaftEngine, region_id: u64, low: u64, high: u64) {
+        for i in low..high {
+            let mut state = RaftLocalState::new();
+            state.set_last_index(i);
+            let mut batch = RaftLogBatch::new();
+            batch.append(region_id, &[new_entry(i)]);
+            batch.put_state(region_id, &state);
+            engine.write(batch, false).unwrap();
+        }
+    }
+
+    fn file_count(dir: &str) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn test_segment_recover() {
+        let path = TempDir::new("test-segment-recover").unwrap();
+        let dir = path.path().to_str().unwrap();
+        {
+            let engine = SegmentRaftEngine::new(dir, 4096, 1024, 100).unwrap();
+            append(&engine, 1, 1, 50);
+            append(&engine, 2, 1, 10);
+            append(&engine, 1, 40, 60);
+            assert_eq!(engine.gc(1, 0, 20).unwrap(), 19);
+            let mut batch = RaftLogBatch::new();
+            batch.clean(2, 9);
+            engine.write(batch, true).unwrap();
+        }
+
+        // Append a broken record to the last file.
+        let last = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| {
+                e.unwrap().file_name().to_str().and_then(parse_file_num)
+            })
+            .max()
+            .unwrap();
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(file_path(dir, last))
+            .unwrap();
+        f.write_all(&[10, 0, 0, 0, 1, 2]).unwrap();
+
+        let engine = SegmentRaftEngine::new(dir, 4096, 1024, 100).unwrap();
+        assert_eq!(engine.first_index(1).unwrap(), Some(20));
+        assert_eq!(
+            engine.get_raft_state(1).unwrap().unwrap().get_last_index(),
+            59
+        );
+        assert_eq!(engine.get_entry(1, 59).unwrap(), Some(new_entry(59)));
+        assert_eq!(engine.get_entry(1, 60).unwrap(), None);
+        assert_eq!(engine.get_raft_state(2).unwrap(), None);
+
+        // The broken tail is truncated, so the new records can be recovered.
+        append(&engine, 1, 60, 61);
+        drop(engine);
+        let engine = SegmentRaftEngine::new(dir, 4096, 1024, 100).unwrap();
+        assert_eq!(engine.get_entry(1, 60).unwrap(), Some(new_entry(60)));
+    }
+
+    #[test]
+    fn test_segment_purge() {
+        let path = TempDir::new("test-segment-purge").unwrap();
+        let dir = path.path().to_str().unwrap();
+        let engine = SegmentRaftEngine::new(dir, 1024, 0, 100).unwrap();
+        append(&engine, 2, 1, 2);
+        engine.gc(2, 0, 2).unwrap();
+        append(&engine, 1, 1, 100);
+        let count = file_count(dir);
+        assert!(count > 5);
+
+        // Only the state of region 2 is in the first file, it's rewritten
+        // before the file is purged.
+        engine.gc(1, 0, 95).unwrap();
+        assert!(file_count(dir) < count);
+        assert!(!file_path(dir, 1).exists());
+        assert_eq!(
+            engine.get_raft_state(2).unwrap().unwrap().get_last_index(),
+            1
+        );
+
+        // The logs of region 2 prevent the files from being purged.
+        append(&engine, 2, 2, 3);
+        append(&engine, 1, 100, 200);
+        engine.gc(1, 0, 195).unwrap();
+        let count = file_count(dir);
+        assert!(count > 5);
+        engine.gc(2, 0, 3).unwrap();
+        assert!(file_count(dir) < count);
+
+        drop(engine);
+        let engine = SegmentRaftEngine::new(dir, 1024, 0, 100).unwrap();
+        assert_eq!(engine.first_index(1).unwrap(), Some(195));
+        assert_eq!(engine.get_entry(1, 199).unwrap(), Some(new_entry(199)));
+        assert_eq!(engine.first_index(2).unwrap(), None);
+        assert_eq!(
+            engine.get_raft_state(2).unwrap().unwrap().get_last_index(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_segment_rewrite_expired() {
+        let path = TempDir::new("test-segment-rewrite-expired").unwrap();
+        let dir = path.path().to_str().unwrap();
+        let engine = SegmentRaftEngine::new(dir, 1024, 0, 3).unwrap();
+        // Region 2 is idle after its logs are written.
+        append(&engine, 2, 1, 6);
+        engine.gc(2, 0, 3).unwrap();
+        for i in 0..10 {
+            append(&engine, 1, i * 20 + 1, i * 20 + 21);
+            engine.gc(1, 0, i * 20 + 21).unwrap();
+        }
+
+        // The logs of region 2 are rewritten, so the expired files are purged.
+        assert!(!file_path(dir, 1).exists());
+        assert!(file_count(dir) <= 5, "{} files left", file_count(dir));
+        for i in 3..6 {
+            assert_eq!(engine.get_entry(2, i).unwrap(), Some(new_entry(i)));
+        }
+        assert_eq!(engine.first_index(2).unwrap(), Some(3));
+
+        drop(engine);
+        let engine = SegmentRaftEngine::new(dir, 1024, 0, 3).unwrap();
+        assert_eq!(engine.first_index(2).unwrap(), Some(3));
+        assert_eq!(engine.get_entry(2, 5).unwrap(), Some(new_entry(5)));
+        assert_eq!(engine.get_entry(2, 6).unwrap(), None);
+        assert_eq!(
+            engine.get_raft_state(2).unwrap().unwrap().get_last_index(),
+            5
+        );
+        assert_eq!(engine.get_entry(1, 200).unwrap(), Some(new_entry(200)));
+    }
+}
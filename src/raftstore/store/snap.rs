@@ -15,6 +15,7 @@ use std::error;
 use std::io::{self, ErrorKind, Read, Write};
 use std::fmt::{self, Display, Formatter};
 use std::fs::{self, Metadata};
+use std::mem;
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::path::Path;
@@ -37,14 +38,15 @@ use storage::{CfName, CF_DEFAULT, CF_LOCK, CF_WRITE};
 use util::transport::SendCh;
 use util::HandyRwLock;
 use util::collections::{HashMap, HashMapEntry as Entry};
+use util::io_limiter::IOLimiter;
 use util::codec::bytes::{BytesEncoder, CompactBytesDecoder};
 
-use raftstore::store::engine::{Iterable, Snapshot as DbSnapshot};
+use raftstore::store::engine::{Iterable, Snapshot as DbSnapshot, SyncSnapshot};
 use raftstore::store::keys::{self, enc_end_key, enc_start_key};
 
 
 use raftstore::store::metrics::{SNAPSHOT_BUILD_TIME_HISTOGRAM, SNAPSHOT_CF_KV_COUNT,
-                                SNAPSHOT_CF_SIZE};
+                                SNAPSHOT_CF_SIZE, SNAPSHOT_THROTTLED_DURATION_VEC};
 use raftstore::store::peer_storage::JOB_STATUS_CANCELLING;
 
 // Data in CF_RAFT should be excluded for a snapshot.
@@ -135,6 +137,30 @@ impl Display for SnapKey {
     }
 }
 
+/// Returns the total size of the snapshot files, 0 means only the meta of
+/// the snapshot is sent.
+pub fn get_snapshot_file_size(snap: &RaftSnapshot) -> io::Result<u64> {
+    let mut snap_data = RaftSnapshotData::new();
+    if let Err(e) = snap_data.merge_from_bytes(snap.get_data()) {
+        return Err(io::Error::new(ErrorKind::Other, e));
+    }
+    Ok(snap_data.get_file_size())
+}
+
+/// Strips the files from the snapshot so only its meta is sent, a witness
+/// stores no data and receives such snapshots.
+pub fn strip_snapshot_files(snap: &mut RaftSnapshot) -> RaftStoreResult<()> {
+    let mut snap_data = RaftSnapshotData::new();
+    try!(snap_data.merge_from_bytes(snap.get_data()));
+    snap_data.set_file_size(0);
+    for cf_file in snap_data.mut_meta().mut_cf_files().iter_mut() {
+        cf_file.set_size(0);
+        cf_file.set_checksum(0);
+    }
+    snap.set_data(try!(snap_data.write_to_bytes()));
+    Ok(())
+}
+
 #[derive(Default)]
 pub struct SnapshotStatistics {
     pub size: u64,
@@ -166,7 +192,7 @@ pub struct ApplyOptions {
 pub trait Snapshot: Read + Write + Send {
     fn build(
         &mut self,
-        snap: &DbSnapshot,
+        snap: &SyncSnapshot,
         region: &Region,
         snap_data: &mut RaftSnapshotData,
         stat: &mut SnapshotStatistics,
@@ -227,7 +253,11 @@ use util::time::duration_to_sec;
 use util::file::{delete_file_if_exist, file_exists, get_file_size};
 use util::rocksdb::get_fastest_supported_compression_type;
 
-pub const SNAPSHOT_VERSION: u64 = 2;
+// Since version 3, a cf may be split into several files by key range.
+pub const SNAPSHOT_VERSION: u64 = 3;
+// The version of snapshots whose cfs are all in one file, which can be
+// received by the stores not knowing version 3.
+const SNAPSHOT_VERSION_SINGLE_FILE: u64 = 2;
 const META_FILE_SUFFIX: &'static str = ".meta";
 const DIGEST_BUFFER_SIZE: usize = 10240;
 
@@ -250,6 +280,19 @@ fn calc_crc32(p: &PathBuf) -> io::Result<u32> {
     }
 }
 
+// Return the path and tmp path of the `index`th file of cf.
+fn gen_cf_file_paths(dir: &Path, prefix: &str, cf: &str, index: usize) -> (PathBuf, PathBuf) {
+    // The first file keeps the name used before a cf can be split into several files.
+    let filename = if index == 0 {
+        format!("{}_{}{}", prefix, cf, SST_FILE_SUFFIX)
+    } else {
+        format!("{}_{}_{}{}", prefix, cf, index, SST_FILE_SUFFIX)
+    };
+    let path = dir.join(&filename);
+    let tmp_path = dir.join(format!("{}{}", filename, TMP_FILE_SUFFIX));
+    (path, tmp_path)
+}
+
 fn gen_snapshot_meta(cf_files: &[CfFile]) -> RaftStoreResult<SnapshotMeta> {
     let mut meta = Vec::with_capacity(cf_files.len());
     for cf_file in cf_files {
@@ -310,15 +353,163 @@ struct CfFile {
     pub cf: CfName,
     pub path: PathBuf,
     pub tmp_path: PathBuf,
-    pub sst_writer: Option<SstFileWriter>,
     pub file: Option<File>,
-    pub kv_count: u64,
     pub size: u64,
     pub written_size: u64,
     pub checksum: u32,
     pub write_digest: Option<Digest>,
 }
 
+/// The result of building all the files of a cf.
+struct CfBuildResult {
+    // Size and checksum of each file, ordered by key range.
+    files: Vec<(u64, u32)>,
+    key_count: usize,
+    size: usize,
+}
+
+/// `CfFileBuilder` builds the files of a cf in its own thread, a cf which is
+/// not stored in plain file is split into several sst files when it exceeds
+/// `max_file_size`.
+struct CfFileBuilder {
+    cf: CfName,
+    dir_path: PathBuf,
+    prefix: String,
+    max_file_size: u64,
+    snap: SyncSnapshot,
+    begin_key: Vec<u8>,
+    end_key: Vec<u8>,
+}
+
+impl CfFileBuilder {
+    fn build(&self) -> RaftStoreResult<CfBuildResult> {
+        let mut res = CfBuildResult {
+            files: vec![],
+            key_count: 0,
+            size: 0,
+        };
+        let r = if plain_file_used(self.cf) {
+            self.build_plain(&mut res)
+        } else {
+            self.build_sst(&mut res)
+        };
+        if let Err(e) = r {
+            // Clean up all the files that may be created, including the one being written.
+            for index in 0..res.files.len() + 2 {
+                let (path, tmp_path) = self.file_paths(index);
+                delete_file_if_exist(&tmp_path);
+                delete_file_if_exist(&path);
+            }
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    fn file_paths(&self, index: usize) -> (PathBuf, PathBuf) {
+        gen_cf_file_paths(&self.dir_path, &self.prefix, self.cf, index)
+    }
+
+    fn build_plain(&self, res: &mut CfBuildResult) -> RaftStoreResult<()> {
+        let (_, tmp_path) = self.file_paths(0);
+        {
+            let mut file = try!(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&tmp_path)
+            );
+            let (key_count, size) = try!(build_plain_cf_file(
+                &mut file,
+                &self.snap,
+                self.cf,
+                &self.begin_key,
+                &self.end_key
+            ));
+            res.key_count = key_count;
+            res.size = size;
+        }
+        self.save_file(res)
+    }
+
+    fn new_sst_writer(&self, index: usize) -> RaftStoreResult<SstFileWriter> {
+        let (_, tmp_path) = self.file_paths(index);
+        let handle = try!(self.snap.cf_handle(self.cf));
+        let mut io_options = self.snap.get_db().get_options_cf(handle).clone();
+        io_options.compression(get_fastest_supported_compression_type());
+        // in rocksdb 5.5.1, SstFileWriter will try to use bottommost_compression and
+        // compression_per_level first, so to make sure our specified compression type
+        // being used, we must set them empty or disabled.
+        io_options.compression_per_level(&[]);
+        io_options.bottommost_compression(DBCompressionType::Disable);
+        let mut writer = SstFileWriter::new(EnvOptions::new(), io_options);
+        box_try!(writer.open(tmp_path.as_path().to_str().unwrap()));
+        Ok(writer)
+    }
+
+    fn build_sst(&self, res: &mut CfBuildResult) -> RaftStoreResult<()> {
+        let mut writer = try!(self.new_sst_writer(0));
+        let mut file_kv_count = 0;
+        let mut file_size = 0;
+        try!(self.snap.scan_cf(
+            self.cf,
+            &self.begin_key,
+            &self.end_key,
+            false,
+            &mut |key, value| {
+                if self.max_file_size > 0 && file_size >= self.max_file_size {
+                    // Keys are scanned in order, so each file covers a different key range.
+                    let next_writer = try!(self.new_sst_writer(res.files.len() + 1));
+                    let w = mem::replace(&mut writer, next_writer);
+                    try!(self.save_sst_file(w, file_kv_count, res));
+                    file_kv_count = 0;
+                    file_size = 0;
+                }
+                box_try!(writer.add(key, value));
+                file_kv_count += 1;
+                file_size += (key.len() + value.len()) as u64;
+                res.key_count += 1;
+                res.size += key.len() + value.len();
+                Ok(true)
+            }
+        ));
+        self.save_sst_file(writer, file_kv_count, res)
+    }
+
+    fn save_sst_file(
+        &self,
+        mut writer: SstFileWriter,
+        kv_count: usize,
+        res: &mut CfBuildResult,
+    ) -> RaftStoreResult<()> {
+        if kv_count > 0 {
+            box_try!(writer.finish());
+        }
+        drop(writer);
+        self.save_file(res)
+    }
+
+    // Save the file being written, which is the next file of `res`.
+    fn save_file(&self, res: &mut CfBuildResult) -> RaftStoreResult<()> {
+        let (path, tmp_path) = self.file_paths(res.files.len());
+        let size = if file_exists(&tmp_path) {
+            try!(get_file_size(&tmp_path))
+        } else {
+            0
+        };
+        if size == 0 {
+            // Clean up the `tmp_path` if this cf file is empty.
+            delete_file_if_exist(&tmp_path);
+            res.files.push((0, 0));
+            return Ok(());
+        }
+        try!(fs::rename(&tmp_path, &path));
+        let checksum = try!(calc_crc32(&path));
+        res.files.push((size, checksum));
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 struct MetaFile {
     pub meta: SnapshotMeta,
@@ -329,13 +520,54 @@ struct MetaFile {
     pub tmp_path: PathBuf,
 }
 
+/// `SnapLimiter` throttles the IO of snapshots and records the throttled time.
+#[derive(Clone)]
+struct SnapLimiter {
+    limiter: Arc<IOLimiter>,
+    tp: &'static str,
+}
+
+impl SnapLimiter {
+    fn new(bytes_per_sec: u64, tp: &'static str) -> Option<SnapLimiter> {
+        if bytes_per_sec == 0 {
+            return None;
+        }
+        Some(SnapLimiter {
+            limiter: Arc::new(IOLimiter::new(bytes_per_sec)),
+            tp: tp,
+        })
+    }
+
+    fn request(&self, bytes: u64) {
+        let wait = self.consume(bytes);
+        if wait > time::Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    fn consume(&self, bytes: u64) -> time::Duration {
+        let wait = self.limiter.consume(bytes);
+        if wait > time::Duration::from_millis(0) {
+            SNAPSHOT_THROTTLED_DURATION_VEC
+                .with_label_values(&[self.tp])
+                .inc_by(duration_to_sec(wait))
+                .unwrap();
+        }
+        wait
+    }
+}
+
 pub struct Snap {
     key: SnapKey,
+    dir_path: PathBuf,
+    prefix: String,
     display_path: String,
+    max_file_size: u64,
     cf_files: Vec<CfFile>,
     cf_index: usize,
     meta_file: MetaFile,
     size_track: Arc<RwLock<u64>>,
+    limiter: Option<SnapLimiter>,
 }
 
 impl Snap {
@@ -361,9 +593,7 @@ impl Snap {
 
         let mut cf_files = Vec::with_capacity(SNAPSHOT_CFS.len());
         for cf in SNAPSHOT_CFS {
-            let filename = format!("{}_{}{}", prefix, cf, SST_FILE_SUFFIX);
-            let path = dir_path.join(&filename);
-            let tmp_path = dir_path.join(format!("{}{}", filename, TMP_FILE_SUFFIX));
+            let (path, tmp_path) = gen_cf_file_paths(&dir_path, &prefix, cf, 0);
             let cf_file = CfFile {
                 cf: cf,
                 path: path,
@@ -384,11 +614,15 @@ impl Snap {
 
         let mut s = Snap {
             key: key.clone(),
+            dir_path: dir_path,
+            prefix: prefix,
             display_path: display_path,
+            max_file_size: 0,
             cf_files: cf_files,
             cf_index: 0,
             meta_file: meta_file,
             size_track: size_track,
+            limiter: None,
         };
 
         // load snapshot meta if meta_file exists
@@ -498,29 +732,9 @@ impl Snap {
         if self.exists() {
             return Ok(());
         }
-        for cf_file in &mut self.cf_files {
-            if plain_file_used(cf_file.cf) {
-                let f = try!(
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(true)
-                        .open(&cf_file.tmp_path)
-                );
-                cf_file.file = Some(f);
-            } else {
-                let handle = try!(snap.cf_handle(cf_file.cf));
-                let mut io_options = snap.get_db().get_options_cf(handle).clone();
-                io_options.compression(get_fastest_supported_compression_type());
-                // in rocksdb 5.5.1, SstFileWriter will try to use bottommost_compression and
-                // compression_per_level first, so to make sure our specified compression type
-                // being used, we must set them empty or disabled.
-                io_options.compression_per_level(&[]);
-                io_options.bottommost_compression(DBCompressionType::Disable);
-                let mut writer = SstFileWriter::new(EnvOptions::new(), io_options);
-                box_try!(writer.open(cf_file.tmp_path.as_path().to_str().unwrap()));
-                cf_file.sst_writer = Some(writer);
-            }
+        // The cf files are created when building, make sure all the cfs exist here.
+        for cf in SNAPSHOT_CFS {
+            try!(snap.cf_handle(cf));
         }
         let file = try!(
             OpenOptions::new()
@@ -543,34 +757,63 @@ impl Snap {
     }
 
     fn set_snapshot_meta(&mut self, snapshot_meta: SnapshotMeta) -> RaftStoreResult<()> {
-        if snapshot_meta.get_cf_files().len() != self.cf_files.len() {
-            return Err(box_err!(
-                "invalid cf number of snapshot meta, expect {}, got {}",
-                SNAPSHOT_CFS.len(),
-                snapshot_meta.get_cf_files().len()
-            ));
-        }
-        for (i, cf_file) in self.cf_files.iter_mut().enumerate() {
-            let meta = snapshot_meta.get_cf_files().get(i).unwrap();
-            if meta.get_cf() != cf_file.cf {
-                return Err(box_err!(
-                    "invalid {} cf in snapshot meta, expect {}, got {}",
-                    i,
-                    cf_file.cf,
-                    meta.get_cf()
-                ));
-            }
+        let cf_files = try!(self.gen_cf_files_from_meta(&snapshot_meta));
+        self.cf_files = cf_files;
+        self.cf_index = 0;
+        for cf_file in &self.cf_files {
             if file_exists(&cf_file.path) {
                 // Check only the file size for `exists()` to work correctly.
-                try!(check_file_size(&cf_file.path, meta.get_size()));
+                try!(check_file_size(&cf_file.path, cf_file.size));
             }
-            cf_file.size = meta.get_size();
-            cf_file.checksum = meta.get_checksum();
         }
         self.meta_file.meta = snapshot_meta;
         Ok(())
     }
 
+    fn gen_cf_files_from_meta(
+        &self,
+        snapshot_meta: &SnapshotMeta,
+    ) -> RaftStoreResult<Vec<CfFile>> {
+        let metas = snapshot_meta.get_cf_files();
+        let mut cf_files = Vec::with_capacity(metas.len());
+        // The files of a cf are placed together by the order of `SNAPSHOT_CFS`.
+        let mut pos = 0;
+        for cf in SNAPSHOT_CFS {
+            let mut index = 0;
+            while pos < metas.len() && metas[pos].get_cf() == *cf {
+                let (path, tmp_path) = gen_cf_file_paths(&self.dir_path, &self.prefix, cf, index);
+                cf_files.push(CfFile {
+                    cf: cf,
+                    path: path,
+                    tmp_path: tmp_path,
+                    size: metas[pos].get_size(),
+                    checksum: metas[pos].get_checksum(),
+                    ..Default::default()
+                });
+                index += 1;
+                pos += 1;
+            }
+            if index == 0 {
+                return Err(box_err!("missing cf {} in snapshot meta", cf));
+            }
+            if index > 1 && plain_file_used(cf) {
+                return Err(box_err!(
+                    "invalid snapshot meta, cf {} has {} plain files",
+                    cf,
+                    index
+                ));
+            }
+        }
+        if pos != metas.len() {
+            return Err(box_err!(
+                "invalid {} cf in snapshot meta, got {}",
+                pos,
+                metas[pos].get_cf()
+            ));
+        }
+        Ok(cf_files)
+    }
+
     fn load_snapshot_meta(&mut self) -> RaftStoreResult<()> {
         let snapshot_meta = try!(self.read_snapshot_meta());
         try!(self.set_snapshot_meta(snapshot_meta));
@@ -612,58 +855,6 @@ impl Snap {
         Ok(())
     }
 
-    fn switch_to_cf_file(&mut self, cf: &str) -> io::Result<()> {
-        match self.cf_files.iter().position(|x| x.cf == cf) {
-            Some(index) => {
-                self.cf_index = index;
-                Ok(())
-            }
-            None => Err(io::Error::new(
-                ErrorKind::Other,
-                format!("fail to find cf {}", cf),
-            )),
-        }
-    }
-
-    fn add_kv(&mut self, k: &[u8], v: &[u8]) -> io::Result<()> {
-        let cf_file = &mut self.cf_files[self.cf_index];
-        let writer = cf_file.sst_writer.as_mut().unwrap();
-        if let Err(e) = writer.add(k, v) {
-            return Err(io::Error::new(ErrorKind::Other, e));
-        }
-        cf_file.kv_count += 1;
-        Ok(())
-    }
-
-    fn save_cf_files(&mut self) -> io::Result<()> {
-        for cf_file in &mut self.cf_files {
-            if plain_file_used(cf_file.cf) {
-                let _ = cf_file.file.take();
-            } else if cf_file.kv_count == 0 {
-                let _ = cf_file.sst_writer.take().unwrap();
-            } else {
-                let mut writer = cf_file.sst_writer.take().unwrap();
-                if let Err(e) = writer.finish() {
-                    return Err(io::Error::new(ErrorKind::Other, e));
-                }
-            }
-            let size = try!(get_file_size(&cf_file.tmp_path));
-            if size > 0 {
-                try!(fs::rename(&cf_file.tmp_path, &cf_file.path));
-                cf_file.size = size;
-                // add size
-                let mut size_track = self.size_track.wl();
-                *size_track = size_track.saturating_add(size);
-
-                cf_file.checksum = try!(calc_crc32(&cf_file.path));
-            } else {
-                // Clean up the `tmp_path` if this cf file is empty.
-                delete_file_if_exist(&cf_file.tmp_path);
-            }
-        }
-        Ok(())
-    }
-
     fn save_meta_file(&mut self) -> RaftStoreResult<()> {
         let mut v = vec![];
         box_try!(self.meta_file.meta.write_to_vec(&mut v));
@@ -678,7 +869,7 @@ impl Snap {
 
     fn do_build(
         &mut self,
-        snap: &DbSnapshot,
+        snap: &SyncSnapshot,
         region: &Region,
         stat: &mut SnapshotStatistics,
         deleter: Box<SnapshotDeleter>,
@@ -706,48 +897,84 @@ impl Snap {
             }
         }
 
-        let mut snap_key_count = 0;
         let (begin_key, end_key) = (enc_start_key(region), enc_end_key(region));
+        let mut handles = Vec::with_capacity(SNAPSHOT_CFS.len());
         for cf in SNAPSHOT_CFS {
-            try!(self.switch_to_cf_file(cf));
-            let (cf_key_count, cf_size) = if plain_file_used(cf) {
-                let file = self.cf_files[self.cf_index].file.as_mut().unwrap();
-                try!(build_plain_cf_file(file, snap, cf, &begin_key, &end_key))
-            } else {
-                let mut key_count = 0;
-                let mut size = 0;
-                try!(snap.scan_cf(
-                    cf,
-                    &begin_key,
-                    &end_key,
-                    false,
-                    &mut |key, value| {
-                        key_count += 1;
-                        size += key.len() + value.len();
-                        try!(self.add_kv(key, value));
-                        Ok(true)
-                    }
-                ));
-                (key_count, size)
+            let builder = CfFileBuilder {
+                cf: cf,
+                dir_path: self.dir_path.clone(),
+                prefix: self.prefix.clone(),
+                max_file_size: self.max_file_size,
+                snap: snap.clone(),
+                begin_key: begin_key.clone(),
+                end_key: end_key.clone(),
             };
-            snap_key_count += cf_key_count;
+            let h = try!(
+                thread::Builder::new()
+                    .name(thd_name!(format!("snap-build-{}", cf)))
+                    .spawn(move || builder.build())
+            );
+            handles.push(h);
+        }
+
+        // Wait for all the cfs even if some of them fail, so no file is left behind.
+        let mut cf_files = Vec::with_capacity(SNAPSHOT_CFS.len());
+        let mut snap_key_count = 0;
+        let mut res: RaftStoreResult<()> = Ok(());
+        for (cf, h) in SNAPSHOT_CFS.iter().zip(handles) {
+            let built = match h.join() {
+                Ok(Ok(built)) => built,
+                Ok(Err(e)) => {
+                    res = Err(e);
+                    continue;
+                }
+                Err(_) => {
+                    res = Err(box_err!("thread building cf {} panicked", cf));
+                    continue;
+                }
+            };
+            for (index, &(size, checksum)) in built.files.iter().enumerate() {
+                let (path, tmp_path) = gen_cf_file_paths(&self.dir_path, &self.prefix, cf, index);
+                cf_files.push(CfFile {
+                    cf: cf,
+                    path: path,
+                    tmp_path: tmp_path,
+                    size: size,
+                    checksum: checksum,
+                    ..Default::default()
+                });
+            }
+            snap_key_count += built.key_count;
             SNAPSHOT_CF_KV_COUNT
                 .with_label_values(&[cf])
-                .observe(cf_key_count as f64);
+                .observe(built.key_count as f64);
             SNAPSHOT_CF_SIZE
                 .with_label_values(&[cf])
-                .observe(cf_size as f64);
+                .observe(built.size as f64);
             info!(
-                "[region {}] scan snapshot {}, cf {}, key count {}, size {}",
+                "[region {}] scan snapshot {}, cf {}, key count {}, size {}, files {}",
                 region.get_id(),
                 self.path(),
                 cf,
-                cf_key_count,
-                cf_size
+                built.key_count,
+                built.size,
+                built.files.len()
             );
         }
+        if let Err(e) = res {
+            for cf_file in &cf_files {
+                delete_file_if_exist(&cf_file.path);
+            }
+            return Err(e);
+        }
 
-        try!(self.save_cf_files());
+        {
+            let size = cf_files.iter().fold(0, |acc, f| acc + f.size);
+            let mut size_track = self.size_track.wl();
+            *size_track = size_track.saturating_add(size);
+        }
+        self.cf_files = cf_files;
+        self.cf_index = 0;
         stat.kv_count = snap_key_count;
         // save snapshot meta to meta file
         let snapshot_meta = try!(gen_snapshot_meta(&self.cf_files[..]));
@@ -818,12 +1045,15 @@ fn apply_plain_cf_file<D: CompactBytesDecoder>(
 impl Snapshot for Snap {
     fn build(
         &mut self,
-        snap: &DbSnapshot,
+        snap: &SyncSnapshot,
         region: &Region,
         snap_data: &mut RaftSnapshotData,
         stat: &mut SnapshotStatistics,
         deleter: Box<SnapshotDeleter>,
     ) -> RaftStoreResult<()> {
+        fail_point!("snapshot_build", |_| {
+            Err(box_err!("failed to build snapshot by fail point"))
+        });
         let t = Instant::now();
         try!(self.do_build(snap, region, stat, deleter));
 
@@ -831,7 +1061,13 @@ impl Snapshot for Snap {
         stat.size = total_size;
         // set snapshot meta data
         snap_data.set_file_size(total_size);
-        snap_data.set_version(SNAPSHOT_VERSION);
+        // Only mark the snapshot as version 3 if some cf is really split, so
+        // it stays compatible with old stores unless splitting is enabled.
+        if self.cf_files.len() > SNAPSHOT_CFS.len() {
+            snap_data.set_version(SNAPSHOT_VERSION);
+        } else {
+            snap_data.set_version(SNAPSHOT_VERSION_SINGLE_FILE);
+        }
         snap_data.set_meta(self.meta_file.meta.clone());
 
         SNAPSHOT_BUILD_TIME_HISTOGRAM.observe(duration_to_sec(t.elapsed()) as f64);
@@ -935,29 +1171,39 @@ impl Snapshot for Snap {
     }
 
     fn apply(&mut self, options: ApplyOptions) -> Result<()> {
+        fail_point!("snapshot_apply", |_| {
+            Err(box_err!("failed to apply snapshot by fail point"))
+        });
         box_try!(self.validate());
 
-        for cf_file in &mut self.cf_files {
-            if cf_file.size == 0 {
-                // Skip empty cf file.
-                continue;
-            }
-
+        for cf in SNAPSHOT_CFS {
             try!(check_abort(&options.abort));
-            let cf_handle = box_try!(rocksdb::get_cf_handle(&options.db, cf_file.cf));
-            if plain_file_used(cf_file.cf) {
-                let mut file = box_try!(File::open(&cf_file.path));
-                try!(apply_plain_cf_file(&mut file, &options, cf_handle));
-            } else {
+            let cf_handle = box_try!(rocksdb::get_cf_handle(&options.db, cf));
+            let mut ssts = vec![];
+            for cf_file in self.cf_files.iter().filter(|f| f.cf == *cf) {
+                if cf_file.size == 0 {
+                    // Skip empty cf file.
+                    continue;
+                }
+
+                if plain_file_used(cf) {
+                    let mut file = box_try!(File::open(&cf_file.path));
+                    try!(apply_plain_cf_file(&mut file, &options, cf_handle));
+                } else {
+                    ssts.push(cf_file.path.as_path().to_str().unwrap());
+                }
+            }
+            if !ssts.is_empty() {
+                try!(check_abort(&options.abort));
                 let ingest_opt = IngestExternalFileOptions::new();
                 // TODO: move SST file instead of copy
                 // after changing logic in raft, ask for resending snapshot if applying fail.
                 // ingest_opt.move_files(true);
-                let path = cf_file.path.as_path().to_str().unwrap();
+                // The files of a cf cover different key ranges, so they are ingested together.
                 box_try!(
                     options
                         .db
-                        .ingest_external_file_cf(cf_handle, &ingest_opt, &[path])
+                        .ingest_external_file_cf(cf_handle, &ingest_opt, &ssts)
                 );
             }
         }
@@ -982,6 +1228,9 @@ impl Read for Snap {
                     self.cf_index += 1;
                 }
                 Ok(n) => {
+                    if let Some(ref limiter) = self.limiter {
+                        limiter.request(n as u64);
+                    }
                     return Ok(n);
                 }
                 e => return e,
@@ -996,6 +1245,9 @@ impl Write for Snap {
         if buf.is_empty() {
             return Ok(0);
         }
+        if let Some(ref limiter) = self.limiter {
+            limiter.request(buf.len() as u64);
+        }
 
         let mut next_buf = buf;
         while self.cf_index < self.cf_files.len() {
@@ -1094,10 +1346,51 @@ pub struct SnapManager {
     // directory to store snapfile.
     core: Arc<RwLock<SnapManagerCore>>,
     ch: Option<SendCh<Msg>>,
+    send_limiter: Option<SnapLimiter>,
+    recv_limiter: Option<SnapLimiter>,
+    max_send_snap_count: usize,
+    max_recv_snap_count: usize,
+    max_file_size: u64,
 }
 
-impl SnapManager {
-    pub fn new<T: Into<String>>(path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
+/// `SnapManagerBuilder` builds a `SnapManager` with IO and concurrency limits,
+/// 0 means no limit.
+#[derive(Clone, Default)]
+pub struct SnapManagerBuilder {
+    max_send_bytes_per_sec: u64,
+    max_recv_bytes_per_sec: u64,
+    max_send_snap_count: usize,
+    max_recv_snap_count: usize,
+    max_file_size: u64,
+}
+
+impl SnapManagerBuilder {
+    pub fn max_send_bytes_per_sec(mut self, bytes: u64) -> SnapManagerBuilder {
+        self.max_send_bytes_per_sec = bytes;
+        self
+    }
+
+    pub fn max_recv_bytes_per_sec(mut self, bytes: u64) -> SnapManagerBuilder {
+        self.max_recv_bytes_per_sec = bytes;
+        self
+    }
+
+    pub fn max_send_snap_count(mut self, count: usize) -> SnapManagerBuilder {
+        self.max_send_snap_count = count;
+        self
+    }
+
+    pub fn max_recv_snap_count(mut self, count: usize) -> SnapManagerBuilder {
+        self.max_recv_snap_count = count;
+        self
+    }
+
+    pub fn max_file_size(mut self, bytes: u64) -> SnapManagerBuilder {
+        self.max_file_size = bytes;
+        self
+    }
+
+    pub fn build<T: Into<String>>(self, path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
         SnapManager {
             core: Arc::new(RwLock::new(SnapManagerCore {
                 base: path.into(),
@@ -1105,6 +1398,40 @@ impl SnapManager {
                 snap_size: Arc::new(RwLock::new(0)),
             })),
             ch: ch,
+            send_limiter: SnapLimiter::new(self.max_send_bytes_per_sec, "send"),
+            recv_limiter: SnapLimiter::new(self.max_recv_bytes_per_sec, "recv"),
+            max_send_snap_count: self.max_send_snap_count,
+            max_recv_snap_count: self.max_recv_snap_count,
+            max_file_size: self.max_file_size,
+        }
+    }
+}
+
+impl SnapManager {
+    pub fn new<T: Into<String>>(path: T, ch: Option<SendCh<Msg>>) -> SnapManager {
+        SnapManagerBuilder::default().build(path, ch)
+    }
+
+    /// The maximum number of snapshots that can be sent at the same time, 0 means no limit.
+    #[inline]
+    pub fn max_send_snap_count(&self) -> usize {
+        self.max_send_snap_count
+    }
+
+    /// The maximum number of snapshots that can be received at the same time,
+    /// 0 means no limit.
+    #[inline]
+    pub fn max_recv_snap_count(&self) -> usize {
+        self.max_recv_snap_count
+    }
+
+    /// Consumes `bytes` of the receiving rate limit, returns the duration the
+    /// receiver should wait before writing them. The receiver waits on its own
+    /// so a throttled snapshot doesn't block the others.
+    pub fn recv_throttle(&self, bytes: u64) -> time::Duration {
+        match self.recv_limiter {
+            Some(ref limiter) => limiter.consume(bytes),
+            None => time::Duration::from_millis(0),
         }
     }
 
@@ -1169,6 +1496,7 @@ impl SnapManager {
                     |s| {
                         s.split('_')
                             .skip(1)
+                            .take(3)
                             .filter_map(|s| s.parse().ok())
                             .collect()
                     },
@@ -1204,24 +1532,26 @@ impl SnapManager {
             let core = self.core.rl();
             (core.base.clone(), core.snap_size.clone())
         };
-        let f = try!(Snap::new_for_building(
+        let mut f = try!(Snap::new_for_building(
             dir,
             key,
             snap,
             snap_size,
             Box::new(self.clone())
         ));
+        f.max_file_size = self.max_file_size;
         Ok(Box::new(f))
     }
 
     pub fn get_snapshot_for_sending(&self, key: &SnapKey) -> RaftStoreResult<Box<Snapshot>> {
         let core = self.core.rl();
-        let s = try!(Snap::new_for_sending(
+        let mut s = try!(Snap::new_for_sending(
             &core.base,
             key,
             core.snap_size.clone(),
             Box::new(self.clone())
         ));
+        s.limiter = self.send_limiter.clone();
         Ok(Box::new(s))
     }
 
@@ -1233,6 +1563,13 @@ impl SnapManager {
         let core = self.core.rl();
         let mut snapshot_data = RaftSnapshotData::new();
         try!(snapshot_data.merge_from_bytes(data));
+        if snapshot_data.get_version() > SNAPSHOT_VERSION {
+            return Err(box_err!(
+                "unsupported snapshot version {}, the latest known is {}",
+                snapshot_data.get_version(),
+                SNAPSHOT_VERSION
+            ));
+        }
         let f = try!(Snap::new_for_receiving(
             &core.base,
             key,
@@ -1369,13 +1706,15 @@ mod test {
     use tempdir::TempDir;
     use protobuf::Message;
 
-    use super::{ApplyOptions, Snap, SnapEntry, SnapKey, SnapManager, Snapshot, SnapshotDeleter,
-                SnapshotStatistics, META_FILE_SUFFIX, SNAPSHOT_CFS, SNAP_GEN_PREFIX};
+    use super::{get_snapshot_file_size, strip_snapshot_files, ApplyOptions, Snap, SnapEntry,
+                SnapKey, SnapManager, Snapshot, SnapshotDeleter, SnapshotStatistics,
+                META_FILE_SUFFIX, SNAPSHOT_CFS, SNAP_GEN_PREFIX};
 
     use std::path::PathBuf;
+    use kvproto::eraftpb::Snapshot as RaftSnapshot;
     use kvproto::metapb::{Peer, Region};
     use kvproto::raft_serverpb::{RaftSnapshotData, SnapshotMeta};
-    use rocksdb::DB;
+    use rocksdb::{Writable, DB};
 
     use storage::{ALL_CFS, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
     use util::{rocksdb, HandyRwLock};
@@ -1541,7 +1880,7 @@ mod test {
         let region = get_test_region(region_id, 1, 1);
         let src_db_dir = TempDir::new("test-snap-file-db-src").unwrap();
         let db = get_db(&src_db_dir).unwrap();
-        let snapshot = DbSnapshot::new(db.clone());
+        let snapshot = DbSnapshot::new(db.clone()).into_sync();
 
         let src_dir = TempDir::new("test-snap-file-src").unwrap();
         let key = SnapKey::new(region_id, 1, 1);
@@ -1571,6 +1910,8 @@ mod test {
 
         // Ensure that this snapshot file does exist after being built.
         assert!(s1.exists());
+        // No cf is split, so old stores can receive it.
+        assert_eq!(snap_data.get_version(), SNAPSHOT_VERSION_SINGLE_FILE);
         let total_size = s1.total_size().unwrap();
         // Ensure the `size_track` is modified correctly.
         let size = *size_track.rl();
@@ -1643,6 +1984,98 @@ mod test {
         assert_eq_db(db, dst_db.as_ref());
     }
 
+    #[test]
+    fn test_snap_file_split() {
+        let region_id = 1;
+        let region = get_test_region(region_id, 1, 1);
+        let src_db_dir = TempDir::new("test-snap-file-split-db-src").unwrap();
+        let db = get_test_db(&src_db_dir).unwrap();
+        // Write enough data to split the sst cfs into several files.
+        for cf in &[CF_DEFAULT, CF_WRITE] {
+            let handle = rocksdb::get_cf_handle(&db, cf).unwrap();
+            for i in 0..100 {
+                let key = keys::data_key(format!("b{:03}", i).as_bytes());
+                db.put_cf(handle, &key, &[0; 100]).unwrap();
+            }
+        }
+        let snapshot = DbSnapshot::new(db.clone()).into_sync();
+
+        let src_dir = TempDir::new("test-snap-file-split-src").unwrap();
+        let key = SnapKey::new(region_id, 1, 1);
+        let size_track = Arc::new(RwLock::new(0));
+        let deleter = Box::new(DummyDeleter {});
+        let mut s1 = Snap::new_for_building(
+            src_dir.path(),
+            &key,
+            &snapshot,
+            size_track.clone(),
+            deleter.clone(),
+        ).unwrap();
+        s1.max_file_size = 1024;
+
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s1.build(
+            &snapshot,
+            &region,
+            &mut snap_data,
+            &mut stat,
+            deleter.clone(),
+        ).unwrap();
+        assert!(s1.exists());
+        assert_eq!(stat.kv_count, get_kv_count(&snapshot));
+        assert_eq!(*size_track.rl(), s1.total_size().unwrap());
+        assert_eq!(snap_data.get_version(), SNAPSHOT_VERSION);
+
+        {
+            let files = snap_data.get_meta().get_cf_files();
+            let count = |cf: &str| files.iter().filter(|f| f.get_cf() == cf).count();
+            // Plain cf file is never split.
+            assert_eq!(count(CF_LOCK), 1);
+            assert!(count(CF_DEFAULT) > 1);
+            assert!(count(CF_WRITE) > 1);
+        }
+
+        // Ensure the split files could be sent, received and applied.
+        let mut s2 =
+            Snap::new_for_sending(src_dir.path(), &key, size_track.clone(), deleter.clone())
+                .unwrap();
+        assert!(s2.exists());
+
+        let dst_dir = TempDir::new("test-snap-file-split-dst").unwrap();
+        let mut s3 = Snap::new_for_receiving(
+            dst_dir.path(),
+            &key,
+            snap_data.take_meta(),
+            size_track.clone(),
+            deleter.clone(),
+        ).unwrap();
+        assert!(!s3.exists());
+        io::copy(&mut s2, &mut s3).unwrap();
+        s3.save().unwrap();
+        assert!(s3.exists());
+
+        let mut s4 =
+            Snap::new_for_applying(dst_dir.path(), &key, size_track.clone(), deleter).unwrap();
+        assert!(s4.exists());
+
+        let dst_db_dir = TempDir::new("test-snap-file-split-db-dst").unwrap();
+        let dst_db_path = dst_db_dir.path().to_str().unwrap();
+        let dst_db = Arc::new(rocksdb::new_engine(dst_db_path, ALL_CFS).unwrap());
+        let options = ApplyOptions {
+            db: dst_db.clone(),
+            region: region.clone(),
+            abort: Arc::new(AtomicUsize::new(JOB_STATUS_RUNNING)),
+            write_batch_size: TEST_WRITE_BATCH_SIZE,
+        };
+        s4.apply(options).unwrap();
+
+        let dst_snapshot = DbSnapshot::new(dst_db.clone());
+        assert_eq!(get_kv_count(&dst_snapshot), stat.kv_count);
+        assert_eq_db(db, dst_db.as_ref());
+    }
+
     #[test]
     fn test_empty_snap_validation() {
         test_snap_validation(get_test_empty_db);
@@ -1658,7 +2091,7 @@ mod test {
         let region = get_test_region(region_id, 1, 1);
         let db_dir = TempDir::new("test-snap-validation-db").unwrap();
         let db = get_db(&db_dir).unwrap();
-        let snapshot = DbSnapshot::new(db.clone());
+        let snapshot = DbSnapshot::new(db.clone()).into_sync();
 
         let dir = TempDir::new("test-snap-validation").unwrap();
         let key = SnapKey::new(region_id, 1, 1);
@@ -1831,7 +2264,7 @@ mod test {
         let region = get_test_region(region_id, 1, 1);
         let db_dir = TempDir::new("test-snap-corruption-db").unwrap();
         let db = get_test_db(&db_dir).unwrap();
-        let snapshot = DbSnapshot::new(db);
+        let snapshot = DbSnapshot::new(db).into_sync();
 
         let dir = TempDir::new("test-snap-corruption").unwrap();
         let key = SnapKey::new(region_id, 1, 1);
@@ -1932,7 +2365,7 @@ mod test {
         let region = get_test_region(region_id, 1, 1);
         let db_dir = TempDir::new("test-snapshot-corruption-meta-db").unwrap();
         let db = get_test_db(&db_dir).unwrap();
-        let snapshot = DbSnapshot::new(db);
+        let snapshot = DbSnapshot::new(db).into_sync();
 
         let dir = TempDir::new("test-snap-corruption-meta").unwrap();
         let key = SnapKey::new(region_id, 1, 1);
@@ -2037,7 +2470,7 @@ mod test {
         assert_eq!(mgr.get_total_snap_size(), 0);
 
         let db_dir = TempDir::new("test-snap-mgr-delete-temp-files-v2-db").unwrap();
-        let snapshot = DbSnapshot::new(get_test_db(&db_dir).unwrap());
+        let snapshot = DbSnapshot::new(get_test_db(&db_dir).unwrap()).into_sync();
         let key1 = SnapKey::new(1, 1, 1);
         let size_track = Arc::new(RwLock::new(0));
         let deleter = Box::new(mgr.clone());
@@ -2124,7 +2557,7 @@ mod test {
 
         let src_db_dir = TempDir::new("test-snap-deletion-on-registry-src-db").unwrap();
         let db = get_test_db(&src_db_dir).unwrap();
-        let snapshot = DbSnapshot::new(db);
+        let snapshot = DbSnapshot::new(db).into_sync();
 
         let key = SnapKey::new(1, 1, 1);
         let region = get_test_region(1, 1, 1);
@@ -2179,4 +2612,50 @@ mod test {
         dst_mgr.delete_snapshot(&key, s4.as_ref(), false);
         assert!(s5.exists());
     }
+
+    #[test]
+    fn test_strip_snapshot_files() {
+        let src_temp_dir = TempDir::new("test-strip-snapshot-files-src").unwrap();
+        let src_mgr = SnapManager::new(src_temp_dir.path().to_str().unwrap(), None);
+        src_mgr.init().unwrap();
+        let db_dir = TempDir::new("test-strip-snapshot-files-db").unwrap();
+        let snapshot = DbSnapshot::new(get_test_db(&db_dir).unwrap()).into_sync();
+
+        let key = SnapKey::new(1, 1, 1);
+        let region = get_test_region(1, 1, 1);
+        let mut s1 = src_mgr.get_snapshot_for_building(&key, &snapshot).unwrap();
+        let mut snap_data = RaftSnapshotData::new();
+        snap_data.set_region(region.clone());
+        let mut stat = SnapshotStatistics::new();
+        s1.build(
+            &snapshot,
+            &region,
+            &mut snap_data,
+            &mut stat,
+            Box::new(src_mgr.clone()),
+        ).unwrap();
+        let mut snap = RaftSnapshot::new();
+        snap.mut_metadata().set_index(1);
+        snap.mut_metadata().set_term(1);
+        snap.set_data(snap_data.write_to_bytes().unwrap());
+        assert!(get_snapshot_file_size(&snap).unwrap() > 0);
+
+        strip_snapshot_files(&mut snap).unwrap();
+        assert_eq!(get_snapshot_file_size(&snap).unwrap(), 0);
+        assert_eq!(SnapKey::from_snap(&snap).unwrap(), key);
+
+        // The stripped snapshot is complete without receiving any data.
+        let dst_temp_dir = TempDir::new("test-strip-snapshot-files-dst").unwrap();
+        let dst_mgr = SnapManager::new(dst_temp_dir.path().to_str().unwrap(), None);
+        dst_mgr.init().unwrap();
+        let mut s2 = dst_mgr
+            .get_snapshot_for_receiving(&key, snap.get_data())
+            .unwrap();
+        assert!(!s2.exists());
+        s2.save().unwrap();
+        let s3 = dst_mgr.get_snapshot_for_applying(&key).unwrap();
+        assert!(s3.exists());
+        assert_eq!(s3.total_size().unwrap(), 0);
+        assert!(src_mgr.get_snapshot_for_sending(&key).unwrap().exists());
+    }
 }
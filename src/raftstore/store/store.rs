@@ -11,7 +11,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::mem;
 use std::sync::mpsc::{self, Receiver as StdReceiver, TryRecvError};
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -29,7 +30,7 @@ use time::{self, Timespec};
 
 use kvproto::raft_serverpb::{PeerState, RaftMessage, RaftSnapshotData, RaftTruncatedState,
                              RegionLocalState};
-use kvproto::eraftpb::{ConfChangeType, MessageType};
+use kvproto::eraftpb::{ConfChange, ConfChangeType, Entry, MessageType};
 use kvproto::pdpb::StoreStats;
 use util::escape;
 use util::time::{duration_to_sec, SlowTimer};
@@ -43,41 +44,49 @@ use kvproto::metapb;
 use util::worker::{FutureWorker, Scheduler, Worker};
 use util::transport::SendCh;
 use util::{rocksdb, RingQueue};
+use util::rocksdb::engine_metrics;
 use util::collections::{HashMap, HashSet};
-use storage::{CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
-use raftstore::coprocessor::CoprocessorHost;
+use storage::{ReadStats, CF_DEFAULT, CF_LOCK, CF_RAFT, CF_WRITE};
+use raftstore::coprocessor::{CoprocessorHost, RegionChangeEvent};
 use raftstore::coprocessor::split_observer::SplitObserver;
 use super::worker::{ApplyRunner, ApplyTask, ApplyTaskRes, CompactRunner, CompactTask,
                     ConsistencyCheckRunner, ConsistencyCheckTask, PdRunner, PdTask,
-                    RaftlogGcRunner, RaftlogGcTask, RegionRunner, RegionTask, SplitCheckRunner,
-                    SplitCheckTask};
+                    RaftlogFetchRunner, RaftlogFetchTask, RaftlogGcRunner, RaftlogGcTask,
+                    RegionRunner, RegionTask, SplitCheckRunner, SplitCheckTask};
 use super::worker::apply::{ChangePeer, ExecResult};
 use super::{util, Msg, SnapManager, SnapshotDeleter, SnapshotStatusMsg, Tick};
 use super::keys::{self, data_end_key, data_key, enc_end_key, enc_start_key};
 use super::engine::{Iterable, Peekable, Snapshot as EngineSnapshot};
 use super::config::Config;
+use super::unsafe_recovery::{self, RegionRecovery};
+use super::batch_split::BatchSplitRequest;
 use super::peer::{self, ConsistencyState, Peer, ReadyContext, StaleState};
 use super::peer_storage::{self, ApplySnapResult, CacheQueryStats};
-use super::msg::{BatchCallback, Callback};
+use super::msg::{BatchCallback, Callback, DrainCallback, RecoveryCallback, SplitCallback};
 use super::cmd_resp::{bind_term, new_error};
 use super::transport::Transport;
 use super::metrics::*;
 use super::local_metrics::RaftMetrics;
+use super::flow_control::{self, WriteFlowControl};
+use super::consistency::{self, ConsistencyCheckPolicy};
+use super::raft_engine::{RaftEngine, RaftLogBatch};
 use prometheus::local::LocalHistogram;
 
 type Key = Vec<u8>;
 
 const MIO_TICK_RATIO: u64 = 10;
 const PENDING_VOTES_CAP: usize = 20;
+// How many raft base ticks a split waits for the store to catch up with its epoch.
+const MAX_SPLIT_REGION_RETRY: usize = 10;
 
 #[derive(Clone)]
 pub struct Engines {
     pub kv_engine: Arc<DB>,
-    pub raft_engine: Arc<DB>,
+    pub raft_engine: Arc<RaftEngine>,
 }
 
 impl Engines {
-    pub fn new(kv_engine: Arc<DB>, raft_engine: Arc<DB>) -> Engines {
+    pub fn new(kv_engine: Arc<DB>, raft_engine: Arc<RaftEngine>) -> Engines {
         Engines {
             kv_engine: kv_engine,
             raft_engine: raft_engine,
@@ -94,6 +103,8 @@ pub struct StoreChannel {
 pub struct StoreStat {
     pub region_bytes_written: LocalHistogram,
     pub region_keys_written: LocalHistogram,
+    pub region_bytes_read: LocalHistogram,
+    pub region_keys_read: LocalHistogram,
     pub lock_cf_bytes_written: u64,
     pub engine_total_bytes_written: u64,
     pub engine_total_keys_written: u64,
@@ -104,6 +115,8 @@ impl Default for StoreStat {
         StoreStat {
             region_bytes_written: REGION_WRITTEN_BYTES_HISTOGRAM.local(),
             region_keys_written: REGION_WRITTEN_KEYS_HISTOGRAM.local(),
+            region_bytes_read: REGION_READ_BYTES_HISTOGRAM.local(),
+            region_keys_read: REGION_READ_KEYS_HISTOGRAM.local(),
             lock_cf_bytes_written: 0,
             engine_total_bytes_written: 0,
             engine_total_keys_written: 0,
@@ -111,6 +124,97 @@ impl Default for StoreStat {
     }
 }
 
+// A manual split whose epoch is newer than the region, it waits for the
+// store to handle the split result that bumped the epoch.
+struct PendingSplit {
+    region_id: u64,
+    region_epoch: metapb::RegionEpoch,
+    split_keys: Vec<Vec<u8>>,
+    callback: SplitCallback,
+    retry: usize,
+}
+
+// Splits a region at the keys one by one when batch split is disabled. The
+// next key is always in the region which keeps the origin id, so the leader
+// of the region doesn't change between the splits.
+struct SequentialSplit {
+    ch: SendCh<Msg>,
+    region_id: u64,
+    // The keys which haven't been split at, the next one is at the end.
+    split_keys: Vec<Vec<u8>>,
+    right_derive: bool,
+    // The regions split out, the latest one is at the end.
+    regions: Vec<metapb::Region>,
+    callback: SplitCallback,
+}
+
+impl SequentialSplit {
+    fn new(
+        ch: SendCh<Msg>,
+        region_id: u64,
+        mut split_keys: Vec<Vec<u8>>,
+        right_derive: bool,
+        callback: SplitCallback,
+    ) -> SequentialSplit {
+        // If the right region derives the origin id, the left regions are
+        // split out from the smallest key.
+        if right_derive {
+            split_keys.reverse();
+        }
+        SequentialSplit {
+            ch: ch,
+            region_id: region_id,
+            split_keys: split_keys,
+            right_derive: right_derive,
+            regions: vec![],
+            callback: callback,
+        }
+    }
+
+    // Returns the next key and the callback of the split at it.
+    fn next(mut self) -> (Vec<u8>, SplitCallback) {
+        let key = self.split_keys.pop().unwrap();
+        (key, box move |res: Result<Vec<metapb::Region>>| self.on_split(res))
+    }
+
+    fn on_split(mut self, res: Result<Vec<metapb::Region>>) {
+        let mut regions = match res {
+            Ok(regions) => regions,
+            Err(e) => return self.callback.call_box((Err(e),)),
+        };
+        let derived = if self.right_derive {
+            regions.pop().unwrap()
+        } else {
+            regions.remove(0)
+        };
+        self.regions.extend(regions);
+        if self.split_keys.is_empty() {
+            let mut regions = self.regions;
+            if self.right_derive {
+                regions.push(derived);
+            } else {
+                regions.push(derived);
+                regions.reverse();
+            }
+            return self.callback.call_box((Ok(regions),));
+        }
+
+        let ch = self.ch.clone();
+        let region_id = self.region_id;
+        let epoch = derived.get_region_epoch().clone();
+        let (key, cb) = self.next();
+        if let Err(e) = ch.send(Msg::new_split_region(region_id, epoch, vec![key], cb)) {
+            error!("[region {}] failed to continue splitting: {:?}", region_id, e);
+        }
+    }
+}
+
+// The failed peers being removed from a region by an online unsafe recovery.
+struct UnsafeRecovery {
+    store_ids: Vec<u64>,
+    start: Instant,
+}
+
 pub struct StoreInfo {
     pub engine: Arc<DB>,
     pub capacity: u64,
@@ -119,7 +223,7 @@ pub struct StoreInfo {
 pub struct Store<T, C: 'static> {
     cfg: Rc<Config>,
     kv_engine: Arc<DB>,
-    raft_engine: Arc<DB>,
+    raft_engine: Arc<RaftEngine>,
     store: metapb::Store,
     sendch: SendCh<Msg>,
 
@@ -133,9 +237,14 @@ pub struct Store<T, C: 'static> {
     region_ranges: BTreeMap<Key, u64>,
     // the regions with pending snapshots between two mio ticks.
     pending_snapshot_regions: Vec<metapb::Region>,
+    pending_splits: Vec<PendingSplit>,
+    // region id -> the failed stores whose peers are being removed from the
+    // region by unsafe recovery.
+    unsafe_recoveries: HashMap<u64, UnsafeRecovery>,
     split_check_worker: Worker<SplitCheckTask>,
     region_worker: Worker<RegionTask>,
     raftlog_gc_worker: Worker<RaftlogGcTask>,
+    raftlog_fetch_worker: Worker<RaftlogFetchTask>,
     compact_worker: Worker<CompactTask>,
     pd_worker: FutureWorker<PdTask>,
     consistency_check_worker: Worker<ConsistencyCheckTask>,
@@ -156,10 +265,27 @@ pub struct Store<T, C: 'static> {
 
     start_time: Timespec,
     is_busy: bool,
+    write_flow_control: WriteFlowControl,
+    consistency_check_policy: ConsistencyCheckPolicy,
 
     pending_votes: RingQueue<RaftMessage>,
 
     store_stat: StoreStat,
+    // The read traffic of regions collected by the storage and the coprocessor.
+    read_stats: Arc<ReadStats>,
+
+    // A draining store transfers its leaders away and doesn't start elections.
+    draining: bool,
+    // The callbacks of all the drain requests, called once all leaders are drained.
+    drain_callbacks: Vec<DrainCallback>,
+
+    // Whether the stores are labeled as witness, the peers on witness stores
+    // never become leaders and store no data. The stores are resolved from pd
+    // by the pd worker, the ones not resolved yet are absent.
+    witness_stores: Arc<RwLock<HashMap<u64, bool>>>,
+    // The election priorities of the stores resolved from pd, the peers on
+    // the stores with higher priorities are preferred to be the leaders.
+    store_priorities: Arc<RwLock<HashMap<u64, u64>>>,
 }
 
 pub fn create_event_loop<T, C>(cfg: &Config) -> Result<EventLoop<Store<T, C>>>
@@ -198,6 +324,7 @@ impl<T, C> Store<T, C> {
         trans: T,
         pd_client: Arc<C>,
         mgr: SnapManager,
+        read_stats: Arc<ReadStats>,
     ) -> Result<Store<T, C>> {
         // TODO: we can get cluster meta regularly too later.
         try!(cfg.validate());
@@ -211,6 +338,17 @@ impl<T, C> Store<T, C> {
             .registry
             .register_observer(100, box SplitObserver);
 
+        let write_flow_control = WriteFlowControl::new(&cfg);
+        let consistency_check_policy = try!(ConsistencyCheckPolicy::new(&cfg));
+        let entry_cache_stats = CacheQueryStats::new(cfg.raft_entry_cache_limit.0);
+        let mut witness_stores = HashMap::default();
+        let witness = util::is_witness_store(&meta);
+        if witness {
+            info!("{} is a witness store", tag);
+        }
+        witness_stores.insert(meta.get_id(), witness);
+        let mut store_priorities = HashMap::default();
+        store_priorities.insert(meta.get_id(), util::get_election_priority(&meta));
         let mut s = Store {
             cfg: Rc::new(cfg),
             store: meta,
@@ -224,6 +362,7 @@ impl<T, C> Store<T, C> {
             split_check_worker: Worker::new("split check worker"),
             region_worker: Worker::new("snapshot worker"),
             raftlog_gc_worker: Worker::new("raft gc worker"),
+            raftlog_fetch_worker: Worker::new("raftlog fetch worker"),
             compact_worker: Worker::new("compact worker"),
             pd_worker: FutureWorker::new("pd worker"),
             consistency_check_worker: Worker::new("consistency check worker"),
@@ -231,17 +370,26 @@ impl<T, C> Store<T, C> {
             apply_res_receiver: None,
             region_ranges: BTreeMap::new(),
             pending_snapshot_regions: vec![],
+            pending_splits: vec![],
+            unsafe_recoveries: HashMap::default(),
             trans: trans,
             pd_client: pd_client,
             coprocessor_host: Arc::new(coprocessor_host),
             snap_mgr: mgr,
             raft_metrics: RaftMetrics::default(),
-            entry_cache_metries: Rc::new(RefCell::new(CacheQueryStats::default())),
+            entry_cache_metries: Rc::new(RefCell::new(entry_cache_stats)),
             pending_votes: RingQueue::with_capacity(PENDING_VOTES_CAP),
             tag: tag,
             start_time: time::get_time(),
             is_busy: false,
+            write_flow_control: write_flow_control,
+            consistency_check_policy: consistency_check_policy,
             store_stat: StoreStat::default(),
+            read_stats: read_stats,
+            draining: false,
+            drain_callbacks: vec![],
+            witness_stores: Arc::new(RwLock::new(witness_stores)),
+            store_priorities: Arc::new(RwLock::new(store_priorities)),
         };
         try!(s.init());
         Ok(s)
@@ -261,7 +409,7 @@ impl<T, C> Store<T, C> {
 
         let t = Instant::now();
         let mut kv_wb = WriteBatch::new();
-        let mut raft_wb = WriteBatch::new();
+        let mut raft_wb = RaftLogBatch::new();
         try!(kv_engine.scan_cf(
             CF_RAFT,
             start_key,
@@ -292,7 +440,7 @@ impl<T, C> Store<T, C> {
                     // but not write raft_local_state to raft rocksdb in time.
                     try!(peer_storage::recover_from_applying_state(
                         &self.kv_engine,
-                        &self.raft_engine,
+                        self.raft_engine.as_ref(),
                         region_id
                     ));
                 }
@@ -322,7 +470,7 @@ impl<T, C> Store<T, C> {
         }
 
         if !raft_wb.is_empty() {
-            self.raft_engine.write(raft_wb).unwrap();
+            self.raft_engine.write(raft_wb, false).unwrap();
         }
 
         info!(
@@ -343,11 +491,10 @@ impl<T, C> Store<T, C> {
     fn clear_stale_meta(
         &mut self,
         kv_wb: &mut WriteBatch,
-        raft_wb: &mut WriteBatch,
+        raft_wb: &mut RaftLogBatch,
         region: &metapb::Region,
     ) {
-        let raft_key = keys::raft_state_key(region.get_id());
-        let raft_state = match self.raft_engine.get_msg(&raft_key).unwrap() {
+        let raft_state = match self.raft_engine.get_raft_state(region.get_id()).unwrap() {
             // it has been cleaned up.
             None => return,
             Some(value) => value,
@@ -355,7 +502,6 @@ impl<T, C> Store<T, C> {
 
         peer_storage::clear_meta(
             &self.kv_engine,
-            &self.raft_engine,
             kv_wb,
             raft_wb,
             region.get_id(),
@@ -417,7 +563,7 @@ impl<T, C> Store<T, C> {
         self.kv_engine.clone()
     }
 
-    pub fn raft_engine(&self) -> Arc<DB> {
+    pub fn raft_engine(&self) -> Arc<RaftEngine> {
         self.raft_engine.clone()
     }
 
@@ -425,6 +571,28 @@ impl<T, C> Store<T, C> {
         self.store.get_id()
     }
 
+    /// Returns true if the peers on the store are witnesses.
+    pub fn is_witness(&self) -> bool {
+        util::is_witness_store(&self.store)
+    }
+
+    pub fn raftlog_fetch_scheduler(&self) -> Scheduler<RaftlogFetchTask> {
+        self.raftlog_fetch_worker.scheduler()
+    }
+
+    pub fn witness_stores(&self) -> Arc<RwLock<HashMap<u64, bool>>> {
+        self.witness_stores.clone()
+    }
+
+    /// Returns the election priority of the peers on the store.
+    pub fn election_priority(&self) -> u64 {
+        util::get_election_priority(&self.store)
+    }
+
+    pub fn store_priorities(&self) -> Arc<RwLock<HashMap<u64, u64>>> {
+        self.store_priorities.clone()
+    }
+
     pub fn get_peers(&self) -> &HashMap<u64, Peer> {
         &self.region_peers
     }
@@ -432,7 +600,9 @@ impl<T, C> Store<T, C> {
     pub fn config(&self) -> Rc<Config> {
         self.cfg.clone()
     }
+}
 
+impl<T: Transport, C: PdClient> Store<T, C> {
     fn poll_snapshot_status(&mut self) {
         if self.sent_snapshot_count == 0 {
             return;
@@ -468,6 +638,9 @@ impl<T, C> Store<T, C> {
     fn report_snapshot_status(&mut self, region_id: u64, to_peer_id: u64, status: SnapshotStatus) {
         self.sent_snapshot_count -= 1;
         if let Some(peer) = self.region_peers.get_mut(&region_id) {
+            if peer.report_delegated_snap_status(to_peer_id, status, &self.trans) {
+                return;
+            }
             let to_peer = match peer.get_peer_from_cache(to_peer_id) {
                 Some(peer) => peer,
                 None => {
@@ -490,9 +663,7 @@ impl<T, C> Store<T, C> {
             peer.raft_group.report_snapshot(to_peer_id, status)
         }
     }
-}
 
-impl<T: Transport, C: PdClient> Store<T, C> {
     pub fn run(&mut self, event_loop: &mut EventLoop<Self>) -> Result<()> {
         try!(self.snap_mgr.init());
 
@@ -506,6 +677,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         self.register_compact_lock_cf_tick(event_loop);
         self.register_consistency_check_tick(event_loop);
         self.register_report_region_flow_tick(event_loop);
+        self.register_compaction_pressure_tick(event_loop);
 
         let split_check_runner = SplitCheckRunner::new(
             self.kv_engine.clone(),
@@ -520,19 +692,33 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             self.raft_engine.clone(),
             self.snap_mgr.clone(),
             self.cfg.snap_apply_batch_size.0 as usize,
+            self.is_witness(),
+            self.cfg.concurrent_apply_snap_limit,
         );
         box_try!(self.region_worker.start(runner));
 
         let raftlog_gc_runner = RaftlogGcRunner::new(None);
         box_try!(self.raftlog_gc_worker.start(raftlog_gc_runner));
 
+        let raftlog_fetch_runner = RaftlogFetchRunner::new(self.sendch.clone());
+        box_try!(self.raftlog_fetch_worker.start(raftlog_fetch_runner));
+
         let compact_runner = CompactRunner::new(self.kv_engine.clone());
         box_try!(self.compact_worker.start(compact_runner));
 
-        let pd_runner = PdRunner::new(self.store_id(), self.pd_client.clone(), self.sendch.clone());
+        let pd_runner = PdRunner::new(
+            self.store_id(),
+            self.pd_client.clone(),
+            self.sendch.clone(),
+            self.witness_stores.clone(),
+            self.store_priorities.clone(),
+        );
         box_try!(self.pd_worker.start(pd_runner));
 
-        let consistency_check_runner = ConsistencyCheckRunner::new(self.sendch.clone());
+        let consistency_check_runner = ConsistencyCheckRunner::new(
+            self.sendch.clone(),
+            self.cfg.consistency_check_bytes_per_sec.0,
+        );
         box_try!(
             self.consistency_check_worker
                 .start(consistency_check_runner)
@@ -560,6 +746,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         handles.push(self.split_check_worker.stop());
         handles.push(self.region_worker.stop());
         handles.push(self.raftlog_gc_worker.stop());
+        handles.push(self.raftlog_fetch_worker.stop());
         handles.push(self.compact_worker.stop());
         handles.push(self.pd_worker.stop());
         handles.push(self.consistency_check_worker.stop());
@@ -590,10 +777,14 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
     fn on_raft_base_tick(&mut self, event_loop: &mut EventLoop<Self>) {
         let timer = self.raft_metrics.process_tick.start_coarse_timer();
+        let fetch_scheduler = self.raftlog_fetch_worker.scheduler();
+        let mut transfer_leader_requests = vec![];
         for peer in &mut self.region_peers.values_mut() {
             if peer.pending_remove {
                 continue;
             }
+            peer.on_snap_gen_delegation_tick(&self.trans);
+            peer.prefetch_entries(&fetch_scheduler);
             // When having pending snapshot, if election timeout is met, it can't pass
             // the pending conf change check because first index has been updated to
             // a value that is larger than last index.
@@ -603,10 +794,20 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 continue;
             }
 
-            if peer.raft_group.tick() {
+            // Followers of a draining store and quarantined followers don't
+            // tick, so they never campaign and become leaders again.
+            if (self.draining || peer.consistency_state.quarantined) && !peer.is_leader() {
+                // Skip.
+            } else if peer.raft_group.tick() {
                 peer.mark_to_be_checked(&mut self.pending_raft_groups);
             }
 
+            if peer.consistency_state.quarantined && peer.is_leader() {
+                if let Some(target) = peer.pick_transfer_leader_target() {
+                    transfer_leader_requests.push(new_transfer_leader_request(peer, target));
+                }
+            }
+
             // If this peer detects the leader is missing for a long long time,
             // it should consider itself as a stale peer which is removed from
             // the original cluster.
@@ -640,7 +841,24 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             }
         }
 
+        for req in transfer_leader_requests {
+            self.propose_raft_command(req, box |_| {});
+        }
+
+        for split in mem::replace(&mut self.pending_splits, vec![]) {
+            self.prepare_split_region(
+                split.region_id,
+                split.region_epoch,
+                split.split_keys,
+                split.callback,
+                split.retry,
+            );
+        }
+
+        self.propose_unsafe_recoveries();
+
         self.poll_snapshot_status();
+        self.update_write_flow_control();
 
         timer.observe_duration();
 
@@ -650,6 +868,60 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         self.register_raft_base_tick(event_loop);
     }
 
+    fn update_write_flow_control(&mut self) {
+        let apply_backlog: u64 = self.region_peers
+            .values()
+            .map(|p| {
+                let committed = p.raft_group.raft.raft_log.committed;
+                committed.saturating_sub(p.get_store().applied_index())
+            })
+            .sum();
+        APPLY_BACKLOG_GAUGE.set(apply_backlog as f64);
+
+        if self.write_flow_control.update(apply_backlog) {
+            match self.write_flow_control.stall() {
+                Some(stall) => warn!("{} delay writes because {}", self.tag, stall),
+                None => info!("{} accept writes again", self.tag),
+            }
+        }
+
+        let (ready, timed_out) = self.write_flow_control.take_delayed();
+        for w in timed_out {
+            self.reject_stalled_write(w.cb);
+        }
+        for w in ready {
+            WRITE_STALL_DELAY_HISTOGRAM.observe(duration_to_sec(w.delay()));
+            self.propose_raft_command_with_context(w.req, w.context, w.cb);
+        }
+        WRITE_STALL_DELAYED_GAUGE.set(self.write_flow_control.delayed_count() as f64);
+    }
+
+    fn reject_stalled_write(&self, cb: Callback) {
+        let stall = self.write_flow_control.stall().unwrap();
+        WRITE_STALL_REJECTED_COUNTER
+            .with_label_values(&[stall.tag()])
+            .inc();
+        cb.call_box((new_error(Error::ServerIsBusy(stall.to_string())),));
+    }
+
+    fn register_compaction_pressure_tick(&self, event_loop: &mut EventLoop<Self>) {
+        if let Err(e) = register_timer(
+            event_loop,
+            Tick::CompactionPressure,
+            self.cfg.compaction_pressure_check_interval.as_millis(),
+        ) {
+            error!("{} register compaction pressure tick err: {:?}", self.tag, e);
+        };
+    }
+
+    fn on_compaction_pressure_tick(&mut self, event_loop: &mut EventLoop<Self>) {
+        let (pending_compaction_bytes, level0_files) =
+            engine_metrics::get_engine_compaction_pressure(&self.kv_engine);
+        self.write_flow_control
+            .update_compaction_pressure(pending_compaction_bytes, level0_files);
+        self.register_compaction_pressure_tick(event_loop);
+    }
+
     fn poll_apply(&mut self) {
         loop {
             match self.apply_res_receiver.as_ref().unwrap().try_recv() {
@@ -780,6 +1052,20 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             return Ok(());
         }
 
+        if peer::is_delegate_snap_msg(msg.get_message()) {
+            self.on_delegate_snap_msg(msg);
+            return Ok(());
+        }
+
+        if self.draining && msg.get_message().get_msg_type() == MessageType::MsgTimeoutNow {
+            info!(
+                "{} is draining, reject leader transfer of region {}",
+                self.tag,
+                region_id
+            );
+            return Ok(());
+        }
+
         if !try!(self.maybe_create_peer(region_id, &msg)) {
             return Ok(());
         }
@@ -789,6 +1075,12 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         }
 
         let peer = self.region_peers.get_mut(&region_id).unwrap();
+        if peer.consistency_state.quarantined &&
+            msg.get_message().get_msg_type() == MessageType::MsgTimeoutNow
+        {
+            info!("{} is quarantined, reject leader transfer", peer.tag);
+            return Ok(());
+        }
         peer.insert_peer_cache(msg.take_from_peer());
         try!(peer.step(msg.take_message()));
 
@@ -798,6 +1090,25 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         Ok(())
     }
 
+    fn on_delegate_snap_msg(&mut self, mut msg: RaftMessage) {
+        let region_id = msg.get_region_id();
+        let peer = match self.region_peers.get_mut(&region_id) {
+            Some(peer) => peer,
+            None => {
+                debug!(
+                    "[region {}] peer not found, ignore snapshot delegation message",
+                    region_id
+                );
+                return;
+            }
+        };
+        let from_peer = msg.take_from_peer();
+        peer.insert_peer_cache(from_peer.clone());
+        if peer.on_delegate_snap_msg(from_peer, msg.take_message(), &self.trans) {
+            self.sent_snapshot_count += 1;
+        }
+    }
+
     // return false means the message is invalid, and can be ignored.
     fn is_raft_msg_valid(&self, msg: &RaftMessage) -> bool {
         let region_id = msg.get_region_id();
@@ -1091,10 +1402,8 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
         if !raft_wb.is_empty() {
             // RaftLocalState, Raft Log Entry
-            let mut write_opts = WriteOptions::new();
-            write_opts.set_sync(self.cfg.sync_log);
             self.raft_engine
-                .write_opt(raft_wb, &write_opts)
+                .write(raft_wb, self.cfg.sync_log)
                 .unwrap_or_else(|e| {
                     panic!("{} failed to save raft append result: {:?}", self.tag, e);
                 });
@@ -1166,9 +1475,71 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
         self.trans.flush();
 
+        self.evict_entry_cache();
+
         slow_log!(t, "{} on {} regions raft ready", self.tag, pending_count);
     }
 
+    fn evict_entry_cache(&mut self) {
+        let limit = self.cfg.raft_entry_cache_limit.0;
+        if self.entry_cache_metries.borrow().mem_size <= limit {
+            return;
+        }
+
+        // Drop the entries that no peer needs any more first.
+        let mut evicted = 0;
+        for peer in self.region_peers.values_mut() {
+            let old_size = peer.get_store().cache_mem_size();
+            let idx = peer.cache_evictable_index();
+            peer.mut_store().compact_to(idx);
+            evicted += old_size - peer.get_store().cache_mem_size();
+        }
+        RAFT_ENTRY_CACHE_EVICT_COUNTER
+            .with_label_values(&["replicated"])
+            .inc_by(evicted as f64)
+            .unwrap();
+        if self.entry_cache_metries.borrow().mem_size <= limit {
+            return;
+        }
+
+        // Then drop the largest caches, the entries will be read from the raft
+        // engine again if they are needed.
+        let mut sizes: Vec<_> = self.region_peers
+            .iter()
+            .map(|(id, p)| (p.get_store().cache_mem_size(), *id))
+            .filter(|&(size, _)| size > 0)
+            .collect();
+        sizes.sort_by(|a, b| b.cmp(a));
+        let mut evicted = 0;
+        for (_, region_id) in sizes {
+            if self.entry_cache_metries.borrow().mem_size <= limit {
+                break;
+            }
+            let peer = self.region_peers.get_mut(&region_id).unwrap();
+            evicted += peer.mut_store().evict_cache();
+        }
+        RAFT_ENTRY_CACHE_EVICT_COUNTER
+            .with_label_values(&["full"])
+            .inc_by(evicted as f64)
+            .unwrap();
+        warn!(
+            "{} entry cache exceeds the limit {}, evicted {} bytes",
+            self.tag,
+            limit,
+            evicted
+        );
+    }
+
+    fn on_raft_log_fetched(&mut self, region_id: u64, seq: u64, entries: Vec<Entry>) {
+        if let Some(peer) = self.region_peers.get_mut(&region_id) {
+            peer.mut_store().on_entries_prefetched(seq, entries);
+            // Let raft retry the peers waiting for the entries even if the fetch
+            // failed or is stale, otherwise they would wait forever.
+            peer.raft_group.on_entries_fetched();
+            peer.mark_to_be_checked(&mut self.pending_raft_groups);
+        }
+    }
+
     fn destroy_peer(&mut self, region_id: u64, peer: metapb::Peer) {
         info!("[region {}] destroy peer {:?}", region_id, peer);
         // TODO: should we check None here?
@@ -1176,6 +1547,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         let mut p = self.region_peers.remove(&region_id).unwrap();
         // We can't destroy a peer which is applying snapshot.
         assert!(!p.is_applying_snapshot());
+        self.unsafe_recoveries.remove(&region_id);
 
         let is_initialized = p.is_initialized();
         if let Err(e) = p.destroy() {
@@ -1191,6 +1563,8 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 e
             );
         }
+        self.coprocessor_host
+            .on_region_changed(p.region(), RegionChangeEvent::Destroy);
 
         if is_initialized &&
             self.region_ranges
@@ -1217,6 +1591,8 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 return;
             }
             p.mut_store().region = cp.region;
+            self.coprocessor_host
+                .on_region_changed(p.region(), RegionChangeEvent::ConfChange);
             if p.is_leader() {
                 // Notify pd immediately.
                 info!(
@@ -1289,96 +1665,95 @@ impl<T: Transport, C: PdClient> Store<T, C> {
     fn on_ready_split_region(
         &mut self,
         region_id: u64,
-        left: metapb::Region,
-        right: metapb::Region,
-        right_derive: bool,
+        derived: metapb::Region,
+        regions: Vec<metapb::Region>,
     ) {
-        let (origin_region, new_region) = if right_derive {
-            (right.clone(), left.clone())
-        } else {
-            (left.clone(), right.clone())
-        };
-
         self.region_peers
             .get_mut(&region_id)
             .unwrap()
             .mut_store()
-            .region = origin_region.clone();
-        let new_region_id = new_region.get_id();
-        if let Some(peer) = self.region_peers.get(&new_region_id) {
-            // If the store received a raft msg with the new region raft group
-            // before splitting, it will creates a uninitialized peer.
-            // We can remove this uninitialized peer directly.
-            if peer.get_store().is_initialized() {
-                panic!("duplicated region {} for split region", new_region_id);
-            }
-        }
+            .region = derived;
 
-        let mut campaigned = false;
-        let peer;
-        match Peer::create(self, &new_region) {
-            Err(e) => {
-                // peer information is already written into db, can't recover.
-                // there is probably a bug.
-                panic!("create new split region {:?} err {:?}", new_region, e);
+        let last_region_id = regions.last().unwrap().get_id();
+        let mut not_campaigned = Vec::with_capacity(regions.len());
+        for new_region in &regions {
+            let new_region_id = new_region.get_id();
+            if new_region_id == region_id {
+                continue;
             }
-            Ok(mut new_peer) => {
-                for peer in new_region.get_peers() {
-                    // Add this peer to cache.
-                    new_peer.insert_peer_cache(peer.clone());
-                }
-                peer = new_peer.peer.clone();
-                if let Some(origin_peer) = self.region_peers.get(&region_id) {
-                    // New peer derive write flow from parent region,
-                    // this will be used by balance write flow.
-                    new_peer.peer_stat = origin_peer.peer_stat.clone();
-
-                    campaigned =
-                        new_peer.maybe_campaign(origin_peer, &mut self.pending_raft_groups);
-
-                    if origin_peer.is_leader() {
-                        // Notify pd immediately to let it update the region meta.
-                        if right_derive {
-                            self.report_split_pd(&new_peer, origin_peer);
-                        } else {
-                            self.report_split_pd(origin_peer, &new_peer);
-                        }
-                    }
+            if let Some(peer) = self.region_peers.get(&new_region_id) {
+                // If the store received a raft msg with the new region raft group
+                // before splitting, it will creates a uninitialized peer.
+                // We can remove this uninitialized peer directly.
+                if peer.get_store().is_initialized() {
+                    panic!("duplicated region {} for split region", new_region_id);
                 }
+            }
 
-                // Insert new regions and validation
-                info!("insert new regions left: {:?}, right:{:?}", left, right);
-                if self.region_ranges
-                    .insert(enc_end_key(&left), left.get_id())
-                    .is_some()
-                {
-                    panic!("region should not exist, {:?}", left);
-                }
-                if self.region_ranges
-                    .insert(enc_end_key(&right), right.get_id())
-                    .is_none()
-                {
-                    panic!("region should exist, {:?}", right);
+            let mut new_peer = match Peer::create(self, new_region) {
+                Ok(new_peer) => new_peer,
+                Err(e) => {
+                    // peer information is already written into db, can't recover.
+                    // there is probably a bug.
+                    panic!("create new split region {:?} err {:?}", new_region, e);
                 }
+            };
+            for peer in new_region.get_peers() {
+                // Add this peer to cache.
+                new_peer.insert_peer_cache(peer.clone());
+            }
 
-                // To prevent from big region, the right region need run split
-                // check again after split.
-                if right_derive {
-                    self.region_peers
-                        .get_mut(&region_id)
-                        .unwrap()
-                        .size_diff_hint = self.cfg.region_split_check_diff.0;
-                } else {
-                    new_peer.size_diff_hint = self.cfg.region_split_check_diff.0;
+            {
+                let origin_peer = &self.region_peers[&region_id];
+                // New peer derive write flow from parent region,
+                // this will be used by balance write flow.
+                new_peer.peer_stat = origin_peer.peer_stat.clone();
+                if !new_peer.maybe_campaign(origin_peer, &mut self.pending_raft_groups) {
+                    not_campaigned.push(new_peer.peer.clone());
                 }
-                self.apply_worker
-                    .schedule(ApplyTask::register(&new_peer))
-                    .unwrap();
-                self.region_peers.insert(new_region_id, new_peer);
             }
+
+            // To prevent from big region, the right region need run split
+            // check again after split.
+            if new_region_id == last_region_id {
+                new_peer.size_diff_hint = self.cfg.region_split_check_diff.0;
+            }
+            self.apply_worker
+                .schedule(ApplyTask::register(&new_peer))
+                .unwrap();
+            self.region_peers.insert(new_region_id, new_peer);
+        }
+        if region_id == last_region_id {
+            self.region_peers
+                .get_mut(&region_id)
+                .unwrap()
+                .size_diff_hint = self.cfg.region_split_check_diff.0;
+        }
+
+        // Insert new regions and validation, only the last region shares
+        // the end key with the region before split.
+        info!("[region {}] insert new regions {:?}", region_id, regions);
+        let last = regions.len() - 1;
+        for (i, region) in regions.iter().enumerate() {
+            let exists = self.region_ranges
+                .insert(enc_end_key(region), region.get_id())
+                .is_some();
+            if exists && i != last {
+                panic!("region should not exist, {:?}", region);
+            }
+            if !exists && i == last {
+                panic!("region should exist, {:?}", region);
+            }
+            self.coprocessor_host
+                .on_region_changed(region, RegionChangeEvent::Split);
+        }
+
+        if self.region_peers[&region_id].is_leader() {
+            // Notify pd immediately to let it update the region meta.
+            self.report_split_pd(&regions);
         }
 
-        if !campaigned {
+        for peer in not_campaigned {
             if let Some(msg) = self.pending_votes
                 .swap_remove_front(|m| m.get_to_peer() == &peer)
             {
@@ -1387,27 +1762,22 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         }
     }
 
-    fn report_split_pd(&self, left: &Peer, right: &Peer) {
-        let left_region = left.region();
-        let right_region = right.region();
-
-        info!(
-            "notify pd with split left {:?}, right {:?}",
-            left_region,
-            right_region
-        );
-        right.heartbeat_pd(&self.pd_worker);
-        left.heartbeat_pd(&self.pd_worker);
+    fn report_split_pd(&self, regions: &[metapb::Region]) {
+        info!("notify pd with split {:?}", regions);
+        for region in regions {
+            self.region_peers[&region.get_id()].heartbeat_pd(&self.pd_worker);
+        }
 
         // Now pd only uses ReportSplit for history operation show,
         // so we send it independently here.
-        let task = PdTask::ReportSplit {
-            left: left_region.clone(),
-            right: right_region.clone(),
-        };
-
-        if let Err(e) = self.pd_worker.schedule(task) {
-            error!("{} failed to notify pd: {}", self.tag, e);
+        for pair in regions.windows(2) {
+            let task = PdTask::ReportSplit {
+                left: pair[0].clone(),
+                right: pair[1].clone(),
+            };
+            if let Err(e) = self.pd_worker.schedule(task) {
+                error!("{} failed to notify pd: {}", self.tag, e);
+            }
         }
     }
 
@@ -1444,6 +1814,16 @@ impl<T: Transport, C: PdClient> Store<T, C> {
 
         self.region_ranges
             .insert(enc_end_key(&region), region.get_id());
+
+        // The data is replaced by the snapshot, and the persisted quarantine
+        // state is cleared with the old meta.
+        if let Some(peer) = self.region_peers.get_mut(&region_id) {
+            if peer.consistency_state.quarantined {
+                info!("{} is no longer quarantined after applying snapshot", peer.tag);
+                peer.consistency_state.quarantined = false;
+            }
+            peer.consistency_state.mismatched_leader = raft::INVALID_ID;
+        }
     }
 
     fn on_ready_result(&mut self, region_id: u64, exec_results: Vec<ExecResult>) {
@@ -1454,11 +1834,9 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 ExecResult::CompactLog { first_index, state } => {
                     self.on_ready_compact_log(region_id, first_index, state)
                 }
-                ExecResult::SplitRegion {
-                    left,
-                    right,
-                    right_derive,
-                } => self.on_ready_split_region(region_id, left, right, right_derive),
+                ExecResult::SplitRegion { derived, regions } => {
+                    self.on_ready_split_region(region_id, derived, regions)
+                }
                 ExecResult::ComputeHash {
                     region,
                     index,
@@ -1485,10 +1863,42 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             return Ok(Some(resp));
         }
         try!(self.validate_region(msg));
+
+        let region_id = msg.get_header().get_region_id();
+        if !msg.has_admin_request() && self.region_peers[&region_id].consistency_state.quarantined {
+            // Let the client retry, the leadership will be transferred away soon.
+            return Err(Error::NotLeader(region_id, None));
+        }
         Ok(None)
     }
 
     fn propose_raft_command(&mut self, msg: RaftCmdRequest, cb: Callback) {
+        self.propose_raft_command_with_context(msg, vec![], cb)
+    }
+
+    fn propose_batch_split(
+        &mut self,
+        msg: RaftCmdRequest,
+        splits: BatchSplitRequest,
+        cb: Callback,
+    ) {
+        // The split request of the command is the only split, so it's proposed
+        // as a plain split, which the stores without batch split can apply.
+        if splits.requests.len() == 1 {
+            return self.propose_raft_command(msg, cb);
+        }
+        match splits.encode() {
+            Ok(context) => self.propose_raft_command_with_context(msg, context, cb),
+            Err(e) => cb.call_box((new_error(e),)),
+        }
+    }
+
+    fn propose_raft_command_with_context(
+        &mut self,
+        msg: RaftCmdRequest,
+        context: Vec<u8>,
+        cb: Callback,
+    ) {
         match self.pre_propose_raft_command(&msg) {
             Ok(Some(resp)) => {
                 cb.call_box((resp,));
@@ -1501,6 +1911,13 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             _ => (),
         }
 
+        if self.write_flow_control.stall().is_some() && flow_control::is_write_request(&msg) {
+            if let Some(cb) = self.write_flow_control.delay(msg, context, cb) {
+                self.reject_stalled_write(cb);
+            }
+            return;
+        }
+
         // Note:
         // The peer that is being checked is a leader. It might step down to be a follower later. It
         // doesn't matter whether the peer is a leader or not. If it's not a leader, the proposing
@@ -1511,7 +1928,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         let peer = self.region_peers.get_mut(&region_id).unwrap();
         let term = peer.term();
         bind_term(&mut resp, term);
-        if peer.propose(cb, msg, resp, &mut self.raft_metrics.propose) {
+        if peer.propose(cb, msg, context, resp, &mut self.raft_metrics.propose) {
             peer.mark_to_be_checked(&mut self.pending_raft_groups);
         }
 
@@ -1634,10 +2051,63 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         };
     }
 
+    fn register_drain_leaders_tick(&self, event_loop: &mut EventLoop<Self>) {
+        if let Err(e) = register_timer(
+            event_loop,
+            Tick::DrainLeaders,
+            self.cfg.raft_base_tick_interval.as_millis() * self.cfg.raft_heartbeat_ticks as u64,
+        ) {
+            error!("{} register drain leaders tick err: {:?}", self.tag, e);
+        };
+    }
+
+    fn on_drain_leaders(&mut self, event_loop: &mut EventLoop<Self>, callback: DrainCallback) {
+        info!("{} start to drain leaders", self.tag);
+        let already_draining = !self.drain_callbacks.is_empty();
+        self.draining = true;
+        self.drain_callbacks.push(callback);
+        if !already_draining {
+            self.on_drain_leaders_tick(event_loop);
+        }
+    }
+
+    fn on_drain_leaders_tick(&mut self, event_loop: &mut EventLoop<Self>) {
+        let mut remaining = 0;
+        let mut requests = vec![];
+        for peer in self.region_peers.values() {
+            // A leader without other peers can't be drained.
+            if !peer.is_leader() || peer.pending_remove || peer.region().get_peers().len() < 2 {
+                continue;
+            }
+            remaining += 1;
+            if let Some(target) = peer.pick_transfer_leader_target() {
+                requests.push(new_transfer_leader_request(peer, target));
+            }
+        }
+
+        if remaining == 0 {
+            info!("{} all leaders are drained", self.tag);
+            for cb in self.drain_callbacks.drain(..) {
+                cb.call_box(());
+            }
+            return;
+        }
+
+        info!("{} {} leaders left to drain", self.tag, remaining);
+        for req in requests {
+            self.propose_raft_command(req, box |_| {});
+        }
+        self.register_drain_leaders_tick(event_loop);
+    }
+
     fn on_report_region_flow(&mut self, event_loop: &mut EventLoop<Self>) {
-        for peer in self.region_peers.values_mut() {
+        let read_flows = self.read_stats.take_flows();
+        for (region_id, peer) in &mut self.region_peers {
             peer.peer_stat.last_written_bytes = peer.peer_stat.written_bytes;
             peer.peer_stat.last_written_keys = peer.peer_stat.written_keys;
+            let read_flow = read_flows.get(region_id).cloned().unwrap_or_default();
+            peer.peer_stat.last_read_bytes = read_flow.read_bytes as u64;
+            peer.peer_stat.last_read_keys = read_flow.read_keys as u64;
             if !peer.is_leader() {
                 peer.peer_stat.written_bytes = 0;
                 peer.peer_stat.written_keys = 0;
@@ -1650,11 +2120,19 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             self.store_stat
                 .region_keys_written
                 .observe(peer.peer_stat.written_keys as f64);
+            self.store_stat
+                .region_bytes_read
+                .observe(read_flow.read_bytes as f64);
+            self.store_stat
+                .region_keys_read
+                .observe(read_flow.read_keys as f64);
             peer.peer_stat.written_bytes = 0;
             peer.peer_stat.written_keys = 0;
         }
         self.store_stat.region_bytes_written.flush();
         self.store_stat.region_keys_written.flush();
+        self.store_stat.region_bytes_read.flush();
+        self.store_stat.region_keys_read.flush();
 
         self.register_report_region_flow_tick(event_loop);
     }
@@ -1869,9 +2347,145 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         }
     }
 
+    fn validate_split_region(
+        &self,
+        region_id: u64,
+        epoch: &metapb::RegionEpoch,
+        split_keys: &[Vec<u8>],
+    ) -> Result<()> {
+        if split_keys.is_empty() {
+            return Err(box_err!("[region {}] missing split key", region_id));
+        }
+
+        let peer = match self.region_peers.get(&region_id) {
+            None => return Err(Error::RegionNotFound(region_id)),
+            Some(peer) => peer,
+        };
+        if !peer.is_leader() {
+            return Err(Error::NotLeader(
+                region_id,
+                peer.get_peer_from_cache(peer.leader_id()),
+            ));
+        }
+
+        let region = peer.region();
+        let latest_epoch = region.get_region_epoch();
+        if latest_epoch.get_version() != epoch.get_version() {
+            return Err(Error::StaleEpoch(
+                format!(
+                    "{} epoch changed {:?} != {:?}, retry later",
+                    peer.tag,
+                    latest_epoch,
+                    epoch
+                ),
+                vec![region.to_owned()],
+            ));
+        }
+
+        for (i, key) in split_keys.iter().enumerate() {
+            if key.is_empty() || key.as_slice() <= region.get_start_key() {
+                return Err(box_err!(
+                    "{} invalid split key {}",
+                    peer.tag,
+                    escape(key)
+                ));
+            }
+            try!(util::check_key_in_region(key, region));
+            if i > 0 && split_keys[i - 1] >= *key {
+                return Err(box_err!(
+                    "{} split keys should be sorted and unique, {} >= {}",
+                    peer.tag,
+                    escape(&split_keys[i - 1]),
+                    escape(key)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_prepare_split_region(
+        &mut self,
+        region_id: u64,
+        region_epoch: metapb::RegionEpoch,
+        split_keys: Vec<Vec<u8>>,
+        cb: SplitCallback,
+    ) {
+        self.prepare_split_region(region_id, region_epoch, split_keys, cb, 0);
+    }
+
+    fn prepare_split_region(
+        &mut self,
+        region_id: u64,
+        region_epoch: metapb::RegionEpoch,
+        split_keys: Vec<Vec<u8>>,
+        cb: SplitCallback,
+        retry: usize,
+    ) {
+        if let Some(peer) = self.region_peers.get(&region_id) {
+            let region = peer.region();
+            if peer.is_leader() &&
+                region_epoch.get_version() > region.get_region_epoch().get_version()
+            {
+                if retry >= MAX_SPLIT_REGION_RETRY {
+                    let msg = format!(
+                        "{} epoch {:?} is still newer than {:?} after {} retries",
+                        peer.tag,
+                        region_epoch,
+                        region.get_region_epoch(),
+                        retry
+                    );
+                    cb.call_box((Err(Error::StaleEpoch(msg, vec![region.clone()])),));
+                    return;
+                }
+                // A split with the epoch has been applied, but the store hasn't
+                // handled its result yet, try again on next base tick.
+                self.pending_splits.push(PendingSplit {
+                    region_id: region_id,
+                    region_epoch: region_epoch,
+                    split_keys: split_keys,
+                    callback: cb,
+                    retry: retry + 1,
+                });
+                return;
+            }
+        }
+
+        if let Err(e) = self.validate_split_region(region_id, &region_epoch, &split_keys) {
+            cb.call_box((Err(e),));
+            return;
+        }
+
+        if !self.cfg.use_batch_split && split_keys.len() > 1 {
+            let split = SequentialSplit::new(
+                self.sendch.clone(),
+                region_id,
+                split_keys,
+                self.cfg.right_derive_when_split,
+                cb,
+            );
+            let (key, cb) = split.next();
+            return self.prepare_split_region(region_id, region_epoch, vec![key], cb, retry);
+        }
+
+        let peer = &self.region_peers[&region_id];
+        info!("{} try to split at {} keys", peer.tag, split_keys.len());
+        let task = PdTask::AskBatchSplit {
+            region: peer.region().clone(),
+            split_keys: split_keys,
+            peer: peer.peer.clone(),
+            right_derive: self.cfg.right_derive_when_split,
+            callback: cb,
+        };
+        if let Err(e) = self.pd_worker.schedule(task) {
+            error!("{} failed to notify pd to split: {}", peer.tag, e);
+        }
+    }
+
     fn on_pd_heartbeat_tick(&mut self, event_loop: &mut EventLoop<Self>) {
         for peer in self.region_peers.values_mut() {
             peer.check_peers();
+            peer.update_election_priorities(&self.pd_worker);
         }
 
         let mut leader_count = 0;
@@ -1948,7 +2562,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         self.store_stat.engine_total_keys_written = engine_total_keys_written;
         stats.set_keys_written(delta);
 
-        stats.set_is_busy(self.is_busy);
+        stats.set_is_busy(self.is_busy || self.write_flow_control.stall().is_some());
         self.is_busy = false;
 
         let store_info = StoreInfo {
@@ -2096,17 +2710,151 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             peer.raft_group.report_unreachable(to_peer_id);
         }
     }
+
+    fn on_remove_failed_stores(
+        &mut self,
+        store_ids: Vec<u64>,
+        dry_run: bool,
+        callback: RecoveryCallback,
+    ) {
+        let res = self.remove_failed_stores(&store_ids, dry_run);
+        if let Err(ref e) = res {
+            error!(
+                "{} failed to remove failed stores {:?}: {:?}",
+                self.tag,
+                store_ids,
+                e
+            );
+        }
+        callback.call_box((res,));
+    }
+
+    // Removes the peers on the failed stores from the regions of this store. It's
+    // the online counterpart of `tikv-ctl unsafe-recover`.
+    fn remove_failed_stores(
+        &mut self,
+        store_ids: &[u64],
+        dry_run: bool,
+    ) -> Result<Vec<RegionRecovery>> {
+        let recoveries = {
+            let regions = self.region_peers
+                .values()
+                .filter(|p| !p.pending_remove && !p.is_applying_snapshot())
+                .map(|p| p.region());
+            try!(unsafe_recovery::plan(self.store_id(), regions, store_ids))
+        };
+        for r in &recoveries {
+            warn!("{} unsafe recovery, dry run {}: {}", self.tag, dry_run, r);
+        }
+        if dry_run {
+            return Ok(recoveries);
+        }
+
+        for r in &recoveries {
+            let region_id = r.region.get_id();
+            let p = self.region_peers.get_mut(&region_id).unwrap();
+            // Only the remaining peers count in the quorum from now on, so they can
+            // elect a leader. It's only changed in memory, the region is changed by
+            // the conf changes the leader proposes later, which also bring raft
+            // back to the persisted conf state if the store restarts before that.
+            for peer in r.removed_peers() {
+                let mut cc = ConfChange::new();
+                cc.set_change_type(ConfChangeType::RemoveNode);
+                cc.set_node_id(peer.get_id());
+                p.raft_group.apply_conf_change(&cc);
+            }
+            if !p.is_leader() {
+                if let Err(e) = p.raft_group.campaign() {
+                    error!("{} failed to campaign after unsafe recovery: {:?}", p.tag, e);
+                }
+            }
+            p.mark_to_be_checked(&mut self.pending_raft_groups);
+            let recovery = UnsafeRecovery {
+                store_ids: store_ids.to_vec(),
+                start: Instant::now(),
+            };
+            self.unsafe_recoveries.insert(region_id, recovery);
+        }
+        Ok(recoveries)
+    }
+
+    // Proposes the conf changes removing the failed peers one by one, as raft
+    // accepts only one pending conf change.
+    fn propose_unsafe_recoveries(&mut self) {
+        if self.unsafe_recoveries.is_empty() {
+            return;
+        }
+        let mut finished = vec![];
+        let mut requests = vec![];
+        let timeout = self.cfg.unsafe_recovery_timeout.0;
+        for (&region_id, recovery) in &self.unsafe_recoveries {
+            let p = match self.region_peers.get(&region_id) {
+                Some(p) => p,
+                None => {
+                    finished.push(region_id);
+                    continue;
+                }
+            };
+            if recovery.start.elapsed() >= timeout {
+                warn!(
+                    "{} unsafe recovery of stores {:?} timed out: {:?}",
+                    p.tag,
+                    recovery.store_ids,
+                    p.region()
+                );
+                finished.push(region_id);
+                continue;
+            }
+            let failed = p.region()
+                .get_peers()
+                .iter()
+                .find(|peer| recovery.store_ids.contains(&peer.get_store_id()));
+            match failed {
+                None => {
+                    info!("{} unsafe recovery finished: {:?}", p.tag, p.region());
+                    finished.push(region_id);
+                }
+                Some(peer) => if p.is_leader() && !p.raft_group.raft.pending_conf {
+                    requests.push(new_remove_peer_request(p, peer.clone()));
+                },
+            }
+        }
+        for region_id in finished {
+            self.unsafe_recoveries.remove(&region_id);
+        }
+        for req in requests {
+            let tag = self.tag.clone();
+            let cb = box move |resp: RaftCmdResponse| if resp.get_header().has_error() {
+                warn!(
+                    "{} failed to remove peer for unsafe recovery: {:?}",
+                    tag,
+                    resp.get_header().get_error()
+                );
+            };
+            self.propose_raft_command(req, cb);
+        }
+    }
 }
 
 // Consistency Check implementation.
 
-/// Verify and store the hash to state. return true means the hash has been stored successfully.
+#[derive(Debug, PartialEq)]
+enum HashVerification {
+    // The hash is stored to be verified later.
+    Stored,
+    Skipped,
+    Matched,
+    // The hash is different from the stored hash, which is returned.
+    Mismatched(Vec<u8>),
+}
+
+/// Verify the hash with the stored one at the same index, or store it to be verified later.
 fn verify_and_store_hash(
     region_id: u64,
     state: &mut ConsistencyState,
     expected_index: u64,
     expected_hash: Vec<u8>,
-) -> bool {
+) -> HashVerification {
     if expected_index < state.index {
         REGION_HASH_COUNTER_VEC
             .with_label_values(&["verify", "miss"])
@@ -2117,7 +2865,7 @@ fn verify_and_store_hash(
             state.index,
             expected_index
         );
-        return false;
+        return HashVerification::Skipped;
     }
 
     if state.index == expected_index {
@@ -2126,16 +2874,13 @@ fn verify_and_store_hash(
                 "[region {}] duplicated consistency check detected, skip.",
                 region_id
             );
-            return false;
+            return HashVerification::Skipped;
         }
         if state.hash != expected_hash {
-            panic!(
-                "[region {}] hash at {} not correct, want \"{}\", got \"{}\"!!!",
-                region_id,
-                state.index,
-                escape(&expected_hash),
-                escape(&state.hash)
-            );
+            REGION_HASH_COUNTER_VEC
+                .with_label_values(&["verify", "mismatched"])
+                .inc();
+            return HashVerification::Mismatched(mem::replace(&mut state.hash, vec![]));
         }
         info!(
             "[region {}] consistency check at {} pass.",
@@ -2146,7 +2891,7 @@ fn verify_and_store_hash(
             .with_label_values(&["verify", "matched"])
             .inc();
         state.hash = vec![];
-        return false;
+        return HashVerification::Matched;
     }
 
     if state.index != INVALID_INDEX && !state.hash.is_empty() {
@@ -2170,7 +2915,7 @@ fn verify_and_store_hash(
     );
     state.index = expected_index;
     state.hash = expected_hash;
-    true
+    HashVerification::Stored
 }
 
 impl<T: Transport, C: PdClient> Store<T, C> {
@@ -2191,12 +2936,19 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             self.register_consistency_check_tick(event_loop);
             return;
         }
-        let (mut candidate_id, mut candidate_check_time) = (0, Instant::now());
+        let now = Instant::now();
+        let (mut candidate_id, mut candidate_check_time) = (0, now);
         for (&region_id, peer) in &mut self.region_peers {
-            if !peer.is_leader() {
+            if !peer.is_leader() || peer.consistency_state.quarantined {
+                continue;
+            }
+            let last_check_time = peer.consistency_state.last_check_time;
+            if !self.consistency_check_policy
+                .is_due(peer.region(), last_check_time, now)
+            {
                 continue;
             }
-            if peer.consistency_state.last_check_time < candidate_check_time {
+            if last_check_time < candidate_check_time {
                 candidate_id = region_id;
                 candidate_check_time = peer.consistency_state.last_check_time;
             }
@@ -2239,7 +2991,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         expected_index: u64,
         expected_hash: Vec<u8>,
     ) {
-        let state = match self.region_peers.get_mut(&region_id) {
+        let res = match self.region_peers.get_mut(&region_id) {
             None => {
                 warn!(
                     "[region {}] receive stale hash at index {}",
@@ -2248,14 +3000,25 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 );
                 return;
             }
-            Some(p) => &mut p.consistency_state,
+            Some(p) => verify_and_store_hash(
+                region_id,
+                &mut p.consistency_state,
+                expected_index,
+                expected_hash.clone(),
+            ),
         };
 
-        verify_and_store_hash(region_id, state, expected_index, expected_hash);
+        match res {
+            HashVerification::Mismatched(hash) => {
+                self.on_inconsistent_hash(region_id, expected_index, &expected_hash, &hash)
+            }
+            HashVerification::Matched => self.on_consistent_hash(region_id),
+            _ => {}
+        }
     }
 
     fn on_hash_computed(&mut self, region_id: u64, index: u64, hash: Vec<u8>) {
-        let (state, peer) = match self.region_peers.get_mut(&region_id) {
+        let (res, msg) = match self.region_peers.get_mut(&region_id) {
             None => {
                 warn!(
                     "[region {}] receive stale hash at index {}",
@@ -2264,25 +3027,114 @@ impl<T: Transport, C: PdClient> Store<T, C> {
                 );
                 return;
             }
-            Some(p) => (&mut p.consistency_state, &p.peer),
+            Some(p) => {
+                let res =
+                    verify_and_store_hash(region_id, &mut p.consistency_state, index, hash.clone());
+                let msg = if res == HashVerification::Stored {
+                    Some(Msg::new_raft_cmd(
+                        new_verify_hash_request(region_id, p.peer.clone(), &p.consistency_state),
+                        Box::new(|_| {}),
+                    ))
+                } else {
+                    None
+                };
+                (res, msg)
+            }
         };
 
-        if !verify_and_store_hash(region_id, state, index, hash) {
-            return;
+        match res {
+            HashVerification::Mismatched(leader_hash) => {
+                self.on_inconsistent_hash(region_id, index, &leader_hash, &hash);
+                return;
+            }
+            HashVerification::Matched => self.on_consistent_hash(region_id),
+            _ => {}
+        }
+
+        if let Some(msg) = msg {
+            if let Err(e) = self.sendch.send(msg) {
+                error!(
+                    "[region {}] failed to schedule verify command for index {}: {:?}",
+                    region_id,
+                    index,
+                    e
+                );
+            }
+        }
+    }
+
+    fn on_consistent_hash(&mut self, region_id: u64) {
+        if let Some(peer) = self.region_peers.get_mut(&region_id) {
+            // The leader whose hash mismatched last time may be the
+            // inconsistent one.
+            peer.consistency_state.mismatched_leader = raft::INVALID_ID;
         }
+    }
 
-        let msg = Msg::new_raft_cmd(
-            new_verify_hash_request(region_id, peer.clone(), state),
-            Box::new(|_| {}),
+    /// Reports that the data of the region is inconsistent with its leader at
+    /// `index`. If it's enabled, the peer is quarantined when the hashes of
+    /// two different leaders mismatch the local one, which means the local
+    /// data is more likely to be inconsistent than the leaders'.
+    fn on_inconsistent_hash(
+        &mut self,
+        region_id: u64,
+        index: u64,
+        leader_hash: &[u8],
+        local_hash: &[u8],
+    ) {
+        let quarantine = self.cfg.consistency_check_quarantine;
+        let peer = match self.region_peers.get_mut(&region_id) {
+            Some(p) => p,
+            None => return,
+        };
+        let leader_id = peer.leader_id();
+        error!(
+            "{} data is inconsistent with leader {} at index {}, leader hash \"{}\", \
+             local hash \"{}\", region {:?}, applied index {}",
+            peer.tag,
+            leader_id,
+            index,
+            escape(leader_hash),
+            escape(local_hash),
+            peer.region(),
+            peer.get_store().applied_index()
         );
-        if let Err(e) = self.sendch.send(msg) {
-            error!(
-                "[region {}] failed to schedule verify command for index {}: {:?}",
-                region_id,
-                index,
-                e
+        let task = PdTask::ReportInconsistency {
+            region: peer.region().clone(),
+            peer: peer.peer.clone(),
+            index: index,
+        };
+        if let Err(e) = self.pd_worker.schedule(task) {
+            error!("{} failed to report inconsistency: {}", peer.tag, e);
+        }
+        if !quarantine || peer.consistency_state.quarantined || leader_id == raft::INVALID_ID {
+            return;
+        }
+        let mismatched_leader = peer.consistency_state.mismatched_leader;
+        if mismatched_leader == raft::INVALID_ID || mismatched_leader == leader_id {
+            info!(
+                "{} wait for another leader to confirm the inconsistency",
+                peer.tag
             );
+            peer.consistency_state.mismatched_leader = leader_id;
+            return;
+        }
+
+        warn!(
+            "{} is quarantined as the data is inconsistent with leader {} and {}, \
+             it won't serve requests until it's removed",
+            peer.tag,
+            mismatched_leader,
+            leader_id
+        );
+        // Persist it first, otherwise the peer may become leader after restart.
+        if let Err(e) = consistency::write_quarantine_state(&self.kv_engine, region_id, index) {
+            panic!("{} failed to persist quarantine state: {:?}", peer.tag, e);
         }
+        peer.consistency_state.quarantined = true;
+        REGION_HASH_COUNTER_VEC
+            .with_label_values(&["verify", "quarantined"])
+            .inc();
     }
 }
 
@@ -2308,6 +3160,35 @@ fn new_verify_hash_request(
     request
 }
 
+fn new_transfer_leader_request(peer: &Peer, target: metapb::Peer) -> RaftCmdRequest {
+    let mut request = new_admin_request(peer.region().get_id(), peer.peer.clone());
+    request
+        .mut_header()
+        .set_region_epoch(peer.region().get_region_epoch().clone());
+
+    let mut admin = AdminRequest::new();
+    admin.set_cmd_type(AdminCmdType::TransferLeader);
+    admin.mut_transfer_leader().set_peer(target);
+    request.set_admin_request(admin);
+    request
+}
+
+fn new_remove_peer_request(peer: &Peer, target: metapb::Peer) -> RaftCmdRequest {
+    let mut request = new_admin_request(peer.region().get_id(), peer.peer.clone());
+    request
+        .mut_header()
+        .set_region_epoch(peer.region().get_region_epoch().clone());
+
+    let mut admin = AdminRequest::new();
+    admin.set_cmd_type(AdminCmdType::ChangePeer);
+    admin
+        .mut_change_peer()
+        .set_change_type(ConfChangeType::RemoveNode);
+    admin.mut_change_peer().set_peer(target);
+    request.set_admin_request(admin);
+    request
+}
+
 fn new_compute_hash_request(region_id: u64, peer: metapb::Peer) -> RaftCmdRequest {
     let mut request = new_admin_request(region_id, peer);
 
@@ -2375,6 +3256,11 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
                     .observe(duration_to_sec(send_time.elapsed()) as f64);
                 self.propose_raft_command(request, callback)
             }
+            Msg::BatchSplit {
+                request,
+                splits,
+                callback,
+            } => self.propose_batch_split(request, splits, callback),
             // For now, it is only called by batch snapshot.
             Msg::BatchRaftSnapCmds {
                 send_time,
@@ -2399,6 +3285,15 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
                 info!("[region {}] split check complete.", region_id);
                 self.on_split_check_result(region_id, epoch, split_key);
             }
+            Msg::SplitRegion {
+                region_id,
+                region_epoch,
+                split_keys,
+                callback,
+            } => {
+                info!("[region {}] on split region.", region_id);
+                self.on_prepare_split_region(region_id, region_epoch, split_keys, callback);
+            }
             Msg::ReportUnreachable {
                 region_id,
                 to_peer_id,
@@ -2413,6 +3308,18 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
             } => {
                 self.on_hash_computed(region_id, index, hash);
             }
+            Msg::RemoveFailedStores {
+                store_ids,
+                dry_run,
+                callback,
+            } => self.on_remove_failed_stores(store_ids, dry_run, callback),
+            Msg::DrainLeaders { callback } => self.on_drain_leaders(event_loop, callback),
+            Msg::HealthCheck { callback } => callback.call_box(()),
+            Msg::RaftLogFetched {
+                region_id,
+                seq,
+                entries,
+            } => self.on_raft_log_fetched(region_id, seq, entries),
         }
     }
 
@@ -2429,6 +3336,8 @@ impl<T: Transport, C: PdClient> mio::Handler for Store<T, C> {
             Tick::CompactLockCf => self.on_compact_lock_cf(event_loop),
             Tick::ConsistencyCheck => self.on_consistency_check_tick(event_loop),
             Tick::ReportRegionFlow => self.on_report_region_flow(event_loop),
+            Tick::DrainLeaders => self.on_drain_leaders_tick(event_loop),
+            Tick::CompactionPressure => self.on_compaction_pressure_tick(event_loop),
         }
         slow_log!(t, "{} handle timeout {:?}", self.tag, timeout);
     }
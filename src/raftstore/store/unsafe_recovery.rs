@@ -0,0 +1,234 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unsafe recovery removes the peers on permanently failed stores from the
+//! regions of this store, so that the remaining peers can elect a leader
+//! again after the quorum is lost.
+//!
+//! It's unsafe because the logs that were only committed by the removed
+//! peers are lost, use it only when the failed stores can never come back.
+//!
+//! Offline, `tikv-ctl unsafe-recover` rewrites the region and raft states of
+//! the stopped store. Online, the remaining peers stop counting the failed
+//! ones in their quorum, elect a leader, and remove the failed peers through
+//! conf changes in the raft log.
+
+use std::fmt::{self, Display, Formatter};
+
+use protobuf;
+use rocksdb::{WriteBatch, DB};
+use rocksdb::rocksdb_options::WriteOptions;
+use kvproto::metapb::{Peer, Region};
+use kvproto::raft_serverpb::{PeerState, RegionLocalState, StoreIdent};
+
+use raftstore::Result;
+use storage::CF_RAFT;
+use super::engine::{Iterable, Peekable};
+use super::keys;
+use super::peer_storage::write_peer_state;
+use super::raft_engine::{RaftEngine, RaftLogBatch};
+
+/// The change unsafe recovery makes to a region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionRecovery {
+    /// The region before recovery.
+    pub origin: Region,
+    /// The region without the peers on the failed stores.
+    pub region: Region,
+}
+
+impl RegionRecovery {
+    pub fn removed_peers(&self) -> Vec<&Peer> {
+        self.origin
+            .get_peers()
+            .iter()
+            .filter(|p| !self.region.get_peers().contains(p))
+            .collect()
+    }
+}
+
+impl Display for RegionRecovery {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let ids = |peers: &[&Peer]| -> Vec<u64> { peers.iter().map(|p| p.get_id()).collect() };
+        let remaining: Vec<_> = self.region.get_peers().iter().collect();
+        write!(
+            f,
+            "region {} remove peers {:?}, remaining peers {:?}, conf_ver {} -> {}",
+            self.region.get_id(),
+            ids(&self.removed_peers()),
+            ids(&remaining),
+            self.origin.get_region_epoch().get_conf_ver(),
+            self.region.get_region_epoch().get_conf_ver()
+        )
+    }
+}
+
+/// Plans to remove the peers on `failed_stores` from `regions`, the regions
+/// which have no peer on the failed stores are skipped.
+pub fn plan<'a, I>(store_id: u64, regions: I, failed_stores: &[u64]) -> Result<Vec<RegionRecovery>>
+where
+    I: IntoIterator<Item = &'a Region>,
+{
+    if failed_stores.contains(&store_id) {
+        return Err(box_err!("can't remove the peers on store {} itself", store_id));
+    }
+
+    let mut recoveries = vec![];
+    for origin in regions {
+        let (removed, remaining): (Vec<_>, Vec<_>) = origin
+            .get_peers()
+            .iter()
+            .cloned()
+            .partition(|p| failed_stores.contains(&p.get_store_id()));
+        if removed.is_empty() {
+            continue;
+        }
+        if remaining.iter().all(|p| p.get_store_id() != store_id) {
+            return Err(box_err!(
+                "region {:?} has no peer on store {}",
+                origin,
+                store_id
+            ));
+        }
+
+        let mut region = origin.clone();
+        region.set_peers(protobuf::RepeatedField::from_vec(remaining));
+        // Bump the conf version as if the peers were removed one by one.
+        let conf_ver = origin.get_region_epoch().get_conf_ver() + removed.len() as u64;
+        region.mut_region_epoch().set_conf_ver(conf_ver);
+        recoveries.push(RegionRecovery {
+            origin: origin.clone(),
+            region: region,
+        });
+    }
+    Ok(recoveries)
+}
+
+/// Loads the store id and all the normal regions of an offline store.
+pub fn load_regions(kv_engine: &DB) -> Result<(u64, Vec<Region>)> {
+    let ident: StoreIdent = match try!(kv_engine.get_msg(&keys::store_ident_key())) {
+        Some(ident) => ident,
+        None => return Err(box_err!("store is not bootstrapped")),
+    };
+
+    let mut regions = vec![];
+    try!(kv_engine.scan_cf(
+        CF_RAFT,
+        keys::REGION_META_MIN_KEY,
+        keys::REGION_META_MAX_KEY,
+        false,
+        &mut |key, value| {
+            let (_, suffix) = try!(keys::decode_region_meta_key(key));
+            if suffix != keys::REGION_STATE_SUFFIX {
+                return Ok(true);
+            }
+            let mut local_state = try!(protobuf::parse_from_bytes::<RegionLocalState>(value));
+            if local_state.get_state() == PeerState::Normal {
+                regions.push(local_state.take_region());
+            }
+            Ok(true)
+        }
+    ));
+    Ok((ident.get_store_id(), regions))
+}
+
+/// Persists the recovered regions of a stopped store. The conf state of raft
+/// is built from the region peers when the peer is created. In the raft
+/// engine, the votes for the removed peers are cleared, so the remaining
+/// peers can vote for each other in the current term.
+pub fn write_recoveries(
+    kv_engine: &DB,
+    raft_engine: &RaftEngine,
+    recoveries: &[RegionRecovery],
+) -> Result<()> {
+    let mut batch = RaftLogBatch::new();
+    for r in recoveries {
+        let region_id = r.region.get_id();
+        let mut raft_state = match try!(raft_engine.get_raft_state(region_id)) {
+            Some(state) => state,
+            None => return Err(box_err!("raft state of region {} is missing", region_id)),
+        };
+        let vote = raft_state.get_hard_state().get_vote();
+        if r.removed_peers().iter().any(|p| p.get_id() == vote) {
+            raft_state.mut_hard_state().set_vote(0);
+            batch.put_state(region_id, &raft_state);
+        }
+    }
+    // The raft states are written first, the region states make them take effect.
+    try!(raft_engine.write(batch, true));
+
+    let wb = WriteBatch::new();
+    for r in recoveries {
+        try!(write_peer_state(
+            kv_engine,
+            &wb,
+            &r.region,
+            PeerState::Normal
+        ));
+    }
+    let mut write_opts = WriteOptions::new();
+    write_opts.set_sync(true);
+    try!(kv_engine.write_opt(wb, &write_opts));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb::Region;
+    use raftstore::store::util::new_peer;
+
+    use super::*;
+
+    fn new_region(id: u64, peers: &[(u64, u64)]) -> Region {
+        let mut region = Region::new();
+        region.set_id(id);
+        for &(store_id, peer_id) in peers {
+            region.mut_peers().push(new_peer(store_id, peer_id));
+        }
+        region.mut_region_epoch().set_conf_ver(3);
+        region.mut_region_epoch().set_version(2);
+        region
+    }
+
+    #[test]
+    fn test_plan_unsafe_recovery() {
+        let regions = vec![
+            new_region(1, &[(1, 1), (2, 2), (3, 3)]),
+            new_region(2, &[(1, 4), (4, 5), (5, 6)]),
+            new_region(3, &[(1, 7), (2, 8), (4, 9)]),
+        ];
+
+        let recoveries = plan(1, &regions, &[2, 3]).unwrap();
+        assert_eq!(recoveries.len(), 2);
+        assert_eq!(recoveries[0].origin, regions[0]);
+        assert_eq!(recoveries[0].region, {
+            let mut r = new_region(1, &[(1, 1)]);
+            r.mut_region_epoch().set_conf_ver(5);
+            r
+        });
+        assert_eq!(
+            recoveries[0].removed_peers(),
+            vec![&new_peer(2, 2), &new_peer(3, 3)]
+        );
+        assert_eq!(recoveries[1].region.get_id(), 3);
+        assert_eq!(recoveries[1].region.get_peers(), &[new_peer(1, 7), new_peer(4, 9)]);
+        assert_eq!(recoveries[1].region.get_region_epoch().get_conf_ver(), 4);
+        assert_eq!(recoveries[1].region.get_region_epoch().get_version(), 2);
+
+        assert!(plan(1, &regions, &[6]).unwrap().is_empty());
+        // the store itself can't be removed.
+        assert!(plan(1, &regions, &[1, 2]).is_err());
+        // the region must have a peer on the store.
+        assert!(plan(4, &regions[..1], &[2]).is_err());
+    }
+}
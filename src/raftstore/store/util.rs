@@ -89,6 +89,33 @@ pub fn conf_change_type_str(conf_type: &eraftpb::ConfChangeType) -> &'static str
     }
 }
 
+/// The store label that marks all the peers on the store as witnesses, for
+/// example `labels = { witness = "true" }` in the server config.
+pub const WITNESS_LABEL_KEY: &'static str = "witness";
+
+// check whether the peers on the store are witnesses.
+pub fn is_witness_store(store: &metapb::Store) -> bool {
+    store
+        .get_labels()
+        .iter()
+        .any(|l| l.get_key() == WITNESS_LABEL_KEY && l.get_value() == "true")
+}
+
+/// The store label that sets the election priority of the peers on the store,
+/// for example `labels = { election-priority = "1" }`. The peers with higher
+/// priorities are preferred to be the leaders.
+pub const ELECTION_PRIORITY_LABEL_KEY: &'static str = "election-priority";
+
+// get the election priority of the peers on the store, 0 if it's not labeled.
+pub fn get_election_priority(store: &metapb::Store) -> u64 {
+    store
+        .get_labels()
+        .iter()
+        .find(|l| l.get_key() == ELECTION_PRIORITY_LABEL_KEY)
+        .and_then(|l| l.get_value().parse().ok())
+        .unwrap_or(0)
+}
+
 // check whether epoch is staler than check_epoch.
 pub fn is_epoch_stale(epoch: &metapb::RegionEpoch, check_epoch: &metapb::RegionEpoch) -> bool {
     epoch.get_version() < check_epoch.get_version() ||
@@ -187,6 +214,37 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_witness_store() {
+        let mut store = metapb::Store::new();
+        assert!(!is_witness_store(&store));
+
+        let mut label = metapb::StoreLabel::new();
+        label.set_key(WITNESS_LABEL_KEY.to_owned());
+        label.set_value("false".to_owned());
+        store.mut_labels().push(label.clone());
+        assert!(!is_witness_store(&store));
+
+        label.set_value("true".to_owned());
+        store.mut_labels().push(label);
+        assert!(is_witness_store(&store));
+    }
+
+    #[test]
+    fn test_election_priority() {
+        let mut store = metapb::Store::new();
+        assert_eq!(get_election_priority(&store), 0);
+
+        let mut label = metapb::StoreLabel::new();
+        label.set_key(ELECTION_PRIORITY_LABEL_KEY.to_owned());
+        label.set_value("high".to_owned());
+        store.mut_labels().push(label);
+        assert_eq!(get_election_priority(&store), 0);
+
+        store.mut_labels()[0].set_value("2".to_owned());
+        assert_eq!(get_election_priority(&store), 2);
+    }
+
     #[test]
     fn test_first_vote_msg() {
         let tbl = vec![
@@ -35,6 +35,7 @@ use raftstore::{Error, Result};
 use raftstore::coprocessor::CoprocessorHost;
 use raftstore::store::{cmd_resp, keys, util, Store};
 use raftstore::store::msg::Callback;
+use raftstore::store::batch_split::BatchSplitRequest;
 use raftstore::store::engine::{Mutable, Peekable, Snapshot};
 use raftstore::store::peer_storage::{self, compact_raft_log, write_initial_apply_state,
                                      write_peer_state};
@@ -151,9 +152,10 @@ pub enum ExecResult {
         first_index: u64,
     },
     SplitRegion {
-        left: Region,
-        right: Region,
-        right_derive: bool,
+        // All the regions after split, ordered by start key.
+        regions: Vec<Region>,
+        // The region that keeps the origin region id.
+        derived: Region,
     },
     ComputeHash {
         region: Region,
@@ -283,6 +285,8 @@ pub struct ApplyDelegate {
     term: u64,
     pending_cmds: PendingCmdQueue,
     metrics: ApplyMetrics,
+    // A witness only applies the admin commands, the data is never written.
+    witness: bool,
 }
 
 impl ApplyDelegate {
@@ -311,6 +315,7 @@ impl ApplyDelegate {
             term: reg.term,
             pending_cmds: Default::default(),
             metrics: Default::default(),
+            witness: reg.witness,
         }
     }
 
@@ -405,12 +410,17 @@ impl ApplyDelegate {
 
         if !data.is_empty() {
             let cmd = parse_data_at(data, index, &self.tag);
+            // A context which can't be decoded fails the split on every
+            // store, see `exec_admin_cmd`.
+            let batch_split = BatchSplitRequest::decode(entry.get_context());
 
             if should_flush_to_engine(&cmd, apply_ctx.wb_ref().count()) {
                 self.write_apply_state(apply_ctx.wb_mut());
 
                 self.update_metrics(apply_ctx);
 
+                fail_point!("apply_before_write_engine");
+
                 // flush to engine
                 self.engine
                     .write(apply_ctx.wb.take().unwrap())
@@ -426,7 +436,7 @@ impl ApplyDelegate {
                 apply_ctx.mark_last_bytes_and_keys();
             }
 
-            return self.process_raft_cmd(apply_ctx, index, term, cmd);
+            return self.process_raft_cmd(apply_ctx, index, term, cmd, batch_split);
         }
 
         // when a peer become leader, it will send an empty entry.
@@ -455,7 +465,7 @@ impl ApplyDelegate {
         let conf_change: ConfChange = parse_data_at(entry.get_data(), index, &self.tag);
         let cmd = parse_data_at(conf_change.get_context(), index, &self.tag);
         Some(
-            self.process_raft_cmd(apply_ctx, index, term, cmd)
+            self.process_raft_cmd(apply_ctx, index, term, cmd, Ok(None))
                 .map_or_else(
                     || {
                         // If failed, tell raft that the config change was aborted.
@@ -507,6 +517,7 @@ impl ApplyDelegate {
         index: u64,
         term: u64,
         mut cmd: RaftCmdRequest,
+        batch_split: Result<Option<BatchSplitRequest>>,
     ) -> Option<ExecResult> {
         if index == 0 {
             panic!(
@@ -517,16 +528,23 @@ impl ApplyDelegate {
 
         let cmd_cb = self.find_cb(index, term, &cmd);
         apply_ctx.host.pre_apply(&self.region, &mut cmd);
-        let (mut resp, exec_result) = self.apply_raft_cmd(apply_ctx.wb_mut(), index, term, &cmd);
+        let (mut resp, exec_result) = self.apply_raft_cmd(
+            apply_ctx.wb_mut(),
+            index,
+            term,
+            &cmd,
+            &batch_split,
+        );
 
         debug!("{} applied command at log index {}", self.tag, index);
 
+        apply_ctx.host.post_apply(&self.region, index, &cmd, &resp);
+
         let cb = match cmd_cb {
             None => return exec_result,
             Some(cb) => cb,
         };
 
-        // TODO: Involve post apply hook.
         // TODO: if we have exec_result, maybe we should return this callback too. Outer
         // store will call it after handing exec result.
         cmd_resp::bind_term(&mut resp, self.term);
@@ -547,11 +565,14 @@ impl ApplyDelegate {
         index: u64,
         term: u64,
         req: &RaftCmdRequest,
+        batch_split: &Result<Option<BatchSplitRequest>>,
     ) -> (RaftCmdResponse, Option<ExecResult>) {
         // if pending remove, apply should be aborted already.
         assert!(!self.pending_remove);
 
-        let mut ctx = self.new_ctx(wb, index, term, req);
+        fail_point!("apply_raft_cmd");
+
+        let mut ctx = self.new_ctx(wb, index, term, req, batch_split);
         ctx.wb.set_save_point();
         let (resp, exec_result) = self.exec_raft_cmd(&mut ctx).unwrap_or_else(|e| {
             // clear dirty values.
@@ -577,16 +598,8 @@ impl ApplyDelegate {
                 ExecResult::VerifyHash { .. } |
                 ExecResult::CompactLog { .. } |
                 ExecResult::DeleteRange { .. } => {}
-                ExecResult::SplitRegion {
-                    ref left,
-                    ref right,
-                    right_derive,
-                } => {
-                    if right_derive {
-                        self.region = right.clone();
-                    } else {
-                        self.region = left.clone();
-                    }
+                ExecResult::SplitRegion { ref derived, .. } => {
+                    self.region = derived.clone();
                     self.metrics.size_diff_hint = 0;
                     self.metrics.delete_keys_hint = 0;
                 }
@@ -641,11 +654,13 @@ impl ApplyDelegate {
         index: u64,
         term: u64,
         req: &'a RaftCmdRequest,
+        batch_split: &'a Result<Option<BatchSplitRequest>>,
     ) -> ExecContext<'a> {
         ExecContext {
             apply_state: self.apply_state.clone(),
             wb: wb,
             req: req,
+            batch_split: batch_split,
             index: index,
             term: term,
         }
@@ -656,6 +671,8 @@ struct ExecContext<'a> {
     apply_state: RaftApplyState,
     wb: &'a mut WriteBatch,
     req: &'a RaftCmdRequest,
+    // All the splits of a batch split, which is proposed as a split.
+    batch_split: &'a Result<Option<BatchSplitRequest>>,
     index: u64,
     term: u64,
 }
@@ -689,9 +706,14 @@ impl ApplyDelegate {
             ctx.index
         );
 
+        let batch_split = ctx.batch_split;
         let (mut response, exec_result) = try!(match cmd_type {
             AdminCmdType::ChangePeer => self.exec_change_peer(ctx, request),
-            AdminCmdType::Split => self.exec_split(ctx, request),
+            AdminCmdType::Split => match *batch_split {
+                Ok(Some(ref splits)) => self.exec_batch_split(ctx, splits),
+                Ok(None) => self.exec_split(ctx, request),
+                Err(ref e) => Err(box_err!("invalid batch split context: {:?}", e)),
+            },
             AdminCmdType::CompactLog => self.exec_compact_log(ctx, request),
             AdminCmdType::TransferLeader => Err(box_err!("transfer leader won't exec")),
             AdminCmdType::ComputeHash => self.exec_compute_hash(ctx, request),
@@ -918,25 +940,69 @@ impl ApplyDelegate {
             .with_label_values(&["split", "success"])
             .inc();
 
-        if right_derive {
-            Ok((
-                resp,
-                Some(ExecResult::SplitRegion {
-                    left: new_region,
-                    right: region,
-                    right_derive: true,
-                }),
-            ))
+        let regions = if right_derive {
+            vec![new_region, region.clone()]
         } else {
-            Ok((
-                resp,
-                Some(ExecResult::SplitRegion {
-                    left: region,
-                    right: new_region,
-                    right_derive: false,
-                }),
-            ))
+            vec![region.clone(), new_region]
+        };
+        Ok((
+            resp,
+            Some(ExecResult::SplitRegion {
+                regions: regions,
+                derived: region,
+            }),
+        ))
+    }
+
+    fn exec_batch_split(
+        &mut self,
+        ctx: &ExecContext,
+        splits: &BatchSplitRequest,
+    ) -> Result<(AdminResponse, Option<ExecResult>)> {
+        PEER_ADMIN_CMD_COUNTER_VEC
+            .with_label_values(&["batch-split", "all"])
+            .inc();
+
+        let (regions, derived_index) = try!(splits.split(&self.region));
+        info!(
+            "{} batch split at {} keys, region: {:?}",
+            self.tag,
+            splits.requests.len(),
+            self.region
+        );
+
+        for (i, region) in regions.iter().enumerate() {
+            let mut res = write_peer_state(&self.engine, ctx.wb, region, PeerState::Normal);
+            if res.is_ok() && i != derived_index {
+                res = write_initial_apply_state(&self.engine, ctx.wb, region.get_id());
+            }
+            if let Err(e) = res {
+                panic!(
+                    "{} failed to save split region {:?}: {:?}",
+                    self.tag,
+                    region,
+                    e
+                );
+            }
         }
+
+        // The other regions are rebuilt from the request by the proposer.
+        let mut resp = AdminResponse::new();
+        resp.mut_split().set_left(regions[0].clone());
+        resp.mut_split().set_right(regions[regions.len() - 1].clone());
+
+        PEER_ADMIN_CMD_COUNTER_VEC
+            .with_label_values(&["batch-split", "success"])
+            .inc();
+
+        let derived = regions[derived_index].clone();
+        Ok((
+            resp,
+            Some(ExecResult::SplitRegion {
+                regions: regions,
+                derived: derived,
+            }),
+        ))
     }
 
     fn exec_compact_log(
@@ -1001,6 +1067,11 @@ impl ApplyDelegate {
         &mut self,
         ctx: &ExecContext,
     ) -> Result<(RaftCmdResponse, Option<ExecResult>)> {
+        if self.witness {
+            // Nobody waits for the responses, as a witness never becomes leader.
+            return Ok((RaftCmdResponse::new(), None));
+        }
+
         let requests = ctx.req.get_requests();
         let mut responses = Vec::with_capacity(requests.len());
 
@@ -1246,6 +1317,10 @@ impl ApplyDelegate {
         _: &AdminRequest,
     ) -> Result<(AdminResponse, Option<ExecResult>)> {
         let resp = AdminResponse::new();
+        if self.witness {
+            // There is no data to check on a witness.
+            return Ok((resp, None));
+        }
         Ok((
             resp,
             Some(ExecResult::ComputeHash {
@@ -1265,10 +1340,13 @@ impl ApplyDelegate {
         _: &ExecContext,
         req: &AdminRequest,
     ) -> Result<(AdminResponse, Option<ExecResult>)> {
+        let resp = AdminResponse::new();
+        if self.witness {
+            return Ok((resp, None));
+        }
         let verify_req = req.get_verify_hash();
         let index = verify_req.get_index();
         let hash = verify_req.get_hash().to_vec();
-        let resp = AdminResponse::new();
         Ok((
             resp,
             Some(ExecResult::VerifyHash {
@@ -1302,6 +1380,7 @@ pub struct Registration {
     pub apply_state: RaftApplyState,
     pub applied_index_term: u64,
     pub region: Region,
+    pub witness: bool,
 }
 
 impl Registration {
@@ -1312,6 +1391,7 @@ impl Registration {
             apply_state: peer.get_store().apply_state.clone(),
             applied_index_term: peer.get_store().applied_index_term,
             region: peer.region().clone(),
+            witness: peer.is_witness(),
         }
     }
 }
@@ -1866,6 +1946,20 @@ mod tests {
             self
         }
 
+        fn split(mut self, key: &[u8], new_region_id: u64) -> EntryBuilder {
+            let mut admin = AdminRequest::new();
+            admin.set_cmd_type(AdminCmdType::Split);
+            admin.mut_split().set_split_key(key.to_vec());
+            admin.mut_split().set_new_region_id(new_region_id);
+            self.req.set_admin_request(admin);
+            self
+        }
+
+        fn context(mut self, context: &[u8]) -> EntryBuilder {
+            self.entry.set_context(context.to_vec());
+            self
+        }
+
         fn build(mut self) -> Entry {
             self.entry.set_data(self.req.write_to_bytes().unwrap());
             self.entry
@@ -2072,4 +2166,62 @@ mod tests {
             WRITE_BATCH_MAX_KEYS as u64 + 8
         );
     }
+
+    #[test]
+    fn test_witness_skip_data() {
+        let (_path, db) = create_tmp_engine("test-witness");
+        let mut reg = Registration::default();
+        reg.region.mut_region_epoch().set_version(3);
+        reg.witness = true;
+        let mut delegate = ApplyDelegate::from_registration(db.clone(), reg);
+
+        let entries = vec![
+            EntryBuilder::new(1, 1)
+                .put(b"k1", b"v1")
+                .epoch(1, 3)
+                .build(),
+            EntryBuilder::new(2, 1)
+                .delete_range(b"k0", b"k2")
+                .epoch(1, 3)
+                .build(),
+        ];
+        let host = CoprocessorHost::new();
+        let mut apply_ctx = ApplyContext::new(&host);
+        let res = delegate.handle_raft_committed_entries(&mut apply_ctx, entries);
+        db.write(apply_ctx.wb.take().unwrap()).unwrap();
+        assert!(res.is_empty());
+        assert!(db.get(&keys::data_key(b"k1")).unwrap().is_none());
+        assert_eq!(delegate.applied_index_term, 1);
+        assert_eq!(delegate.apply_state.get_applied_index(), 2);
+    }
+
+    #[test]
+    fn test_invalid_batch_split_context() {
+        let (_path, db) = create_tmp_engine("test-batch-split");
+        let mut reg = Registration::default();
+        reg.region.set_id(1);
+        reg.region.mut_region_epoch().set_version(3);
+        let mut delegate = ApplyDelegate::from_registration(db.clone(), reg);
+        let (tx, rx) = mpsc::channel();
+
+        // The flag of a batch split followed by a truncated split request.
+        let entry = EntryBuilder::new(1, 1)
+            .split(b"k1", 2)
+            .epoch(1, 3)
+            .context(b"\x01\x00\x02")
+            .capture_resp(&mut delegate, tx)
+            .build();
+        let host = CoprocessorHost::new();
+        let mut apply_ctx = ApplyContext::new(&host);
+        let res = delegate.handle_raft_committed_entries(&mut apply_ctx, vec![entry]);
+        db.write(apply_ctx.wb.take().unwrap()).unwrap();
+        for (cb, resp) in apply_ctx.cbs.drain(..) {
+            cb(resp);
+        }
+        assert!(res.is_empty());
+        let resp = rx.try_recv().unwrap();
+        assert!(resp.get_header().has_error(), "{:?}", resp);
+        assert_eq!(delegate.region.get_region_epoch().get_version(), 3);
+        assert_eq!(delegate.apply_state.get_applied_index(), 1);
+    }
 }
@@ -21,12 +21,16 @@ use kvproto::metapb::Region;
 use raftstore::store::{keys, Msg};
 use raftstore::store::engine::{Iterable, Peekable, Snapshot};
 use storage::CF_RAFT;
+use util::io_limiter::IOLimiter;
 use util::worker::Runnable;
 
 use super::metrics::*;
 use raftstore::store::metrics::*;
 use super::MsgSender;
 
+// Request the scanned bytes from the limiter in batches to reduce the cost.
+const LIMITER_REQUEST_BYTES: u64 = 64 * 1024;
+
 /// Consistency checking task.
 pub enum Task {
     ComputeHash {
@@ -58,11 +62,21 @@ impl Display for Task {
 
 pub struct Runner<C: MsgSender> {
     ch: C,
+    limiter: Option<IOLimiter>,
 }
 
 impl<C: MsgSender> Runner<C> {
-    pub fn new(ch: C) -> Runner<C> {
-        Runner { ch: ch }
+    /// Creates a runner which scans at most `bytes_per_sec` bytes per second,
+    /// 0 means no limit.
+    pub fn new(ch: C, bytes_per_sec: u64) -> Runner<C> {
+        Runner {
+            ch: ch,
+            limiter: if bytes_per_sec > 0 {
+                Some(IOLimiter::new(bytes_per_sec))
+            } else {
+                None
+            },
+        }
     }
 
     fn compute_hash(&mut self, region: Region, index: u64, snap: Snapshot) {
@@ -78,10 +92,19 @@ impl<C: MsgSender> Runner<C> {
         cf_names.sort();
         let start_key = keys::enc_start_key(&region);
         let end_key = keys::enc_end_key(&region);
+        let limiter = self.limiter.as_ref();
+        let mut scanned_bytes = 0;
         for cf in cf_names {
             let res = snap.scan_cf(cf, &start_key, &end_key, false, &mut |k, v| {
                 digest.write(k);
                 digest.write(v);
+                if let Some(limiter) = limiter {
+                    scanned_bytes += (k.len() + v.len()) as u64;
+                    if scanned_bytes >= LIMITER_REQUEST_BYTES {
+                        limiter.request(scanned_bytes);
+                        scanned_bytes = 0;
+                    }
+                }
                 Ok(true)
             });
             if let Err(e) = res {
@@ -163,7 +186,7 @@ mod test {
         region.mut_peers().push(Peer::new());
 
         let (tx, rx) = mpsc::channel();
-        let mut runner = Runner::new(tx);
+        let mut runner = Runner::new(tx, 0);
         let mut digest = Digest::new(crc32::IEEE);
         let kvs = vec![(b"k1", b"v1"), (b"k2", b"v2")];
         for (k, v) in kvs {
@@ -11,24 +11,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::fmt::{self, Display, Formatter};
 
-use futures::Future;
+use futures::{future, Future};
 use tokio_core::reactor::Handle;
 
 use kvproto::metapb;
 use kvproto::eraftpb::ConfChangeType;
-use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, RaftCmdRequest};
+use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, RaftCmdRequest, RaftCmdResponse,
+                          SplitRequest};
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::pdpb;
 
 use util::worker::FutureRunnable as Runnable;
-use util::escape;
+use util::{escape, HandyRwLock};
+use util::collections::{HashMap, HashSet};
 use util::transport::SendCh;
 use pd::{PdClient, RegionStat};
-use raftstore::store::Msg;
-use raftstore::store::util::is_epoch_stale;
+use raftstore::Error;
+use raftstore::store::{Msg, SplitCallback};
+use raftstore::store::batch_split::BatchSplitRequest;
+use raftstore::store::util::{get_election_priority, is_epoch_stale, is_witness_store};
 use raftstore::store::metrics::*;
 use fs2;
 use super::metrics::*;
@@ -44,6 +48,14 @@ pub enum Task {
         // If true, right region derive origin region_id.
         right_derive: bool,
     },
+    AskBatchSplit {
+        region: metapb::Region,
+        // Ordered origin keys.
+        split_keys: Vec<Vec<u8>>,
+        peer: metapb::Peer,
+        right_derive: bool,
+        callback: SplitCallback,
+    },
     Heartbeat {
         region: metapb::Region,
         peer: metapb::Peer,
@@ -51,6 +63,8 @@ pub enum Task {
         pending_peers: Vec<metapb::Peer>,
         written_bytes: u64,
         written_keys: u64,
+        read_bytes: u64,
+        read_keys: u64,
         approximate_size: u64,
     },
     StoreHeartbeat {
@@ -65,6 +79,13 @@ pub enum Task {
         region: metapb::Region,
         peer: metapb::Peer,
     },
+    ReportInconsistency {
+        region: metapb::Region,
+        peer: metapb::Peer,
+        index: u64,
+    },
+    // Resolves the labels of the stores.
+    ResolveStores { store_ids: Vec<u64> },
 }
 
 impl Display for Task {
@@ -80,6 +101,16 @@ impl Display for Task {
                 region.get_id(),
                 escape(split_key)
             ),
+            Task::AskBatchSplit {
+                ref region,
+                ref split_keys,
+                ..
+            } => write!(
+                f,
+                "ask batch split region {} with {} keys",
+                region.get_id(),
+                split_keys.len()
+            ),
             Task::Heartbeat {
                 ref region,
                 ref peer,
@@ -101,6 +132,18 @@ impl Display for Task {
                 ref region,
                 ref peer,
             } => write!(f, "validate peer {:?} with region {:?}", peer, region),
+            Task::ReportInconsistency {
+                ref region,
+                ref peer,
+                index,
+            } => write!(
+                f,
+                "report inconsistency of peer {:?} at index {}, region {:?}",
+                peer,
+                index,
+                region
+            ),
+            Task::ResolveStores { ref store_ids } => write!(f, "resolve stores {:?}", store_ids),
         }
     }
 }
@@ -110,15 +153,61 @@ pub struct Runner<T: PdClient> {
     pd_client: Arc<T>,
     ch: SendCh<Msg>,
     is_hb_receiver_scheduled: bool,
+    // The stores whose labels are resolved or being resolved.
+    resolving_stores: Arc<Mutex<HashSet<u64>>>,
+    witness_stores: Arc<RwLock<HashMap<u64, bool>>>,
+    store_priorities: Arc<RwLock<HashMap<u64, u64>>>,
 }
 
 impl<T: PdClient> Runner<T> {
-    pub fn new(store_id: u64, pd_client: Arc<T>, ch: SendCh<Msg>) -> Runner<T> {
+    pub fn new(
+        store_id: u64,
+        pd_client: Arc<T>,
+        ch: SendCh<Msg>,
+        witness_stores: Arc<RwLock<HashMap<u64, bool>>>,
+        store_priorities: Arc<RwLock<HashMap<u64, u64>>>,
+    ) -> Runner<T> {
         Runner {
             store_id: store_id,
             pd_client: pd_client,
             ch: ch,
             is_hb_receiver_scheduled: false,
+            resolving_stores: Arc::new(Mutex::new(HashSet::default())),
+            witness_stores: witness_stores,
+            store_priorities: store_priorities,
+        }
+    }
+
+    // Checks whether the stores are witness stores and gets their election
+    // priorities without blocking the worker, each store is only resolved
+    // once unless it fails.
+    fn resolve_stores<I: IntoIterator<Item = u64>>(&self, handle: &Handle, store_ids: I) {
+        for store_id in store_ids {
+            if !self.resolving_stores.lock().unwrap().insert(store_id) {
+                continue;
+            }
+            let resolving_stores = self.resolving_stores.clone();
+            let witness_stores = self.witness_stores.clone();
+            let store_priorities = self.store_priorities.clone();
+            let f = self.pd_client.get_store_async(store_id).then(move |res| {
+                match res {
+                    Ok(store) => {
+                        let witness = is_witness_store(&store);
+                        if witness {
+                            info!("store {} is a witness store", store_id);
+                        }
+                        let priority = get_election_priority(&store);
+                        store_priorities.wl().insert(store_id, priority);
+                        witness_stores.wl().insert(store_id, witness);
+                    }
+                    Err(e) => {
+                        debug!("failed to get store {}: {:?}", store_id, e);
+                        resolving_stores.lock().unwrap().remove(&store_id);
+                    }
+                }
+                Ok(())
+            });
+            handle.spawn(f);
         }
     }
 
@@ -165,6 +254,70 @@ impl<T: PdClient> Runner<T> {
         handle.spawn(f)
     }
 
+    fn handle_ask_batch_split(
+        &self,
+        handle: &Handle,
+        region: metapb::Region,
+        split_keys: Vec<Vec<u8>>,
+        peer: metapb::Peer,
+        right_derive: bool,
+        callback: SplitCallback,
+    ) {
+        PD_REQ_COUNTER_VEC
+            .with_label_values(&["ask batch split", "all"])
+            .inc();
+
+        // pd allocates the ids of one new region for each ask split request.
+        let asks: Vec<_> = split_keys
+            .iter()
+            .map(|_| self.pd_client.ask_split(region.clone()))
+            .collect();
+        let ch = self.ch.clone();
+        let f = future::join_all(asks).then(move |resp| {
+            match resp {
+                Ok(resps) => {
+                    PD_REQ_COUNTER_VEC
+                        .with_label_values(&["ask batch split", "success"])
+                        .inc();
+
+                    let requests: Vec<_> = split_keys
+                        .into_iter()
+                        .zip(resps)
+                        .map(|(split_key, mut resp)| {
+                            new_split_request(
+                                split_key,
+                                resp.get_new_region_id(),
+                                resp.take_new_peer_ids(),
+                                right_derive,
+                            )
+                        })
+                        .collect();
+                    info!(
+                        "[region {}] try to batch split with new region ids {:?} for region {:?}",
+                        region.get_id(),
+                        requests
+                            .iter()
+                            .map(|r| r.get_new_region_id())
+                            .collect::<Vec<_>>(),
+                        region
+                    );
+                    let splits = BatchSplitRequest::new(requests, right_derive);
+                    send_batch_split_request(&ch, region, peer, splits, callback);
+                }
+                Err(e) => {
+                    debug!(
+                        "[region {}] failed to ask batch split: {:?}",
+                        region.get_id(),
+                        e
+                    );
+                    callback.call_box((Err(e.into()),));
+                }
+            }
+            Ok(())
+        });
+        handle.spawn(f)
+    }
+
     fn handle_heartbeat(
         &self,
         handle: &Handle,
@@ -176,6 +329,11 @@ impl<T: PdClient> Runner<T> {
             .with_label_values(&["heartbeat", "all"])
             .inc();
 
+        self.resolve_stores(
+            handle,
+            region.get_peers().iter().map(|p| p.get_store_id()),
+        );
+
         // Now we use put region protocol for heartbeat.
         let f = self.pd_client
             .region_heartbeat(region.clone(), peer.clone(), region_stat)
@@ -268,6 +426,40 @@ impl<T: PdClient> Runner<T> {
         handle.spawn(f);
     }
 
+    fn handle_report_inconsistency(
+        &self,
+        handle: &Handle,
+        region: metapb::Region,
+        peer: metapb::Peer,
+        index: u64,
+    ) {
+        PD_REQ_COUNTER_VEC
+            .with_label_values(&["report inconsistency", "all"])
+            .inc();
+
+        let region_id = region.get_id();
+        let f = self.pd_client
+            .report_inconsistency(region, peer, index)
+            .then(move |resp| {
+                match resp {
+                    Ok(_) => {
+                        PD_REQ_COUNTER_VEC
+                            .with_label_values(&["report inconsistency", "success"])
+                            .inc();
+                    }
+                    Err(e) => {
+                        error!(
+                            "[region {}] report inconsistency failed {:?}",
+                            region_id,
+                            e
+                        );
+                    }
+                }
+                Ok(())
+            });
+        handle.spawn(f);
+    }
+
     fn handle_validate_peer(
         &self,
         handle: &Handle,
@@ -403,6 +595,20 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
                 peer,
                 right_derive,
             } => self.handle_ask_split(handle, region, split_key, peer, right_derive),
+            Task::AskBatchSplit {
+                region,
+                split_keys,
+                peer,
+                right_derive,
+                callback,
+            } => self.handle_ask_batch_split(
+                handle,
+                region,
+                split_keys,
+                peer,
+                right_derive,
+                callback,
+            ),
             Task::Heartbeat {
                 region,
                 peer,
@@ -410,6 +616,8 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
                 pending_peers,
                 written_bytes,
                 written_keys,
+                read_bytes,
+                read_keys,
                 approximate_size,
             } => self.handle_heartbeat(
                 handle,
@@ -420,6 +628,8 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
                     pending_peers,
                     written_bytes,
                     written_keys,
+                    read_bytes,
+                    read_keys,
                     approximate_size,
                 ),
             ),
@@ -428,6 +638,12 @@ impl<T: PdClient> Runnable<Task> for Runner<T> {
             }
             Task::ReportSplit { left, right } => self.handle_report_split(handle, left, right),
             Task::ValidatePeer { region, peer } => self.handle_validate_peer(handle, region, peer),
+            Task::ReportInconsistency {
+                region,
+                peer,
+                index,
+            } => self.handle_report_inconsistency(handle, region, peer, index),
+            Task::ResolveStores { store_ids } => self.resolve_stores(handle, store_ids),
         };
     }
 }
@@ -440,6 +656,20 @@ fn new_change_peer_request(change_type: ConfChangeType, peer: metapb::Peer) -> A
     req
 }
 
+fn new_split_request(
+    split_key: Vec<u8>,
+    new_region_id: u64,
+    peer_ids: Vec<u64>,
+    right_derive: bool,
+) -> SplitRequest {
+    let mut req = SplitRequest::new();
+    req.set_split_key(split_key);
+    req.set_new_region_id(new_region_id);
+    req.set_new_peer_ids(peer_ids);
+    req.set_right_derive(right_derive);
+    req
+}
+
 fn new_split_region_request(
     split_key: Vec<u8>,
     new_region_id: u64,
@@ -448,10 +678,12 @@ fn new_split_region_request(
 ) -> AdminRequest {
     let mut req = AdminRequest::new();
     req.set_cmd_type(AdminCmdType::Split);
-    req.mut_split().set_split_key(split_key);
-    req.mut_split().set_new_region_id(new_region_id);
-    req.mut_split().set_new_peer_ids(peer_ids);
-    req.mut_split().set_right_derive(right_derive);
+    req.set_split(new_split_request(
+        split_key,
+        new_region_id,
+        peer_ids,
+        right_derive,
+    ));
     req
 }
 
@@ -499,6 +731,50 @@ fn send_admin_request_raw(
     }
 }
 
+// The first split makes the request a split command to the raftstore, and
+// all the splits are proposed along with it.
+fn send_batch_split_request(
+    ch: &SendCh<Msg>,
+    mut region: metapb::Region,
+    peer: metapb::Peer,
+    splits: BatchSplitRequest,
+    callback: SplitCallback,
+) {
+    let region_id = region.get_id();
+    let mut req = RaftCmdRequest::new();
+    req.mut_header().set_region_id(region_id);
+    req.mut_header().set_region_epoch(region.take_region_epoch());
+    req.mut_header().set_peer(peer);
+    let mut admin = AdminRequest::new();
+    admin.set_cmd_type(AdminCmdType::Split);
+    admin.set_split(splits.requests[0].clone());
+    req.set_admin_request(admin);
+
+    let request = splits.clone();
+    let cb = box move |mut resp: RaftCmdResponse| {
+        let res = if resp.get_header().has_error() {
+            let mut err = resp.take_header().take_error();
+            if err.has_stale_epoch() {
+                let new_regions = err.mut_stale_epoch().take_new_regions().into_vec();
+                Err(Error::StaleEpoch(err.take_message(), new_regions))
+            } else {
+                Err(box_err!("[region {}] split failed: {:?}", region_id, err))
+            }
+        } else {
+            request.regions_from_response(resp.get_admin_response().get_split())
+        };
+        callback.call_box((res,));
+    };
+    let msg = Msg::BatchSplit {
+        request: req,
+        splits: splits,
+        callback: cb,
+    };
+    if let Err(e) = ch.try_send(msg) {
+        error!("[region {}] send batch split request err {:?}", region_id, e);
+    }
+}
+
 // send a raft message to destroy the specified stale peer
 fn send_destroy_peer_message(
     ch: SendCh<Msg>,
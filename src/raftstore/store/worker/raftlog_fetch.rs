@@ -0,0 +1,151 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt::{self, Display, Formatter};
+use std::u64;
+
+use raftstore::store::Msg;
+use raftstore::store::raft_engine::RaftEngine;
+use raftstore::store::metrics::RAFT_ENTRY_FETCHES;
+use util::worker::Runnable;
+use super::MsgSender;
+
+/// Reads the raft logs in `[low, high)` of a region from the raft engine.
+pub struct Task {
+    pub raft_engine: Arc<RaftEngine>,
+    pub region_id: u64,
+    pub seq: u64,
+    // The prefetching flag of the peer storage, reset if the result can't
+    // be sent back, otherwise the peer would never prefetch again.
+    pub prefetching: Arc<AtomicBool>,
+    pub low: u64,
+    pub high: u64,
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Fetch Raft Log Task [region: {}, from: {}, to: {}]",
+            self.region_id,
+            self.low,
+            self.high
+        )
+    }
+}
+
+pub struct Runner<C: MsgSender> {
+    ch: C,
+}
+
+impl<C: MsgSender> Runner<C> {
+    pub fn new(ch: C) -> Runner<C> {
+        Runner { ch: ch }
+    }
+}
+
+impl<C: MsgSender> Runnable<Task> for Runner<C> {
+    fn run(&mut self, task: Task) {
+        RAFT_ENTRY_FETCHES.with_label_values(&["async"]).inc();
+        let mut entries = Vec::with_capacity((task.high - task.low) as usize);
+        if let Err(e) = task.raft_engine.fetch_entries_to(
+            task.region_id,
+            task.low,
+            task.high,
+            u64::MAX,
+            &mut entries,
+        ) {
+            // The log may be compacted, let the peer know the fetch is finished anyway.
+            warn!("[region {}] failed to fetch raft log: {:?}", task.region_id, e);
+            entries.clear();
+        }
+        let msg = Msg::RaftLogFetched {
+            region_id: task.region_id,
+            seq: task.seq,
+            entries: entries,
+        };
+        if let Err(e) = self.ch.try_send(msg) {
+            error!(
+                "[region {}] failed to send fetched raft log: {:?}",
+                task.region_id,
+                e
+            );
+            task.prefetching.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Sender};
+
+    use tempdir::TempDir;
+
+    use raftstore::store::Msg;
+    use raftstore::store::raft_engine::{RaftEngine, RocksRaftEngine};
+    use storage::CF_DEFAULT;
+    use util::rocksdb;
+    use super::*;
+
+    struct FailSender;
+
+    impl MsgSender for FailSender {
+        fn send(&self, _: Msg) -> ::raftstore::Result<()> {
+            Err(box_err!("full"))
+        }
+
+        fn try_send(&self, _: Msg) -> ::raftstore::Result<()> {
+            Err(box_err!("full"))
+        }
+    }
+
+    fn new_task(raft_engine: &Arc<RaftEngine>, prefetching: &Arc<AtomicBool>) -> Task {
+        Task {
+            raft_engine: raft_engine.clone(),
+            region_id: 1,
+            seq: 0,
+            prefetching: prefetching.clone(),
+            low: 1,
+            high: 3,
+        }
+    }
+
+    #[test]
+    fn test_fetch_result_not_sent() {
+        let path = TempDir::new("test-raftlog-fetch").unwrap();
+        let db = rocksdb::new_engine(path.path().to_str().unwrap(), &[CF_DEFAULT]).unwrap();
+        let raft_engine: Arc<RaftEngine> = Arc::new(RocksRaftEngine::new(Arc::new(db)));
+        let prefetching = Arc::new(AtomicBool::new(true));
+
+        let (tx, rx) = mpsc::channel();
+        let mut runner: Runner<Sender<Msg>> = Runner::new(tx);
+        runner.run(new_task(&raft_engine, &prefetching));
+        match rx.try_recv().unwrap() {
+            Msg::RaftLogFetched { seq, entries, .. } => {
+                assert_eq!(seq, 0);
+                assert!(entries.is_empty());
+            }
+            _ => panic!("unexpected msg"),
+        }
+        // The peer resets it when the result is handled.
+        assert!(prefetching.load(Ordering::SeqCst));
+
+        let mut runner = Runner::new(FailSender);
+        runner.run(new_task(&raft_engine, &prefetching));
+        assert!(!prefetching.load(Ordering::SeqCst));
+    }
+}
@@ -11,18 +11,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use raftstore::store::keys;
-use raftstore::store::engine::Iterable;
+use raftstore::store::raft_engine::RaftEngine;
 use util::worker::Runnable;
 
-use rocksdb::{Writable, WriteBatch, DB};
 use std::sync::Arc;
 use std::fmt::{self, Display, Formatter};
 use std::error;
 use std::sync::mpsc::Sender;
 
 pub struct Task {
-    pub raft_engine: Arc<DB>,
+    pub raft_engine: Arc<RaftEngine>,
     pub region_id: u64,
     pub start_idx: u64,
     pub end_idx: u64,
@@ -68,31 +66,16 @@ impl Runner {
     /// Do the gc job and return the count of log collected.
     fn gc_raft_log(
         &mut self,
-        raft_engine: Arc<DB>,
+        raft_engine: Arc<RaftEngine>,
         region_id: u64,
         start_idx: u64,
         end_idx: u64,
     ) -> Result<u64, Error> {
-        let mut first_idx = start_idx;
-        if first_idx == 0 {
-            let start_key = keys::raft_log_key(region_id, 0);
-            first_idx = end_idx;
-            if let Some((k, _)) = box_try!(raft_engine.seek(&start_key)) {
-                first_idx = box_try!(keys::raft_log_index(&k));
-            }
-        }
-        if first_idx >= end_idx {
+        let collected = box_try!(raft_engine.gc(region_id, start_idx, end_idx));
+        if collected == 0 {
             info!("[region {}] no need to gc", region_id);
-            return Ok(0);
         }
-        let raft_wb = WriteBatch::new();
-        for idx in first_idx..end_idx {
-            let key = keys::raft_log_key(region_id, idx);
-            box_try!(raft_wb.delete(&key));
-        }
-        // TODO: disable WAL here.
-        raft_engine.write(raft_wb).unwrap();
-        Ok(end_idx - first_idx)
+        Ok(collected)
     }
 
     fn report_collected(&self, collected: u64) {
@@ -138,28 +121,34 @@ impl Runnable<Task> for Runner {
 mod test {
     use std::sync::mpsc;
     use std::time::Duration;
+    use kvproto::eraftpb::Entry;
     use util::rocksdb::new_engine;
     use tempdir::TempDir;
     use storage::CF_DEFAULT;
+    use raftstore::store::raft_engine::{RaftLogBatch, RocksRaftEngine};
     use super::*;
 
     #[test]
     fn test_gc_raft_log() {
         let path = TempDir::new("gc-raft-log-test").unwrap();
         let raft_db = new_engine(path.path().to_str().unwrap(), &[CF_DEFAULT]).unwrap();
-        let raft_db = Arc::new(raft_db);
+        let raft_db: Arc<RaftEngine> = Arc::new(RocksRaftEngine::new(Arc::new(raft_db)));
 
         let (tx, rx) = mpsc::channel();
         let mut runner = Runner::new(Some(tx));
 
         // generate raft logs
         let region_id = 1;
-        let raft_wb = WriteBatch::new();
-        for i in 0..100 {
-            let k = keys::raft_log_key(region_id, i);
-            raft_wb.put(&k, b"entry").unwrap();
-        }
-        raft_db.write(raft_wb).unwrap();
+        let entries: Vec<_> = (0..100)
+            .map(|i| {
+                let mut e = Entry::new();
+                e.set_index(i);
+                e
+            })
+            .collect();
+        let mut raft_wb = RaftLogBatch::new();
+        raft_wb.append(region_id, &entries);
+        raft_db.write(raft_wb, false).unwrap();
 
         let tbls = vec![
             (
@@ -212,22 +201,25 @@ mod test {
             runner.run(task);
             let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
             assert_eq!(res.collected, expected_collectd);
-            raft_log_must_not_exist(&raft_db, 1, not_exist_range.0, not_exist_range.1);
-            raft_log_must_exist(&raft_db, 1, exist_range.0, exist_range.1);
+            raft_log_must_not_exist(raft_db.as_ref(), 1, not_exist_range.0, not_exist_range.1);
+            raft_log_must_exist(raft_db.as_ref(), 1, exist_range.0, exist_range.1);
         }
     }
 
-    fn raft_log_must_not_exist(raft_engine: &DB, region_id: u64, start_idx: u64, end_idx: u64) {
+    fn raft_log_must_not_exist(
+        raft_engine: &RaftEngine,
+        region_id: u64,
+        start_idx: u64,
+        end_idx: u64,
+    ) {
         for i in start_idx..end_idx {
-            let k = keys::raft_log_key(region_id, i);
-            assert!(raft_engine.get(&k).unwrap().is_none());
+            assert!(raft_engine.get_entry(region_id, i).unwrap().is_none());
         }
     }
 
-    fn raft_log_must_exist(raft_engine: &DB, region_id: u64, start_idx: u64, end_idx: u64) {
+    fn raft_log_must_exist(raft_engine: &RaftEngine, region_id: u64, start_idx: u64, end_idx: u64) {
         for i in start_idx..end_idx {
-            let k = keys::raft_log_key(region_id, i);
-            assert!(raft_engine.get(&k).unwrap().is_some());
+            assert!(raft_engine.get_entry(region_id, i).unwrap().is_some());
         }
     }
 }
@@ -16,8 +16,8 @@ use std::fmt::{self, Display, Formatter};
 use std::sync::Arc;
 use std::sync::mpsc::SyncSender;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
-use std::str;
+use std::time::{Duration, Instant};
+use std::{str, thread};
 
 use rocksdb::{Writable, WriteBatch, DB};
 use kvproto::raft_serverpb::{PeerState, RaftApplyState, RegionLocalState};
@@ -26,6 +26,7 @@ use threadpool::ThreadPool;
 
 use util::worker::Runnable;
 use util::{escape, rocksdb};
+use util::time::duration_to_sec;
 use raftstore::store::engine::{IterOption, Iterable, Mutable, Snapshot};
 use raftstore::store::peer_storage::{JOB_STATUS_CANCELLED, JOB_STATUS_CANCELLING,
                                      JOB_STATUS_FAILED, JOB_STATUS_FINISHED, JOB_STATUS_PENDING,
@@ -33,11 +34,14 @@ use raftstore::store::peer_storage::{JOB_STATUS_CANCELLED, JOB_STATUS_CANCELLING
 use raftstore::store::{self, check_abort, keys, ApplyOptions, Peekable, SnapEntry, SnapKey,
                        SnapManager};
 use raftstore::store::snap::{Error, Result};
+use raftstore::store::raft_engine::RaftEngine;
+use raftstore::store::metrics::SNAPSHOT_THROTTLED_DURATION_VEC;
 use storage::CF_RAFT;
 
 use super::metrics::*;
 
 const GENERATE_POOL_SIZE: usize = 2;
+const WAIT_APPLY_INTERVAL_MS: u64 = 10;
 
 /// region related task.
 pub enum Task {
@@ -92,20 +96,22 @@ impl Display for Task {
 #[derive(Clone)]
 struct SnapContext {
     kv_db: Arc<DB>,
-    raft_db: Arc<DB>,
+    raft_engine: Arc<RaftEngine>,
     batch_size: usize,
     mgr: SnapManager,
+    // A witness store only applies the meta of snapshots.
+    witness: bool,
 }
 
 impl SnapContext {
     fn generate_snap(&self, region_id: u64, notifier: SyncSender<RaftSnapshot>) -> Result<()> {
         // do we need to check leader here?
-        let raft_db = self.raft_db.clone();
-        let raw_snap = Snapshot::new(self.kv_db.clone());
+        let raft_engine = self.raft_engine.clone();
+        let raw_snap = Snapshot::new(self.kv_db.clone()).into_sync();
 
         let snap = box_try!(store::do_snapshot(
             self.mgr.clone(),
-            &raft_db,
+            raft_engine.as_ref(),
             &raw_snap,
             region_id
         ));
@@ -230,13 +236,15 @@ impl SnapContext {
         }
         try!(check_abort(&abort));
         let timer = Instant::now();
-        let options = ApplyOptions {
-            db: self.kv_db.clone(),
-            region: region.clone(),
-            abort: abort.clone(),
-            write_batch_size: self.batch_size,
-        };
-        try!(s.apply(options));
+        if !self.witness {
+            let options = ApplyOptions {
+                db: self.kv_db.clone(),
+                region: region.clone(),
+                abort: abort.clone(),
+                write_batch_size: self.batch_size,
+            };
+            try!(s.apply(options));
+        }
 
         let wb = WriteBatch::new();
         region_state.set_state(PeerState::Normal);
@@ -309,18 +317,32 @@ impl SnapContext {
 
 pub struct Runner {
     pool: ThreadPool,
+    // Applies snapshots, its size bounds the snapshots applied at the same time.
+    apply_pool: ThreadPool,
+    // The number of snapshots scheduled to the apply pool and not finished.
+    applying: Arc<AtomicUsize>,
     ctx: SnapContext,
 }
 
 impl Runner {
-    pub fn new(kv_db: Arc<DB>, raft_db: Arc<DB>, mgr: SnapManager, batch_size: usize) -> Runner {
+    pub fn new(
+        kv_db: Arc<DB>,
+        raft_engine: Arc<RaftEngine>,
+        mgr: SnapManager,
+        batch_size: usize,
+        witness: bool,
+        apply_limit: usize,
+    ) -> Runner {
         Runner {
             pool: ThreadPool::new_with_name(thd_name!("snap generator"), GENERATE_POOL_SIZE),
+            apply_pool: ThreadPool::new_with_name(thd_name!("snap applier"), apply_limit),
+            applying: Arc::new(AtomicUsize::new(0)),
             ctx: SnapContext {
                 kv_db: kv_db,
-                raft_db: raft_db,
+                raft_engine: raft_engine,
                 mgr: mgr,
                 batch_size: batch_size,
+                witness: witness,
             },
         }
     }
@@ -339,12 +361,33 @@ impl Runnable<Task> for Runner {
                 self.pool
                     .execute(move || ctx.handle_gen(region_id, notifier))
             }
-            Task::Apply { region_id, status } => self.ctx.handle_apply(region_id, status),
+            Task::Apply { region_id, status } => {
+                let ctx = self.ctx.clone();
+                let applying = self.applying.clone();
+                applying.fetch_add(1, Ordering::SeqCst);
+                let scheduled = Instant::now();
+                self.apply_pool.execute(move || {
+                    defer!({
+                        applying.fetch_sub(1, Ordering::SeqCst);
+                    });
+                    SNAPSHOT_THROTTLED_DURATION_VEC
+                        .with_label_values(&["apply"])
+                        .inc_by(duration_to_sec(scheduled.elapsed()))
+                        .unwrap();
+                    ctx.handle_apply(region_id, status)
+                })
+            }
             Task::Destroy {
                 region_id,
                 start_key,
                 end_key,
-            } => self.ctx.handle_destroy(region_id, start_key, end_key),
+            } => {
+                // The range may be written by the snapshots scheduled before.
+                while self.applying.load(Ordering::SeqCst) > 0 {
+                    thread::sleep(Duration::from_millis(WAIT_APPLY_INTERVAL_MS));
+                }
+                self.ctx.handle_destroy(region_id, start_key, end_key)
+            }
         }
     }
 }
@@ -26,12 +26,14 @@ pub use storage::Config as StorageConfig;
 pub const DEFAULT_CLUSTER_ID: u64 = 0;
 pub const DEFAULT_LISTENING_ADDR: &'static str = "127.0.0.1:20160";
 const DEFAULT_ADVERTISE_LISTENING_ADDR: &'static str = "";
+const DEFAULT_STATUS_ADDR: &'static str = "";
 const DEFAULT_NOTIFY_CAPACITY: usize = 40960;
 const DEFAULT_GRPC_CONCURRENCY: usize = 4;
 const DEFAULT_GRPC_CONCURRENT_STREAM: usize = 1024;
 const DEFAULT_GRPC_RAFT_CONN_NUM: usize = 10;
 const DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE: u64 = 2 * 1024 * 1024;
 const DEFAULT_MESSAGES_PER_TICK: usize = 4096;
+const DEFAULT_RAFT_MSG_MAX_BATCH_SIZE: usize = 128;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -46,13 +48,22 @@ pub struct Config {
     // Server advertise listening address for outer communication.
     // If not set, we will use listening address instead.
     pub advertise_addr: String,
+    // The address of the HTTP status server, it's disabled if not set.
+    pub status_addr: String,
     pub notify_capacity: usize,
     pub messages_per_tick: usize,
     pub grpc_concurrency: usize,
     pub grpc_concurrent_stream: usize,
     pub grpc_raft_conn_num: usize,
     pub grpc_stream_initial_window_size: ReadableSize,
+    // Max number of raft messages packed into one grpc message, 1 means no batching.
+    pub raft_msg_max_batch_size: usize,
     pub end_point_concurrency: usize,
+    // Snapshot IO limits of the store, 0 means no limit.
+    pub snap_max_send_bytes_per_sec: ReadableSize,
+    pub snap_max_recv_bytes_per_sec: ReadableSize,
+    pub concurrent_send_snap_limit: usize,
+    pub concurrent_recv_snap_limit: usize,
     // Server labels to specify some attributes about this server.
     #[serde(with = "config::order_map_serde")]
     pub labels: HashMap<String, String>,
@@ -71,13 +82,19 @@ impl Default for Config {
             addr: DEFAULT_LISTENING_ADDR.to_owned(),
             labels: HashMap::default(),
             advertise_addr: DEFAULT_ADVERTISE_LISTENING_ADDR.to_owned(),
+            status_addr: DEFAULT_STATUS_ADDR.to_owned(),
             notify_capacity: DEFAULT_NOTIFY_CAPACITY,
             messages_per_tick: DEFAULT_MESSAGES_PER_TICK,
             grpc_concurrency: DEFAULT_GRPC_CONCURRENCY,
             grpc_concurrent_stream: DEFAULT_GRPC_CONCURRENT_STREAM,
             grpc_raft_conn_num: DEFAULT_GRPC_RAFT_CONN_NUM,
             grpc_stream_initial_window_size: ReadableSize(DEFAULT_GRPC_STREAM_INITIAL_WINDOW_SIZE),
+            raft_msg_max_batch_size: DEFAULT_RAFT_MSG_MAX_BATCH_SIZE,
             end_point_concurrency: concurrency,
+            snap_max_send_bytes_per_sec: ReadableSize(0),
+            snap_max_recv_bytes_per_sec: ReadableSize(0),
+            concurrent_send_snap_limit: 0,
+            concurrent_recv_snap_limit: 0,
         }
     }
 }
@@ -97,6 +114,17 @@ impl Config {
                 self.advertise_addr
             ));
         }
+        if !self.status_addr.is_empty() {
+            box_try!(config::check_addr(&self.status_addr));
+        }
+
+        if self.raft_msg_max_batch_size == 0 {
+            return Err(box_err!(
+                "server.raft-msg-max-batch-size: {} is invalid, \
+                 shouldn't be 0",
+                self.raft_msg_max_batch_size
+            ));
+        }
 
         if self.end_point_concurrency == 0 {
             return Err(box_err!(
@@ -167,6 +195,11 @@ mod tests {
         invalid_cfg.advertise_addr = "127.0.0.1:1000".to_owned();
         invalid_cfg.validate().unwrap();
 
+        invalid_cfg.status_addr = "127.0.0.1".to_owned();
+        assert!(invalid_cfg.validate().is_err());
+        invalid_cfg.status_addr = "127.0.0.1:20180".to_owned();
+        invalid_cfg.validate().unwrap();
+
         cfg.labels.insert("k1".to_owned(), "v1".to_owned());
         cfg.validate().unwrap();
         cfg.labels.insert("k2".to_owned(), "v2?".to_owned());
@@ -103,6 +103,10 @@ quick_error!{
         Sink {
             description("failed to poll from mpsc receiver")
         }
+        SnapRejected(reason: String) {
+            description("snapshot is rejected")
+            display("snapshot is rejected: {}", reason)
+        }
         Canceled(err: Canceled) {
             from()
             cause(err)
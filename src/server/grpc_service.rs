@@ -16,6 +16,7 @@ use std::fmt::Debug;
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use mio::Token;
 use grpc::{ClientStreamingSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode, UnarySink};
 use futures::{future, Future, Stream};
@@ -26,16 +27,20 @@ use kvproto::raft_serverpb::*;
 use kvproto::kvrpcpb::*;
 use kvproto::coprocessor::*;
 use kvproto::errorpb::{Error as RegionError, ServerIsBusy};
+use tokio_timer::Timer;
 
 use util::worker::Scheduler;
 use util::buf::PipeBuffer;
+use util::time::GLOBAL_TIMER;
 use storage::{self, Key, Mutation, Options, Storage, Value};
 use storage::txn::Error as TxnError;
 use storage::mvcc::{Error as MvccError, Write as MvccWrite, WriteType};
 use storage::engine::Error as EngineError;
+use raftstore::store::SnapManager;
 use super::transport::RaftStoreRouter;
 use coprocessor::{EndPointTask, RequestTask};
-use super::snap::Task as SnapTask;
+use super::snap::{Callback as SnapCallback, Task as SnapTask};
+use super::raft_client::{self, BatchHandshake};
 use super::metrics::*;
 use super::Error;
 
@@ -51,7 +56,12 @@ pub struct Service<T: RaftStoreRouter + 'static> {
     ch: T,
     // For handling snapshot.
     snap_scheduler: Scheduler<SnapTask>,
+    // For throttling received snapshots.
+    snap_mgr: SnapManager,
+    timer: Timer,
     token: Arc<AtomicUsize>, // TODO: remove it.
+    // The batch handshake, shared with `RaftClient`.
+    batch_handshake: Arc<BatchHandshake>,
 }
 
 impl<T: RaftStoreRouter + 'static> Service<T> {
@@ -60,13 +70,18 @@ impl<T: RaftStoreRouter + 'static> Service<T> {
         end_point_scheduler: Scheduler<EndPointTask>,
         ch: T,
         snap_scheduler: Scheduler<SnapTask>,
+        snap_mgr: SnapManager,
+        batch_handshake: Arc<BatchHandshake>,
     ) -> Service<T> {
         Service {
             storage: storage,
             end_point_scheduler: end_point_scheduler,
             ch: ch,
             snap_scheduler: snap_scheduler,
+            snap_mgr: snap_mgr,
+            timer: GLOBAL_TIMER.clone(),
             token: Arc::new(AtomicUsize::new(1)),
+            batch_handshake: batch_handshake,
         }
     }
 
@@ -773,12 +788,20 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         _: ClientStreamingSink<Done>,
     ) {
         let ch = self.ch.clone();
+        let batch_handshake = self.batch_handshake.clone();
         ctx.spawn(
             stream
                 .map_err(Error::from)
                 .for_each(move |msg| {
                     let res = match msg.get_region_id() {
-                        0 => Ok(()),
+                        // Batches and the batch handshake.
+                        0 => raft_client::unpack_batch(msg, &batch_handshake)
+                            .into_iter()
+                            .map(|m| {
+                                RAFT_MESSAGE_RECV_COUNTER.inc();
+                                ch.send_raft_msg(m)
+                            })
+                            .collect(),
                         _ => {
                             RAFT_MESSAGE_RECV_COUNTER.inc();
                             ch.send_raft_msg(msg)
@@ -800,38 +823,68 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         let token = Token(self.token.fetch_add(1, Ordering::SeqCst));
         let sched = self.snap_scheduler.clone();
         let sched2 = sched.clone();
+        let snap_mgr = self.snap_mgr.clone();
+        let timer = self.timer.clone();
         ctx.spawn(
             stream
                 .map_err(Error::from)
-                .for_each(move |mut chunk| {
-                    let res = if chunk.has_message() {
-                        sched
-                            .schedule(SnapTask::Register(token, chunk.take_message()))
-                            .map_err(Error::from)
+                .for_each(move |mut chunk| -> Box<Future<Item = (), Error = Error> + Send> {
+                    if chunk.has_message() {
+                        // Wait for the snap worker to accept the snapshot, so a rejected
+                        // one fails the RPC before receiving the data.
+                        let (tx, rx) = oneshot::channel();
+                        let cb: SnapCallback = box move |res| { let _ = tx.send(res); };
+                        let task = SnapTask::Register(token, chunk.take_message(), cb);
+                        if let Err(e) = sched.schedule(task) {
+                            return box future::err(Error::from(e));
+                        }
+                        box rx.map_err(Error::from).and_then(|res| res)
                     } else if !chunk.get_data().is_empty() {
                         // TODO: Remove PipeBuffer or take good use of it.
                         let mut b = PipeBuffer::new(chunk.get_data().len());
                         b.write_all(chunk.get_data()).unwrap();
-                        sched
-                            .schedule(SnapTask::Write(token, b))
-                            .map_err(Error::from)
+                        let sched = sched.clone();
+                        let write = move |_: ()| {
+                            sched
+                                .schedule(SnapTask::Write(token, b))
+                                .map_err(Error::from)
+                        };
+                        // Throttle here instead of in the snap worker, so the worker
+                        // thread is never blocked by the limiter.
+                        let wait = snap_mgr.recv_throttle(chunk.get_data().len() as u64);
+                        if wait == Duration::from_millis(0) {
+                            return box future::result(write(()));
+                        }
+                        box timer
+                            .sleep(wait)
+                            .map_err(|e| -> Error { box_err!("throttle snapshot err: {:?}", e) })
+                            .and_then(write)
                     } else {
-                        Err(box_err!("empty chunk"))
-                    };
-                    future::result(res)
+                        let e: Error = box_err!("empty chunk");
+                        box future::err(e)
+                    }
                 })
                 .then(move |res| {
                     let res = match res {
-                        Ok(_) => sched2.schedule(SnapTask::Close(token)),
+                        Ok(_) => sched2.schedule(SnapTask::Close(token)).map_err(Error::from),
                         Err(e) => {
                             error!("receive snapshot err: {}", e);
-                            sched2.schedule(SnapTask::Discard(token))
+                            let _ = sched2.schedule(SnapTask::Discard(token));
+                            Err(e)
                         }
                     };
-                    future::result(res.map_err(Error::from))
+                    match res {
+                        Ok(_) => sink.success(Done::new()),
+                        Err(e) => {
+                            let code = match e {
+                                Error::SnapRejected(_) => RpcStatusCode::ResourceExhausted,
+                                _ => RpcStatusCode::Unknown,
+                            };
+                            sink.fail(RpcStatus::new(code, Some(format!("{}", e))))
+                        }
+                    }
                 })
-                .and_then(|_| sink.success(Done::new()).map_err(Error::from))
-                .then(|_| future::ok::<_, ()>(())),
+                .map_err(|e| error!("send snapshot response err: {:?}", e)),
         );
     }
 
@@ -11,7 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::{Counter, CounterVec, Histogram, HistogramVec};
+use prometheus::{exponential_buckets, Counter, CounterVec, Histogram, HistogramVec};
 
 lazy_static! {
     pub static ref SEND_SNAP_HISTOGRAM: Histogram =
@@ -47,6 +47,13 @@ lazy_static! {
             "Total number of raft messages received"
         ).unwrap();
 
+    pub static ref RAFT_MESSAGE_BATCH_SIZE_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_server_raft_message_batch_size",
+            "Bucketed histogram of the number of raft messages in a batch",
+            exponential_buckets(1.0, 2.0, 12).unwrap()
+        ).unwrap();
+
     pub static ref RESOLVE_STORE_COUNTER: CounterVec =
         register_counter_vec!(
             "tikv_server_resolve_store_total",
@@ -29,7 +29,7 @@ use raftstore::store::{self, keys, Config as StoreConfig, Engines, Msg, Peekable
                        SnapshotStatusMsg, Store, StoreChannel, Transport};
 use super::Result;
 use server::Config as ServerConfig;
-use storage::{Config as StorageConfig, RaftKv, Storage};
+use storage::{Config as StorageConfig, RaftKv, ReadStats, Storage};
 use super::transport::RaftStoreRouter;
 
 const MAX_CHECK_CLUSTER_BOOTSTRAPPED_RETRY_COUNT: u64 = 60;
@@ -124,6 +124,7 @@ where
         trans: T,
         snap_mgr: SnapManager,
         snap_status_receiver: Receiver<SnapshotStatusMsg>,
+        read_stats: Arc<ReadStats>,
     ) -> Result<()>
     where
         T: Transport + 'static,
@@ -144,6 +145,8 @@ where
         }
 
         self.store.set_id(store_id);
+        let witness = store::util::is_witness_store(&self.store);
+        try!(store::check_store_witness(&engines.kv_engine, witness));
         try!(self.check_prepare_bootstrap_cluster(&engines));
         if !bootstrapped {
             // cluster is not bootstrapped, and we choose first store to bootstrap
@@ -160,7 +163,8 @@ where
             engines,
             trans,
             snap_mgr,
-            snap_status_receiver
+            snap_status_receiver,
+            read_stats
         ));
         Ok(())
     }
@@ -324,6 +328,7 @@ where
         trans: T,
         snap_mgr: SnapManager,
         snapshot_status_receiver: Receiver<SnapshotStatusMsg>,
+        read_stats: Arc<ReadStats>,
     ) -> Result<()>
     where
         T: Transport + 'static,
@@ -346,7 +351,16 @@ where
                 sender: sender,
                 snapshot_status_receiver: snapshot_status_receiver,
             };
-            let mut store = match Store::new(ch, store, cfg, engines, trans, pd_client, snap_mgr) {
+            let mut store = match Store::new(
+                ch,
+                store,
+                cfg,
+                engines,
+                trans,
+                pd_client,
+                snap_mgr,
+                read_stats,
+            ) {
                 Err(e) => panic!("construct store {} err {:?}", store_id, e),
                 Ok(s) => s,
             };
@@ -377,6 +391,26 @@ where
         Ok(())
     }
 
+    /// Transfers all the leaders on the store to other stores, waits until
+    /// they are all transferred or `timeout` is reached.
+    pub fn drain_leaders(&self, timeout: Duration) -> Result<()> {
+        let store_id = self.store.get_id();
+        info!("drain leaders of store {}", store_id);
+        let (tx, rx) = mpsc::channel();
+        let cb = box move || {
+            let _ = tx.send(());
+        };
+        box_try!(self.ch.send(Msg::DrainLeaders { callback: cb }));
+        if rx.recv_timeout(timeout).is_err() {
+            warn!(
+                "failed to drain all leaders of store {} in {:?}",
+                store_id,
+                timeout
+            );
+        }
+        Ok(())
+    }
+
     pub fn stop(&mut self) -> Result<()> {
         let store_id = self.store.get_id();
         self.stop_store(store_id)
@@ -11,46 +11,211 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::SocketAddr;
+use std::mem;
 
 use futures::sync::mpsc::{self, UnboundedSender};
 use futures::sync::oneshot::{self, Sender};
 use futures::{stream, Future, Sink, Stream};
 use grpc::{ChannelBuilder, Environment, WriteFlags};
+use protobuf::{self, Message};
+use rand;
+use kvproto::eraftpb::{Entry, MessageType};
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::tikvpb_grpc::TikvClient;
 
 const MAX_GRPC_RECV_MSG_LEN: usize = 10 * 1024 * 1024;
 const MAX_GRPC_SEND_MSG_LEN: usize = 10 * 1024 * 1024;
 const INITIAL_BUFFER_CAP: usize = 1024;
+// A batch is closed once it exceeds the size, so it never gets close to the
+// grpc message limit.
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
 
+// Messages with region id 0 are dropped by the stores that don't know
+// batching, so they are used to carry batches and the batch handshake.
+// The kind is told by the context of the inner raft message.
+const BATCH_HELLO_CONTEXT: &'static [u8] = b"batch_raft_msg_hello";
+const BATCH_ACK_CONTEXT: &'static [u8] = b"batch_raft_msg_ack";
+const BATCH_MSG_CONTEXT: &'static [u8] = b"batch_raft_msg";
+
+use util::HandyRwLock;
 use util::collections::HashMap;
+use util::security::SecurityManager;
 use super::{Config, Error, Result};
 use super::metrics::*;
 
+/// The batch handshake shared by `RaftClient` and the raft service.
+///
+/// A connection starts with a hello carrying its id, and the receiver
+/// answers it with an ack sent along with its own raft messages to the
+/// sender. A connection packs messages into batches only after its hello is
+/// answered, so whether the store accepts batches is learned again by every
+/// new connection.
+#[derive(Default)]
+pub struct BatchHandshake {
+    // store id -> the ids of the connections from the store whose hellos
+    // haven't been answered.
+    pending_acks: RwLock<HashMap<u64, Vec<u64>>>,
+    // connection id -> whether the connection can send batches.
+    conns: RwLock<HashMap<u64, Arc<AtomicBool>>>,
+}
+
+impl BatchHandshake {
+    fn register(&self, conn_id: u64) -> Arc<AtomicBool> {
+        let batch = Arc::new(AtomicBool::new(false));
+        self.conns.wl().insert(conn_id, batch.clone());
+        batch
+    }
+
+    fn unregister(&self, conn_id: u64) {
+        self.conns.wl().remove(&conn_id);
+    }
+
+    fn take_pending_acks(&self, store_id: u64) -> Vec<u64> {
+        if !self.pending_acks.rl().contains_key(&store_id) {
+            return vec![];
+        }
+        self.pending_acks.wl().remove(&store_id).unwrap_or_else(Vec::new)
+    }
+
+    /// Handles the hello or the ack, returns false if it's neither.
+    fn handle(&self, msg: &RaftMessage) -> bool {
+        let context = msg.get_message().get_context();
+        let conn_id = msg.get_message().get_index();
+        let store_id = msg.get_from_peer().get_store_id();
+        if context == BATCH_HELLO_CONTEXT {
+            self.pending_acks
+                .wl()
+                .entry(store_id)
+                .or_insert_with(Vec::new)
+                .push(conn_id);
+        } else if context == BATCH_ACK_CONTEXT {
+            if let Some(batch) = self.conns.rl().get(&conn_id) {
+                info!("server: store {} accepts batched raft messages", store_id);
+                batch.store(true, Ordering::SeqCst);
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+fn new_handshake_msg(context: &[u8], from_store_id: u64, conn_id: u64) -> RaftMessage {
+    let mut msg = RaftMessage::new();
+    msg.mut_from_peer().set_store_id(from_store_id);
+    msg.mut_message().set_msg_type(MessageType::MsgSnapStatus);
+    msg.mut_message().set_context(context.to_vec());
+    msg.mut_message().set_index(conn_id);
+    msg
+}
+
+/// Tells the receiver that the connection accepts batched raft messages.
+fn new_batch_hello(from_store_id: u64, conn_id: u64) -> RaftMessage {
+    new_handshake_msg(BATCH_HELLO_CONTEXT, from_store_id, conn_id)
+}
+
+/// Answers the hello of the connection.
+fn new_batch_ack(from_store_id: u64, conn_id: u64) -> RaftMessage {
+    new_handshake_msg(BATCH_ACK_CONTEXT, from_store_id, conn_id)
+}
+
+/// Packs the messages into one message, every message is an entry.
+fn pack_batch(msgs: Vec<RaftMessage>) -> RaftMessage {
+    let mut batch = RaftMessage::new();
+    batch.mut_message().set_msg_type(MessageType::MsgSnapStatus);
+    batch.mut_message().set_context(BATCH_MSG_CONTEXT.to_vec());
+    for msg in msgs {
+        let mut e = Entry::new();
+        e.set_data(msg.write_to_bytes().unwrap());
+        batch.mut_message().mut_entries().push(e);
+    }
+    batch
+}
+
+/// Handles a message with region id 0 received from the raft stream,
+/// returns the raft messages it carries. The handshake messages are passed
+/// to `handshake`.
+pub fn unpack_batch(mut msg: RaftMessage, handshake: &BatchHandshake) -> Vec<RaftMessage> {
+    if handshake.handle(&msg) {
+        return vec![];
+    }
+    if msg.get_message().get_context() != BATCH_MSG_CONTEXT {
+        return vec![];
+    }
+
+    let entries = msg.mut_message().take_entries().into_vec();
+    let mut msgs = Vec::with_capacity(entries.len());
+    for e in entries {
+        match protobuf::parse_from_bytes::<RaftMessage>(e.get_data()) {
+            Ok(m) => if m.get_region_id() != 0 {
+                msgs.push(m);
+            } else if !handshake.handle(&m) {
+                // The handshake may be packed too, but a batch is never packed
+                // into another one, so don't unpack it recursively.
+                warn!("server: drop nested batched raft message");
+            },
+            Err(e) => error!("server: failed to parse batched raft message: {:?}", e),
+        }
+    }
+    msgs
+}
+
+/// Splits the messages into batches bounded by `max_count` and `MAX_BATCH_BYTES`.
+fn split_batches(msgs: Vec<RaftMessage>, max_count: usize) -> Vec<Vec<RaftMessage>> {
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_bytes = 0;
+    for msg in msgs {
+        let size = msg.compute_size() as usize;
+        if !batch.is_empty() && (batch.len() >= max_count || batch_bytes + size > MAX_BATCH_BYTES) {
+            batches.push(mem::replace(&mut batch, vec![]));
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+        batch.push(msg);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
 struct Conn {
     stream: UnboundedSender<Vec<(RaftMessage, WriteFlags)>>,
     buffer: Option<Vec<(RaftMessage, WriteFlags)>>,
     store_id: u64,
     alive: Arc<AtomicBool>,
+    // Identifies the connection in the batch handshake.
+    id: u64,
+    hello_sent: bool,
+    // Whether the hello of the connection is answered.
+    batch: Arc<AtomicBool>,
 
     _client: TikvClient,
     _close: Sender<()>,
 }
 
 impl Conn {
-    fn new(env: Arc<Environment>, addr: SocketAddr, cfg: &Config, store_id: u64) -> Conn {
+    fn new(
+        env: Arc<Environment>,
+        addr: SocketAddr,
+        cfg: &Config,
+        security_mgr: &SecurityManager,
+        handshake: &BatchHandshake,
+        store_id: u64,
+    ) -> Conn {
         info!("server: new connection with tikv endpoint: {}", addr);
 
         let alive = Arc::new(AtomicBool::new(true));
         let alive1 = alive.clone();
-        let channel = ChannelBuilder::new(env)
+        let cb = ChannelBuilder::new(env)
             .stream_initial_window_size(cfg.grpc_stream_initial_window_size.0 as usize)
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
-            .max_send_message_len(MAX_GRPC_SEND_MSG_LEN)
-            .connect(&format!("{}", addr));
+            .max_send_message_len(MAX_GRPC_SEND_MSG_LEN);
+        let channel = security_mgr.connect_peer(cb, &format!("{}", addr));
         let client = TikvClient::new(channel);
         let (tx, rx) = mpsc::unbounded();
         let (tx_close, rx_close) = oneshot::channel();
@@ -82,11 +247,15 @@ impl Conn {
                 .map(|_| ())
                 .map_err(|_| ()),
         );
+        let id = rand::random();
         Conn {
             stream: tx,
             buffer: Some(Vec::with_capacity(INITIAL_BUFFER_CAP)),
             store_id: store_id,
             alive: alive1,
+            id: id,
+            hello_sent: false,
+            batch: handshake.register(id),
 
             _client: client,
             _close: tx_close,
@@ -95,44 +264,73 @@ impl Conn {
 }
 
 /// `RaftClient` is used for sending raft messages to other stores.
+///
+/// The messages buffered for a connection are packed into batches when
+/// flushed if the receiver has answered the hello of the connection, so
+/// batching adds no latency.
 pub struct RaftClient {
     env: Arc<Environment>,
     conns: HashMap<(SocketAddr, usize), Conn>,
     pub addrs: HashMap<u64, SocketAddr>,
+    handshake: Arc<BatchHandshake>,
     cfg: Config,
+    security_mgr: Arc<SecurityManager>,
 }
 
 impl RaftClient {
-    pub fn new(env: Arc<Environment>, cfg: Config) -> RaftClient {
+    pub fn new(
+        env: Arc<Environment>,
+        cfg: Config,
+        security_mgr: Arc<SecurityManager>,
+    ) -> RaftClient {
         RaftClient {
             env: env,
             conns: HashMap::default(),
             addrs: HashMap::default(),
+            handshake: Arc::new(BatchHandshake::default()),
             cfg: cfg,
+            security_mgr: security_mgr,
         }
     }
 
+    pub fn batch_handshake(&self) -> Arc<BatchHandshake> {
+        self.handshake.clone()
+    }
+
     fn get_conn(&mut self, addr: SocketAddr, region_id: u64, store_id: u64) -> &mut Conn {
         let index = region_id as usize % self.cfg.grpc_raft_conn_num;
         let cfg = &self.cfg;
         let env = &self.env;
-        self.conns
-            .entry((addr, index))
-            .or_insert_with(|| Conn::new(env.clone(), addr, cfg, store_id))
+        let security_mgr = &self.security_mgr;
+        let handshake = &self.handshake;
+        self.conns.entry((addr, index)).or_insert_with(|| {
+            Conn::new(env.clone(), addr, cfg, security_mgr, handshake, store_id)
+        })
     }
 
     pub fn send(&mut self, store_id: u64, addr: SocketAddr, msg: RaftMessage) -> Result<()> {
+        let from_store_id = msg.get_from_peer().get_store_id();
+        let acks = self.handshake.take_pending_acks(store_id);
         let conn = self.get_conn(addr, msg.region_id, store_id);
-        conn.buffer
-            .as_mut()
-            .unwrap()
-            .push((msg, WriteFlags::default().buffer_hint(true)));
+        let buffer = conn.buffer.as_mut().unwrap();
+        if !conn.hello_sent {
+            let hello = new_batch_hello(from_store_id, conn.id);
+            buffer.push((hello, WriteFlags::default().buffer_hint(true)));
+            conn.hello_sent = true;
+        }
+        for conn_id in acks {
+            let ack = new_batch_ack(from_store_id, conn_id);
+            buffer.push((ack, WriteFlags::default().buffer_hint(true)));
+        }
+        buffer.push((msg, WriteFlags::default().buffer_hint(true)));
         Ok(())
     }
 
 
     pub fn flush(&mut self) {
         let addrs = &mut self.addrs;
+        let handshake = &self.handshake;
+        let max_batch_size = self.cfg.raft_msg_max_batch_size;
         self.conns.retain(|&mut (addr, _), conn| {
             let store_id = conn.store_id;
             if !conn.alive.load(Ordering::SeqCst) {
@@ -141,6 +339,7 @@ impl RaftClient {
                         addrs.insert(store_id, addr_current);
                     }
                 }
+                handshake.unregister(conn.id);
                 return false;
             }
 
@@ -149,6 +348,16 @@ impl RaftClient {
             }
 
             let mut msgs = conn.buffer.take().unwrap();
+            if max_batch_size > 1 && conn.batch.load(Ordering::SeqCst) {
+                let raw = msgs.into_iter().map(|(m, _)| m).collect();
+                msgs = split_batches(raw, max_batch_size)
+                    .into_iter()
+                    .map(|b| {
+                        RAFT_MESSAGE_BATCH_SIZE_HISTOGRAM.observe(b.len() as f64);
+                        (pack_batch(b), WriteFlags::default().buffer_hint(true))
+                    })
+                    .collect();
+            }
             msgs.last_mut().unwrap().1 = WriteFlags::default();
             if let Err(e) = UnboundedSender::send(&conn.stream, msgs) {
                 error!(
@@ -162,6 +371,7 @@ impl RaftClient {
                         addrs.insert(store_id, addr_current);
                     }
                 }
+                handshake.unregister(conn.id);
                 return false;
             }
 
@@ -177,3 +387,84 @@ impl Drop for RaftClient {
         self.conns.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use kvproto::raft_serverpb::RaftMessage;
+
+    use super::*;
+
+    fn new_msg(region_id: u64, data_len: usize) -> RaftMessage {
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(region_id);
+        msg.mut_message().set_context(vec![0; data_len]);
+        msg
+    }
+
+    #[test]
+    fn test_batch_raft_message() {
+        let handshake = BatchHandshake::default();
+        let msgs: Vec<_> = (1..6).map(|id| new_msg(id, 10)).collect();
+        let batches = split_batches(msgs.clone(), 2);
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        let mut unpacked = vec![];
+        for b in batches {
+            unpacked.extend(unpack_batch(pack_batch(b), &handshake));
+        }
+        assert_eq!(unpacked, msgs);
+
+        // A large message is sent in a batch of its own.
+        let msgs = vec![new_msg(1, 10), new_msg(2, MAX_BATCH_BYTES), new_msg(3, 10)];
+        let batches = split_batches(msgs, 128);
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![1, 1, 1]
+        );
+
+        // Unknown messages are ignored.
+        assert!(unpack_batch(new_msg(0, 10), &handshake).is_empty());
+
+        // Nested batches are dropped.
+        let inner = pack_batch(vec![new_msg(2, 10)]);
+        let batch = pack_batch(vec![new_msg(1, 10), inner]);
+        assert_eq!(unpack_batch(batch, &handshake), vec![new_msg(1, 10)]);
+        let mut batch = pack_batch(vec![new_msg(3, 10)]);
+        for _ in 0..1000 {
+            batch = pack_batch(vec![batch]);
+        }
+        assert!(unpack_batch(batch, &handshake).is_empty());
+    }
+
+    #[test]
+    fn test_batch_handshake() {
+        let sender = BatchHandshake::default();
+        let receiver = BatchHandshake::default();
+        let batch = sender.register(10);
+
+        // The receiver answers the hello along with its messages to the sender,
+        // the handshake can be packed with other messages.
+        let msgs = pack_batch(vec![new_batch_hello(1, 10), new_msg(1, 10)]);
+        assert_eq!(unpack_batch(msgs, &receiver), vec![new_msg(1, 10)]);
+        assert_eq!(receiver.take_pending_acks(2), Vec::<u64>::new());
+        assert_eq!(receiver.take_pending_acks(1), vec![10]);
+        assert_eq!(receiver.take_pending_acks(1), Vec::<u64>::new());
+        assert!(!batch.load(Ordering::SeqCst));
+
+        let msgs = pack_batch(vec![new_batch_ack(2, 10), new_msg(1, 10)]);
+        assert_eq!(unpack_batch(msgs, &sender), vec![new_msg(1, 10)]);
+        assert!(batch.load(Ordering::SeqCst));
+
+        // A new connection learns it again, the ack of a closed connection is
+        // ignored.
+        sender.unregister(10);
+        let batch = sender.register(11);
+        assert!(unpack_batch(new_batch_ack(2, 10), &sender).is_empty());
+        assert!(!batch.load(Ordering::SeqCst));
+        assert!(sender.conns.rl().get(&10).is_none());
+        assert!(unpack_batch(new_batch_ack(2, 11), &sender).is_empty());
+        assert!(batch.load(Ordering::SeqCst));
+    }
+}
@@ -195,6 +195,9 @@ mod tests {
             store.set_address(format!("{}:{}", sock.ip(), sock.port()));
             Ok(store)
         }
+        fn get_store_async(&self, _: u64) -> PdFuture<metapb::Store> {
+            unimplemented!();
+        }
         fn get_cluster_config(&self) -> Result<metapb::Cluster> {
             unimplemented!();
         }
@@ -229,6 +232,14 @@ mod tests {
         fn report_split(&self, _: metapb::Region, _: metapb::Region) -> PdFuture<()> {
             unimplemented!();
         }
+        fn report_inconsistency(
+            &self,
+            _: metapb::Region,
+            _: metapb::Peer,
+            _: u64,
+        ) -> PdFuture<()> {
+            unimplemented!();
+        }
     }
 
     fn new_store(addr: &str, state: metapb::StoreState) -> metapb::Store {
@@ -19,6 +19,7 @@ use std::str::FromStr;
 use grpc::{ChannelBuilder, Environment, Server as GrpcServer, ServerBuilder};
 use kvproto::tikvpb_grpc::*;
 use util::worker::Worker;
+use util::security::SecurityManager;
 use storage::Storage;
 use raftstore::store::{SnapManager, SnapshotStatusMsg};
 
@@ -35,6 +36,7 @@ const MAX_GRPC_RECV_MSG_LEN: usize = 10 * 1024 * 1024;
 
 pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver + 'static> {
     env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
     // Grpc server.
     grpc_server: GrpcServer,
     local_addr: SocketAddr,
@@ -59,9 +61,12 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
         snapshot_status_sender: Sender<SnapshotStatusMsg>,
         resolver: S,
         snap_mgr: SnapManager,
+        security_mgr: Arc<SecurityManager>,
     ) -> Result<Server<T, S>> {
         let env = Arc::new(Environment::new(cfg.grpc_concurrency));
-        let raft_client = Arc::new(RwLock::new(RaftClient::new(env.clone(), cfg.clone())));
+        let raft_client = RaftClient::new(env.clone(), cfg.clone(), security_mgr.clone());
+        let batch_handshake = raft_client.batch_handshake();
+        let raft_client = Arc::new(RwLock::new(raft_client));
         let end_point_worker = Worker::new("end-point-worker");
         let snap_worker = Worker::new("snap-handler");
 
@@ -70,6 +75,8 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             end_point_worker.scheduler(),
             raft_router.clone(),
             snap_worker.scheduler(),
+            snap_mgr.clone(),
+            batch_handshake,
         );
         let addr = try!(SocketAddr::from_str(&cfg.addr));
         let ip = format!("{}", addr.ip());
@@ -79,10 +86,10 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             .max_receive_message_len(MAX_GRPC_RECV_MSG_LEN)
             .max_send_message_len(region_split_size as usize * 4)
             .build_args();
+        let sb = ServerBuilder::new(env.clone()).register_service(create_tikv(h));
         let grpc_server = try!(
-            ServerBuilder::new(env.clone())
-                .register_service(create_tikv(h))
-                .bind(ip, addr.port())
+            security_mgr
+                .bind(sb, &ip, addr.port())
                 .channel_args(channel_args)
                 .build()
         );
@@ -102,6 +109,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
 
         let svr = Server {
             env: env.clone(),
+            security_mgr: security_mgr,
             grpc_server: grpc_server,
             local_addr: addr,
             trans: trans,
@@ -124,6 +132,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             self.storage.get_engine(),
             self.end_point_worker.scheduler(),
             cfg.end_point_concurrency,
+            self.storage.get_read_stats(),
         );
         box_try!(
             self.end_point_worker
@@ -133,6 +142,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver + 'static> Server<T, S> {
             self.env.clone(),
             self.snap_mgr.clone(),
             self.raft_router.clone(),
+            self.security_mgr.clone(),
         );
         box_try!(self.snap_worker.start(snap_runner));
         self.grpc_server.start();
@@ -244,6 +254,7 @@ mod tests {
             snapshot_status_sender,
             MockResolver { addr: addr.clone() },
             SnapManager::new("", None),
+            Arc::new(SecurityManager::default()),
         ).unwrap();
         *addr.lock().unwrap() = Some(server.listening_addr());
 
@@ -19,6 +19,7 @@ use std::boxed::FnBox;
 use std::time::Instant;
 use std::result;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use threadpool::ThreadPool;
 use mio::Token;
@@ -28,11 +29,12 @@ use kvproto::raft_serverpb::SnapshotChunk;
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::tikvpb_grpc::TikvClient;
 
-use raftstore::store::{SnapEntry, SnapKey, SnapManager, Snapshot};
+use raftstore::store::{get_snapshot_file_size, SnapEntry, SnapKey, SnapManager, Snapshot};
 use util::worker::Runnable;
 use util::buf::PipeBuffer;
 use util::collections::{HashMap, HashMapEntry as Entry};
 use util::HandyRwLock;
+use util::security::SecurityManager;
 
 use super::metrics::*;
 use super::{Error, Result};
@@ -44,13 +46,14 @@ const DEFAULT_SENDER_POOL_SIZE: usize = 3;
 
 /// `Task` that `Runner` can handle.
 ///
-/// `Register` register a pending snapshot file with token;
+/// `Register` register a pending snapshot file with token, the callback is
+/// called with an error if the snapshot is rejected;
 /// `Write` write data to snapshot file;
 /// `Close` save the snapshot file;
 /// `Discard` discard all the unsaved changes made to snapshot file;
 /// `SendTo` send the snapshot file to specified address.
 pub enum Task {
-    Register(Token, RaftMessage),
+    Register(Token, RaftMessage, Callback),
     Write(Token, PipeBuffer),
     Close(Token),
     Discard(Token),
@@ -64,7 +67,7 @@ pub enum Task {
 impl Display for Task {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match *self {
-            Task::Register(token, ref meta) => write!(f, "Register {:?} token: {:?}", meta, token),
+            Task::Register(token, ref meta, _) => write!(f, "Register {:?} token: {:?}", meta, token),
             Task::Write(token, _) => write!(f, "Write snap for {:?}", token),
             Task::Close(token) => write!(f, "Close file {:?}", token),
             Task::Discard(token) => write!(f, "Discard file {:?}", token),
@@ -107,6 +110,7 @@ impl Iterator for SnapChunk {
 fn send_snap(
     env: Arc<Environment>,
     mgr: SnapManager,
+    security_mgr: &SecurityManager,
     addr: SocketAddr,
     msg: RaftMessage,
 ) -> Result<()> {
@@ -115,9 +119,14 @@ fn send_snap(
 
     let send_timer = SEND_SNAP_HISTOGRAM.start_coarse_timer();
 
-    let key = {
+    let (key, meta_only) = {
         let snap = msg.get_message().get_snapshot();
-        try!(SnapKey::from_snap(snap))
+        // A snapshot without files only carries the meta, e.g. the one sent
+        // to a witness.
+        (
+            try!(SnapKey::from_snap(snap)),
+            try!(get_snapshot_file_size(snap)) == 0,
+        )
     };
     mgr.register(key.clone(), SnapEntry::Sending);
     defer!({
@@ -127,7 +136,11 @@ fn send_snap(
     if !s.exists() {
         return Err(box_err!("missing snap file: {:?}", s.path()));
     }
-    let total_size = try!(s.total_size());
+    let total_size = if meta_only {
+        0
+    } else {
+        try!(s.total_size())
+    };
 
     // snapshot file has been validated when created, so no need to validate again.
     let s = Arc::new(RwLock::new(s));
@@ -152,7 +165,7 @@ fn send_snap(
         first.chain(rests)
     };
 
-    let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+    let channel = security_mgr.connect_peer(ChannelBuilder::new(env), &format!("{}", addr));
     let client = TikvClient::new(channel);
     let (sink, receiver) = client.snapshot();
     let send = stream::iter(chunks.into_iter()).forward(sink);
@@ -165,7 +178,10 @@ fn send_snap(
                 total_size,
                 timer.elapsed()
             );
-            s.wl().delete();
+            // The snapshot files may still be sent to other peers.
+            if !meta_only {
+                s.wl().delete();
+            }
             Ok(())
         })
         .wait()
@@ -181,16 +197,25 @@ pub struct Runner<R: RaftStoreRouter + 'static> {
     files: HashMap<Token, (Box<Snapshot>, RaftMessage)>,
     pool: ThreadPool,
     raft_router: R,
+    security_mgr: Arc<SecurityManager>,
+    sending_count: Arc<AtomicUsize>,
 }
 
 impl<R: RaftStoreRouter + 'static> Runner<R> {
-    pub fn new(env: Arc<Environment>, snap_mgr: SnapManager, r: R) -> Runner<R> {
+    pub fn new(
+        env: Arc<Environment>,
+        snap_mgr: SnapManager,
+        r: R,
+        security_mgr: Arc<SecurityManager>,
+    ) -> Runner<R> {
         Runner {
             env: env,
             snap_mgr: snap_mgr,
             files: map![],
             pool: ThreadPool::new_with_name(thd_name!("snap sender"), DEFAULT_SENDER_POOL_SIZE),
             raft_router: r,
+            security_mgr: security_mgr,
+            sending_count: Arc::new(AtomicUsize::new(0)),
         }
     }
 }
@@ -198,13 +223,29 @@ impl<R: RaftStoreRouter + 'static> Runner<R> {
 impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
     fn run(&mut self, task: Task) {
         match task {
-            Task::Register(token, meta) => {
+            Task::Register(token, meta, cb) => {
                 SNAP_TASK_COUNTER.with_label_values(&["register"]).inc();
+                let limit = self.snap_mgr.max_recv_snap_count();
+                if limit > 0 && self.files.len() >= limit {
+                    SNAP_TASK_COUNTER
+                        .with_label_values(&["recv_rejected"])
+                        .inc();
+                    warn!(
+                        "too many receiving snapshot tasks, reject snap {:?} token {:?}",
+                        meta,
+                        token
+                    );
+                    cb(Err(Error::SnapRejected(
+                        format!("too many receiving snapshot tasks: {}", limit),
+                    )));
+                    return;
+                }
                 let mgr = self.snap_mgr.clone();
                 let key = match SnapKey::from_snap(meta.get_message().get_snapshot()) {
                     Ok(k) => k,
                     Err(e) => {
                         error!("failed to create snap key for token {:?}: {:?}", token, e);
+                        cb(Err(box_err!("failed to create snap key: {:?}", e)));
                         return;
                     }
                 };
@@ -221,11 +262,13 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                             if let Err(e) = self.raft_router.send_raft_msg(meta) {
                                 error!("send snapshot for key {} token {:?}: {:?}", key, token, e);
                             }
+                            cb(Ok(()));
                             return;
                         }
                         debug!("begin to receive snap {:?}", meta);
                         mgr.register(key, SnapEntry::Receiving);
                         self.files.insert(token, (snap, meta));
+                        cb(Ok(()));
                     }
                     Err(e) => {
                         error!(
@@ -233,7 +276,7 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
                             token,
                             e
                         );
-                        return;
+                        cb(Err(box_err!("failed to create snapshot file: {:?}", e)));
                     }
                 }
             }
@@ -292,10 +335,27 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
             }
             Task::SendTo { addr, msg, cb } => {
                 SNAP_TASK_COUNTER.with_label_values(&["send"]).inc();
+                let limit = self.snap_mgr.max_send_snap_count();
+                if limit > 0 && self.sending_count.load(Ordering::SeqCst) >= limit {
+                    SNAP_TASK_COUNTER
+                        .with_label_values(&["send_rejected"])
+                        .inc();
+                    warn!(
+                        "too many sending snapshot tasks, drop SendTo Snap[to: {}, snap: {:?}]",
+                        addr,
+                        msg
+                    );
+                    cb(Err(box_err!("too many sending snapshot tasks")));
+                    return;
+                }
                 let env = self.env.clone();
                 let mgr = self.snap_mgr.clone();
+                let security_mgr = self.security_mgr.clone();
+                let sending_count = self.sending_count.clone();
+                sending_count.fetch_add(1, Ordering::SeqCst);
                 self.pool.execute(move || {
-                    let res = send_snap(env, mgr, addr, msg);
+                    let res = send_snap(env, mgr, &security_mgr, addr, msg);
+                    sending_count.fetch_sub(1, Ordering::SeqCst);
                     if res.is_err() {
                         error!("failed to send snap to {}: {:?}", addr, res);
                     }
@@ -305,3 +365,91 @@ impl<R: RaftStoreRouter + 'static> Runnable<Task> for Runner<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use mio::Token;
+    use grpc::Environment;
+    use protobuf::Message;
+    use tempdir::TempDir;
+    use kvproto::raft_serverpb::{RaftMessage, RaftSnapshotData, SnapshotCFFile};
+
+    use raftstore::Result as RaftStoreResult;
+    use raftstore::store::{Msg as StoreMsg, SnapManagerBuilder};
+    use storage::{CF_DEFAULT, CF_LOCK, CF_WRITE};
+    use util::worker::Runnable;
+    use util::security::SecurityManager;
+    use super::super::transport::RaftStoreRouter;
+    use super::super::Error;
+    use super::*;
+
+    #[derive(Clone)]
+    struct NoopRouter;
+
+    impl RaftStoreRouter for NoopRouter {
+        fn send(&self, _: StoreMsg) -> RaftStoreResult<()> {
+            Ok(())
+        }
+
+        fn try_send(&self, _: StoreMsg) -> RaftStoreResult<()> {
+            Ok(())
+        }
+    }
+
+    fn new_snap_msg(region_id: u64) -> RaftMessage {
+        let mut data = RaftSnapshotData::new();
+        data.mut_region().set_id(region_id);
+        for cf in &[CF_DEFAULT, CF_LOCK, CF_WRITE] {
+            let mut f = SnapshotCFFile::new();
+            f.set_cf(cf.to_string());
+            data.mut_meta().mut_cf_files().push(f);
+        }
+        let mut msg = RaftMessage::new();
+        msg.set_region_id(region_id);
+        {
+            let snap = msg.mut_message().mut_snapshot();
+            snap.mut_metadata().set_index(10);
+            snap.mut_metadata().set_term(5);
+            snap.set_data(data.write_to_bytes().unwrap());
+        }
+        msg
+    }
+
+    fn register(runner: &mut Runner<NoopRouter>, token: usize, region_id: u64) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let cb: Callback = box move |res| tx.send(res).unwrap();
+        runner.run(Task::Register(Token(token), new_snap_msg(region_id), cb));
+        rx.recv_timeout(Duration::from_secs(3)).unwrap()
+    }
+
+    #[test]
+    fn test_reject_receiving_snapshot() {
+        let dir = TempDir::new("test-reject-receiving-snapshot").unwrap();
+        let snap_mgr = SnapManagerBuilder::default()
+            .max_recv_snap_count(1)
+            .build(dir.path().to_str().unwrap(), None);
+        snap_mgr.init().unwrap();
+        let mut runner = Runner::new(
+            Arc::new(Environment::new(1)),
+            snap_mgr,
+            NoopRouter,
+            Arc::new(SecurityManager::default()),
+        );
+
+        register(&mut runner, 1, 1).unwrap();
+        // the second one exceeds the limit, the callback tells the receiver so
+        // the RPC can fail with RESOURCE_EXHAUSTED.
+        match register(&mut runner, 2, 2) {
+            Err(Error::SnapRejected(_)) => {}
+            res => panic!("expect rejected, but got {:?}", res),
+        }
+
+        runner.run(Task::Discard(Token(1)));
+        register(&mut runner, 3, 3).unwrap();
+        runner.run(Task::Discard(Token(3)));
+    }
+}
@@ -0,0 +1,830 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A tiny HTTP server for the status of the node, which serves:
+//!
+//! - `/metrics`: the metrics in the Prometheus text format, for scraping.
+//! - `/status`: the health of the node, which is 503 if the raftstore doesn't
+//!   handle messages in time, or any registered health check fails.
+//! - `/config`: the effective config as JSON.
+//! - `/debug/pprof/heap`: a jemalloc heap profile, dumped on demand.
+//! - `/debug/hot-keys?region-id=&limit=`: the sampled hottest read keys of
+//!   the region, hex encoded, with their estimated read counts.
+//!
+//! And the admin operations of the raftstore, which are POST requests with
+//! the arguments in the query string:
+//!
+//! - `/debug/region/split?region-id=&version=&conf-ver=&keys=`: splits the
+//!   region at the hex encoded keys, separated by comma.
+//! - `/debug/drain-leaders?timeout-secs=`: transfers all the leaders away
+//!   and stops accepting leaders until restart, before shutting down the
+//!   node. Waits until all leaders are drained or the timeout is reached.
+//! - `/debug/unsafe-recover?stores=&apply=`: removes the peers on the failed
+//!   stores, separated by comma, from the regions of the store. Only reports
+//!   the affected regions unless `apply` is true.
+//!
+//! The server speaks plain HTTP. When TLS is configured for the grpc servers,
+//! the admin operations are only accepted from the loopback addresses, so
+//! they can't be issued remotely without a client certificate.
+//!
+//! Every connection carries one request and is closed after the response,
+//! which is all the scrapers and `curl` need. The connections are served by a
+//! small thread pool, so a slow admin operation doesn't block the scrapers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use kvproto::metapb::{Region, RegionEpoch};
+use prometheus::{self, Encoder, TextEncoder};
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde_json;
+use tempdir::TempDir;
+use url::form_urlencoded;
+
+use config::TiKvConfig;
+use raftstore::Result as RaftStoreResult;
+use raftstore::store::{DrainCallback, HealthCheckCallback, Msg, RecoveryCallback,
+                       SplitCallback};
+use raftstore::store::unsafe_recovery::RegionRecovery;
+use storage::ReadStats;
+use util;
+use util::threadpool::{Context, ContextFactory, ThreadPool, DEFAULT_TASKS_PER_TICK};
+use super::Result;
+use super::transport::{RaftStoreRouter, ServerRaftStoreRouter};
+
+const MAX_REQUEST_HEADER_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT_SECS: u64 = 5;
+const ADMIN_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_HOT_KEYS_LIMIT: usize = 10;
+const HEALTH_CHECK_TIMEOUT_MS: u64 = 2000;
+const WORKER_COUNT: usize = 4;
+
+/// Dumps a heap profile to the path.
+pub type HeapProfiler = Box<Fn(&str) -> result::Result<(), String> + Send + Sync>;
+
+/// Checks a component of the node, returns the reason if it's unhealthy.
+pub type HealthCheck = Box<Fn() -> result::Result<(), String> + Send + Sync>;
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct StatusInfo {
+    status: &'static str,
+    version: &'static str,
+    git_hash: String,
+    uptime_secs: u64,
+    // The unhealthy components and the reasons.
+    failures: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RegionInfo {
+    id: u64,
+    start_key: String,
+    end_key: String,
+    version: u64,
+    conf_ver: u64,
+}
+
+impl<'a> From<&'a Region> for RegionInfo {
+    fn from(region: &Region) -> RegionInfo {
+        RegionInfo {
+            id: region.get_id(),
+            start_key: region.get_start_key().to_hex(),
+            end_key: region.get_end_key().to_hex(),
+            version: region.get_region_epoch().get_version(),
+            conf_ver: region.get_region_epoch().get_conf_ver(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct HotKeyInfo {
+    key: String,
+    read_count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RecoveryInfo {
+    region_id: u64,
+    removed_peers: Vec<u64>,
+    remaining_peers: Vec<u64>,
+    conf_ver: u64,
+}
+
+impl<'a> From<&'a RegionRecovery> for RecoveryInfo {
+    fn from(r: &RegionRecovery) -> RecoveryInfo {
+        RecoveryInfo {
+            region_id: r.region.get_id(),
+            removed_peers: r.removed_peers().iter().map(|p| p.get_id()).collect(),
+            remaining_peers: r.region.get_peers().iter().map(|p| p.get_id()).collect(),
+            conf_ver: r.region.get_region_epoch().get_conf_ver(),
+        }
+    }
+}
+
+struct Response {
+    code: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new<S: Into<String>>(code: u16, content_type: S, body: Vec<u8>) -> Response {
+        Response {
+            code: code,
+            content_type: content_type.into(),
+            body: body,
+        }
+    }
+
+    fn text<S: Into<String>>(code: u16, body: S) -> Response {
+        Response::new(code, "text/plain", body.into().into_bytes())
+    }
+
+    fn json<T: ::serde::Serialize>(value: &T) -> Response {
+        Response::new(200, "application/json", serde_json::to_vec(value).unwrap())
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let reason = match self.code {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        try!(write!(
+            w,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.code,
+            reason,
+            self.content_type,
+            self.body.len()
+        ));
+        try!(w.write_all(&self.body));
+        w.flush()
+    }
+}
+
+type Params = HashMap<String, String>;
+
+fn parse_params(query: &str) -> Params {
+    form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn get_param<T: FromStr>(params: &Params, name: &str) -> result::Result<T, Response> {
+    match params.get(name).map(|v| v.parse()) {
+        Some(Ok(v)) => Ok(v),
+        Some(Err(_)) => Err(Response::text(400, format!("invalid {}", name))),
+        None => Err(Response::text(400, format!("missing {}", name))),
+    }
+}
+
+fn wait_result<T>(rx: mpsc::Receiver<T>) -> result::Result<T, Response> {
+    rx.recv_timeout(Duration::from_secs(ADMIN_TIMEOUT_SECS))
+        .map_err(|e| Response::text(500, format!("failed to wait for the result: {:?}", e)))
+}
+
+struct Handler {
+    config: String,
+    start_time: Instant,
+    heap_profiler: Option<HeapProfiler>,
+    raft_router: Option<Mutex<ServerRaftStoreRouter>>,
+    read_stats: Option<Arc<ReadStats>>,
+    health_checks: Vec<(String, HealthCheck)>,
+    // Accepts the admin operations only from the loopback addresses.
+    local_admin_only: bool,
+}
+
+impl Handler {
+    fn handle(&self, method: &str, uri: &str, peer: IpAddr) -> Response {
+        let mut parts = uri.splitn(2, '?');
+        let path = parts.next().unwrap();
+        let params = parse_params(parts.next().unwrap_or(""));
+        let expected_method = match path {
+            "/metrics" | "/status" | "/config" | "/debug/pprof/heap" | "/debug/hot-keys" => "GET",
+            "/debug/region/split" | "/debug/drain-leaders" | "/debug/unsafe-recover" => "POST",
+            _ => return Response::text(404, format!("{} is not found", path)),
+        };
+        if method != expected_method {
+            return Response::text(405, format!("method {} is not allowed", method));
+        }
+        if method == "POST" && self.local_admin_only && !peer.is_loopback() {
+            return Response::text(403, format!("{} is only allowed from localhost", path));
+        }
+        let res = match path {
+            "/metrics" => Ok(self.metrics()),
+            "/status" => Ok(self.status()),
+            "/config" => Ok(Response::new(
+                200,
+                "application/json",
+                self.config.clone().into_bytes(),
+            )),
+            "/debug/pprof/heap" => Ok(self.heap_profile()),
+            "/debug/hot-keys" => self.hot_keys(&params),
+            "/debug/region/split" => self.split_region(&params),
+            "/debug/drain-leaders" => self.drain_leaders(&params),
+            "/debug/unsafe-recover" => self.unsafe_recover(&params),
+            _ => unreachable!(),
+        };
+        res.unwrap_or_else(|resp| resp)
+    }
+
+    fn raft_router(&self) -> result::Result<ServerRaftStoreRouter, Response> {
+        match self.raft_router {
+            Some(ref router) => Ok(router.lock().unwrap().clone()),
+            None => Err(Response::text(503, "raftstore is not available")),
+        }
+    }
+
+    fn split_region(&self, params: &Params) -> result::Result<Response, Response> {
+        let region_id = try!(get_param(params, "region-id"));
+        let mut epoch = RegionEpoch::new();
+        epoch.set_version(try!(get_param(params, "version")));
+        epoch.set_conf_ver(try!(get_param(params, "conf-ver")));
+        let keys: String = try!(get_param(params, "keys"));
+        let mut split_keys = vec![];
+        for key in keys.split(',') {
+            match key.from_hex() {
+                Ok(key) => split_keys.push(key),
+                Err(_) => return Err(Response::text(400, format!("invalid key {}", key))),
+            }
+        }
+
+        let router = try!(self.raft_router());
+        let (tx, rx) = mpsc::channel();
+        let cb: SplitCallback = box move |res: RaftStoreResult<Vec<Region>>| {
+            let _ = tx.send(res);
+        };
+        if let Err(e) = router.split_region(region_id, epoch, split_keys, cb) {
+            return Err(Response::text(500, format!("failed to split region: {:?}", e)));
+        }
+        match try!(wait_result(rx)) {
+            Ok(regions) => {
+                let regions: Vec<RegionInfo> = regions.iter().map(RegionInfo::from).collect();
+                Ok(Response::json(&regions))
+            }
+            Err(e) => Err(Response::text(500, format!("failed to split region: {:?}", e))),
+        }
+    }
+
+    fn hot_keys(&self, params: &Params) -> result::Result<Response, Response> {
+        let region_id = try!(get_param(params, "region-id"));
+        let limit = match params.get("limit") {
+            Some(_) => try!(get_param(params, "limit")),
+            None => DEFAULT_HOT_KEYS_LIMIT,
+        };
+        let read_stats = match self.read_stats {
+            Some(ref stats) => stats,
+            None => return Err(Response::text(503, "read stats are not available")),
+        };
+        let keys: Vec<HotKeyInfo> = read_stats
+            .hot_keys(region_id, limit)
+            .into_iter()
+            .map(|(key, count)| {
+                HotKeyInfo {
+                    key: key.to_hex(),
+                    read_count: count,
+                }
+            })
+            .collect();
+        Ok(Response::json(&keys))
+    }
+
+    fn drain_leaders(&self, params: &Params) -> result::Result<Response, Response> {
+        let timeout = match params.get("timeout-secs") {
+            Some(_) => try!(get_param(params, "timeout-secs")),
+            None => ADMIN_TIMEOUT_SECS,
+        };
+        let router = try!(self.raft_router());
+        let (tx, rx) = mpsc::channel();
+        let cb: DrainCallback = box move || {
+            let _ = tx.send(());
+        };
+        if let Err(e) = router.try_send(Msg::DrainLeaders { callback: cb }) {
+            return Err(Response::text(500, format!("failed to drain leaders: {:?}", e)));
+        }
+        match rx.recv_timeout(Duration::from_secs(timeout)) {
+            Ok(()) => Ok(Response::text(200, "all leaders are drained")),
+            Err(_) => Err(Response::text(
+                500,
+                format!("failed to drain all leaders in {}s", timeout),
+            )),
+        }
+    }
+
+    fn unsafe_recover(&self, params: &Params) -> result::Result<Response, Response> {
+        let stores: String = try!(get_param(params, "stores"));
+        let mut store_ids = vec![];
+        for id in stores.split(',') {
+            match id.trim().parse() {
+                Ok(id) => store_ids.push(id),
+                Err(_) => return Err(Response::text(400, format!("invalid store id {}", id))),
+            }
+        }
+        let apply = params.get("apply").map_or(false, |v| v == "true");
+
+        let router = try!(self.raft_router());
+        let (tx, rx) = mpsc::channel();
+        let cb: RecoveryCallback = box move |res: RaftStoreResult<Vec<RegionRecovery>>| {
+            let _ = tx.send(res);
+        };
+        let msg = Msg::RemoveFailedStores {
+            store_ids: store_ids,
+            dry_run: !apply,
+            callback: cb,
+        };
+        if let Err(e) = router.try_send(msg) {
+            return Err(Response::text(500, format!("failed to recover: {:?}", e)));
+        }
+        match try!(wait_result(rx)) {
+            Ok(recoveries) => {
+                let infos: Vec<RecoveryInfo> = recoveries.iter().map(RecoveryInfo::from).collect();
+                Ok(Response::json(&infos))
+            }
+            Err(e) => Err(Response::text(500, format!("failed to recover: {:?}", e))),
+        }
+    }
+
+    fn metrics(&self) -> Response {
+        let encoder = TextEncoder::new();
+        let mut buf = vec![];
+        match encoder.encode(&prometheus::gather(), &mut buf) {
+            Ok(_) => Response::new(200, encoder.format_type(), buf),
+            Err(e) => Response::text(500, format!("failed to encode metrics: {:?}", e)),
+        }
+    }
+
+    // Checks the raftstore handles a message in time.
+    fn check_raftstore(&self) -> result::Result<(), String> {
+        let router = try!(self.raft_router().map_err(|_| "raftstore is not available"));
+        let (tx, rx) = mpsc::channel();
+        let cb: HealthCheckCallback = box move || {
+            let _ = tx.send(());
+        };
+        if let Err(e) = router.try_send(Msg::HealthCheck { callback: cb }) {
+            return Err(format!("failed to send to raftstore: {:?}", e));
+        }
+        rx.recv_timeout(Duration::from_millis(HEALTH_CHECK_TIMEOUT_MS))
+            .map_err(|_| format!("raftstore doesn't respond in {}ms", HEALTH_CHECK_TIMEOUT_MS))
+    }
+
+    fn status(&self) -> Response {
+        let mut failures = BTreeMap::new();
+        if let Err(e) = self.check_raftstore() {
+            failures.insert("raftstore".to_owned(), e);
+        }
+        for &(ref component, ref check) in &self.health_checks {
+            if let Err(e) = check() {
+                failures.insert(component.clone(), e);
+            }
+        }
+        let healthy = failures.is_empty();
+        let (hash, _, _, _) = util::build_info();
+        let info = StatusInfo {
+            status: if healthy { "ok" } else { "unhealthy" },
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: hash,
+            uptime_secs: self.start_time.elapsed().as_secs(),
+            failures: failures,
+        };
+        let code = if healthy { 200 } else { 503 };
+        Response::new(code, "application/json", serde_json::to_vec(&info).unwrap())
+    }
+
+    fn heap_profile(&self) -> Response {
+        let profiler = match self.heap_profiler {
+            Some(ref p) => p,
+            None => return Response::text(404, "heap profiling is not supported"),
+        };
+        let dir = match TempDir::new("heap_profile") {
+            Ok(dir) => dir,
+            Err(e) => return Response::text(500, format!("failed to create temp dir: {}", e)),
+        };
+        let path = dir.path().join("heap.prof");
+        let path = path.to_str().unwrap();
+        if let Err(e) = profiler(path) {
+            return Response::text(500, format!("failed to dump heap profile: {}", e));
+        }
+        let mut buf = vec![];
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            return Response::text(500, format!("failed to read heap profile: {}", e));
+        }
+        Response::new(200, "application/octet-stream", buf)
+    }
+}
+
+// Reads the request line and headers, returns the method and the path.
+fn read_request(stream: &mut TcpStream) -> io::Result<Option<(String, String)>> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_HEADER_SIZE {
+        let n = try!(stream.read(&mut chunk));
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/") => {
+            Ok(Some((method.to_owned(), path.to_owned())))
+        }
+        _ => Ok(None),
+    }
+}
+
+struct DummyContext {}
+
+impl Context for DummyContext {
+    fn on_task_started(&mut self) {}
+    fn on_task_finished(&mut self) {}
+    fn on_tick(&mut self) {}
+}
+
+struct DummyContextFactory {}
+
+impl ContextFactory<DummyContext> for DummyContextFactory {
+    fn create(&self) -> DummyContext {
+        DummyContext {}
+    }
+}
+
+/// Returns the address to connect to the listener on `addr`, which is the
+/// loopback address if it listens on all the addresses.
+pub fn connectable_addr(mut addr: SocketAddr) -> SocketAddr {
+    let loopback = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            Some(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)))
+        }
+        _ => None,
+    };
+    if let Some(ip) = loopback {
+        addr.set_ip(ip);
+    }
+    addr
+}
+
+fn handle_connection(handler: &Handler, mut stream: TcpStream) -> io::Result<()> {
+    let peer = try!(stream.peer_addr()).ip();
+    let resp = match try!(read_request(&mut stream)) {
+        Some((method, path)) => handler.handle(&method, &path, peer),
+        None => Response::text(400, "bad request"),
+    };
+    resp.write_to(&mut stream)
+}
+
+/// `StatusServer` serves the status of the node over HTTP.
+pub struct StatusServer {
+    handler: Arc<Handler>,
+    addr: Option<SocketAddr>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatusServer {
+    pub fn new(cfg: &TiKvConfig) -> StatusServer {
+        StatusServer {
+            handler: Arc::new(Handler {
+                config: serde_json::to_string_pretty(cfg).unwrap(),
+                start_time: Instant::now(),
+                heap_profiler: None,
+                raft_router: None,
+                read_stats: None,
+                health_checks: vec![],
+                local_admin_only: !cfg.security.ca_path.is_empty(),
+            }),
+            addr: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        }
+    }
+
+    /// Enables `/debug/pprof/heap`, it should be called before `start`.
+    pub fn set_heap_profiler(&mut self, profiler: HeapProfiler) {
+        Arc::get_mut(&mut self.handler).unwrap().heap_profiler = Some(profiler);
+    }
+
+    /// Enables the admin operations of the raftstore, it should be called
+    /// before `start`.
+    pub fn set_raft_router(&mut self, router: ServerRaftStoreRouter) {
+        Arc::get_mut(&mut self.handler).unwrap().raft_router = Some(Mutex::new(router));
+    }
+
+    /// Enables `/debug/hot-keys`, it should be called before `start`.
+    pub fn set_read_stats(&mut self, read_stats: Arc<ReadStats>) {
+        Arc::get_mut(&mut self.handler).unwrap().read_stats = Some(read_stats);
+    }
+
+    /// Adds a check of the component to `/status`, it should be called
+    /// before `start`. The raftstore is always checked.
+    pub fn add_health_check<S: Into<String>>(&mut self, component: S, check: HealthCheck) {
+        let handler = Arc::get_mut(&mut self.handler).unwrap();
+        handler.health_checks.push((component.into(), check));
+    }
+
+    pub fn start(&mut self, addr: &str) -> Result<()> {
+        let addr = try!(SocketAddr::from_str(addr));
+        let listener = try!(TcpListener::bind(addr));
+        let addr = try!(listener.local_addr());
+        let handler = self.handler.clone();
+        let stopped = self.stopped.clone();
+        let handle = try!(
+            thread::Builder::new()
+                .name(thd_name!("status-server"))
+                .spawn(move || {
+                    let mut pool = ThreadPool::new(
+                        thd_name!("status-server-worker"),
+                        WORKER_COUNT,
+                        DEFAULT_TASKS_PER_TICK,
+                        DummyContextFactory {},
+                    );
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!("status server failed to accept a connection: {}", e);
+                                continue;
+                            }
+                        };
+                        let handler = handler.clone();
+                        pool.execute(move |_| {
+                            if let Err(e) = handle_connection(&handler, stream) {
+                                warn!("status server failed to serve a request: {}", e);
+                            }
+                        });
+                    }
+                    if let Err(e) = pool.stop() {
+                        error!("failed to stop status server workers: {}", e);
+                    }
+                })
+        );
+        info!("status server is listening on {}", addr);
+        self.addr = Some(addr);
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    pub fn listening_addr(&self) -> SocketAddr {
+        self.addr.unwrap()
+    }
+
+    pub fn stop(&mut self) {
+        let handle = match self.handle.take() {
+            Some(h) => h,
+            None => return,
+        };
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the listener blocked in accepting.
+        if let Err(e) = TcpStream::connect(connectable_addr(self.listening_addr())) {
+            error!("failed to wake up status server: {}", e);
+            return;
+        }
+        if let Err(e) = handle.join() {
+            error!("failed to join status server: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+
+    use serde_json::{self, Value};
+
+    use config::TiKvConfig;
+    use storage::FlowStatistics;
+    use super::*;
+
+    fn request(server: &StatusServer, req: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(server.listening_addr()).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        let pos = resp.find("\r\n\r\n").unwrap();
+        let code = resp.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (code, resp[pos + 4..].to_owned())
+    }
+
+    fn get(server: &StatusServer, path: &str) -> (u16, String) {
+        request(server, &format!("GET {} HTTP/1.1\r\nHost: tikv\r\n\r\n", path))
+    }
+
+    fn post(server: &StatusServer, path: &str) -> (u16, String) {
+        request(server, &format!("POST {} HTTP/1.1\r\nHost: tikv\r\n\r\n", path))
+    }
+
+    #[test]
+    fn test_status_server() {
+        let mut cfg = TiKvConfig::default();
+        cfg.server.status_addr = "127.0.0.1:0".to_owned();
+        let mut server = StatusServer::new(&cfg);
+        server.add_health_check("grpc", box || Ok(()));
+        server.add_health_check("engine", box || Err("engine is closed".to_owned()));
+        server.start(&cfg.server.status_addr).unwrap();
+
+        let (code, body) = get(&server, "/metrics");
+        assert_eq!(code, 200);
+        assert!(body.is_empty() || body.contains("# TYPE"), "{}", body);
+
+        // The raft router is not set and a check fails.
+        let (code, body) = get(&server, "/status?verbose=1");
+        assert_eq!(code, 503);
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["status"], "unhealthy");
+        let failures = status["failures"].as_object().unwrap();
+        assert_eq!(failures.len(), 2, "{}", body);
+        assert_eq!(failures["raftstore"], "raftstore is not available");
+        assert_eq!(failures["engine"], "engine is closed");
+
+        let (code, body) = get(&server, "/config");
+        assert_eq!(code, 200);
+        let config: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(config["server"]["status-addr"], "127.0.0.1:0");
+        assert_eq!(config, serde_json::to_value(&cfg).unwrap());
+
+        assert_eq!(get(&server, "/debug/pprof/heap").0, 404);
+        assert_eq!(get(&server, "/unknown").0, 404);
+        assert_eq!(request(&server, "POST /status HTTP/1.1\r\n\r\n").0, 405);
+        assert_eq!(request(&server, "hello\r\n\r\n").0, 400);
+
+        let split = "/debug/region/split?region-id=2&version=1&conf-ver=1&keys=6b31";
+        assert_eq!(get(&server, split).0, 405);
+        assert_eq!(post(&server, "/debug/region/split?region-id=2").0, 400);
+        let invalid_key = "/debug/region/split?region-id=2&version=1&conf-ver=1&keys=k1";
+        assert_eq!(post(&server, invalid_key).0, 400);
+        // The raft router is not set.
+        assert_eq!(post(&server, split).0, 503);
+
+        assert_eq!(get(&server, "/debug/drain-leaders").0, 405);
+        assert_eq!(post(&server, "/debug/drain-leaders?timeout-secs=a").0, 400);
+        assert_eq!(post(&server, "/debug/drain-leaders").0, 503);
+
+        assert_eq!(get(&server, "/debug/unsafe-recover?stores=2").0, 405);
+        assert_eq!(post(&server, "/debug/unsafe-recover").0, 400);
+        assert_eq!(post(&server, "/debug/unsafe-recover?stores=2,a").0, 400);
+        assert_eq!(post(&server, "/debug/unsafe-recover?stores=2,3").0, 503);
+
+        assert_eq!(post(&server, "/debug/hot-keys?region-id=2").0, 405);
+        assert_eq!(get(&server, "/debug/hot-keys").0, 400);
+        assert_eq!(get(&server, "/debug/hot-keys?region-id=2&limit=a").0, 400);
+        assert_eq!(get(&server, "/debug/hot-keys?region-id=2").0, 503);
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_status_server_local_admin_only() {
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let split = "/debug/region/split?region-id=2&version=1&conf-ver=1&keys=6b31";
+
+        let mut cfg = TiKvConfig::default();
+        let server = StatusServer::new(&cfg);
+        // The raft router is not set.
+        assert_eq!(server.handler.handle("POST", split, remote).code, 503);
+
+        cfg.security.ca_path = "ca.pem".to_owned();
+        let server = StatusServer::new(&cfg);
+        assert_eq!(server.handler.handle("POST", split, remote).code, 403);
+        let drain = "/debug/drain-leaders";
+        assert_eq!(server.handler.handle("POST", drain, remote).code, 403);
+        let recover = "/debug/unsafe-recover?stores=2";
+        assert_eq!(server.handler.handle("POST", recover, remote).code, 403);
+        assert_eq!(server.handler.handle("POST", split, local).code, 503);
+        let v6_local: IpAddr = "::1".parse().unwrap();
+        assert_eq!(server.handler.handle("POST", drain, v6_local).code, 503);
+        // The status routes are still served to everyone.
+        assert_eq!(server.handler.handle("GET", "/metrics", remote).code, 200);
+        assert_eq!(server.handler.handle("GET", split, remote).code, 405);
+    }
+
+    #[test]
+    fn test_status_server_hot_keys() {
+        let read_stats = Arc::new(ReadStats::new());
+        let flow = FlowStatistics {
+            read_keys: 1,
+            read_bytes: 1,
+        };
+        // Only a part of the keys are sampled, record enough of them.
+        let mut keys = vec![b"k1".to_vec(); 64];
+        keys.extend(vec![b"k2".to_vec(); 32]);
+        read_stats.record(2, &flow, keys.iter().map(|k| k.as_slice()));
+        let hot_keys = read_stats.hot_keys(2, 10);
+        assert_eq!(hot_keys.len(), 2);
+
+        let mut server = StatusServer::new(&TiKvConfig::default());
+        server.set_read_stats(read_stats);
+        server.start("127.0.0.1:0").unwrap();
+        let (code, body) = get(&server, "/debug/hot-keys?region-id=2");
+        assert_eq!(code, 200);
+        let keys: Value = serde_json::from_str(&body).unwrap();
+        let keys = keys.as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        for (key, &(ref k, count)) in keys.iter().zip(&hot_keys) {
+            assert_eq!(key["key"], k.to_hex());
+            assert_eq!(key["read-count"], count);
+        }
+        assert_eq!(keys[0]["key"], "6b31");
+
+        let (code, body) = get(&server, "/debug/hot-keys?region-id=2&limit=1");
+        assert_eq!(code, 200);
+        let keys: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(keys.as_array().unwrap().len(), 1);
+        assert_eq!(get(&server, "/debug/hot-keys?region-id=3"), (200, "[]".to_owned()));
+        server.stop();
+    }
+
+    #[test]
+    fn test_status_server_heap_profile() {
+        let mut server = StatusServer::new(&TiKvConfig::default());
+        server.set_heap_profiler(box |path: &str| {
+            File::create(path)
+                .and_then(|mut f| f.write_all(b"heap profile"))
+                .map_err(|e| format!("{}", e))
+        });
+        server.start("127.0.0.1:0").unwrap();
+        assert_eq!(
+            get(&server, "/debug/pprof/heap"),
+            (200, "heap profile".to_owned())
+        );
+        server.stop();
+
+        let mut server = StatusServer::new(&TiKvConfig::default());
+        server.set_heap_profiler(box |_: &str| Err("not enabled".to_owned()));
+        server.start("127.0.0.1:0").unwrap();
+        let (code, body) = get(&server, "/debug/pprof/heap");
+        assert_eq!(code, 500);
+        assert!(body.contains("not enabled"), "{}", body);
+        server.stop();
+    }
+
+    #[test]
+    fn test_status_server_concurrent_requests() {
+        let (tx, rx) = mpsc::channel();
+        let rx = Mutex::new(rx);
+        let mut server = StatusServer::new(&TiKvConfig::default());
+        // Dumping the heap profile blocks until it's told to finish.
+        server.set_heap_profiler(box move |path: &str| {
+            rx.lock().unwrap().recv().unwrap();
+            File::create(path)
+                .and_then(|mut f| f.write_all(b"heap profile"))
+                .map_err(|e| format!("{}", e))
+        });
+        server.start("127.0.0.1:0").unwrap();
+        let addr = server.listening_addr();
+        let profile = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let req = "GET /debug/pprof/heap HTTP/1.1\r\nHost: tikv\r\n\r\n";
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+            resp
+        });
+
+        // Other requests are served while the profile is being dumped.
+        for _ in 0..3 {
+            assert_eq!(get(&server, "/config").0, 200);
+        }
+        tx.send(()).unwrap();
+        let resp = profile.join().unwrap();
+        assert!(resp.ends_with("\r\n\r\nheap profile"), "{}", resp);
+        server.stop();
+    }
+}
@@ -16,13 +16,15 @@ use std::sync::mpsc::Sender;
 use std::net::SocketAddr;
 use kvproto::raft_serverpb::RaftMessage;
 use kvproto::raft_cmdpb::RaftCmdRequest;
+use kvproto::metapb::RegionEpoch;
 
 use util::transport::SendCh;
 use util::HandyRwLock;
 use util::worker::{Scheduler, Stopped};
 use util::collections::HashSet;
 use raft::SnapshotStatus;
-use raftstore::store::{BatchCallback, Callback, Msg as StoreMsg, SnapshotStatusMsg, Transport};
+use raftstore::store::{BatchCallback, Callback, Msg as StoreMsg, SnapshotStatusMsg, SplitCallback,
+                       Transport};
 use raftstore::Result as RaftStoreResult;
 use server::raft_client::RaftClient;
 use server::Result;
@@ -56,6 +58,22 @@ pub trait RaftStoreRouter: Send + Clone {
         self.try_send(StoreMsg::new_batch_raft_snapshot_cmd(batch, on_finished))
     }
 
+    // Split the region at the given keys, the callback receives all the new regions.
+    fn split_region(
+        &self,
+        region_id: u64,
+        region_epoch: RegionEpoch,
+        split_keys: Vec<Vec<u8>>,
+        cb: SplitCallback,
+    ) -> RaftStoreResult<()> {
+        self.try_send(StoreMsg::new_split_region(
+            region_id,
+            region_epoch,
+            split_keys,
+            cb,
+        ))
+    }
+
     fn report_unreachable(&self, region_id: u64, to_peer_id: u64, _: u64) -> RaftStoreResult<()> {
         self.try_send(StoreMsg::ReportUnreachable {
             region_id: region_id,
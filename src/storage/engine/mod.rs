@@ -175,6 +175,26 @@ pub enum ScanMode {
     Mixed,
 }
 
+/// FlowStatistics collects the keys and bytes returned to user.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct FlowStatistics {
+    pub read_keys: usize,
+    pub read_bytes: usize,
+}
+
+impl FlowStatistics {
+    #[inline]
+    pub fn add_read(&mut self, key: &[u8], value: &[u8]) {
+        self.read_keys += 1;
+        self.read_bytes += key.len() + value.len();
+    }
+
+    pub fn add(&mut self, other: &FlowStatistics) {
+        self.read_keys += other.read_keys;
+        self.read_bytes += other.read_bytes;
+    }
+}
+
 /// Statistics collects the ops taken when fetching data.
 #[derive(Default)]
 pub struct CFStatistics {
@@ -186,6 +206,8 @@ pub struct CFStatistics {
     pub prev: usize,
     pub seek: usize,
     pub seek_for_prev: usize,
+    // The data returned to user. This should be increased by the caller too.
+    pub flow_stats: FlowStatistics,
 }
 
 impl CFStatistics {
@@ -223,6 +245,13 @@ impl Statistics {
         self.lock.processed + self.write.processed + self.data.processed
     }
 
+    pub fn total_read_flow(&self) -> FlowStatistics {
+        let mut flow = self.lock.flow_stats;
+        flow.add(&self.write.flow_stats);
+        flow.add(&self.data.flow_stats);
+        flow
+    }
+
     pub fn details(&self) -> Vec<(&str, Vec<(&str, usize)>)> {
         vec![
             (CF_DEFAULT, self.data.details()),
@@ -29,10 +29,12 @@ pub mod txn;
 pub mod config;
 pub mod types;
 mod metrics;
+mod read_stats;
 
 pub use self::config::{Config, DEFAULT_DATA_DIR, DEFAULT_ROCKSDB_SUB_DIR};
 pub use self::engine::{new_local_engine, CFStatistics, Cursor, Engine, Error as EngineError,
-                       Modify, ScanMode, Snapshot, Statistics, TEMP_DIR};
+                       FlowStatistics, Modify, ScanMode, Snapshot, Statistics, TEMP_DIR};
+pub use self::read_stats::{HotKeys, ReadStats};
 pub use self::engine::raftkv::RaftKv;
 pub use self::txn::{Msg, Scheduler, SnapshotStore, StoreScanner};
 pub use self::types::{make_key, Key, KvPair, MvccInfo, Value};
@@ -462,6 +464,7 @@ pub struct Storage {
     engine: Box<Engine>,
     sendch: SyncSendCh<Msg>,
     handle: Arc<Mutex<StorageHandle>>,
+    read_stats: Arc<ReadStats>,
 
     // Storage configurations.
     gc_ratio_threshold: f64,
@@ -480,6 +483,7 @@ impl Storage {
                 handle: None,
                 receiver: Some(rx),
             })),
+            read_stats: Arc::new(ReadStats::new()),
             gc_ratio_threshold: config.gc_ratio_threshold,
         })
     }
@@ -502,6 +506,7 @@ impl Storage {
         let sched_worker_pool_size = config.scheduler_worker_pool_size;
         let sched_too_busy_threshold = config.scheduler_too_busy_threshold;
         let ch = self.sendch.clone();
+        let read_stats = self.read_stats.clone();
         let h = try!(builder.spawn(move || {
             let mut sched = Scheduler::new(
                 engine,
@@ -509,6 +514,7 @@ impl Storage {
                 sched_concurrency,
                 sched_worker_pool_size,
                 sched_too_busy_threshold,
+                read_stats,
             );
             if let Err(e) = sched.run(rx) {
                 panic!("scheduler run err:{:?}", e);
@@ -544,6 +550,12 @@ impl Storage {
         self.engine.clone()
     }
 
+    /// Returns the read traffic collector shared with the coprocessor and
+    /// the raftstore.
+    pub fn get_read_stats(&self) -> Arc<ReadStats> {
+        self.read_stats.clone()
+    }
+
     fn send(&self, cmd: Command, cb: StorageCb) -> Result<()> {
         box_try!(self.sendch.try_send(Msg::RawCmd { cmd: cmd, cb: cb }));
         Ok(())
@@ -879,6 +891,7 @@ impl Clone for Storage {
             engine: self.engine.clone(),
             sendch: self.sendch.clone(),
             handle: self.handle.clone(),
+            read_stats: self.read_stats.clone(),
             gc_ratio_threshold: self.gc_ratio_threshold,
         }
     }
@@ -223,13 +223,16 @@ impl<'a> MvccReader<'a> {
                 Some((commit_ts, mut write)) => match write.write_type {
                     WriteType::Put => {
                         self.statistics.write.processed += 1;
-                        if write.short_value.is_some() {
-                            if self.key_only {
-                                return Ok(Some(vec![]));
-                            }
-                            return Ok(write.short_value.take());
-                        }
-                        return self.load_data(key, write.start_ts).map(Some);
+                        let value = match write.short_value.take() {
+                            Some(_) if self.key_only => vec![],
+                            Some(v) => v,
+                            None => try!(self.load_data(key, write.start_ts)),
+                        };
+                        self.statistics
+                            .write
+                            .flow_stats
+                            .add_read(key.encoded(), &value);
+                        return Ok(Some(value));
                     }
                     WriteType::Delete => {
                         self.statistics.write.processed += 1;
@@ -0,0 +1,262 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem;
+use std::sync::{Mutex, MutexGuard};
+
+use util::collections::HashMap;
+use super::engine::FlowStatistics;
+
+// Only one of every HOT_KEY_SAMPLE_RATE read keys is counted.
+pub const HOT_KEY_SAMPLE_RATE: u64 = 16;
+const HOT_KEYS_CAPACITY: usize = 64;
+// The regions are spread over the shards, so that the readers of different
+// regions rarely contend for the same lock.
+const SHARD_COUNT: usize = 32;
+
+/// HotKeys counts the keys in a bounded space. When it's full, the key
+/// with the least count is replaced and its count is inherited by the new
+/// key (the Space-Saving algorithm), so the heavy hitters are always kept.
+pub struct HotKeys {
+    capacity: usize,
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+impl HotKeys {
+    pub fn new(capacity: usize) -> HotKeys {
+        HotKeys {
+            capacity: capacity,
+            counts: HashMap::default(),
+        }
+    }
+
+    pub fn record(&mut self, key: &[u8], count: u64) {
+        if let Some(c) = self.counts.get_mut(key) {
+            *c += count;
+            return;
+        }
+        let mut count = count;
+        if self.counts.len() >= self.capacity {
+            let min_key = self.counts
+                .iter()
+                .min_by_key(|&(_, c)| *c)
+                .map(|(k, _)| k.clone())
+                .unwrap();
+            count += self.counts.remove(&min_key).unwrap();
+        }
+        self.counts.insert(key.to_vec(), count);
+    }
+
+    pub fn merge(&mut self, other: &HotKeys) {
+        for (key, count) in &other.counts {
+            self.record(key, *count);
+        }
+    }
+
+    /// Returns at most `limit` keys with the largest counts.
+    pub fn top(&self, limit: usize) -> Vec<(Vec<u8>, u64)> {
+        let mut keys: Vec<_> = self.counts
+            .iter()
+            .map(|(k, c)| (k.clone(), *c))
+            .collect();
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(limit);
+        keys
+    }
+}
+
+impl Default for HotKeys {
+    fn default() -> HotKeys {
+        HotKeys::new(HOT_KEYS_CAPACITY)
+    }
+}
+
+#[derive(Default)]
+struct RegionReadStats {
+    flow: FlowStatistics,
+    hot_keys: HotKeys,
+}
+
+#[derive(Default)]
+struct Shard {
+    // The count of read keys, for sampling.
+    read_keys: u64,
+    regions: HashMap<u64, RegionReadStats>,
+    last_hot_keys: HashMap<u64, HotKeys>,
+}
+
+/// ReadStats collects the read traffic and the sampled hot keys of regions
+/// from the storage and the coprocessor. The traffic is reported to PD in
+/// region heartbeats, and the hot keys are served by the status server.
+pub struct ReadStats {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl ReadStats {
+    pub fn new() -> ReadStats {
+        ReadStats {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::new(Shard::default())).collect(),
+        }
+    }
+
+    fn shard(&self, region_id: u64) -> MutexGuard<Shard> {
+        self.shards[(region_id % SHARD_COUNT as u64) as usize]
+            .lock()
+            .unwrap()
+    }
+
+    pub fn record<'a, I>(&self, region_id: u64, flow: &FlowStatistics, keys: I)
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut shard = self.shard(region_id);
+        let mut read_keys = shard.read_keys;
+        {
+            let stats = shard
+                .regions
+                .entry(region_id)
+                .or_insert_with(RegionReadStats::default);
+            stats.flow.add(flow);
+            for key in keys {
+                if read_keys % HOT_KEY_SAMPLE_RATE == 0 {
+                    stats.hot_keys.record(key, 1);
+                }
+                read_keys += 1;
+            }
+        }
+        shard.read_keys = read_keys;
+    }
+
+    /// Takes the read traffic of all regions since the last call, and
+    /// starts a new window for the hot keys.
+    pub fn take_flows(&self) -> HashMap<u64, FlowStatistics> {
+        let mut flows = HashMap::default();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let regions = mem::replace(&mut shard.regions, HashMap::default());
+            let mut hot_keys = HashMap::default();
+            for (region_id, stats) in regions {
+                flows.insert(region_id, stats.flow);
+                hot_keys.insert(region_id, stats.hot_keys);
+            }
+            shard.last_hot_keys = hot_keys;
+        }
+        flows
+    }
+
+    /// Returns the hottest keys of the region in the current and the last
+    /// window with their estimated read counts.
+    pub fn hot_keys(&self, region_id: u64, limit: usize) -> Vec<(Vec<u8>, u64)> {
+        let shard = self.shard(region_id);
+        let mut hot_keys = HotKeys::default();
+        if let Some(keys) = shard.last_hot_keys.get(&region_id) {
+            hot_keys.merge(keys);
+        }
+        if let Some(stats) = shard.regions.get(&region_id) {
+            hot_keys.merge(&stats.hot_keys);
+        }
+        hot_keys
+            .top(limit)
+            .into_iter()
+            .map(|(k, c)| (k, c * HOT_KEY_SAMPLE_RATE))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::engine::FlowStatistics;
+    use super::*;
+
+    #[test]
+    fn test_hot_keys() {
+        let mut hot_keys = HotKeys::new(3);
+        for _ in 0..10 {
+            hot_keys.record(b"k1", 1);
+        }
+        hot_keys.record(b"k2", 3);
+        hot_keys.record(b"k3", 2);
+        // k3 is replaced.
+        hot_keys.record(b"k4", 1);
+        assert_eq!(
+            hot_keys.top(10),
+            vec![
+                (b"k1".to_vec(), 10),
+                (b"k2".to_vec(), 3),
+                (b"k4".to_vec(), 3),
+            ]
+        );
+        assert_eq!(hot_keys.top(1), vec![(b"k1".to_vec(), 10)]);
+
+        let mut other = HotKeys::new(3);
+        other.record(b"k2", 10);
+        hot_keys.merge(&other);
+        assert_eq!(hot_keys.top(1), vec![(b"k2".to_vec(), 13)]);
+    }
+
+    #[test]
+    fn test_read_stats() {
+        let stats = ReadStats::new();
+        let flow = FlowStatistics {
+            read_keys: 1,
+            read_bytes: 10,
+        };
+        let keys = vec![b"k1".to_vec(); HOT_KEY_SAMPLE_RATE as usize * 2];
+        stats.record(1, &flow, keys.iter().map(|k| k.as_slice()));
+        stats.record(1, &flow, vec![]);
+        stats.record(2, &flow, vec![&b"k2"[..]]);
+        assert_eq!(
+            stats.hot_keys(1, 10),
+            vec![(b"k1".to_vec(), HOT_KEY_SAMPLE_RATE * 2)]
+        );
+
+        let flows = stats.take_flows();
+        assert_eq!(flows.len(), 2);
+        assert_eq!(
+            flows[&1],
+            FlowStatistics {
+                read_keys: 2,
+                read_bytes: 20,
+            }
+        );
+        assert_eq!(flows[&2], flow);
+        assert!(stats.take_flows().is_empty());
+
+        // The hot keys of the last window are kept.
+        let stats = ReadStats::new();
+        stats.record(1, &flow, keys.iter().map(|k| k.as_slice()));
+        stats.take_flows();
+        stats.record(1, &flow, keys.iter().map(|k| k.as_slice()));
+        assert_eq!(
+            stats.hot_keys(1, 10),
+            vec![(b"k1".to_vec(), HOT_KEY_SAMPLE_RATE * 4)]
+        );
+        stats.take_flows();
+        stats.take_flows();
+        assert!(stats.hot_keys(1, 10).is_empty());
+
+        // The regions in the same shard are counted separately.
+        let stats = ReadStats::new();
+        let region_ids = [3, 3 + SHARD_COUNT as u64, 4];
+        for (i, &region_id) in region_ids.iter().enumerate() {
+            let key = format!("k{}", i).into_bytes();
+            let keys = vec![key; HOT_KEY_SAMPLE_RATE as usize];
+            stats.record(region_id, &flow, keys.iter().map(|k| k.as_slice()));
+        }
+        for (i, &region_id) in region_ids.iter().enumerate() {
+            let key = format!("k{}", i).into_bytes();
+            assert_eq!(stats.hot_keys(region_id, 10), vec![(key, HOT_KEY_SAMPLE_RATE)]);
+        }
+        assert_eq!(stats.take_flows().len(), 3);
+    }
+}
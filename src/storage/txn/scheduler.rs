@@ -32,6 +32,7 @@
 //! to the scheduler.
 
 use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
 use std::sync::mpsc::Receiver;
 use std::time::Duration;
 use std::thread;
@@ -42,8 +43,8 @@ use threadpool::ThreadPool;
 use prometheus::HistogramTimer;
 use kvproto::kvrpcpb::{CommandPri, Context, LockInfo};
 
-use storage::{Command, Engine, Error as StorageError, Result as StorageResult, ScanMode, Snapshot,
-              Statistics, StorageCb};
+use storage::{Command, Engine, Error as StorageError, ReadStats, Result as StorageResult,
+              ScanMode, Snapshot, Statistics, StorageCb};
 use storage::mvcc::{Error as MvccError, Lock as MvccLock, MvccReader, MvccTxn, Write, WriteType,
                     MAX_TXN_WRITE_SIZE};
 use storage::{Key, KvPair, MvccInfo, Value, CMD_TAG_GC};
@@ -306,6 +307,9 @@ pub struct Scheduler {
 
     // used to control write flow
     running_write_count: usize,
+
+    // collects the read traffic of regions
+    read_stats: Arc<ReadStats>,
 }
 
 // Make clippy happy.
@@ -353,6 +357,7 @@ impl Scheduler {
         concurrency: usize,
         worker_pool_size: usize,
         sched_too_busy_threshold: usize,
+        read_stats: Arc<ReadStats>,
     ) -> Scheduler {
         Scheduler {
             engine: engine,
@@ -369,13 +374,20 @@ impl Scheduler {
             high_priority_pool: ThreadPool::new_with_name(thd_name!("sched-high-pri-pool"), 1),
             has_gc_command: false,
             running_write_count: 0,
+            read_stats: read_stats,
         }
     }
 }
 
 /// Processes a read command within a worker thread, then posts `ReadFinished` message back to the
 /// event loop.
-fn process_read(cid: u64, mut cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<Snapshot>) {
+fn process_read(
+    cid: u64,
+    mut cmd: Command,
+    ch: SyncSendCh<Msg>,
+    snapshot: Box<Snapshot>,
+    read_stats: Arc<ReadStats>,
+) {
     debug!("process read cmd(cid={}) in worker pool.", cid);
     SCHED_WORKER_COUNTER_VEC
         .with_label_values(&[cmd.tag(), "read"])
@@ -666,7 +678,12 @@ fn process_read(cid: u64, mut cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<S
                 .with_label_values(&[tag])
                 .observe(1f64);
             match snapshot.get(key) {
-                Ok(val) => ProcessResult::Value { value: val },
+                Ok(val) => {
+                    if let Some(ref v) = val {
+                        statistics.data.flow_stats.add_read(key.encoded(), v);
+                    }
+                    ProcessResult::Value { value: val }
+                }
                 Err(e) => ProcessResult::Failed {
                     err: StorageError::from(e),
                 },
@@ -689,12 +706,46 @@ fn process_read(cid: u64, mut cmd: Command, ch: SyncSendCh<Msg>, snapshot: Box<S
         _ => panic!("unsupported read command"),
     };
 
+    record_read_flow(&read_stats, &cmd, &pr, &statistics);
+
     if let Err(e) = ch.send(Msg::ReadFinished { cid: cid, pr: pr }) {
         // Todo: if this happens we need to clean up command's context
         panic!("send read finished failed, cid={}, err={:?}", cid, e);
     }
 }
 
+/// Records the read traffic and the read keys of user read commands, the
+/// keys of transactional commands are recorded in raw format. The scans
+/// record the keys they return rather than the start key, which may not
+/// even exist.
+fn record_read_flow(
+    read_stats: &ReadStats,
+    cmd: &Command,
+    pr: &ProcessResult,
+    statistics: &Statistics,
+) {
+    let region_id = cmd.get_context().get_region_id();
+    let flow = statistics.total_read_flow();
+    let keys = match *cmd {
+        Command::Get { ref key, .. } => vec![key.raw().unwrap_or_default()],
+        Command::BatchGet { ref keys, .. } => {
+            keys.iter().map(|k| k.raw().unwrap_or_default()).collect()
+        }
+        Command::RawGet { ref key, .. } => vec![key.encoded().clone()],
+        Command::Scan { .. } | Command::RawScan { .. } => {
+            let pairs: &[StorageResult<KvPair>] = match *pr {
+                ProcessResult::MultiKvpairs { ref pairs } => pairs,
+                _ => &[],
+            };
+            let keys = pairs.iter().filter_map(|p| p.as_ref().ok());
+            read_stats.record(region_id, &flow, keys.map(|&(ref k, _)| k.as_slice()));
+            return;
+        }
+        _ => return,
+    };
+    read_stats.record(region_id, &flow, keys.iter().map(|k| k.as_slice()));
+}
+
 fn process_rawscan(
     snapshot: Box<Snapshot>,
     start_key: &Key,
@@ -707,6 +758,7 @@ fn process_rawscan(
     }
     let mut pairs = vec![];
     while cursor.valid() && pairs.len() < limit {
+        stats.data.flow_stats.add_read(cursor.key(), cursor.value());
         pairs.push(Ok((cursor.key().to_owned(), cursor.value().to_owned())));
         cursor.next(&mut stats.data);
     }
@@ -737,6 +789,9 @@ fn process_write_impl(
     ch: SyncSendCh<Msg>,
     snapshot: &Snapshot,
 ) -> Result<()> {
+    fail_point!("scheduler_process_write", |_| {
+        Err(box_err!("failed to process write by fail point"))
+    });
     let mut statistics = Statistics::default();
     let (pr, modifies) = match cmd {
         Command::Prewrite {
@@ -1004,7 +1059,8 @@ impl Scheduler {
         let readcmd = cmd.readonly();
         let worker_pool = self.fetch_worker_pool(cmd.priority());
         if readcmd {
-            worker_pool.execute(move || process_read(cid, cmd, ch, snapshot));
+            let read_stats = self.read_stats.clone();
+            worker_pool.execute(move || process_read(cid, cmd, ch, snapshot, read_stats));
         } else {
             worker_pool.execute(move || process_write(cid, cmd, ch, snapshot));
         }
@@ -1127,6 +1183,7 @@ impl Scheduler {
             Err(e) => panic!("send SnapshotFinish failed, err {:?}", e),
         };
 
+        fail_point!("scheduler_async_snapshot");
         if let Err(e) = self.engine.async_snapshot(ctx, cb) {
             for cid in cids {
                 SCHED_STAGE_COUNTER_VEC
@@ -1291,6 +1348,10 @@ impl Scheduler {
         if to_be_write.is_empty() {
             return self.on_write_finished(cid, pr, Ok(()));
         }
+        fail_point!("scheduler_async_write", |_| {
+            let e = EngineError::Other(box_err!("failed to write by fail point"));
+            self.finish_with_err(cid, Error::from(e))
+        });
         let engine_cb = make_engine_cb(cid, pr, self.schedch.clone());
         if let Err(e) = self.engine
             .async_write(cmd.get_context(), to_be_write, engine_cb)
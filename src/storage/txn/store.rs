@@ -266,6 +266,14 @@ mod test {
             let data = snapshot_store.get(&make_key(key), &mut statistics).unwrap();
             assert!(data.is_some(), "{:?} expect some, but got none", key);
         }
+        let flow = statistics.total_read_flow();
+        assert_eq!(flow.read_keys, key_num as usize);
+        let expect_bytes: usize = store
+            .keys
+            .iter()
+            .map(|k| make_key(k.as_bytes()).encoded().len() + k.len())
+            .sum();
+        assert_eq!(flow.read_bytes, expect_bytes);
     }
 
     #[test]
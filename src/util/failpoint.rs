@@ -0,0 +1,325 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fail points inject errors, panics and delays at named code sites.
+//!
+//! A fail point is declared by `fail_point!`, which compiles to nothing unless
+//! the `failpoints` feature is enabled. The actions of the fail points are
+//! configured by the `FAILPOINTS` env var, like
+//! `FAILPOINTS="apply_raft_cmd=50%sleep(100);snapshot_build=return"`,
+//! or by `cfg` and `remove` in tests.
+//!
+//! The actions of a fail point are separated by `->`, and each action is
+//! `[p%][cnt*]task[(arg)]`, which means the task is triggered with the
+//! probability of p percent, at most cnt times. The first action which is
+//! triggered takes effect. The tasks are:
+//!
+//! - `off`: does nothing.
+//! - `return(arg)`: returns from the function with the value built from the
+//!   optional arg by the closure of the fail point.
+//! - `sleep(ms)`: sleeps for the milliseconds.
+//! - `panic(msg)`: panics with the optional message.
+//! - `print(msg)`: logs the optional message.
+//! - `pause`: blocks until the fail point is reconfigured or removed.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rand::{self, Rng};
+
+pub const FAILPOINTS_ENV: &'static str = "FAILPOINTS";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Task {
+    Off,
+    Return(Option<String>),
+    Sleep(u64),
+    Panic(Option<String>),
+    Print(Option<String>),
+    Pause,
+}
+
+#[derive(Debug)]
+struct Action {
+    task: Task,
+    // The probability to trigger the task, in [0, 1].
+    freq: f32,
+    // The remaining times to trigger the task if limited.
+    count: Option<AtomicUsize>,
+}
+
+impl Action {
+    fn get_task(&self) -> Option<Task> {
+        // Check the probability first, so the times which are not triggered
+        // don't use up the count.
+        if self.freq < 1.0 && rand::thread_rng().next_f32() >= self.freq {
+            return None;
+        }
+        if let Some(ref count) = self.count {
+            loop {
+                let c = count.load(Ordering::Acquire);
+                if c == 0 {
+                    return None;
+                }
+                if count.compare_and_swap(c, c - 1, Ordering::AcqRel) == c {
+                    break;
+                }
+            }
+        }
+        Some(self.task.clone())
+    }
+}
+
+fn parse_action(s: &str) -> Result<Action, String> {
+    let mut remain = s.trim();
+    let mut freq = 1.0;
+    if let Some(pos) = remain.find('%') {
+        let p: f32 = try!(
+            remain[..pos]
+                .parse()
+                .map_err(|e| format!("invalid probability {:?}: {}", &remain[..pos], e))
+        );
+        freq = p / 100.0;
+        remain = &remain[pos + 1..];
+    }
+    let mut count = None;
+    if let Some(pos) = remain.find('*') {
+        let c: usize = try!(
+            remain[..pos]
+                .parse()
+                .map_err(|e| format!("invalid count {:?}: {}", &remain[..pos], e))
+        );
+        count = Some(AtomicUsize::new(c));
+        remain = &remain[pos + 1..];
+    }
+    let (name, arg) = match remain.find('(') {
+        Some(pos) => {
+            if !remain.ends_with(')') {
+                return Err(format!("parentheses don't match in {:?}", s));
+            }
+            (&remain[..pos], Some(remain[pos + 1..remain.len() - 1].to_owned()))
+        }
+        None => (remain, None),
+    };
+    let task = match name {
+        "off" => Task::Off,
+        "return" => Task::Return(arg),
+        "sleep" => {
+            let ms = match arg {
+                Some(ref ms) => try!(ms.parse().map_err(|e| format!("invalid sleep {}", e))),
+                None => return Err(format!("sleep requires the milliseconds in {:?}", s)),
+            };
+            Task::Sleep(ms)
+        }
+        "panic" => Task::Panic(arg),
+        "print" => Task::Print(arg),
+        "pause" => Task::Pause,
+        _ => return Err(format!("unknown task {:?} in {:?}", name, s)),
+    };
+    Ok(Action {
+        task: task,
+        freq: freq,
+        count: count,
+    })
+}
+
+#[derive(Debug, Default)]
+struct FailPoint {
+    actions: RwLock<(String, Vec<Action>)>,
+    // The version of the actions, the paused threads wait until it changes.
+    version: Mutex<u64>,
+    version_changed: Condvar,
+}
+
+impl FailPoint {
+    fn set_actions(&self, actions_str: &str, actions: Vec<Action>) {
+        let mut current = self.actions.write().unwrap();
+        *current = (actions_str.to_owned(), actions);
+        *self.version.lock().unwrap() += 1;
+        self.version_changed.notify_all();
+    }
+
+    fn eval(&self, name: &str) -> Option<Option<String>> {
+        let (task, version) = {
+            let actions = self.actions.read().unwrap();
+            match actions.1.iter().filter_map(|a| a.get_task()).next() {
+                Some(task) => (task, *self.version.lock().unwrap()),
+                None => return None,
+            }
+        };
+        match task {
+            Task::Off => {}
+            Task::Return(arg) => return Some(arg),
+            Task::Sleep(ms) => thread::sleep(Duration::from_millis(ms)),
+            Task::Panic(Some(msg)) => panic!("{}", msg),
+            Task::Panic(None) => panic!("fail point {} panics", name),
+            Task::Print(msg) => info!("fail point {}: {}", name, msg.unwrap_or_default()),
+            Task::Pause => {
+                let mut current = self.version.lock().unwrap();
+                while *current == version {
+                    current = self.version_changed.wait(current).unwrap();
+                }
+            }
+        }
+        None
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<String, Arc<FailPoint>>> = {
+        let mut registry = HashMap::new();
+        if let Ok(s) = env::var(FAILPOINTS_ENV) {
+            for cfg in s.trim().split(';').filter(|c| !c.trim().is_empty()) {
+                let (name, actions) = match cfg.find('=') {
+                    Some(pos) => (cfg[..pos].trim(), &cfg[pos + 1..]),
+                    None => panic!("invalid fail point config {:?} in {}", cfg, FAILPOINTS_ENV),
+                };
+                let fp = FailPoint::default();
+                match parse_actions(actions) {
+                    Ok(acts) => fp.set_actions(actions, acts),
+                    Err(e) => panic!("invalid fail point config {:?}: {}", cfg, e),
+                }
+                registry.insert(name.to_owned(), Arc::new(fp));
+            }
+        }
+        RwLock::new(registry)
+    };
+}
+
+fn parse_actions(actions: &str) -> Result<Vec<Action>, String> {
+    actions.split("->").map(parse_action).collect()
+}
+
+/// Evaluates the fail point, returns the value built by `f` if the fail point
+/// is configured to return. It's used by `fail_point!`.
+pub fn eval<R, F: FnOnce(Option<String>) -> R>(name: &str, f: F) -> Option<R> {
+    let fp = match REGISTRY.read().unwrap().get(name) {
+        Some(fp) => fp.clone(),
+        None => return None,
+    };
+    fp.eval(name).map(f)
+}
+
+/// Configures the actions of the fail point.
+pub fn cfg<S: Into<String>>(name: S, actions: &str) -> Result<(), String> {
+    let acts = try!(parse_actions(actions));
+    let mut registry = REGISTRY.write().unwrap();
+    let fp = registry
+        .entry(name.into())
+        .or_insert_with(|| Arc::new(FailPoint::default()));
+    fp.set_actions(actions, acts);
+    Ok(())
+}
+
+/// Removes the fail point, the threads paused by it are resumed.
+pub fn remove<S: AsRef<str>>(name: S) {
+    if let Some(fp) = REGISTRY.write().unwrap().remove(name.as_ref()) {
+        fp.set_actions("", vec![]);
+    }
+}
+
+/// Removes all the fail points.
+pub fn teardown() {
+    let mut registry = REGISTRY.write().unwrap();
+    for (_, fp) in registry.drain() {
+        fp.set_actions("", vec![]);
+    }
+}
+
+/// Lists the configured fail points and their actions.
+pub fn list() -> Vec<(String, String)> {
+    let registry = REGISTRY.read().unwrap();
+    registry
+        .iter()
+        .map(|(name, fp)| (name.clone(), fp.actions.read().unwrap().0.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_action() {
+        let cases = vec![
+            ("off", Task::Off, 1.0, None),
+            ("return", Task::Return(None), 1.0, None),
+            ("return(err)", Task::Return(Some("err".to_owned())), 1.0, None),
+            ("50%sleep(100)", Task::Sleep(100), 0.5, None),
+            ("3*panic", Task::Panic(None), 1.0, Some(3)),
+            ("20%2*print(hi)", Task::Print(Some("hi".to_owned())), 0.2, Some(2)),
+            ("pause", Task::Pause, 1.0, None),
+        ];
+        for (s, task, freq, count) in cases {
+            let action = parse_action(s).unwrap();
+            assert_eq!(action.task, task, "{}", s);
+            assert_eq!(action.freq, freq, "{}", s);
+            assert_eq!(action.count.map(|c| c.into_inner()), count, "{}", s);
+        }
+
+        for s in &["", "sleep", "sleep(abc)", "x%off", "return(", "unknown"] {
+            assert!(parse_action(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let name = "test_failpoint_eval";
+        assert_eq!(eval(name, |_| ()), None);
+
+        cfg(name, "2*return(a)->return(b)").unwrap();
+        assert_eq!(eval(name, |arg| arg), Some(Some("a".to_owned())));
+        assert_eq!(eval(name, |arg| arg), Some(Some("a".to_owned())));
+        assert_eq!(eval(name, |arg| arg), Some(Some("b".to_owned())));
+        assert!(list().contains(&(name.to_owned(), "2*return(a)->return(b)".to_owned())));
+
+        cfg(name, "off").unwrap();
+        assert_eq!(eval(name, |_| ()), None);
+        assert!(cfg(name, "invalid").is_err());
+
+        remove(name);
+        assert_eq!(eval(name, |_| ()), None);
+    }
+
+    #[test]
+    fn test_eval_probability_and_count() {
+        let name = "test_failpoint_eval_probability_and_count";
+        cfg(name, "50%3*return").unwrap();
+        let triggered = (0..1000).filter(|_| eval(name, |_| ()).is_some()).count();
+        // The times which are not triggered by the probability don't count.
+        assert_eq!(triggered, 3);
+        remove(name);
+    }
+
+    #[test]
+    fn test_pause() {
+        let name = "test_failpoint_pause";
+        cfg(name, "pause").unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            eval(name, |_| ());
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        remove(name);
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The maximum time that `IOLimiter` lets the caller run ahead of the
+/// configured rate, so a small burst after an idle period is allowed.
+const MAX_BURST_NANOS: u64 = 100_000_000;
+
+/// `IOLimiter` limits the throughput of IO to `bytes_per_sec`.
+///
+/// It is shared between threads, every caller requests bytes before or after
+/// doing IO and will be blocked until the bytes are allowed by the rate.
+pub struct IOLimiter {
+    bytes_per_sec: u64,
+    // The time when all the requested bytes have been consumed.
+    next_free: Mutex<Instant>,
+}
+
+impl IOLimiter {
+    pub fn new(bytes_per_sec: u64) -> IOLimiter {
+        assert!(bytes_per_sec > 0);
+        IOLimiter {
+            bytes_per_sec: bytes_per_sec,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    #[inline]
+    pub fn get_bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Request `bytes` from the limiter, returns the duration it waits.
+    pub fn request(&self, bytes: u64) -> Duration {
+        let wait = self.consume(bytes);
+        if wait > Duration::from_millis(0) {
+            thread::sleep(wait);
+        }
+        wait
+    }
+
+    /// Consume `bytes` from the limiter without blocking, returns the duration
+    /// the caller should wait before doing the IO.
+    pub fn consume(&self, bytes: u64) -> Duration {
+        self.reserve(bytes, Instant::now())
+    }
+
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let cost = nanos_to_duration(bytes_to_nanos(bytes, self.bytes_per_sec));
+        let mut next_free = self.next_free.lock().unwrap();
+        let burst = nanos_to_duration(MAX_BURST_NANOS);
+        if *next_free + burst < now {
+            *next_free = now - burst;
+        }
+        *next_free += cost;
+        if *next_free > now {
+            *next_free - now
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+}
+
+fn bytes_to_nanos(bytes: u64, bytes_per_sec: u64) -> u64 {
+    (bytes as f64 / bytes_per_sec as f64 * 1_000_000_000.0) as u64
+}
+
+fn nanos_to_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_io_limiter_reserve() {
+        let limiter = IOLimiter::new(1024);
+        let now = Instant::now() + Duration::from_secs(1);
+        // the burst covers the first 100ms.
+        assert_eq!(limiter.reserve(100, now), Duration::from_millis(0));
+        let wait = limiter.reserve(1024, now);
+        assert!(wait > Duration::from_millis(900));
+        assert!(wait <= Duration::from_secs(1));
+        // requests are queued one after another.
+        let wait = limiter.reserve(1024, now);
+        assert!(wait > Duration::from_millis(1900));
+
+        // idle for a long time doesn't accumulate more than the burst.
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.reserve(1024, later) > Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_io_limiter_request() {
+        let limiter = IOLimiter::new(10 * 1024 * 1024);
+        let t = Instant::now();
+        for _ in 0..10 {
+            limiter.request(512 * 1024);
+        }
+        // 5MB at 10MB/s takes at least 0.4s with the burst.
+        assert!(t.elapsed() >= Duration::from_millis(350));
+    }
+
+    #[test]
+    fn test_io_limiter_consume() {
+        let limiter = IOLimiter::new(1024);
+        let t = Instant::now();
+        let mut wait = Duration::from_millis(0);
+        for _ in 0..3 {
+            wait = limiter.consume(1024);
+        }
+        // consume never blocks, the wait is left to the caller.
+        assert!(t.elapsed() < Duration::from_millis(500));
+        assert!(wait > Duration::from_millis(2800));
+    }
+}
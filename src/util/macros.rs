@@ -213,3 +213,32 @@ macro_rules! try_opt {
         }
     });
 }
+
+/// Declares a fail point, see `util::failpoint` for how to configure it.
+///
+/// `fail_point!(name)` may sleep, panic or pause, and
+/// `fail_point!(name, |arg| expr)` may also return the value of the closure
+/// from the enclosing function. It compiles to nothing unless the
+/// `failpoints` feature is enabled.
+#[cfg(feature = "failpoints")]
+#[macro_export]
+macro_rules! fail_point {
+    ($name:expr) => ({
+        $crate::util::failpoint::eval($name, |_| {
+            panic!("return is not supported for the fail point {}", $name);
+        });
+    });
+    ($name:expr, $e:expr) => ({
+        if let Some(res) = $crate::util::failpoint::eval($name, $e) {
+            return res;
+        }
+    });
+}
+
+/// Declares a fail point, see `util::failpoint` for how to configure it.
+#[cfg(not(feature = "failpoints"))]
+#[macro_export]
+macro_rules! fail_point {
+    ($name:expr) => ({});
+    ($name:expr, $e:expr) => ({});
+}
@@ -42,6 +42,9 @@ pub mod metrics;
 pub mod threadpool;
 pub mod collections;
 pub mod time;
+pub mod io_limiter;
+pub mod failpoint;
+pub mod security;
 
 pub use self::rocksdb::properties;
 
@@ -11,6 +11,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+
 use prometheus::{exponential_buckets, CounterVec, GaugeVec, HistogramVec};
 use rocksdb::{DBStatisticsHistogramType as HistType, DBStatisticsTickerType as TickerType,
               HistogramData, DB};
@@ -22,6 +24,7 @@ pub const ROCKSDB_CUR_SIZE_ALL_MEM_TABLES: &'static str = "rocksdb.cur-size-all-
 pub const ROCKSDB_ESTIMATE_NUM_KEYS: &'static str = "rocksdb.estimate-num-keys";
 pub const ROCKSDB_PENDING_COMPACTION_BYTES: &'static str = "rocksdb.\
                                                             estimate-pending-compaction-bytes";
+pub const ROCKSDB_NUM_FILES_AT_LEVEL0: &'static str = "rocksdb.num-files-at-level0";
 pub const ENGINE_TICKER_TYPES: &'static [TickerType] = &[
     TickerType::BlockCacheMiss,
     TickerType::BlockCacheHit,
@@ -413,9 +416,32 @@ pub fn flush_engine_properties(engine: &DB, name: &str) {
                 .with_label_values(&[name, cf])
                 .set(pending_compaction_bytes as f64);
         }
+
+        if let Some(num_files) = engine.get_property_int_cf(handle, ROCKSDB_NUM_FILES_AT_LEVEL0) {
+            STORE_ENGINE_NUM_FILES_AT_LEVEL0_VEC
+                .with_label_values(&[name, cf])
+                .set(num_files as f64);
+        }
     }
 }
 
+/// Returns the pending compaction bytes of all column families and the
+/// max level 0 file count among them, which decide whether RocksDB is going
+/// to stall writes.
+pub fn get_engine_compaction_pressure(engine: &DB) -> (u64, u64) {
+    let (mut pending_bytes, mut level0_files) = (0, 0);
+    for cf in engine.cf_names() {
+        let handle = rocksdb::get_cf_handle(engine, cf).unwrap();
+        if let Some(bytes) = engine.get_property_int_cf(handle, ROCKSDB_PENDING_COMPACTION_BYTES) {
+            pending_bytes += bytes;
+        }
+        if let Some(num_files) = engine.get_property_int_cf(handle, ROCKSDB_NUM_FILES_AT_LEVEL0) {
+            level0_files = cmp::max(level0_files, num_files);
+        }
+    }
+    (pending_bytes, level0_files)
+}
+
 lazy_static!{
     pub static ref STORE_ENGINE_SIZE_GAUGE_VEC: GaugeVec =
         register_gauge_vec!(
@@ -515,6 +541,13 @@ lazy_static!{
             &["db", "cf"]
         ).unwrap();
 
+    pub static ref STORE_ENGINE_NUM_FILES_AT_LEVEL0_VEC: GaugeVec =
+        register_gauge_vec!(
+            "tikv_engine_num_files_at_level0",
+            "Number of files at level 0.",
+            &["db", "cf"]
+        ).unwrap();
+
     pub static ref STORE_ENGINE_COMPACTION_FLOW_VEC: GaugeVec =
         register_gauge_vec!(
             "tikv_engine_compaction_flow_bytes",
@@ -40,7 +40,8 @@ impl MetricsFlusher {
 
     pub fn start(&mut self) -> Result<(), io::Error> {
         let db = self.engines.kv_engine.clone();
-        let raft_db = self.engines.raft_engine.clone();
+        // Other raft engines don't have the statistics of RocksDB.
+        let raft_db = self.engines.raft_engine.rocksdb();
         let (tx, rx) = mpsc::channel();
         let interval = self.interval;
         self.sender = Some(tx);
@@ -50,7 +51,9 @@ impl MetricsFlusher {
                 .spawn(move || {
                     while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
                         flush_metrics(&db, "kv");
-                        flush_metrics(&raft_db, "raft");
+                        if let Some(ref raft_db) = raft_db {
+                            flush_metrics(raft_db, "raft");
+                        }
                     }
                 })
         );
@@ -95,6 +98,7 @@ mod tests {
     use rocksdb::{ColumnFamilyOptions, DBOptions};
     use util::rocksdb::{self, CFOptions};
     use storage::{CF_DEFAULT, CF_LOCK, CF_WRITE};
+    use raftstore::store::raft_engine::RocksRaftEngine;
     use std::thread::sleep;
 
     #[test]
@@ -113,10 +117,10 @@ mod tests {
         );
 
         let cfs_opts = vec![CFOptions::new(CF_DEFAULT, ColumnFamilyOptions::new())];
-        let raft_engine = Arc::new(
+        let raft_db =
             rocksdb::new_engine_opt(raft_path.to_str().unwrap(), DBOptions::new(), cfs_opts)
-                .unwrap(),
-        );
+                .unwrap();
+        let raft_engine = Arc::new(RocksRaftEngine::new(Arc::new(raft_db)));
         let engines = Engines::new(engine, raft_engine);
         let mut metrics_flusher = MetricsFlusher::new(engines, Duration::from_millis(100));
 
@@ -0,0 +1,216 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS for the grpc servers and channels.
+//!
+//! When the CA, certificate and key are all configured, the servers require
+//! the clients to present certificates signed by the CA (mutual TLS), and the
+//! channels verify the servers the same way. Otherwise everything stays in
+//! plaintext.
+//!
+//! Any client with a certificate signed by the CA is accepted. grpcio doesn't
+//! expose the auth context of a call to the services yet, so the servers can't
+//! check the common names of the clients, and `cert-common-name` is rejected
+//! by the config validation until they can.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+use grpc::{Channel, ChannelBuilder, ChannelCredentialsBuilder, ServerBuilder,
+           ServerCredentialsBuilder};
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SecurityConfig {
+    pub ca_path: String,
+    pub cert_path: String,
+    pub key_path: String,
+    /// If it's not empty, the certificates of other TiKV servers must be
+    /// issued for the name, instead of the addresses the channels connect to.
+    /// It doesn't apply to PD. It's not supported yet, as only one side of
+    /// the connections could check it.
+    pub cert_common_name: String,
+}
+
+fn check_file_exists(tag: &str, path: &str) -> Result<(), Box<Error>> {
+    if path.is_empty() {
+        return Ok(());
+    }
+    match File::open(path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to open {} {:?}: {}", tag, path, e).into()),
+    }
+}
+
+fn load_file(tag: &str, path: &str) -> Result<Vec<u8>, Box<Error>> {
+    if path.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut content = vec![];
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut content)) {
+        return Err(format!("failed to load {} {:?}: {}", tag, path, e).into());
+    }
+    Ok(content)
+}
+
+impl SecurityConfig {
+    pub fn validate(&self) -> Result<(), Box<Error>> {
+        try!(check_file_exists("ca", &self.ca_path));
+        try!(check_file_exists("certificate", &self.cert_path));
+        try!(check_file_exists("private key", &self.key_path));
+        let paths = [&self.ca_path, &self.cert_path, &self.key_path];
+        if paths.iter().any(|p| p.is_empty()) && paths.iter().any(|p| !p.is_empty()) {
+            return Err(
+                "security.ca-path, security.cert-path and security.key-path \
+                 should be all set or all empty"
+                    .into(),
+            );
+        }
+        if !self.cert_common_name.is_empty() {
+            return Err(
+                "security.cert-common-name is not supported yet, the servers can't \
+                 check the common names of the clients"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `SecurityManager` holds the loaded certificates, and sets up the grpc
+/// servers and channels with them.
+#[derive(Default)]
+pub struct SecurityManager {
+    ca: Vec<u8>,
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    cert_common_name: String,
+}
+
+impl SecurityManager {
+    pub fn new(cfg: &SecurityConfig) -> Result<SecurityManager, Box<Error>> {
+        Ok(SecurityManager {
+            ca: try!(load_file("ca", &cfg.ca_path)),
+            cert: try!(load_file("certificate", &cfg.cert_path)),
+            key: try!(load_file("private key", &cfg.key_path)),
+            cert_common_name: cfg.cert_common_name.clone(),
+        })
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        !self.ca.is_empty()
+    }
+
+    /// Connects to a PD server, its certificate must be issued for the
+    /// address.
+    pub fn connect(&self, cb: ChannelBuilder, addr: &str) -> Channel {
+        self.connect_with_target(cb, addr, None)
+    }
+
+    /// Connects to another TiKV server, its certificate must be issued for
+    /// `cert_common_name` if it's set, or the address otherwise.
+    pub fn connect_peer(&self, cb: ChannelBuilder, addr: &str) -> Channel {
+        let target = if self.cert_common_name.is_empty() {
+            None
+        } else {
+            Some(self.cert_common_name.clone())
+        };
+        self.connect_with_target(cb, addr, target)
+    }
+
+    fn connect_with_target(
+        &self,
+        mut cb: ChannelBuilder,
+        addr: &str,
+        target: Option<String>,
+    ) -> Channel {
+        if !self.is_tls_enabled() {
+            return cb.connect(addr);
+        }
+        if let Some(target) = target {
+            cb = cb.override_ssl_target(target);
+        }
+        let cred = ChannelCredentialsBuilder::new()
+            .root_cert(self.ca.clone())
+            .cert(self.cert.clone(), self.key.clone())
+            .build();
+        cb.secure_connect(addr, cred)
+    }
+
+    pub fn bind(&self, sb: ServerBuilder, host: &str, port: u16) -> ServerBuilder {
+        if !self.is_tls_enabled() {
+            return sb.bind(host, port);
+        }
+        // Requires the clients to present certificates signed by the CA.
+        let cred = ServerCredentialsBuilder::new()
+            .root_cert(self.ca.clone(), true)
+            .add_cert(self.cert.clone(), self.key.clone())
+            .build();
+        sb.bind_secure(host, port, cred)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_security() {
+        let cfg = SecurityConfig::default();
+        cfg.validate().unwrap();
+        let mgr = SecurityManager::new(&cfg).unwrap();
+        assert!(!mgr.is_tls_enabled());
+
+        let temp = TempDir::new("test_security").unwrap();
+        let mut cfg = SecurityConfig::default();
+        let paths: Vec<_> = (0..3)
+            .map(|i| {
+                let p = temp.path().join(format!("{}.pem", i));
+                File::create(&p)
+                    .unwrap()
+                    .write_all(format!("pem {}", i).as_bytes())
+                    .unwrap();
+                p.to_str().unwrap().to_owned()
+            })
+            .collect();
+        cfg.ca_path = paths[0].clone();
+        assert!(cfg.validate().is_err());
+        cfg.cert_path = paths[1].clone();
+        cfg.key_path = paths[2].clone();
+        cfg.validate().unwrap();
+        cfg.cert_common_name = "tikv".to_owned();
+        assert!(cfg.validate().is_err());
+        cfg.cert_common_name.clear();
+
+        let mgr = SecurityManager::new(&cfg).unwrap();
+        assert!(mgr.is_tls_enabled());
+        assert_eq!(mgr.ca, b"pem 0");
+        assert_eq!(mgr.cert, b"pem 1");
+        assert_eq!(mgr.key, b"pem 2");
+
+        cfg.key_path = temp.path().join("missing").to_str().unwrap().to_owned();
+        assert!(cfg.validate().is_err());
+        assert!(SecurityManager::new(&cfg).is_err());
+
+        let mut cfg = SecurityConfig::default();
+        cfg.cert_common_name = "tikv".to_owned();
+        assert!(cfg.validate().is_err());
+    }
+}
@@ -18,6 +18,13 @@ use std::ops::{Add, Sub};
 use std::cmp::Ordering;
 
 use time::{Duration as TimeDuration, Timespec};
+use tokio_timer::Timer;
+
+lazy_static! {
+    /// The timer shared by the futures which need to wait, every `Timer`
+    /// starts a thread of its own.
+    pub static ref GLOBAL_TIMER: Timer = Timer::default();
+}
 
 /// Convert Duration to milliseconds.
 #[inline]
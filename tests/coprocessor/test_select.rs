@@ -12,7 +12,7 @@
 // limitations under the License.
 
 use std::collections::{BTreeMap, HashMap};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::i64;
 use std::thread;
@@ -23,7 +23,7 @@ use tikv::coprocessor;
 use kvproto::kvrpcpb::Context;
 use tikv::coprocessor::codec::{datum, table, Datum};
 use tikv::util::codec::number::*;
-use tikv::storage::{Key, Mutation, ALL_CFS};
+use tikv::storage::{Key, Mutation, ReadStats, ALL_CFS};
 use tikv::storage::engine::{self, Engine, TEMP_DIR};
 use tikv::util::worker::Worker;
 use kvproto::coprocessor::{KeyRange, Request, Response};
@@ -616,7 +616,12 @@ fn init_data_with_engine_and_commit(
         store.commit_with_ctx(ctx);
     }
     let mut end_point = Worker::new("test select worker");
-    let runner = EndPointHost::new(store.get_engine(), end_point.scheduler(), 8);
+    let runner = EndPointHost::new(
+        store.get_engine(),
+        end_point.scheduler(),
+        8,
+        Arc::new(ReadStats::new()),
+    );
     end_point.start_batch(runner, 5).unwrap();
 
     (store, end_point)
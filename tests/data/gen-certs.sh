@@ -0,0 +1,28 @@
+#!/bin/bash
+# Generates the CA, certificate and private key used by the TLS tests. Both
+# the servers and the clients in the tests use the same certificate, which
+# is issued for "tikv-server" and valid for localhost.
+
+set -e
+
+cd "$(dirname "$0")"
+
+cat > openssl.cnf <<CNF
+[req]
+distinguished_name = dn
+[dn]
+[ext]
+basicConstraints = CA:FALSE
+keyUsage = digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth, clientAuth
+subjectAltName = DNS:tikv-server, DNS:localhost, IP:127.0.0.1
+CNF
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=TiKV Test CA" \
+    -keyout ca-key.pem -out ca.pem
+openssl req -new -newkey rsa:2048 -nodes -subj "/CN=tikv-server" -config openssl.cnf \
+    -keyout key.pem -out cert.csr
+openssl x509 -req -days 36500 -in cert.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+    -extfile openssl.cnf -extensions ext -out cert.pem
+
+rm -f openssl.cnf cert.csr ca-key.pem ca.srl
@@ -0,0 +1,239 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The fail points are global in a process, so the tests using them run in
+// their own binary, and one by one.
+
+#![allow(stable_features)]
+#![feature(mpsc_recv_timeout)]
+#![feature(plugin)]
+#![cfg_attr(feature = "dev", plugin(clippy))]
+#![cfg_attr(not(feature = "dev"), allow(unknown_lints))]
+#![feature(box_syntax)]
+#![feature(fnbox)]
+#![feature(btree_range, collections_bound)]
+#![allow(new_without_default)]
+#![allow(needless_pass_by_value)]
+#![allow(unreadable_literal)]
+
+extern crate futures;
+extern crate grpcio as grpc;
+extern crate kvproto;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+extern crate protobuf;
+extern crate rand;
+extern crate rocksdb;
+extern crate tempdir;
+#[macro_use]
+extern crate tikv;
+
+// Only the cluster helpers of the raftstore tests are shared, the tests
+// themselves run in the `tests` binary.
+#[allow(dead_code)]
+mod raftstore {
+    pub mod util;
+    pub mod cluster;
+    pub mod node;
+    pub mod server;
+    pub mod pd;
+    pub mod transport_simulate;
+}
+
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use kvproto::kvrpcpb::Context;
+use tikv::raftstore::store::{keys, Peekable};
+use tikv::storage::{make_key, Mutation, Options, Storage};
+use tikv::storage::config::Config;
+use tikv::util::failpoint;
+
+use raftstore::cluster::{Cluster, Simulator};
+use raftstore::node::new_node_cluster;
+use raftstore::util::*;
+
+lazy_static! {
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn setup<'a>() -> MutexGuard<'a, ()> {
+    // A failed test poisons the lock, it doesn't matter.
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    failpoint::teardown();
+    guard
+}
+
+fn new_storage() -> Storage {
+    let config = Config::default();
+    let mut storage = Storage::new(&config).unwrap();
+    storage.start(&config).unwrap();
+    storage
+}
+
+fn prewrite(storage: &Storage, key: &[u8], start_ts: u64) -> tikv::storage::Result<()> {
+    let mutations = vec![Mutation::Put((make_key(key), b"v".to_vec()))];
+    let res = wait_op!(|cb| {
+        storage
+            .async_prewrite(
+                Context::new(),
+                mutations,
+                key.to_vec(),
+                start_ts,
+                Options::default(),
+                cb,
+            )
+            .unwrap()
+    }).unwrap();
+    for r in try!(res) {
+        r.unwrap();
+    }
+    Ok(())
+}
+
+#[test]
+fn test_failpoint_scheduler_async_write() {
+    let _guard = setup();
+    let mut storage = new_storage();
+    failpoint::cfg("scheduler_async_write", "return").unwrap();
+    assert!(prewrite(&storage, b"k1", 1).is_err());
+    failpoint::remove("scheduler_async_write");
+    prewrite(&storage, b"k1", 2).unwrap();
+    storage.stop().unwrap();
+}
+
+#[test]
+fn test_failpoint_scheduler_process_write_count() {
+    let _guard = setup();
+    let mut storage = new_storage();
+    // Fails only the first write.
+    failpoint::cfg("scheduler_process_write", "1*return->off").unwrap();
+    assert!(prewrite(&storage, b"k1", 1).is_err());
+    prewrite(&storage, b"k1", 2).unwrap();
+    storage.stop().unwrap();
+}
+
+#[test]
+fn test_failpoint_scheduler_async_snapshot_pause() {
+    let _guard = setup();
+    let mut storage = new_storage();
+    failpoint::cfg("scheduler_async_snapshot", "pause").unwrap();
+    let (tx, rx) = mpsc::channel();
+    let s = storage.clone();
+    thread::spawn(move || {
+        tx.send(prewrite(&s, b"k1", 1)).unwrap();
+    });
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+    failpoint::remove("scheduler_async_snapshot");
+    rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+    storage.stop().unwrap();
+}
+
+// Proposes a put of `key` on the leader of its region, and doesn't wait long for
+// it to be applied.
+fn try_put<T: Simulator>(cluster: &mut Cluster<T>, key: &[u8], value: &[u8]) {
+    let region = cluster.get_region(key);
+    let req = new_request(
+        region.get_id(),
+        region.get_region_epoch().clone(),
+        vec![new_put_cmd(key, value)],
+        false,
+    );
+    let _ = cluster.call_command_on_leader(req, Duration::from_millis(500));
+}
+
+fn test_write_paused_by(fp: &str) {
+    let _guard = setup();
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+
+    failpoint::cfg(fp, "pause").unwrap();
+    try_put(&mut cluster, b"k2", b"v2");
+    let written: Vec<_> = (1..4)
+        .map(|id| {
+            let engine = cluster.get_engine(id);
+            engine.get_value(&keys::data_key(b"k2")).unwrap().is_some()
+        })
+        .collect();
+    // Resumes the paused threads before any assertion, or the cluster
+    // can't be shut down.
+    failpoint::remove(fp);
+    assert_eq!(written, vec![false; 3], "{}", fp);
+
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k2", b"v2");
+    }
+    cluster.must_put(b"k3", b"v3");
+}
+
+#[test]
+fn test_failpoint_apply_raft_cmd() {
+    test_write_paused_by("apply_raft_cmd");
+}
+
+#[test]
+fn test_failpoint_apply_before_write_engine() {
+    test_write_paused_by("apply_before_write_engine");
+}
+
+#[test]
+fn test_failpoint_peer_storage_append() {
+    test_write_paused_by("peer_storage_append");
+}
+
+// Adds peer 2 to region 1, which is caught up by a snapshot, as the logs of a
+// new region start after `RAFT_INIT_LOG_INDEX`.
+fn test_add_peer_with_snapshot(fp: &str, actions: &str) {
+    let _guard = setup();
+    let mut cluster = new_node_cluster(0, 3);
+    let pd_client = cluster.pd_client.clone();
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    cluster.must_put(b"k1", b"v1");
+
+    failpoint::cfg(fp, actions).unwrap();
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    sleep_ms(500);
+    let engine_2 = cluster.get_engine(2);
+    let applied = engine_2.get_value(&keys::data_key(b"k1")).unwrap().is_some();
+    failpoint::remove(fp);
+    assert!(!applied, "{}", fp);
+
+    must_get_equal(&engine_2, b"k1", b"v1");
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&engine_2, b"k2", b"v2");
+}
+
+#[test]
+fn test_failpoint_snapshot_build() {
+    // The leader panics if the snapshot fails to be generated for
+    // `MAX_SNAP_TRY_CNT` times, so it only fails a few times, then the
+    // generation is paused until the fail point is removed.
+    test_add_peer_with_snapshot("snapshot_build", "3*return->pause");
+}
+
+#[test]
+fn test_failpoint_peer_storage_do_snapshot() {
+    test_add_peer_with_snapshot("peer_storage_do_snapshot", "3*return->pause");
+}
+
+#[test]
+fn test_failpoint_snapshot_apply() {
+    // A snapshot which fails to be applied panics the store, so it's paused
+    // instead.
+    test_add_peer_with_snapshot("snapshot_apply", "pause");
+}
@@ -17,6 +17,7 @@ use futures::{Future, Sink, Stream};
 use grpc::{DuplexSink, Environment, RequestStream, RpcContext, RpcStatus, RpcStatusCode,
            Server as GrpcServer, ServerBuilder, UnarySink, WriteFlags};
 use tikv::pd::Error as PdError;
+use tikv::util::security::SecurityManager;
 
 use kvproto::pdpb::*;
 use kvproto::pdpb_grpc::{self, Pd};
@@ -41,6 +42,18 @@ impl Server {
         handler: Arc<Service>,
         case: Option<Arc<C>>,
     ) -> Server
+    where
+        C: PdMocker + Send + Sync + 'static,
+    {
+        Server::run_with_security(eps, handler, case, &SecurityManager::default())
+    }
+
+    pub fn run_with_security<C>(
+        eps: Vec<(String, u16)>,
+        handler: Arc<Service>,
+        case: Option<Arc<C>>,
+        security_mgr: &SecurityManager,
+    ) -> Server
     where
         C: PdMocker + Send + Sync + 'static,
     {
@@ -52,7 +65,7 @@ impl Server {
         let env = Arc::new(Environment::new(1));
         let mut sb = ServerBuilder::new(env).register_service(service);
         for (host, port) in eps {
-            sb = sb.bind(host, port);
+            sb = security_mgr.bind(sb, &host, port);
         }
 
         let mut server = sb.build().unwrap();
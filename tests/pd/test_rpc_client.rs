@@ -15,13 +15,16 @@ use std::thread;
 use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
-use grpc::EnvBuilder;
+use grpc::{ChannelBuilder, EnvBuilder};
 use futures::Future;
 use futures_cpupool::Builder;
 use kvproto::metapb;
 use kvproto::pdpb;
+use kvproto::pdpb_grpc::PdClient as PdGrpcClient;
 
 use tikv::pd::{validate_endpoints, Error as PdError, PdClient, RegionStat, RpcClient};
+use tikv::util::security::{SecurityConfig, SecurityManager};
+use util::new_security_cfg;
 
 use super::mock::mocker::*;
 use super::mock::Server as MockServer;
@@ -39,7 +42,7 @@ fn test_rpc_client() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     assert_ne!(client.get_cluster_id().unwrap(), 0);
 
     let store_id = client.alloc_id().unwrap();
@@ -65,13 +68,15 @@ fn test_rpc_client() {
 
     let tmp_store = client.get_store(store_id).unwrap();
     assert_eq!(tmp_store.get_id(), store.get_id());
+    let tmp_store = client.get_store_async(store_id).wait().unwrap();
+    assert_eq!(tmp_store.get_id(), store.get_id());
 
     let tmp_region = client.get_region_by_id(region_id).wait().unwrap().unwrap();
     assert_eq!(tmp_region.get_id(), region.get_id());
 
     let mut prev_id = 0;
     for _ in 0..100 {
-        let client = RpcClient::new(&eps).unwrap();
+        let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
         let alloc_id = client.alloc_id().unwrap();
         assert!(alloc_id > prev_id);
         prev_id = alloc_id;
@@ -118,7 +123,7 @@ fn test_reboot() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
 
     assert!(!client.is_cluster_bootstrapped().unwrap());
 
@@ -145,7 +150,7 @@ fn test_validate_endpoints() {
 
     thread::sleep(Duration::from_secs(1));
 
-    assert!(validate_endpoints(env, &eps).is_err());
+    assert!(validate_endpoints(env, &SecurityManager::default(), &eps).is_err());
 }
 
 #[test]
@@ -163,7 +168,7 @@ fn test_retry_async() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
 
     for _ in 0..5 {
         let region = client.get_region_by_id(1);
@@ -186,7 +191,7 @@ fn test_restart_leader() {
 
     thread::sleep(Duration::from_secs(2));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     // Put a region.
     let store_id = client.alloc_id().unwrap();
     let mut store = metapb::Store::new();
@@ -237,7 +242,7 @@ fn test_change_leader_async() {
 
     thread::sleep(Duration::from_secs(1));
 
-    let client = RpcClient::new(&eps).unwrap();
+    let client = RpcClient::new(&eps, Arc::new(SecurityManager::default())).unwrap();
     let leader = client.get_leader();
 
     for _ in 0..5 {
@@ -253,3 +258,53 @@ fn test_change_leader_async() {
 
     panic!("failed, leader should changed");
 }
+
+#[test]
+fn test_secure_connect() {
+    let se = Arc::new(Service::new());
+    let security_mgr = Arc::new(SecurityManager::new(&new_security_cfg()).unwrap());
+    let eps = vec![("127.0.0.1".to_owned(), 0)];
+    let server = MockServer::run_with_security::<Service>(eps, se, None, &security_mgr);
+    let eps: Vec<String> = server
+        .bind_addrs()
+        .into_iter()
+        .map(|addr| format!("{}:{}", addr.0, addr.1))
+        .collect();
+
+    thread::sleep(Duration::from_secs(1));
+
+    let client = RpcClient::new(&eps, security_mgr).unwrap();
+    assert_ne!(client.get_cluster_id().unwrap(), 0);
+
+    // Plaintext clients can't talk to the TLS server.
+    assert!(RpcClient::new(&eps, Arc::new(SecurityManager::default())).is_err());
+}
+
+#[test]
+fn test_secure_connect_peer() {
+    let se = Arc::new(Service::new());
+    let mut cfg = new_security_cfg();
+    let security_mgr = Arc::new(SecurityManager::new(&cfg).unwrap());
+    let eps = vec![("127.0.0.1".to_owned(), 0)];
+    let server = MockServer::run_with_security::<Service>(eps, se, None, &security_mgr);
+    let (host, port) = server.bind_addrs()[0].clone();
+    let addr = format!("{}:{}", host, port);
+
+    thread::sleep(Duration::from_secs(1));
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let get_members = |cfg: &SecurityConfig| {
+        let mgr = SecurityManager::new(cfg).unwrap();
+        let channel = mgr.connect_peer(ChannelBuilder::new(env.clone()), &addr);
+        PdGrpcClient::new(channel).get_members(pdpb::GetMembersRequest::new())
+    };
+
+    // Without a common name, the certificate must be issued for the address.
+    get_members(&cfg).unwrap();
+
+    // The certificate is issued for "tikv-server".
+    cfg.cert_common_name = "tikv-server".to_owned();
+    get_members(&cfg).unwrap();
+    cfg.cert_common_name = "pd-server".to_owned();
+    assert!(get_members(&cfg).is_err());
+}
@@ -17,3 +17,5 @@ mod test_raft_snap;
 mod test_raft_paper;
 mod test_raft_flow_control;
 mod test_raw_node;
+mod test_file_storage;
+mod test_raft_simulator;
@@ -0,0 +1,324 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use kvproto::eraftpb::*;
+use rand::Rng;
+use tempdir::TempDir;
+
+use tikv::raft::*;
+use tikv::raft::file_storage::LOG_FILE_NAME;
+use tikv::raft::storage::MemStorage;
+use super::test_raft::*;
+use super::test_raft_paper::*;
+use util::new_seeded_rng;
+
+const SEED_ENV: &'static str = "FILE_STORAGE_SEED";
+
+// `Harness` runs the same operations on a `FileStorage` and a `MemStorage`,
+// and kills and restarts the `FileStorage` at any point, the recovered
+// storage should be the same as the `MemStorage`.
+struct Harness {
+    path: TempDir,
+    storage: Option<FileStorage>,
+    model: MemStorage,
+}
+
+impl Harness {
+    fn new(name: &str) -> Harness {
+        let path = TempDir::new(name).unwrap();
+        let storage = FileStorage::open(path.path()).unwrap();
+        Harness {
+            path: path,
+            storage: Some(storage),
+            model: MemStorage::new(),
+        }
+    }
+
+    fn storage(&self) -> &FileStorage {
+        self.storage.as_ref().unwrap()
+    }
+
+    fn log_size(&self) -> u64 {
+        fs::metadata(self.path.path().join(LOG_FILE_NAME))
+            .unwrap()
+            .len()
+    }
+
+    // Kills the storage, only the first `log_size` bytes of the log survive,
+    // and restarts it.
+    fn kill_and_restart(&mut self, log_size: u64) {
+        self.storage.take();
+        let log = OpenOptions::new()
+            .write(true)
+            .open(self.path.path().join(LOG_FILE_NAME))
+            .unwrap();
+        log.set_len(log_size).unwrap();
+        drop(log);
+        self.storage = Some(FileStorage::open(self.path.path()).unwrap());
+    }
+
+    fn restart(&mut self) {
+        let size = self.log_size();
+        self.kill_and_restart(size);
+    }
+
+    fn append(&mut self, ents: &[Entry]) {
+        self.storage().wl().append(ents).unwrap();
+        self.model.wl().append(ents).unwrap();
+    }
+
+    // Kills the storage in the middle of appending the entries, the
+    // partially written entries should be dropped.
+    fn torn_append<R: Rng>(&mut self, ents: &[Entry], rng: &mut R) {
+        let size = self.log_size();
+        self.storage().wl().append(ents).unwrap();
+        let written = self.log_size() - size;
+        if written == 0 {
+            return;
+        }
+        let survived = rng.gen_range(0, written);
+        self.kill_and_restart(size + survived);
+    }
+
+    fn check(&self, op: &str) {
+        let (s, m) = (self.storage(), &self.model);
+        let first = m.first_index().unwrap();
+        let last = m.last_index().unwrap();
+        assert_eq!(s.first_index().unwrap(), first, "{}", op);
+        assert_eq!(s.last_index().unwrap(), last, "{}", op);
+        for idx in first - 1..last + 1 {
+            assert_eq!(s.term(idx), m.term(idx), "{} term of {}", op, idx);
+        }
+        if first <= last {
+            assert_eq!(
+                s.entries(first, last + 1, u64::max_value()),
+                m.entries(first, last + 1, u64::max_value()),
+                "{}",
+                op
+            );
+        }
+        let (ss, ms) = (s.initial_state().unwrap(), m.initial_state().unwrap());
+        assert_eq!(ss.hard_state, ms.hard_state, "{}", op);
+        assert_eq!(ss.conf_state, ms.conf_state, "{}", op);
+        assert_eq!(s.snapshot(), m.snapshot(), "{}", op);
+    }
+}
+
+fn new_entries<R: Rng>(low: u64, high: u64, term: u64, rng: &mut R) -> Vec<Entry> {
+    (low..high)
+        .map(|i| {
+            let mut e = empty_entry(term, i);
+            let len = rng.gen_range(0, 256);
+            e.set_data(rng.gen_iter::<u8>().take(len).collect());
+            e
+        })
+        .collect()
+}
+
+#[test]
+fn test_file_storage_torn_append() {
+    let mut h = Harness::new("test-file-storage-torn-append");
+    let mut rng = new_seeded_rng(SEED_ENV, "test_file_storage_torn_append");
+    let ents = new_entries(1, 10, 1, &mut rng);
+    h.append(&ents);
+    h.check("append");
+
+    for _ in 0..20 {
+        let low = rng.gen_range(1, 11);
+        let ents = new_entries(low, low + 5, 2, &mut rng);
+        h.torn_append(&ents, &mut rng);
+        h.check(&format!("torn append at {}", low));
+    }
+
+    // The storage is still writable after recovery.
+    let ents = new_entries(5, 15, 3, &mut rng);
+    h.append(&ents);
+    h.restart();
+    h.check("append after recovery");
+}
+
+fn flip_byte(path: &Path, offset: u64) {
+    let mut data = vec![];
+    File::open(path).unwrap().read_to_end(&mut data).unwrap();
+    data[offset as usize] ^= 0xff;
+    File::create(path).unwrap().write_all(&data).unwrap();
+}
+
+#[test]
+fn test_file_storage_corrupted_record() {
+    let path = TempDir::new("test-file-storage-corrupted-record").unwrap();
+    let log_path = path.path().join(LOG_FILE_NAME);
+    let mut rng = new_seeded_rng(SEED_ENV, "test_file_storage_corrupted_record");
+    let storage = FileStorage::open(path.path()).unwrap();
+    storage
+        .wl()
+        .append(&new_entries(1, 5, 1, &mut rng))
+        .unwrap();
+    let size = fs::metadata(&log_path).unwrap().len();
+    storage
+        .wl()
+        .append(&new_entries(5, 10, 1, &mut rng))
+        .unwrap();
+    drop(storage);
+
+    // A broken last record is a torn write, it's truncated.
+    let total_size = fs::metadata(&log_path).unwrap().len();
+    flip_byte(&log_path, total_size - 1);
+    let storage = FileStorage::open(path.path()).unwrap();
+    assert_eq!(storage.last_index().unwrap(), 4);
+    assert_eq!(fs::metadata(&log_path).unwrap().len(), size);
+    storage
+        .wl()
+        .append(&new_entries(5, 10, 1, &mut rng))
+        .unwrap();
+    drop(storage);
+
+    // A broken record followed by others means the log is corrupted.
+    flip_byte(&log_path, size - 1);
+    assert!(FileStorage::open(path.path()).is_err());
+    flip_byte(&log_path, size - 1);
+    let storage = FileStorage::open(path.path()).unwrap();
+    assert_eq!(storage.last_index().unwrap(), 9);
+}
+
+#[test]
+fn test_file_storage_kill_and_restart() {
+    let mut h = Harness::new("test-file-storage-kill-and-restart");
+    let mut rng = new_seeded_rng(SEED_ENV, "test_file_storage_kill_and_restart");
+    let mut term = 1;
+    for i in 0..300 {
+        let first = h.model.first_index().unwrap();
+        let last = h.model.last_index().unwrap();
+        let snap_index = h.model.snapshot().unwrap().get_metadata().get_index();
+        // Entries before the snapshot are committed, they're never overwritten.
+        let min_append = cmp::max(first, snap_index + 1);
+        let op = match rng.gen_range(0, 8) {
+            0 | 1 => {
+                // Appends new entries, may overwrite the conflicting ones.
+                let low = rng.gen_range(min_append, last + 2);
+                let count = rng.gen_range(1, 10);
+                term += 1;
+                let ents = new_entries(low, low + count, term, &mut rng);
+                h.append(&ents);
+                format!("append [{}, {})", low, low + count)
+            }
+            2 => {
+                let low = rng.gen_range(min_append, last + 2);
+                term += 1;
+                let ents = new_entries(low, low + 5, term, &mut rng);
+                h.torn_append(&ents, &mut rng);
+                format!("torn append at {}", low)
+            }
+            3 if first < last => {
+                let idx = rng.gen_range(first, last + 1);
+                h.storage().wl().compact(idx).unwrap();
+                h.model.wl().compact(idx).unwrap();
+                format!("compact {}", idx)
+            }
+            4 if min_append <= last => {
+                let idx = rng.gen_range(min_append, last + 1);
+                let mut cs = ConfState::new();
+                cs.set_nodes(vec![1, 2, rng.gen_range(3, 100)]);
+                let data = format!("snap {}", idx).into_bytes();
+                h.storage()
+                    .wl()
+                    .create_snapshot(idx, Some(cs.clone()), data.clone())
+                    .unwrap();
+                h.model.wl().create_snapshot(idx, Some(cs), data).unwrap();
+                format!("create snapshot {}", idx)
+            }
+            5 => {
+                let idx = cmp::max(last, snap_index) + rng.gen_range(1, 10);
+                term += 1;
+                let mut snap = new_snapshot(idx, term, vec![1, 2, 3]);
+                snap.set_data(format!("snap {}", idx).into_bytes());
+                h.storage().wl().apply_snapshot(snap.clone()).unwrap();
+                h.model.wl().apply_snapshot(snap).unwrap();
+                format!("apply snapshot {}", idx)
+            }
+            6 => {
+                let hs = hard_state(term, rng.gen_range(0, last + 1), rng.gen_range(0, 4));
+                h.storage().wl().set_hardstate(hs.clone()).unwrap();
+                h.model.wl().set_hardstate(hs);
+                format!("set hard state {:?}", h.model.initial_state().unwrap().hard_state)
+            }
+            _ => {
+                h.restart();
+                "restart".to_owned()
+            }
+        };
+        h.check(&format!("#{} {}", i, op));
+    }
+    h.restart();
+    h.check("final restart");
+}
+
+// Processes all the readies of a single node raft, persists them in the
+// storage, returns the applied entries.
+fn handle_ready(node: &mut RawNode<FileStorage>) -> Vec<Entry> {
+    let mut applied = vec![];
+    while node.has_ready() {
+        let mut rd = node.ready();
+        if let Some(ref hs) = rd.hs {
+            node.mut_store().wl().set_hardstate(hs.clone()).unwrap();
+        }
+        node.mut_store().wl().append(&rd.entries).unwrap();
+        if let Some(ents) = rd.committed_entries.take() {
+            applied.extend(ents);
+        }
+        node.advance(rd);
+    }
+    applied
+}
+
+#[test]
+fn test_file_storage_raft_restart() {
+    let path = TempDir::new("test-file-storage-raft-restart").unwrap();
+    let cfg = new_test_config(1, vec![1], 10, 1);
+    {
+        let storage = FileStorage::open(path.path()).unwrap();
+        let mut node = RawNode::new(&cfg, storage, &[]).unwrap();
+        node.campaign().unwrap();
+        handle_ready(&mut node);
+        for i in 0..10 {
+            node.propose(format!("v{}", i).into_bytes()).unwrap();
+            handle_ready(&mut node);
+        }
+        // Persisted but not committed when killed.
+        node.propose(b"uncommitted".to_vec()).unwrap();
+        let rd = node.ready();
+        node.mut_store().wl().append(&rd.entries).unwrap();
+        assert_eq!(node.get_store().last_index().unwrap(), 12);
+    }
+
+    let storage = FileStorage::open(path.path()).unwrap();
+    assert_eq!(storage.last_index().unwrap(), 12);
+    let hs = storage.initial_state().unwrap().hard_state;
+    assert_eq!(hs.get_term(), 2);
+    assert_eq!(hs.get_commit(), 11);
+
+    // The restarted node replays the committed entries and keeps working.
+    let mut node = RawNode::new(&cfg, storage, &[]).unwrap();
+    let applied = handle_ready(&mut node);
+    assert_eq!(applied.len(), 11);
+    assert_eq!(applied[10].get_data(), b"v9");
+    node.campaign().unwrap();
+    let applied = handle_ready(&mut node);
+    assert_eq!(applied[0].get_data(), b"uncommitted");
+    assert_eq!(node.raft.raft_log.committed, 13);
+}
@@ -25,10 +25,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::cmp;
+use std::rc::Rc;
 
 use protobuf::{self, RepeatedField};
 use kvproto::eraftpb::{ConfChange, ConfChangeType, ConfState, Entry, EntryType, HardState,
@@ -3398,3 +3400,380 @@ fn test_transfer_non_member() {
         .expect("");;
     assert_eq!(raft.state, StateRole::Follower);
 }
+
+fn new_test_witness(id: u64, peers: Vec<u64>) -> Interface {
+    let mut cfg = new_test_config(id, peers, 10, 1);
+    cfg.witness = true;
+    Interface::new(Raft::new(&cfg, new_storage()))
+}
+
+// test_witness_never_campaigns verifies that a witness votes and replicates logs
+// but never starts an election, neither by timeout nor by MsgHup.
+#[test]
+fn test_witness_never_campaigns() {
+    let witness = new_test_witness(3, vec![1, 2, 3]);
+    let mut nt = Network::new(vec![None, None, Some(witness)]);
+
+    let randomized_timeout = nt.peers[&3].get_randomized_election_timeout();
+    for _ in 0..randomized_timeout * 2 {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].term, 0);
+
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].term, 0);
+
+    // The witness votes for others and persists their logs.
+    nt.isolate(2);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    assert_eq!(nt.peers[&1].raft_log.committed, 2);
+    assert_eq!(nt.peers[&3].raft_log.last_index(), 2);
+}
+
+// test_leader_transfer_to_witness verifies that the leadership transferring to
+// a witness is aborted after an election timeout.
+#[test]
+fn test_leader_transfer_to_witness() {
+    let witness = new_test_witness(3, vec![1, 2, 3]);
+    let mut nt = Network::new(vec![None, None, Some(witness)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].leader_id, 1);
+
+    nt.send(vec![new_message(3, 1, MessageType::MsgTransferLeader, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+
+    let election_timeout = nt.peers[&1].get_election_timeout();
+    for _ in 0..election_timeout {
+        nt.peers.get_mut(&1).unwrap().tick();
+    }
+    check_leader_transfer_state(&nt.peers[&1], StateRole::Leader, 1);
+}
+
+// new_test_prioritized creates a raft knowing the election priorities of all
+// the peers, the peers are the ids in priorities.
+fn new_test_prioritized(id: u64, priorities: &[(u64, u64)]) -> Interface {
+    let peers = priorities.iter().map(|&(id, _)| id).collect();
+    let mut cfg = new_test_config(id, peers, 10, 1);
+    cfg.priority = priorities
+        .iter()
+        .find(|&&(peer_id, _)| peer_id == id)
+        .map_or(0, |&(_, priority)| priority);
+    let mut raft = Raft::new(&cfg, new_storage());
+    for &(peer_id, priority) in priorities {
+        raft.set_peer_priority(peer_id, priority);
+    }
+    Interface::new(raft)
+}
+
+// tick_until_elected ticks the nodes in ids and delivers their messages until
+// one of them becomes the leader, returns the leader.
+fn tick_until_elected(nt: &mut Network, ids: &[u64], max_ticks: usize) -> Option<u64> {
+    for _ in 0..max_ticks {
+        for id in ids {
+            let msgs = {
+                let r = nt.peers.get_mut(id).unwrap();
+                r.tick();
+                r.read_messages()
+            };
+            nt.send(msgs);
+        }
+        if let Some(id) = ids.iter().find(|id| nt.peers[*id].state == StateRole::Leader) {
+            return Some(*id);
+        }
+    }
+    None
+}
+
+// test_election_priority verifies that the node with the highest priority
+// campaigns first and becomes the leader.
+#[test]
+fn test_election_priority() {
+    let priorities = vec![(1, 0), (2, 0), (3, 1)];
+    let mut nt = Network::new(vec![
+        Some(new_test_prioritized(1, &priorities)),
+        Some(new_test_prioritized(2, &priorities)),
+        Some(new_test_prioritized(3, &priorities)),
+    ]);
+
+    // The lower priority nodes wait an extra election timeout.
+    for id in 1..3 {
+        let timeout = nt.peers[&id].get_randomized_election_timeout();
+        assert!(timeout >= 20 && timeout < 30, "{} timeout {}", id, timeout);
+    }
+    let timeout = nt.peers[&3].get_randomized_election_timeout();
+    assert!(timeout >= 10 && timeout < 20, "timeout {}", timeout);
+
+    assert_eq!(tick_until_elected(&mut nt, &[1, 2, 3], 100), Some(3));
+}
+
+// test_election_priority_reject_vote verifies that an up-to-date node rejects
+// the votes for the candidates with lower priorities and campaigns itself,
+// but the leadership can still be transferred to them.
+#[test]
+fn test_election_priority_reject_vote() {
+    let priorities = vec![(1, 0), (2, 0), (3, 1)];
+    let mut nt = Network::new(vec![
+        Some(new_test_prioritized(1, &priorities)),
+        Some(new_test_prioritized(2, &priorities)),
+        Some(new_test_prioritized(3, &priorities)),
+    ]);
+    nt.isolate(2);
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Candidate);
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].vote, INVALID_ID);
+
+    // Node 3 campaigns at the next tick.
+    assert_eq!(tick_until_elected(&mut nt, &[3], 1), Some(3));
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+
+    nt.recover();
+    nt.send(vec![new_message(1, 3, MessageType::MsgTransferLeader, 0)]);
+    check_leader_transfer_state(&nt.peers[&1], StateRole::Leader, 1);
+}
+
+// test_election_priority_liveness verifies that the nodes with lower priorities
+// are still elected when the nodes with higher priorities are down or behind.
+#[test]
+fn test_election_priority_liveness() {
+    let priorities = vec![(1, 0), (2, 1), (3, 2)];
+    let mut nt = Network::new(vec![
+        Some(new_test_prioritized(1, &priorities)),
+        Some(new_test_prioritized(2, &priorities)),
+        Some(new_test_prioritized(3, &priorities)),
+    ]);
+
+    // Node 1 waits for both the higher priorities.
+    let timeout = nt.peers[&1].get_randomized_election_timeout();
+    assert!(timeout >= 30 && timeout < 40, "timeout {}", timeout);
+
+    nt.isolate(3);
+    assert_eq!(tick_until_elected(&mut nt, &[1, 2], 100), Some(2));
+
+    // Node 3 has the highest priority but a stale log, so node 1 is elected
+    // after node 2 is down.
+    nt.recover();
+    nt.isolate(2);
+    assert_eq!(tick_until_elected(&mut nt, &[1, 3], 1000), Some(1));
+    assert_eq!(nt.peers[&3].raft_log.last_index(), nt.peers[&1].raft_log.last_index());
+}
+
+// test_async_fetch_entries verifies that the leader doesn't send the entries
+// being fetched by the storage, and sends them after the fetch is done.
+#[test]
+fn test_async_fetch_entries() {
+    let store = new_storage();
+    let ents: Vec<Entry> = (1..6).map(|i| empty_entry(1, i)).collect();
+    store.wl().append(&ents).expect("");
+    let mut r = new_test_raft(1, vec![1, 2], 10, 1, store.clone());
+    r.become_candidate();
+    r.become_leader();
+    r.read_messages();
+
+    // The follower rejects the append and the leader probes from index 1.
+    store.wl().trigger_log_unavailable(true);
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.set_index(5);
+    m.set_reject(true);
+    m.set_reject_hint(0);
+    r.step(m).expect("");
+    assert!(r.read_messages().is_empty());
+    assert_eq!(r.prs[&2].next_idx, 1);
+    assert!(r.prs[&2].pending_fetch);
+
+    store.wl().trigger_log_unavailable(false);
+    r.on_entries_fetched();
+    assert!(!r.prs[&2].pending_fetch);
+    let msgs = r.read_messages();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(msgs[0].get_msg_type(), MessageType::MsgAppend);
+    assert_eq!(msgs[0].get_index(), 0);
+    let indexes: Vec<u64> = msgs[0].get_entries().iter().map(|e| e.get_index()).collect();
+    assert_eq!(indexes, vec![1, 2, 3, 4, 5, 6]);
+}
+
+#[derive(Debug, PartialEq)]
+enum RaftEvent {
+    RoleChanged(u64, StateRole, StateRole),
+    VoteGranted(u64, MessageType, u64),
+    VoteRejected(u64, MessageType, u64, VoteRejectReason),
+    LogConflict(u64, u64, u64, LogConflict),
+    SnapshotSent(u64, u64, u64, u64),
+}
+
+struct EventRecorder {
+    events: Rc<RefCell<Vec<RaftEvent>>>,
+}
+
+impl RaftObserver for EventRecorder {
+    fn on_role_changed(&mut self, term: u64, from: StateRole, to: StateRole) {
+        self.events
+            .borrow_mut()
+            .push(RaftEvent::RoleChanged(term, from, to));
+    }
+
+    fn on_vote_granted(&mut self, term: u64, msg_type: MessageType, candidate: u64) {
+        self.events
+            .borrow_mut()
+            .push(RaftEvent::VoteGranted(term, msg_type, candidate));
+    }
+
+    fn on_vote_rejected(
+        &mut self,
+        term: u64,
+        msg_type: MessageType,
+        candidate: u64,
+        reason: VoteRejectReason,
+    ) {
+        self.events
+            .borrow_mut()
+            .push(RaftEvent::VoteRejected(term, msg_type, candidate, reason));
+    }
+
+    fn on_log_conflict(&mut self, term: u64, leader: u64, index: u64, conflict: LogConflict) {
+        self.events
+            .borrow_mut()
+            .push(RaftEvent::LogConflict(term, leader, index, conflict));
+    }
+
+    fn on_snapshot_sent(&mut self, term: u64, to: u64, snap_index: u64, snap_term: u64) {
+        self.events
+            .borrow_mut()
+            .push(RaftEvent::SnapshotSent(term, to, snap_index, snap_term));
+    }
+}
+
+fn record_events(r: &mut Raft<MemStorage>) -> Rc<RefCell<Vec<RaftEvent>>> {
+    let events = Rc::new(RefCell::new(vec![]));
+    r.set_observer(box EventRecorder {
+        events: events.clone(),
+    });
+    events
+}
+
+#[test]
+fn test_raft_observer_election() {
+    let mut nt = Network::new(vec![None, None, None]);
+    let events1 = record_events(nt.peers.get_mut(&1).unwrap());
+    let events2 = record_events(nt.peers.get_mut(&2).unwrap());
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(
+        *events1.borrow(),
+        vec![
+            RaftEvent::RoleChanged(1, StateRole::Follower, StateRole::Candidate),
+            RaftEvent::RoleChanged(1, StateRole::Candidate, StateRole::Leader),
+        ]
+    );
+    assert_eq!(
+        *events2.borrow(),
+        vec![
+            RaftEvent::RoleChanged(1, StateRole::Follower, StateRole::Follower),
+            RaftEvent::VoteGranted(1, MessageType::MsgRequestVote, 1),
+        ]
+    );
+
+    // Node 3 misses the entry, its vote requests are rejected.
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    nt.recover();
+    events1.borrow_mut().clear();
+    events2.borrow_mut().clear();
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(
+        *events1.borrow(),
+        vec![
+            RaftEvent::RoleChanged(2, StateRole::Leader, StateRole::Follower),
+            RaftEvent::VoteRejected(
+                2,
+                MessageType::MsgRequestVote,
+                3,
+                VoteRejectReason::StaleLog,
+            ),
+        ]
+    );
+    assert_eq!(
+        events2.borrow()[1],
+        RaftEvent::VoteRejected(2, MessageType::MsgRequestVote, 3, VoteRejectReason::StaleLog)
+    );
+}
+
+#[test]
+fn test_raft_observer_log_conflict() {
+    let store = new_storage();
+    store
+        .wl()
+        .append(&[empty_entry(1, 1), empty_entry(1, 2)])
+        .unwrap();
+    let mut r = new_test_raft(1, vec![1, 2], 10, 1, store);
+    r.become_follower(2, 2);
+    let events = record_events(&mut r);
+
+    // The entry at 2 conflicts with the local one and overwrites it.
+    let mut m = new_message_with_entries(2, 1, MessageType::MsgAppend, vec![empty_entry(2, 2)]);
+    m.set_term(2);
+    m.set_index(1);
+    m.set_log_term(1);
+    r.step(m).unwrap();
+    // The local log doesn't contain the entry at 5.
+    let mut m = new_message(2, 1, MessageType::MsgAppend, 0);
+    m.set_term(2);
+    m.set_index(5);
+    m.set_log_term(2);
+    r.step(m).unwrap();
+
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            RaftEvent::LogConflict(2, 2, 2, LogConflict::Truncated),
+            RaftEvent::LogConflict(2, 2, 5, LogConflict::Mismatch),
+        ]
+    );
+}
+
+#[test]
+fn test_raft_observer_snapshot_sent() {
+    let mut sm = new_test_raft(1, vec![1], 10, 1, new_storage());
+    sm.restore(new_snapshot(11, 11, vec![1, 2]));
+    sm.become_candidate();
+    sm.become_leader();
+    let events = record_events(&mut sm);
+
+    sm.prs.get_mut(&2).unwrap().next_idx = sm.raft_log.first_index();
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.set_index(sm.prs[&2].next_idx - 1);
+    m.set_reject(true);
+    sm.step(m).unwrap();
+    assert_eq!(*events.borrow(), vec![RaftEvent::SnapshotSent(1, 2, 11, 11)]);
+}
+
+#[test]
+fn test_raft_status_followers() {
+    let mut nt = Network::new(vec![None, None, None]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+
+    let status = Status::new(&*nt.peers[&1]);
+    assert_eq!(status.followers.len(), 2);
+    assert!(!status.followers.contains_key(&1));
+    for id in 2..4 {
+        let follower = &status.followers[&id];
+        assert!(follower.last_active.is_some(), "{}: {:?}", id, follower);
+        assert!(follower.recent_active);
+        assert_eq!(follower.inflight_count, 0);
+        assert_eq!(follower.inflight_bytes, 0);
+    }
+    assert!(Status::new(&*nt.peers[&2]).followers.is_empty());
+
+    // The entries sent to the isolated node are inflight.
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let status = Status::new(&*nt.peers[&1]);
+    assert_eq!(status.followers[&2].inflight_count, 0);
+    assert_eq!(status.followers[&3].inflight_count, 1);
+    assert!(status.followers[&3].inflight_bytes > 0);
+}
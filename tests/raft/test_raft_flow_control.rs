@@ -27,6 +27,7 @@
 
 use super::test_raft::*;
 use kvproto::eraftpb::*;
+use tikv::raft::ProgressState;
 
 // test_msg_app_flow_control_full ensures:
 // 1. msgApp can fill the sending window until full
@@ -183,3 +184,112 @@ fn test_msg_app_flow_control_recv_heartbeat() {
         r.read_messages();
     }
 }
+
+fn new_propose_with_size(size: usize) -> Message {
+    let mut e = Entry::new();
+    e.set_data(vec![b'v'; size]);
+    new_message_with_entries(1, 1, MessageType::MsgPropose, vec![e])
+}
+
+// test_msg_app_flow_control_bytes ensures:
+// 1. msgApp stops being sent when the size of the inflight entries exceeds
+//    max_inflight_bytes, even if the sending window is not full.
+// 2. valid msgAppResp frees the size of the acknowledged entries.
+#[test]
+fn test_msg_app_flow_control_bytes() {
+    let mut cfg = new_test_config(1, vec![1, 2], 5, 1);
+    cfg.max_inflight_bytes = 2048;
+    let mut r = new_test_raft_with_config(&cfg, new_storage());
+    r.become_candidate();
+    r.become_leader();
+
+    // force the progress to be in replicate state
+    r.prs.get_mut(&2).unwrap().become_replicate();
+    // the first message carries the noop entry 1 and the entry 2.
+    for i in 0..2 {
+        r.step(new_propose_with_size(1024)).expect("");
+        let ms = r.read_messages();
+        if ms.len() != 1 {
+            panic!("#{}: ms count = {}, want 1", i, ms.len());
+        }
+    }
+
+    // ensure 1
+    assert!(r.prs[&2].ins.full());
+    assert!(r.prs[&2].ins.bytes() >= 2048);
+    assert!(r.prs[&2].ins.cap() > 2);
+    r.step(new_propose_with_size(1024)).expect("");
+    assert!(r.read_messages().is_empty());
+
+    // ensure 2
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.set_index(2);
+    r.step(m).expect("");
+    let ms = r.read_messages();
+    assert_eq!(ms.len(), 1);
+    assert_eq!(ms[0].get_entries().len(), 1);
+    assert_eq!(ms[0].get_entries()[0].get_index(), 4);
+    assert!(r.prs[&2].ins.full());
+
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.set_index(4);
+    r.step(m).expect("");
+    assert_eq!(r.prs[&2].ins.bytes(), 0);
+    assert!(!r.prs[&2].ins.full());
+}
+
+// test_msg_app_flow_control_bytes_oversized ensures an entry larger than
+// max_inflight_bytes can still be sent when nothing is in flight.
+#[test]
+fn test_msg_app_flow_control_bytes_oversized() {
+    let mut cfg = new_test_config(1, vec![1, 2], 5, 1);
+    cfg.max_inflight_bytes = 512;
+    let mut r = new_test_raft_with_config(&cfg, new_storage());
+    r.become_candidate();
+    r.become_leader();
+    r.prs.get_mut(&2).unwrap().become_replicate();
+
+    r.step(new_propose_with_size(1024)).expect("");
+    assert_eq!(r.read_messages().len(), 1);
+    assert!(r.prs[&2].ins.full());
+
+    // a heartbeat response frees one inflight to allow progress.
+    r.step(new_message(2, 1, MessageType::MsgHeartbeatResponse, 0))
+        .expect("");
+    assert!(!r.prs[&2].ins.full());
+    r.read_messages();
+    r.step(new_propose_with_size(1024)).expect("");
+    assert_eq!(r.read_messages().len(), 1);
+}
+
+// test_msg_app_probe_size ensures the leader limits the entries sent to the
+// peers in probe state by max_probe_size_per_msg, and sends the rest in one
+// message after the peer becomes replicate.
+#[test]
+fn test_msg_app_probe_size() {
+    let mut cfg = new_test_config(1, vec![1, 2], 5, 1);
+    cfg.max_probe_size_per_msg = 1;
+    let mut r = new_test_raft_with_config(&cfg, new_storage());
+    r.become_candidate();
+    r.become_leader();
+    assert_eq!(r.prs[&2].state, ProgressState::Probe);
+
+    for _ in 0..3 {
+        r.step(new_message(1, 1, MessageType::MsgPropose, 1))
+            .expect("");
+    }
+    // at most one entry is sent for probing, and then the progress is paused.
+    let ms = r.read_messages();
+    assert_eq!(ms.len(), 1);
+    assert_eq!(ms[0].get_entries().len(), 1);
+    assert_eq!(ms[0].get_entries()[0].get_index(), 1);
+
+    let mut m = new_message(2, 1, MessageType::MsgAppendResponse, 0);
+    m.set_index(1);
+    r.step(m).expect("");
+    assert_eq!(r.prs[&2].state, ProgressState::Replicate);
+    let ms = r.read_messages();
+    assert_eq!(ms.len(), 1);
+    let indexes: Vec<u64> = ms[0].get_entries().iter().map(|e| e.get_index()).collect();
+    assert_eq!(indexes, vec![2, 3, 4]);
+}
@@ -213,6 +213,43 @@ fn test_raw_node_propose_and_conf_change() {
     assert_eq!(entries[1].get_data(), &*ccdata);
 }
 
+// test_raw_node_propose_with_context ensures that RawNode.propose_with_context keeps
+// the context in the proposed entry.
+#[test]
+fn test_raw_node_propose_with_context() {
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![], 10, 1, s.clone(), vec![new_peer(1)]);
+    let rd = raw_node.ready();
+    s.wl().append(&rd.entries).expect("");
+    raw_node.advance(rd);
+    raw_node.campaign().expect("");
+    let mut proposed = false;
+    let mut last_index;
+    loop {
+        let rd = raw_node.ready();
+        s.wl().append(&rd.entries).expect("");
+        if !proposed && rd.ss.is_some() && rd.ss.as_ref().unwrap().leader_id == raw_node.raft.id {
+            raw_node
+                .propose_with_context(b"somecontext".to_vec(), b"somedata".to_vec())
+                .expect("");
+            proposed = true;
+        }
+        raw_node.advance(rd);
+
+        // Exit when we have three entries: one ConfChange, one no-op for the election
+        // and our proposed command.
+        last_index = s.last_index().unwrap();
+        if last_index >= 3 {
+            break;
+        }
+    }
+
+    let entries = s.entries(last_index, last_index + 1, NO_LIMIT).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].get_data(), b"somedata");
+    assert_eq!(entries[0].get_context(), b"somecontext");
+}
+
 // test_raw_node_propose_add_duplicate_node ensures that two proposes to add the same node should
 // not affect the later propose to add new node.
 #[test]
@@ -24,7 +24,9 @@ use futures::Future;
 
 use tikv::raftstore::{Error, Result};
 use tikv::raftstore::store::*;
+use tikv::raftstore::store::raft_engine::open_raft_engine;
 use tikv::config::TiKvConfig;
+use tikv::server::{ServerRaftStoreRouter, StatusServer};
 use tikv::storage::{ALL_CFS, CF_DEFAULT};
 use super::util::*;
 use kvproto::pdpb;
@@ -126,14 +128,31 @@ impl<T: Simulator> Cluster<T> {
             let engine = Arc::new(
                 rocksdb::new_engine(item.path().to_str().unwrap(), &kv_cfs).unwrap(),
             );
-            let raft_path = item.path().join(Path::new("raft"));
-            let raft_engine = Arc::new(
-                rocksdb::new_engine(raft_path.to_str().unwrap(), &[CF_DEFAULT]).unwrap(),
-            );
+            let raft_engine = self.create_raft_engine(item.path());
             self.dbs.push(Engines::new(engine, raft_engine));
         }
     }
 
+    fn create_raft_engine(&self, path: &Path) -> Arc<RaftEngine> {
+        let mut cfg = self.cfg.raft_store.clone();
+        let raft_path = path.join(Path::new(&format!("raft-{}", cfg.raft_log_engine)));
+        cfg.raftdb_path = raft_path.to_str().unwrap().to_owned();
+        open_raft_engine(&cfg, |path| {
+            rocksdb::new_engine(path, &[CF_DEFAULT]).map_err(Into::into)
+        }).unwrap()
+    }
+
+    // Switches the raft log engine of all stores, must be called before
+    // the cluster is started.
+    pub fn set_raft_log_engine(&mut self, engine: &str) {
+        assert!(self.engines.is_empty());
+        self.cfg.raft_store.raft_log_engine = engine.to_owned();
+        for i in 0..self.paths.len() {
+            let raft_engine = self.create_raft_engine(self.paths[i].path());
+            self.dbs[i].raft_engine = raft_engine;
+        }
+    }
+
     pub fn start(&mut self) {
         if self.engines.is_empty() {
             let mut sim = self.sim.wl();
@@ -183,7 +202,7 @@ impl<T: Simulator> Cluster<T> {
         self.engines[&node_id].kv_engine.clone()
     }
 
-    pub fn get_raft_engine(&self, node_id: u64) -> Arc<DB> {
+    pub fn get_raft_engine(&self, node_id: u64) -> Arc<RaftEngine> {
         self.engines[&node_id].raft_engine.clone()
     }
 
@@ -777,6 +796,54 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    pub fn split_region(
+        &mut self,
+        region: &metapb::Region,
+        split_keys: Vec<Vec<u8>>,
+        cb: SplitCallback,
+    ) {
+        let leader = self.leader_of_region(region.get_id()).unwrap();
+        let ch = self.sim
+            .rl()
+            .get_store_sendch(leader.get_store_id())
+            .unwrap();
+        ch.try_send(Msg::new_split_region(
+            region.get_id(),
+            region.get_region_epoch().clone(),
+            split_keys,
+            cb,
+        )).unwrap();
+    }
+
+    /// Starts a status server which serves the admin operations of the store.
+    pub fn start_status_server(&self, store_id: u64) -> StatusServer {
+        let ch = self.sim.rl().get_store_sendch(store_id).unwrap();
+        let mut server = StatusServer::new(&self.cfg);
+        server.set_raft_router(ServerRaftStoreRouter::new(ch));
+        server.start("127.0.0.1:0").unwrap();
+        server
+    }
+
+    pub fn remove_failed_stores(
+        &mut self,
+        store_id: u64,
+        failed_stores: Vec<u64>,
+        dry_run: bool,
+        cb: RecoveryCallback,
+    ) {
+        let ch = self.sim.rl().get_store_sendch(store_id).unwrap();
+        ch.try_send(Msg::RemoveFailedStores {
+            store_ids: failed_stores,
+            dry_run: dry_run,
+            callback: cb,
+        }).unwrap();
+    }
+
+    pub fn drain_leaders(&mut self, store_id: u64, cb: DrainCallback) {
+        let ch = self.sim.rl().get_store_sendch(store_id).unwrap();
+        ch.try_send(Msg::DrainLeaders { callback: cb }).unwrap();
+    }
+
     /// Make sure region exists on that store.
     pub fn must_region_exist(&mut self, region_id: u64, store_id: u64) {
         let mut try_cnt = 0;
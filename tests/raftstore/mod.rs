@@ -35,3 +35,10 @@ mod test_region_heartbeat;
 mod test_stale_peer;
 mod test_lease_read;
 mod test_bootstrap;
+mod test_unsafe_recovery;
+mod test_flow_control;
+mod test_consistency_check;
+mod test_raft_engine;
+mod test_witness;
+mod test_election_priority;
+mod test_security;
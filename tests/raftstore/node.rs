@@ -34,6 +34,7 @@ use tikv::util::HandyRwLock;
 use tikv::util::transport::SendCh;
 use tikv::server::transport::{RaftStoreRouter, ServerRaftStoreRouter};
 use tikv::raft::SnapshotStatus;
+use tikv::storage::ReadStats;
 use super::pd::TestPdClient;
 use super::transport_simulate::*;
 
@@ -58,6 +59,21 @@ impl ChannelTransport {
             })),
         }
     }
+
+    fn find_snap_sender(&self, from_store: u64, key: &SnapKey) -> Option<u64> {
+        let core = self.rl();
+        let exists = |store_id: &u64| {
+            core.snap_paths[store_id]
+                .0
+                .get_snapshot_for_sending(key)
+                .map(|s| s.exists())
+                .unwrap_or(false)
+        };
+        if !core.snap_paths.contains_key(&from_store) || exists(&from_store) {
+            return None;
+        }
+        core.snap_paths.keys().find(|id| exists(*id)).cloned()
+    }
 }
 
 impl Deref for ChannelTransport {
@@ -70,7 +86,7 @@ impl Deref for ChannelTransport {
 
 impl Channel<RaftMessage> for ChannelTransport {
     fn send(&self, msg: RaftMessage) -> Result<()> {
-        let from_store = msg.get_from_peer().get_store_id();
+        let mut from_store = msg.get_from_peer().get_store_id();
         let to_store = msg.get_to_peer().get_store_id();
         let to_peer_id = msg.get_to_peer().get_id();
         let region_id = msg.get_region_id();
@@ -79,6 +95,10 @@ impl Channel<RaftMessage> for ChannelTransport {
         if msg.get_message().get_msg_type() == MessageType::MsgSnapshot {
             let snap = msg.get_message().get_snapshot();
             let key = SnapKey::from_snap(snap).unwrap();
+            // The snapshot may be sent by a follower on behalf of the leader.
+            if let Some(store_id) = self.find_snap_sender(from_store, &key) {
+                from_store = store_id;
+            }
             let from = match self.rl().snap_paths.get(&from_store) {
                 Some(p) => {
                     p.0.register(key.clone(), SnapEntry::Sending);
@@ -191,6 +211,7 @@ impl Simulator for NodeCluster {
             simulate_trans.clone(),
             snap_mgr.clone(),
             snap_status_receiver,
+            Arc::new(ReadStats::new()),
         ).unwrap();
         assert!(
             engines
@@ -74,6 +74,8 @@ struct Cluster {
 
     down_peers: HashMap<u64, pdpb::PeerStats>,
     pending_peers: HashMap<u64, metapb::Peer>,
+    // region id -> the peers reported to be inconsistent.
+    inconsistent_peers: HashMap<u64, Vec<metapb::Peer>>,
     is_bootstraped: bool,
 }
 
@@ -94,6 +96,7 @@ impl Cluster {
             split_count: 0,
             down_peers: HashMap::new(),
             pending_peers: HashMap::new(),
+            inconsistent_peers: HashMap::new(),
             is_bootstraped: false,
         }
     }
@@ -588,6 +591,15 @@ impl TestPdClient {
         self.cluster.rl().pending_peers.clone()
     }
 
+    pub fn get_inconsistent_peers(&self, region_id: u64) -> Vec<metapb::Peer> {
+        self.cluster
+            .rl()
+            .inconsistent_peers
+            .get(&region_id)
+            .cloned()
+            .unwrap_or_else(Vec::new)
+    }
+
     pub fn set_bootstrap(&self, is_bootstraped: bool) {
         self.cluster.wl().set_bootstrap(is_bootstraped);
     }
@@ -627,6 +639,13 @@ impl PdClient for TestPdClient {
         self.cluster.rl().get_store(store_id)
     }
 
+    fn get_store_async(&self, store_id: u64) -> PdFuture<metapb::Store> {
+        match self.get_store(store_id) {
+            Ok(store) => ok(store).boxed(),
+            Err(e) => err(e).boxed(),
+        }
+    }
+
 
     fn get_region(&self, key: &[u8]) -> Result<metapb::Region> {
         try!(self.check_bootstrap());
@@ -741,4 +760,22 @@ impl PdClient for TestPdClient {
         self.cluster.wl().split_count += 1;
         ok(()).boxed()
     }
+
+    fn report_inconsistency(
+        &self,
+        region: metapb::Region,
+        peer: metapb::Peer,
+        _: u64,
+    ) -> PdFuture<()> {
+        if let Err(e) = self.check_bootstrap() {
+            return err(e).boxed();
+        }
+        self.cluster
+            .wl()
+            .inconsistent_peers
+            .entry(region.get_id())
+            .or_insert_with(Vec::new)
+            .push(peer);
+        ok(()).boxed()
+    }
 }
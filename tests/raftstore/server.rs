@@ -29,6 +29,7 @@ use tikv::server::transport::ServerRaftStoreRouter;
 use tikv::server::transport::RaftStoreRouter;
 use tikv::raftstore::{store, Error, Result};
 use tikv::raftstore::store::{Engines, Msg as StoreMsg, SnapManager};
+use tikv::util::security::SecurityManager;
 use tikv::util::transport::SendCh;
 use tikv::util::worker::Worker;
 use tikv::storage::{CfName, Engine};
@@ -70,7 +71,11 @@ impl ServerCluster {
             pd_client: pd_client,
             storages: HashMap::new(),
             snap_paths: HashMap::new(),
-            raft_client: RaftClient::new(Arc::new(Environment::new(1)), Config::default()),
+            raft_client: RaftClient::new(
+                Arc::new(Environment::new(1)),
+                Config::default(),
+                Arc::new(SecurityManager::default()),
+            ),
         }
     }
 }
@@ -111,6 +116,7 @@ impl Simulator for ServerCluster {
         // Create pd client, snapshot manager, server.
         let (worker, resolver) = resolve::new_resolver(self.pd_client.clone()).unwrap();
         let snap_mgr = SnapManager::new(tmp_str, Some(store_sendch));
+        let security_mgr = Arc::new(SecurityManager::new(&cfg.security).unwrap());
         let mut server = Server::new(
             &cfg.server,
             cfg.raft_store.region_split_size.0 as usize,
@@ -119,6 +125,7 @@ impl Simulator for ServerCluster {
             snap_status_sender,
             resolver,
             snap_mgr.clone(),
+            security_mgr,
         ).unwrap();
         let addr = server.listening_addr();
         cfg.server.addr = format!("{}", addr);
@@ -138,6 +145,7 @@ impl Simulator for ServerCluster {
             simulate_trans.clone(),
             snap_mgr.clone(),
             snap_status_receiver,
+            store.get_read_stats(),
         ).unwrap();
         assert!(node_id == 0 || node_id == node.id());
         let node_id = node.id();
@@ -15,8 +15,9 @@ use std::sync::{mpsc, Arc};
 use std::path::Path;
 use tikv::raftstore::store::{bootstrap_store, create_event_loop, keys, Engines, Peekable,
                              SnapManager};
+use tikv::raftstore::store::raft_engine::RocksRaftEngine;
 use tikv::server::Node;
-use tikv::storage::{ALL_CFS, CF_RAFT};
+use tikv::storage::{ReadStats, ALL_CFS, CF_RAFT};
 use tikv::util::rocksdb;
 use tempdir::TempDir;
 use kvproto::metapb;
@@ -55,9 +56,10 @@ fn test_node_bootstrap_with_prepared_data() {
         rocksdb::new_engine(tmp_path.path().to_str().unwrap(), ALL_CFS).unwrap(),
     );
     let tmp_path_raft = tmp_path.path().join(Path::new("raft"));
-    let raft_engine = Arc::new(
+    let raft_db = Arc::new(
         rocksdb::new_engine(tmp_path_raft.to_str().unwrap(), &[]).unwrap(),
     );
+    let raft_engine = Arc::new(RocksRaftEngine::new(raft_db));
     let engines = Engines::new(engine.clone(), raft_engine.clone());
     let tmp_mgr = TempDir::new("test_cluster").unwrap();
 
@@ -99,6 +101,7 @@ fn test_node_bootstrap_with_prepared_data() {
         simulate_trans,
         snap_mgr,
         snapshot_status_receiver,
+        Arc::new(ReadStats::new()),
     ).unwrap();
     assert!(
         engine
@@ -87,11 +87,10 @@ fn check_compacted(
 
     for (id, engines) in all_engines {
         for i in 0..compacted_idx[id] {
-            let key = keys::raft_log_key(1, i);
-            if engines.raft_engine.get(&key).unwrap().is_none() {
+            if engines.raft_engine.get_entry(1, i).unwrap().is_none() {
                 break;
             }
-            assert!(engines.raft_engine.get(&key).unwrap().is_none());
+            assert!(engines.raft_engine.get_entry(1, i).unwrap().is_none());
         }
     }
     true
@@ -285,8 +284,7 @@ fn test_compact_size_limit<T: Simulator>(cluster: &mut Cluster<T>) {
         assert!(idx > before_state.get_index());
 
         for i in 0..idx {
-            let key = keys::raft_log_key(1, i);
-            assert!(engines.raft_engine.get(&key).unwrap().is_none());
+            assert!(engines.raft_engine.get_entry(1, i).unwrap().is_none());
         }
     }
 }
@@ -0,0 +1,65 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tikv::raftstore::store::util::ELECTION_PRIORITY_LABEL_KEY;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::util::*;
+
+fn test_election_priority<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+
+    // Restart store 3 with a higher election priority.
+    cluster.stop_node(3);
+    cluster
+        .cfg
+        .server
+        .labels
+        .insert(ELECTION_PRIORITY_LABEL_KEY.to_owned(), "1".to_owned());
+    cluster.run_node(3);
+    cluster.cfg.server.labels.clear();
+
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+    // Wait for the priorities to be resolved from pd.
+    sleep_ms(200);
+
+    // The peer with the higher priority is elected when the leader is down.
+    cluster.stop_node(1);
+    cluster.must_put(b"k2", b"v2");
+    assert_eq!(cluster.leader_of_region(1), Some(new_peer(3, 3)));
+
+    // The peers with lower priorities are still elected when it's down.
+    cluster.run_node(1);
+    must_get_equal(&cluster.get_engine(1), b"k2", b"v2");
+    cluster.stop_node(3);
+    cluster.must_put(b"k3", b"v3");
+    assert_ne!(cluster.leader_of_region(1), Some(new_peer(3, 3)));
+    must_get_equal(&cluster.get_engine(1), b"k3", b"v3");
+    must_get_equal(&cluster.get_engine(2), b"k3", b"v3");
+}
+
+#[test]
+fn test_node_election_priority() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_election_priority(&mut cluster);
+}
+
+#[test]
+fn test_server_election_priority() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_election_priority(&mut cluster);
+}
@@ -0,0 +1,152 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use kvproto::raft_cmdpb::RaftCmdResponse;
+use kvproto::errorpb::Error as PbError;
+use tikv::raftstore::store::Msg;
+use tikv::storage::CF_DEFAULT;
+use tikv::util::HandyRwLock;
+use tikv::util::config::ReadableDuration;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::util::*;
+
+fn flush_default_cf<T: Simulator>(cluster: &mut Cluster<T>) {
+    for engines in cluster.engines.values() {
+        let handle = engines.kv_engine.cf_handle(CF_DEFAULT).unwrap();
+        engines.kv_engine.flush_cf(handle, true).unwrap();
+    }
+}
+
+fn compact_default_cf<T: Simulator>(cluster: &mut Cluster<T>) {
+    for engines in cluster.engines.values() {
+        let handle = engines.kv_engine.cf_handle(CF_DEFAULT).unwrap();
+        engines.kv_engine.compact_range_cf(handle, None, None);
+    }
+}
+
+fn configure_for_level0_files<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.cfg.raft_store.level0_files_limit = 1;
+    cluster.cfg.raft_store.compaction_pressure_check_interval = ReadableDuration::millis(10);
+}
+
+// Creates 2 level 0 files, which exceed the limit.
+fn make_level0_files<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.must_put(b"k1", b"v1");
+    flush_default_cf(cluster);
+    cluster.must_put(b"k2", b"v2");
+    flush_default_cf(cluster);
+}
+
+// Puts the key until the store rejects it because of the write stall.
+fn must_put_rejected<T: Simulator>(cluster: &mut Cluster<T>, key: &[u8], value: &[u8]) -> PbError {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match cluster.put(key, value) {
+            Err(e) => if e.has_server_is_busy() {
+                return e;
+            } else {
+                panic!("unexpected error {:?}", e);
+            },
+            Ok(()) => if Instant::now() > deadline {
+                panic!("the store doesn't stall writes");
+            },
+        }
+        sleep_ms(10);
+    }
+}
+
+fn test_level0_files_flow_control<T: Simulator>(cluster: &mut Cluster<T>) {
+    configure_for_level0_files(cluster);
+    // Reject the writes at once.
+    cluster.cfg.raft_store.write_stall_max_delay = ReadableDuration::secs(0);
+    cluster.run();
+
+    make_level0_files(cluster);
+    let e = must_put_rejected(cluster, b"k3", b"v3");
+    assert!(e.get_server_is_busy().get_reason().contains("level 0"), "{:?}", e);
+    // Reads are still served.
+    assert_eq!(cluster.get(b"k1"), Some(b"v1".to_vec()));
+
+    compact_default_cf(cluster);
+    // The store accepts writes again after the next check.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while cluster.put(b"k4", b"v4").is_err() {
+        if Instant::now() > deadline {
+            panic!("the store still stalls writes");
+        }
+        sleep_ms(10);
+    }
+    must_get_equal(&cluster.get_engine(1), b"k4", b"v4");
+}
+
+#[test]
+fn test_node_level0_files_flow_control() {
+    let mut cluster = new_node_cluster(0, 1);
+    test_level0_files_flow_control(&mut cluster);
+}
+
+#[test]
+fn test_server_level0_files_flow_control() {
+    let mut cluster = new_server_cluster(0, 1);
+    test_level0_files_flow_control(&mut cluster);
+}
+
+fn test_delay_writes<T: Simulator>(cluster: &mut Cluster<T>) {
+    configure_for_level0_files(cluster);
+    cluster.cfg.raft_store.write_stall_max_delay = ReadableDuration::secs(1);
+    cluster.run();
+
+    make_level0_files(cluster);
+    // The writes wait 1s and are rejected while the stall lasts.
+    must_put_rejected(cluster, b"k3", b"v3");
+
+    // A delayed write is proposed once the stall is over.
+    let region = cluster.get_region(b"k5");
+    let leader = cluster.leader_of_region(region.get_id()).unwrap();
+    let mut req = new_request(
+        region.get_id(),
+        region.get_region_epoch().clone(),
+        vec![new_put_cmd(b"k5", b"v5")],
+        false,
+    );
+    req.mut_header().set_peer(leader);
+    let (tx, rx) = mpsc::channel();
+    let msg = Msg::RaftCmd {
+        send_time: Instant::now(),
+        request: req,
+        callback: box move |resp: RaftCmdResponse| tx.send(resp).unwrap(),
+    };
+    cluster.sim.rl().get_store_sendch(1).unwrap().send(msg).unwrap();
+    compact_default_cf(cluster);
+    let resp = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(!resp.get_header().has_error(), "{:?}", resp);
+    must_get_equal(&cluster.get_engine(1), b"k5", b"v5");
+}
+
+#[test]
+fn test_node_delay_writes() {
+    let mut cluster = new_node_cluster(0, 1);
+    test_delay_writes(&mut cluster);
+}
+
+#[test]
+fn test_server_delay_writes() {
+    let mut cluster = new_server_cluster(0, 1);
+    test_delay_writes(&mut cluster);
+}
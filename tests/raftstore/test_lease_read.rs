@@ -22,10 +22,8 @@ use std::collections::HashSet;
 use kvproto::eraftpb::{ConfChangeType, MessageType};
 use kvproto::metapb::{Peer, Region};
 use kvproto::raft_cmdpb::CmdType;
-use kvproto::raft_serverpb::{RaftLocalState, RaftMessage};
+use kvproto::raft_serverpb::RaftMessage;
 use tikv::raftstore::{Error, Result};
-use tikv::raftstore::store::keys;
-use tikv::raftstore::store::engine::Peekable;
 use tikv::util::{escape, HandyRwLock};
 use tikv::util::config::*;
 
@@ -162,8 +160,7 @@ fn test_renew_lease<T: Simulator>(cluster: &mut Cluster<T>) {
     let region_id = region.get_id();
     cluster.must_transfer_leader(region_id, peer.clone());
     let engine = cluster.get_raft_engine(store_id);
-    let state_key = keys::raft_state_key(region_id);
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     let last_index = state.get_last_index();
 
     let detector = LeaseReadFilter::default();
@@ -196,7 +193,7 @@ fn test_renew_lease<T: Simulator>(cluster: &mut Cluster<T>) {
 
     // Check if the leader has renewed its lease so that it can do lease read.
     assert_eq!(cluster.leader_of_region(region_id), Some(peer.clone()));
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     assert_eq!(state.get_last_index(), last_index + 1);
 
     // Issue a read request and check the value on response.
@@ -307,13 +304,12 @@ fn test_lease_unsafe_during_leader_transfers<T: Simulator>(cluster: &mut Cluster
     must_read_on_peer(cluster, peer.clone(), region.clone(), key, b"v1");
 
     let engine = cluster.get_raft_engine(store_id);
-    let state_key = keys::raft_state_key(region_id);
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     let last_index = state.get_last_index();
 
     // Check if the leader does a local read.
     must_read_on_peer(cluster, peer.clone(), region.clone(), key, b"v1");
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     assert_eq!(state.get_last_index(), last_index);
     assert_eq!(detector.ctx.rl().len(), 0);
 
@@ -349,7 +345,7 @@ fn test_lease_unsafe_during_leader_transfers<T: Simulator>(cluster: &mut Cluster
     assert_eq!(detector.ctx.rl().len(), 3);
 
     // Check if the leader also propose an entry to renew its lease.
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     assert_eq!(state.get_last_index(), last_index + 1);
 
     // wait some time for the proposal to be applied.
@@ -357,7 +353,7 @@ fn test_lease_unsafe_during_leader_transfers<T: Simulator>(cluster: &mut Cluster
 
     // Check if the leader does a local read.
     must_read_on_peer(cluster, peer.clone(), region.clone(), key, b"v1");
-    let state: RaftLocalState = engine.get_msg(&state_key).unwrap().unwrap();
+    let state = engine.get_raft_state(region_id).unwrap().unwrap();
     assert_eq!(state.get_last_index(), last_index + 1);
     assert_eq!(detector.ctx.rl().len(), 3);
 }
@@ -795,3 +795,38 @@ fn test_server_batch_write() {
     let mut cluster = new_server_cluster(0, 3);
     test_batch_write(&mut cluster);
 }
+
+fn test_entry_cache_limit<T: Simulator>(cluster: &mut Cluster<T>) {
+    // Evict the entry caches on every ready.
+    cluster.cfg.raft_store.raft_entry_cache_limit = ReadableSize(1);
+    // disable compact log so that peer 3 catches up by logs.
+    cluster.cfg.raft_store.raft_log_gc_threshold = 1000;
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+
+    cluster.add_send_filter(IsolationFilterFactory::new(3));
+    for i in 0..100 {
+        let (k, v) = (format!("k{:03}", i), format!("v{:03}", i));
+        cluster.must_put(k.as_bytes(), v.as_bytes());
+    }
+    must_get_none(&cluster.get_engine(3), b"k099");
+
+    // The logs are read from the raft engine and sent to peer 3.
+    cluster.clear_send_filters();
+    for i in 0..100 {
+        let (k, v) = (format!("k{:03}", i), format!("v{:03}", i));
+        must_get_equal(&cluster.get_engine(3), k.as_bytes(), v.as_bytes());
+    }
+}
+
+#[test]
+fn test_node_entry_cache_limit() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_entry_cache_limit(&mut cluster);
+}
+
+#[test]
+fn test_server_entry_cache_limit() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_entry_cache_limit(&mut cluster);
+}
@@ -0,0 +1,140 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+
+use tikv::raftstore::store::raft_engine::RAFT_ENGINE_SEGMENT;
+use tikv::util::config::*;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::util::*;
+
+fn test_segment_raft_engine<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.cfg.raft_store.raft_log_segment_size = ReadableSize::kb(64);
+    cluster.cfg.raft_store.raft_log_gc_count_limit = 100;
+    cluster.cfg.raft_store.raft_log_gc_threshold = 50;
+    cluster.cfg.raft_store.raft_log_gc_tick_interval = ReadableDuration::millis(100);
+    cluster.set_raft_log_engine(RAFT_ENGINE_SEGMENT);
+    cluster.run();
+
+    let value = vec![b'v'; 256];
+    for i in 0..1000 {
+        cluster.must_put(format!("k{:04}", i).as_bytes(), &value);
+    }
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k0999", &value);
+    }
+
+    // Logs are compacted and the expired segments are purged.
+    sleep_ms(500);
+    for id in 1..4 {
+        let raft_engine = cluster.get_raft_engine(id);
+        let first_index = raft_engine.first_index(1).unwrap().unwrap();
+        assert!(first_index > 1, "store {} first index {}", id, first_index);
+        assert!(raft_engine.get_entry(1, 1).unwrap().is_none());
+    }
+
+    // The peers reload their raft states and logs from the engine after restart.
+    cluster.stop_node(2);
+    cluster.must_put(b"k1000", &value);
+    cluster.shutdown();
+    cluster.start();
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k1000", &value);
+    }
+    cluster.must_put(b"k1001", &value);
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k1001", &value);
+    }
+}
+
+#[test]
+fn test_node_segment_raft_engine() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_segment_raft_engine(&mut cluster);
+}
+
+#[test]
+fn test_server_segment_raft_engine() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_segment_raft_engine(&mut cluster);
+}
+
+fn segment_file_count(dir: &str) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|e| {
+            let name = e.as_ref().unwrap().file_name();
+            name.to_str().unwrap().ends_with(".raftlog")
+        })
+        .count()
+}
+
+fn test_segment_expired<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.cfg.raft_store.raft_log_segment_size = ReadableSize::kb(16);
+    cluster.cfg.raft_store.raft_log_segment_expire_count = 4;
+    cluster.cfg.raft_store.raft_log_gc_count_limit = 100;
+    cluster.cfg.raft_store.raft_log_gc_threshold = 50;
+    cluster.cfg.raft_store.raft_log_gc_tick_interval = ReadableDuration::millis(100);
+    cluster.set_raft_log_engine(RAFT_ENGINE_SEGMENT);
+    cluster.run();
+
+    // Split the data into three regions, the one of "k1" stays idle.
+    let region = cluster.get_region(b"");
+    cluster.must_split(&region, b"k2");
+    let region = cluster.get_region(b"k2");
+    cluster.must_split(&region, b"k3");
+    cluster.must_put(b"k1", b"v1");
+    let idle = cluster.get_region(b"k1").get_id();
+
+    let value = vec![b'v'; 256];
+    for i in 0..1000 {
+        let prefix = if i % 2 == 0 { "k2" } else { "k3" };
+        cluster.must_put(format!("{}{:04}", prefix, i).as_bytes(), &value);
+    }
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k30999", &value);
+    }
+
+    // The logs of the idle region are rewritten, so the old segments are
+    // purged even though they are never compacted.
+    sleep_ms(500);
+    for id in 1..4 {
+        let raft_engine = cluster.get_raft_engine(id);
+        assert!(raft_engine.first_index(idle).unwrap().is_some());
+        let count = segment_file_count(raft_engine.path());
+        assert!(count <= 8, "store {} has {} segments", id, count);
+    }
+
+    // The rewritten logs are recovered after restart.
+    cluster.shutdown();
+    cluster.start();
+    cluster.must_put(b"k1", b"v2");
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k1", b"v2");
+    }
+}
+
+#[test]
+fn test_node_segment_expired() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_segment_expired(&mut cluster);
+}
+
+#[test]
+fn test_server_segment_expired() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_segment_expired(&mut cluster);
+}
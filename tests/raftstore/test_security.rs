@@ -0,0 +1,46 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tikv::util::config::*;
+use util::new_security_cfg;
+
+use super::server::new_server_cluster;
+use super::util::*;
+
+#[test]
+fn test_server_secure_connections() {
+    let mut cluster = new_server_cluster(0, 3);
+    cluster.cfg.security = new_security_cfg();
+    cluster.cfg.raft_store.raft_log_gc_count_limit = 10;
+    cluster.cfg.raft_store.raft_log_gc_threshold = 5;
+    cluster.cfg.raft_store.raft_log_gc_tick_interval = ReadableDuration::millis(10);
+    let pd_client = cluster.pd_client.clone();
+    pd_client.disable_default_rule();
+
+    let r1 = cluster.run_conf_change();
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    cluster.must_put(b"k1", b"v1");
+    // The raft messages go through TLS.
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    for i in 0..50 {
+        let key = format!("key{}", i);
+        cluster.must_put(key.as_bytes(), b"value");
+    }
+    // The logs are compacted, so store 3 catches up by a snapshot sent
+    // through TLS.
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+    must_get_equal(&engine_3, b"key49", b"value");
+}
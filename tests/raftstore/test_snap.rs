@@ -472,3 +472,46 @@ fn test_server_snapshot_with_append() {
     let mut cluster = new_server_cluster(0, 4);
     test_snapshot_with_append(&mut cluster);
 }
+
+fn test_delegate_snap_generation<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.cfg.raft_store.delegate_snap_generation = true;
+
+    let pd_client = cluster.pd_client.clone();
+    // Disable default max peer count check.
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    cluster.must_put(b"k1", b"v1");
+    // there is no follower yet, the leader generates the snapshot itself.
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(2), b"k2", b"v2");
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    let engine3 = cluster.get_engine(3);
+    must_get_equal(&engine3, b"k1", b"v1");
+    must_get_equal(&engine3, b"k2", b"v2");
+
+    // the snapshot for peer 3 is generated by the follower on store 2.
+    let generated: Vec<_> = fs::read_dir(cluster.get_snap_dir(2))
+        .unwrap()
+        .map(|p| p.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("gen_"))
+        .collect();
+    assert!(!generated.is_empty());
+
+    cluster.must_put(b"k3", b"v3");
+    must_get_equal(&engine3, b"k3", b"v3");
+}
+
+#[test]
+fn test_node_delegate_snap_generation() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_delegate_snap_generation(&mut cluster);
+}
+
+#[test]
+fn test_server_delegate_snap_generation() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_delegate_snap_generation(&mut cluster);
+}
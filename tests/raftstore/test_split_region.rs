@@ -11,6 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::mpsc;
 use std::time::Duration;
 use std::{fs, thread};
 use rand::{self, Rng};
@@ -23,6 +24,7 @@ use super::server::new_server_cluster;
 use super::util;
 use tikv::pd::PdClient;
 use tikv::storage::{CF_DEFAULT, CF_WRITE};
+use tikv::raftstore::Error;
 use tikv::raftstore::store::keys::data_key;
 use tikv::raftstore::store::engine::Iterable;
 use tikv::util::config::*;
@@ -674,3 +676,155 @@ fn test_server_quick_election_after_split() {
     let mut cluster = new_server_cluster(0, 3);
     test_quick_election_after_split(&mut cluster);
 }
+
+fn test_split_region_by_keys<T: Simulator>(
+    cluster: &mut Cluster<T>,
+    right_derive: bool,
+    batch_split: bool,
+) {
+    cluster.cfg.raft_store.right_derive_when_split = right_derive;
+    cluster.cfg.raft_store.use_batch_split = batch_split;
+    cluster.run();
+    cluster.must_put(b"k0", b"v0");
+    cluster.must_put(b"k4", b"v4");
+
+    let pd_client = cluster.pd_client.clone();
+    let region = pd_client.get_region(b"k0").unwrap();
+
+    // Split keys must be sorted.
+    let (tx, rx) = mpsc::channel();
+    let split_keys = vec![b"k3".to_vec(), b"k1".to_vec()];
+    cluster.split_region(&region, split_keys, box move |res| tx.send(res).unwrap());
+    let res = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(res.is_err(), "{:?}", res);
+
+    let (tx, rx) = mpsc::channel();
+    let split_keys = vec![b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec()];
+    cluster.split_region(&region, split_keys, box move |res| tx.send(res).unwrap());
+    let regions = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(regions.len(), 4);
+    let keys = vec![b"".to_vec(), b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec(), b"".to_vec()];
+    for (i, r) in regions.iter().enumerate() {
+        assert_eq!(r.get_start_key(), keys[i].as_slice());
+        assert_eq!(r.get_end_key(), keys[i + 1].as_slice());
+    }
+    let origin = if right_derive { &regions[3] } else { &regions[0] };
+    assert_eq!(origin.get_id(), region.get_id());
+    let version = region.get_region_epoch().get_version() + 3;
+    assert_eq!(origin.get_region_epoch().get_version(), version);
+    for r in &regions {
+        if batch_split {
+            // All the regions are split by one raft command.
+            assert_eq!(r.get_region_epoch().get_version(), version);
+        } else {
+            assert!(r.get_region_epoch().get_version() <= version, "{:?}", r);
+        }
+    }
+
+    // Split keys out of the region range should be rejected.
+    let (tx, rx) = mpsc::channel();
+    cluster.split_region(
+        &regions[0],
+        vec![b"k5".to_vec()],
+        box move |res| tx.send(res).unwrap(),
+    );
+    let res = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(res.is_err(), "{:?}", res);
+
+    // A split with an epoch newer than the region's gives up after retries.
+    let mut region = regions[1].clone();
+    let version = region.get_region_epoch().get_version();
+    region.mut_region_epoch().set_version(version + 1);
+    let (tx, rx) = mpsc::channel();
+    cluster.split_region(
+        &region,
+        vec![b"k11".to_vec()],
+        box move |res| tx.send(res).unwrap(),
+    );
+    match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        Err(Error::StaleEpoch(_, new_regions)) => {
+            assert_eq!(new_regions[0].get_id(), region.get_id())
+        }
+        res => panic!("expect stale epoch, but got {:?}", res),
+    }
+
+    cluster.must_put(b"k0", b"vv0");
+    assert_eq!(cluster.get(b"k0").unwrap(), b"vv0".to_vec());
+    cluster.must_put(b"k4", b"vv4");
+    assert_eq!(cluster.get(b"k4").unwrap(), b"vv4".to_vec());
+    for key in &keys[1..4] {
+        cluster.must_put(key, b"v");
+        assert_eq!(cluster.get(key).unwrap(), b"v".to_vec());
+    }
+}
+
+#[test]
+fn test_node_split_region_by_keys_left_derive() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_region_by_keys(&mut cluster, false, true);
+}
+
+#[test]
+fn test_node_split_region_by_keys_right_derive() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_region_by_keys(&mut cluster, true, true);
+}
+
+#[test]
+fn test_server_split_region_by_keys_right_derive() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_split_region_by_keys(&mut cluster, true, true);
+}
+
+#[test]
+fn test_node_split_region_by_keys_one_by_one_left_derive() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_region_by_keys(&mut cluster, false, false);
+}
+
+#[test]
+fn test_node_split_region_by_keys_one_by_one_right_derive() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_region_by_keys(&mut cluster, true, false);
+}
+
+fn test_split_region_by_status_server<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k0", b"v0");
+
+    let region = cluster.get_region(b"k0");
+    let leader = cluster.leader_of_region(region.get_id()).unwrap();
+    let mut status_server = cluster.start_status_server(leader.get_store_id());
+    let addr = status_server.listening_addr();
+    let epoch = region.get_region_epoch();
+    // "k1" and "k2" in hex.
+    let path = format!(
+        "/debug/region/split?region-id={}&version={}&conf-ver={}&keys=6b31,6b32",
+        region.get_id(),
+        epoch.get_version(),
+        epoch.get_conf_ver()
+    );
+    let (code, body) = util::http_request(addr, "POST", &path);
+    assert_eq!(code, 200, "{}", body);
+    assert!(body.contains("\"end-key\":\"6b31\""), "{}", body);
+    assert!(body.contains("\"start-key\":\"6b32\""), "{}", body);
+    assert_eq!(body.matches("\"id\"").count(), 3, "{}", body);
+
+    // The stale epoch is rejected.
+    let (code, body) = util::http_request(addr, "POST", &path);
+    assert_eq!(code, 500, "{}", body);
+    assert!(body.contains("StaleEpoch"), "{}", body);
+    status_server.stop();
+}
+
+#[test]
+fn test_node_split_region_by_status_server() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_region_by_status_server(&mut cluster);
+}
+
+#[test]
+fn test_server_split_region_by_status_server() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_split_region_by_status_server(&mut cluster);
+}
@@ -12,6 +12,7 @@
 // limitations under the License.
 
 use super::server::*;
+use super::util::*;
 
 #[test]
 fn test_region_detail() {
@@ -37,3 +38,22 @@ fn test_region_detail() {
     assert!(region_detail.has_leader());
     assert_eq!(region_detail.get_leader(), &leader);
 }
+
+#[test]
+fn test_status_server_health() {
+    let mut cluster = new_server_cluster(0, 3);
+    cluster.run();
+    let mut status_server = cluster.start_status_server(1);
+    let addr = status_server.listening_addr();
+    let (code, body) = http_request(addr, "GET", "/status");
+    assert_eq!(code, 200, "{}", body);
+    assert!(body.contains(r#""status":"ok""#), "{}", body);
+
+    // The raftstore of the store doesn't handle messages any more.
+    cluster.stop_node(1);
+    let (code, body) = http_request(addr, "GET", "/status");
+    assert_eq!(code, 503, "{}", body);
+    assert!(body.contains(r#""status":"unhealthy""#), "{}", body);
+    assert!(body.contains(r#""raftstore":"#), "{}", body);
+    status_server.stop();
+}
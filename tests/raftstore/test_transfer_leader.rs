@@ -11,6 +11,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::mpsc;
 use std::time::Duration;
 use std::thread;
 
@@ -190,3 +191,83 @@ fn test_node_transfer_leader_during_snapshot() {
     let mut cluster = new_node_cluster(0, 3);
     test_transfer_leader_during_snapshot(&mut cluster);
 }
+
+fn test_drain_leaders<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region = cluster.get_region(b"k1");
+    cluster.must_split(&region, b"k2");
+    cluster.must_put(b"k3", b"v3");
+
+    let left = cluster.get_region(b"k1");
+    let right = cluster.get_region(b"k3");
+    for r in &[&left, &right] {
+        let peer = find_peer(r, 1).unwrap().clone();
+        cluster.must_transfer_leader(r.get_id(), peer);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    cluster.drain_leaders(1, box move || tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    for r in &[&left, &right] {
+        cluster.reset_leader_of_region(r.get_id());
+        let leader = cluster.leader_of_region(r.get_id()).unwrap();
+        assert_ne!(leader.get_store_id(), 1);
+    }
+
+    // A draining store doesn't accept leaders any more.
+    let peer = find_peer(&left, 1).unwrap().clone();
+    cluster.transfer_leader(left.get_id(), peer);
+    sleep_ms(500);
+    cluster.reset_leader_of_region(left.get_id());
+    let leader = cluster.leader_of_region(left.get_id()).unwrap();
+    assert_ne!(leader.get_store_id(), 1);
+
+    cluster.must_put(b"k1", b"v11");
+    cluster.must_put(b"k3", b"v33");
+    must_get_equal(&cluster.get_engine(1), b"k3", b"v33");
+}
+
+#[test]
+fn test_node_drain_leaders() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drain_leaders(&mut cluster);
+}
+
+#[test]
+fn test_server_drain_leaders() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drain_leaders(&mut cluster);
+}
+
+fn test_drain_leaders_by_status_server<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region = cluster.get_region(b"k1");
+    let peer = find_peer(&region, 1).unwrap().clone();
+    cluster.must_transfer_leader(region.get_id(), peer);
+
+    let mut status_server = cluster.start_status_server(1);
+    let addr = status_server.listening_addr();
+    let (code, body) = http_request(addr, "POST", "/debug/drain-leaders?timeout-secs=5");
+    assert_eq!(code, 200, "{}", body);
+    status_server.stop();
+
+    cluster.reset_leader_of_region(region.get_id());
+    let leader = cluster.leader_of_region(region.get_id()).unwrap();
+    assert_ne!(leader.get_store_id(), 1);
+    cluster.must_put(b"k1", b"v11");
+}
+
+#[test]
+fn test_node_drain_leaders_by_status_server() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drain_leaders_by_status_server(&mut cluster);
+}
+
+#[test]
+fn test_server_drain_leaders_by_status_server() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drain_leaders_by_status_server(&mut cluster);
+}
@@ -0,0 +1,139 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::util::{self, *};
+
+// Waits until the failed peers are removed through the raft log and pd is
+// notified.
+fn must_conf_ver<T: Simulator>(cluster: &Cluster<T>, region_id: u64, conf_ver: u64) {
+    for _ in 0..100 {
+        if cluster.get_region_epoch(region_id).get_conf_ver() == conf_ver {
+            return;
+        }
+        sleep_ms(50);
+    }
+    panic!(
+        "region {} conf_ver should be {}, but got {:?}",
+        region_id,
+        conf_ver,
+        cluster.get_region_epoch(region_id)
+    );
+}
+
+fn test_remove_failed_stores<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    // Disable default max peer number check.
+    pd_client.disable_default_rule();
+
+    let r1 = cluster.run_conf_change();
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+    cluster.must_transfer_leader(r1, new_peer(2, 2));
+
+    // The quorum is lost.
+    cluster.stop_node(2);
+    cluster.stop_node(3);
+
+    // The store itself can't be removed.
+    let (tx, rx) = mpsc::channel();
+    cluster.remove_failed_stores(1, vec![1, 2], true, box move |res| tx.send(res).unwrap());
+    let res = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(res.is_err(), "{:?}", res);
+
+    // Dry run only reports the regions.
+    let (tx, rx) = mpsc::channel();
+    cluster.remove_failed_stores(1, vec![2, 3], true, box move |res| tx.send(res).unwrap());
+    let recoveries = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(recoveries.len(), 1);
+    assert_eq!(recoveries[0].region.get_peers(), &[new_peer(1, 1)]);
+    assert_eq!(cluster.get_region_epoch(r1).get_conf_ver(), 3);
+
+    let (tx, rx) = mpsc::channel();
+    cluster.remove_failed_stores(1, vec![2, 3], false, box move |res| tx.send(res).unwrap());
+    let recoveries = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(recoveries.len(), 1);
+    let region = &recoveries[0].region;
+    assert_eq!(region.get_peers(), &[new_peer(1, 1)]);
+    assert_eq!(region.get_region_epoch().get_conf_ver(), 5);
+    must_conf_ver(cluster, r1, 5);
+
+    // The remaining peer becomes leader and serves writes again.
+    cluster.reset_leader_of_region(r1);
+    cluster.must_put(b"k2", b"v2");
+    assert_eq!(cluster.get(b"k1").unwrap(), b"v1".to_vec());
+    must_get_equal(&cluster.get_engine(1), b"k2", b"v2");
+}
+
+#[test]
+fn test_node_remove_failed_stores() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_remove_failed_stores(&mut cluster);
+}
+
+#[test]
+fn test_server_remove_failed_stores() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_remove_failed_stores(&mut cluster);
+}
+
+fn test_unsafe_recover_by_status_server<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    // Disable default max peer number check.
+    pd_client.disable_default_rule();
+
+    let r1 = cluster.run_conf_change();
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+    cluster.stop_node(2);
+    cluster.stop_node(3);
+
+    let mut status_server = cluster.start_status_server(1);
+    let addr = status_server.listening_addr();
+    let (code, body) = util::http_request(addr, "POST", "/debug/unsafe-recover?stores=2,3");
+    assert_eq!(code, 200, "{}", body);
+    assert!(body.contains("\"removed-peers\":[2,3]"), "{}", body);
+    assert_eq!(cluster.get_region_epoch(r1).get_conf_ver(), 3);
+
+    let path = "/debug/unsafe-recover?stores=2,3&apply=true";
+    let (code, body) = util::http_request(addr, "POST", path);
+    assert_eq!(code, 200, "{}", body);
+    assert!(body.contains("\"conf-ver\":5"), "{}", body);
+    must_conf_ver(cluster, r1, 5);
+    status_server.stop();
+
+    cluster.reset_leader_of_region(r1);
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(1), b"k2", b"v2");
+}
+
+#[test]
+fn test_node_unsafe_recover_by_status_server() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_unsafe_recover_by_status_server(&mut cluster);
+}
+
+#[test]
+fn test_server_unsafe_recover_by_status_server() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_unsafe_recover_by_status_server(&mut cluster);
+}
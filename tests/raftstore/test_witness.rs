@@ -0,0 +1,138 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use kvproto::eraftpb::ConfChangeType;
+use kvproto::raft_serverpb::RaftApplyState;
+
+use tikv::raftstore::store::{check_store_witness, keys, Peekable};
+use tikv::raftstore::store::util::WITNESS_LABEL_KEY;
+use tikv::storage::CF_RAFT;
+
+use super::cluster::{Cluster, Simulator};
+use super::node::new_node_cluster;
+use super::server::new_server_cluster;
+use super::util::*;
+
+fn must_applied_on_witness<T: Simulator>(cluster: &mut Cluster<T>, region_id: u64, key: &[u8]) {
+    let leader = cluster.leader_of_region(region_id).unwrap();
+    let leader_engine = cluster.get_engine(leader.get_store_id());
+    let witness_engine = cluster.get_engine(3);
+    let apply_state_key = keys::apply_state_key(region_id);
+    let expected: RaftApplyState = leader_engine
+        .get_msg_cf(CF_RAFT, &apply_state_key)
+        .unwrap()
+        .unwrap();
+    for _ in 0..50 {
+        let state: RaftApplyState = witness_engine
+            .get_msg_cf(CF_RAFT, &apply_state_key)
+            .unwrap()
+            .unwrap_or_default();
+        if state.get_applied_index() >= expected.get_applied_index() {
+            // The logs are applied, but the data is not written.
+            assert!(witness_engine.get_value(&keys::data_key(key)).unwrap().is_none());
+            return;
+        }
+        sleep_ms(20);
+    }
+    panic!("witness doesn't apply to {}", expected.get_applied_index());
+}
+
+fn test_witness<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+
+    // Restart store 3 as a witness store.
+    cluster.stop_node(3);
+    cluster
+        .cfg
+        .server
+        .labels
+        .insert(WITNESS_LABEL_KEY.to_owned(), "true".to_owned());
+    cluster.run_node(3);
+    cluster.cfg.server.labels.clear();
+
+    cluster.must_put(b"k1", b"v1");
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+    // The witness only applies the meta of the snapshot.
+    must_applied_on_witness(cluster, r1, b"k1");
+
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&cluster.get_engine(2), b"k2", b"v2");
+    must_applied_on_witness(cluster, r1, b"k2");
+
+    // The witness store can't be restarted as a full replica.
+    cluster.stop_node(3);
+    assert!(check_store_witness(&cluster.get_engine(3), false).is_err());
+    cluster
+        .cfg
+        .server
+        .labels
+        .insert(WITNESS_LABEL_KEY.to_owned(), "true".to_owned());
+    cluster.run_node(3);
+    cluster.cfg.server.labels.clear();
+    must_applied_on_witness(cluster, r1, b"k2");
+
+    // The witness never becomes leader.
+    cluster.transfer_leader(r1, new_peer(3, 3));
+    sleep_ms(500);
+    assert_ne!(cluster.leader_of_region(r1), Some(new_peer(3, 3)));
+
+    // The witness keeps the quorum with one of the other peers.
+    cluster.stop_node(2);
+    cluster.must_put(b"k3", b"v3");
+    must_applied_on_witness(cluster, r1, b"k3");
+    cluster.run_node(2);
+    must_get_equal(&cluster.get_engine(2), b"k3", b"v3");
+    cluster.stop_node(1);
+    cluster.must_put(b"k4", b"v4");
+    assert_eq!(cluster.leader_of_region(r1), Some(new_peer(2, 2)));
+    must_get_equal(&cluster.get_engine(2), b"k4", b"v4");
+    must_applied_on_witness(cluster, r1, b"k4");
+
+    // The last peer with data can't be removed.
+    pd_client.must_remove_peer(r1, new_peer(1, 1));
+    let epoch = cluster.get_region_epoch(r1);
+    let req = new_admin_request(
+        r1,
+        &epoch,
+        new_change_peer_request(ConfChangeType::RemoveNode, new_peer(2, 2)),
+    );
+    let resp = cluster
+        .call_command_on_leader(req, Duration::from_secs(5))
+        .unwrap();
+    assert!(
+        resp.get_header()
+            .get_error()
+            .get_message()
+            .contains("no known peer with data"),
+        "{:?}",
+        resp
+    );
+}
+
+#[test]
+fn test_node_witness() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_witness(&mut cluster);
+}
+
+#[test]
+fn test_server_witness() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_witness(&mut cluster);
+}
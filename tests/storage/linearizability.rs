@@ -0,0 +1,348 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checkers for the histories recorded by concurrent clients.
+//!
+//! The raw keys are checked as linearizable registers, and the transactions
+//! are checked for snapshot isolation with the timestamps they use. All the
+//! written values are unique, so a read tells which write it observes.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterOp {
+    Write(u64),
+    Read(Option<u64>),
+}
+
+/// An operation on a register, the times are the logical times of the history.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub client: usize,
+    pub key: u64,
+    pub op: RegisterOp,
+    pub invoke: u64,
+    /// None if the result is unknown, e.g. the request timed out, then the
+    /// operation may take effect at any time after it's invoked, or never.
+    pub complete: Option<u64>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let complete = match self.complete {
+            Some(t) => format!("{}", t),
+            None => "?".to_owned(),
+        };
+        write!(
+            f,
+            "client {} {:?} on key {} [{}, {}]",
+            self.client,
+            self.op,
+            self.key,
+            self.invoke,
+            complete
+        )
+    }
+}
+
+struct Search<'a> {
+    ops: &'a [Operation],
+    linearized: Vec<u64>,
+    // The visited (linearized ops, register value) states.
+    visited: HashSet<(Vec<u64>, Option<u64>)>,
+}
+
+impl<'a> Search<'a> {
+    fn is_linearized(&self, i: usize) -> bool {
+        self.linearized[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn toggle(&mut self, i: usize) {
+        self.linearized[i / 64] ^= 1 << (i % 64);
+    }
+
+    fn search(&mut self, value: Option<u64>) -> bool {
+        // The operations with unknown results don't have to take effect.
+        let mut deadline = None;
+        for (i, op) in self.ops.iter().enumerate() {
+            if let Some(t) = op.complete {
+                if !self.is_linearized(i) {
+                    deadline = Some(cmp::min(t, deadline.unwrap_or(t)));
+                }
+            }
+        }
+        let deadline = match deadline {
+            Some(t) => t,
+            None => return true,
+        };
+        if !self.visited.insert((self.linearized.clone(), value)) {
+            return false;
+        }
+        // Only the operations invoked before the earliest pending response can
+        // be linearized next.
+        for i in 0..self.ops.len() {
+            if self.is_linearized(i) || self.ops[i].invoke > deadline {
+                continue;
+            }
+            let next = match self.ops[i].op {
+                RegisterOp::Write(v) => Some(v),
+                RegisterOp::Read(v) if v == value => value,
+                RegisterOp::Read(_) => continue,
+            };
+            self.toggle(i);
+            if self.search(next) {
+                return true;
+            }
+            self.toggle(i);
+        }
+        false
+    }
+}
+
+/// Checks whether the history of a register, which is empty at first, is
+/// linearizable. It's the algorithm of Wing & Gong with the memoization of
+/// Lowe, which is also used by Knossos.
+pub fn check_register(ops: &[Operation]) -> bool {
+    // The writes with unknown results which are never read can be removed,
+    // they reduce the search space a lot.
+    let read: HashSet<u64> = ops.iter()
+        .filter_map(|o| match o.op {
+            RegisterOp::Read(v) => v,
+            RegisterOp::Write(_) => None,
+        })
+        .collect();
+    let ops: Vec<Operation> = ops.iter()
+        .filter(|o| match o.op {
+            RegisterOp::Write(v) => o.complete.is_some() || read.contains(&v),
+            RegisterOp::Read(_) => true,
+        })
+        .cloned()
+        .collect();
+    let mut search = Search {
+        ops: &ops,
+        linearized: vec![0; ops.len() / 64 + 1],
+        visited: HashSet::new(),
+    };
+    search.search(None)
+}
+
+/// Checks all the registers in the history, returns a minimized history of
+/// the register which is not linearizable if any.
+pub fn check_registers(ops: &[Operation]) -> Result<(), Vec<Operation>> {
+    let mut registers: BTreeMap<u64, Vec<Operation>> = BTreeMap::new();
+    for op in ops {
+        registers
+            .entry(op.key)
+            .or_insert_with(Vec::new)
+            .push(op.clone());
+    }
+    for (_, ops) in registers {
+        if !check_register(&ops) {
+            return Err(minimize(ops));
+        }
+    }
+    Ok(())
+}
+
+fn written_values(ops: &[Operation]) -> HashSet<u64> {
+    ops.iter()
+        .filter_map(|o| match o.op {
+            RegisterOp::Write(v) => Some(v),
+            RegisterOp::Read(_) => None,
+        })
+        .collect()
+}
+
+// Returns true if the history is not linearizable, and all the values read
+// are written in the history, so it explains itself.
+fn is_counterexample(ops: &[Operation]) -> bool {
+    let written = written_values(ops);
+    ops.iter().all(|o| match o.op {
+        RegisterOp::Read(Some(v)) => written.contains(&v),
+        _ => true,
+    }) && !check_register(ops)
+}
+
+/// Removes the operations from a history which is not linearizable as long
+/// as the rest is still a counterexample.
+pub fn minimize(mut ops: Vec<Operation>) -> Vec<Operation> {
+    if !is_counterexample(&ops) {
+        // Some value is read but never written, which is a counterexample
+        // itself.
+        let written = written_values(&ops);
+        return ops.into_iter()
+            .filter(|o| match o.op {
+                RegisterOp::Read(Some(v)) => !written.contains(&v),
+                _ => false,
+            })
+            .take(1)
+            .collect();
+    }
+    let mut chunk = cmp::max(ops.len() / 2, 1);
+    loop {
+        let mut i = 0;
+        while i < ops.len() {
+            let end = cmp::min(i + chunk, ops.len());
+            let mut rest = ops[..i].to_vec();
+            rest.extend_from_slice(&ops[end..]);
+            if is_counterexample(&rest) {
+                ops = rest;
+            } else {
+                i = end;
+            }
+        }
+        if chunk == 1 {
+            return ops;
+        }
+        chunk /= 2;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxnStatus {
+    Committed,
+    Aborted,
+    /// The client doesn't know whether the transaction is committed, e.g.
+    /// the commit request timed out.
+    Unknown,
+}
+
+/// A transaction which reads some keys at the start ts, and then writes
+/// some keys.
+#[derive(Clone, Debug)]
+pub struct Txn {
+    pub client: usize,
+    pub start_ts: u64,
+    /// The commit ts, it's set once the client tries to commit.
+    pub commit_ts: Option<u64>,
+    pub status: TxnStatus,
+    pub reads: Vec<(u64, Option<u64>)>,
+    pub writes: Vec<(u64, u64)>,
+}
+
+impl fmt::Display for Txn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "client {} txn [{}, {:?}] {:?} reads {:?} writes {:?}",
+            self.client,
+            self.start_ts,
+            self.commit_ts,
+            self.status,
+            self.reads,
+            self.writes
+        )
+    }
+}
+
+/// A violation of snapshot isolation, with the transactions involved.
+#[derive(Debug)]
+pub struct Anomaly {
+    pub reason: String,
+    pub txns: Vec<Txn>,
+}
+
+fn anomaly(reason: &str, txns: &[&Txn]) -> Anomaly {
+    Anomaly {
+        reason: reason.to_owned(),
+        txns: txns.iter().map(|t| (*t).clone()).collect(),
+    }
+}
+
+/// Checks whether the transactions satisfy snapshot isolation: every read
+/// sees the latest write committed before the start ts of the transaction,
+/// and no concurrent transactions write the same key.
+pub fn check_snapshot_isolation(txns: &[Txn]) -> Result<(), Anomaly> {
+    let mut writers = HashMap::new();
+    for txn in txns {
+        for &(key, value) in &txn.writes {
+            writers.insert((key, value), txn);
+        }
+    }
+    let mut observed = HashSet::new();
+    for txn in txns {
+        for &(key, value) in &txn.reads {
+            let value = match value {
+                Some(v) => v,
+                None => continue,
+            };
+            let writer = match writers.get(&(key, value)) {
+                Some(w) => *w,
+                None => return Err(anomaly("reads a value which is never written", &[txn])),
+            };
+            if writer.status == TxnStatus::Aborted || writer.commit_ts.is_none() {
+                return Err(anomaly(
+                    "reads a write of an aborted transaction",
+                    &[txn, writer],
+                ));
+            }
+            observed.insert(writer.start_ts);
+        }
+    }
+
+    // The transactions which are known to be committed, sorted by commit ts
+    // for every key.
+    let mut committed: HashMap<u64, Vec<&Txn>> = HashMap::new();
+    for txn in txns {
+        if txn.status != TxnStatus::Committed && !observed.contains(&txn.start_ts) {
+            continue;
+        }
+        for &(key, _) in &txn.writes {
+            committed.entry(key).or_insert_with(Vec::new).push(txn);
+        }
+    }
+    for ws in committed.values_mut() {
+        ws.sort_by_key(|t| t.commit_ts.unwrap());
+        for w in ws.windows(2) {
+            if w[1].start_ts < w[0].commit_ts.unwrap() {
+                return Err(anomaly(
+                    "concurrent transactions write the same key",
+                    &[w[0], w[1]],
+                ));
+            }
+        }
+    }
+
+    for txn in txns {
+        for &(key, value) in &txn.reads {
+            let latest = committed.get(&key).and_then(|ws| {
+                ws.iter()
+                    .take_while(|w| w.commit_ts.unwrap() < txn.start_ts)
+                    .last()
+                    .cloned()
+            });
+            let value = match (value, latest) {
+                (None, None) => continue,
+                (None, Some(latest)) => {
+                    return Err(anomaly("misses a committed write", &[txn, latest]));
+                }
+                (Some(v), _) => v,
+            };
+            let writer = writers[&(key, value)];
+            if writer.commit_ts.unwrap() >= txn.start_ts {
+                return Err(anomaly(
+                    "reads a write committed after it starts",
+                    &[txn, writer],
+                ));
+            }
+            if let Some(latest) = latest {
+                if latest.start_ts != writer.start_ts {
+                    return Err(anomaly("reads a stale write", &[txn, writer, latest]));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -17,3 +17,5 @@ pub mod assert_storage;
 mod test_storage;
 mod test_raft_storage;
 pub mod util;
+pub mod linearizability;
+mod test_linearizability;
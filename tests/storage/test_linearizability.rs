@@ -0,0 +1,700 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvproto::errorpb;
+use kvproto::kvrpcpb::Context;
+use kvproto::metapb;
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use tikv::storage::{self, make_key, Engine, EngineError, Mutation, Options, Storage};
+use tikv::storage::Error as StorageError;
+use tikv::storage::mvcc::Error as MvccError;
+use tikv::storage::txn::Error as TxnError;
+use tikv::storage::config::Config;
+use tikv::util::HandyRwLock;
+use raftstore::cluster::Cluster;
+use raftstore::server::{new_server_cluster, ServerCluster};
+use raftstore::transport_simulate::{CloneFilterFactory, DropPacketFilter};
+use raftstore::util::*;
+use super::linearizability::*;
+use util::{new_seed, rng_from_seed};
+
+const STORE_COUNT: usize = 3;
+const CLIENT_COUNT: usize = 4;
+const RAW_KEY_COUNT: u64 = 3;
+const TXN_KEY_COUNT: u64 = 4;
+const NEMESIS_ROUNDS: usize = 15;
+const OP_TIMEOUT_MS: u64 = 3000;
+const MAX_RESOLVE_RETRY: usize = 10;
+// The number of history events the clients run during a fault, and between
+// the faults.
+const FAULT_EVENTS: usize = 40;
+const FAULT_TIMEOUT_MS: u64 = 10000;
+const SEED_ENV: &'static str = "LINEARIZABILITY_SEED";
+
+// The states shared by the clients and the nemesis.
+struct Shared {
+    // The storages of the running stores, keyed by the store id.
+    storages: Mutex<HashMap<u64, Storage>>,
+    // The logical clock of the history.
+    clock: AtomicUsize,
+    // The timestamp oracle of the transactions.
+    oracle: AtomicUsize,
+    // All the written values are unique.
+    values: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst) as u64
+    }
+
+    fn get_ts(&self) -> u64 {
+        self.oracle.fetch_add(1, Ordering::SeqCst) as u64
+    }
+
+    fn next_value(&self) -> u64 {
+        self.values.fetch_add(1, Ordering::SeqCst) as u64
+    }
+}
+
+fn new_storage(engine: Box<Engine>) -> Storage {
+    let config = Config::default();
+    let mut storage = Storage::from_engine(engine, &config).unwrap();
+    storage.start(&config).unwrap();
+    storage
+}
+
+// Calls the async API of the storage, returns None if the result is unknown.
+fn wait<T, F>(f: F) -> Option<storage::Result<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(storage::Callback<T>) -> storage::Result<()>,
+{
+    let (tx, rx) = mpsc::channel();
+    let cb = box move |res: storage::Result<T>| {
+        let _ = tx.send(res);
+    };
+    if let Err(e) = f(cb) {
+        return Some(Err(e));
+    }
+    rx.recv_timeout(Duration::from_millis(OP_TIMEOUT_MS)).ok()
+}
+
+fn region_error(e: &StorageError) -> Option<&errorpb::Error> {
+    match *e {
+        StorageError::Engine(EngineError::Request(ref e)) |
+        StorageError::Txn(TxnError::Engine(EngineError::Request(ref e))) |
+        StorageError::Txn(TxnError::Mvcc(MvccError::Engine(EngineError::Request(ref e)))) => {
+            Some(e)
+        }
+        _ => None,
+    }
+}
+
+// Returns true if the request is rejected before it's proposed, so it never
+// takes effect.
+fn is_rejected(e: &StorageError) -> bool {
+    region_error(e).map_or(false, |e| e.has_not_leader() || e.has_store_not_match())
+}
+
+fn key_is_locked(e: &StorageError) -> Option<(Vec<u8>, u64)> {
+    match *e {
+        StorageError::Txn(TxnError::Mvcc(MvccError::KeyIsLocked {
+            ref primary, ts, ..
+        })) => Some((primary.clone(), ts)),
+        _ => None,
+    }
+}
+
+fn committed_ts(e: &StorageError) -> Option<u64> {
+    match *e {
+        StorageError::Txn(TxnError::Mvcc(MvccError::Committed { commit_ts })) => Some(commit_ts),
+        _ => None,
+    }
+}
+
+fn raw_key(key: u64) -> Vec<u8> {
+    format!("r{}", key).into_bytes()
+}
+
+fn txn_key(key: u64) -> Vec<u8> {
+    format!("t{}", key).into_bytes()
+}
+
+fn encode_value(value: u64) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+fn decode_value(value: Vec<u8>) -> u64 {
+    String::from_utf8(value).unwrap().parse().unwrap()
+}
+
+struct Client {
+    id: usize,
+    shared: Arc<Shared>,
+    contexts: HashMap<u64, Context>,
+    // The store which the requests are sent to, it follows the leader.
+    store: u64,
+    rng: XorShiftRng,
+    ops: Vec<Operation>,
+    txns: Vec<Txn>,
+}
+
+impl Client {
+    fn new(
+        id: usize,
+        shared: Arc<Shared>,
+        contexts: HashMap<u64, Context>,
+        rng: XorShiftRng,
+    ) -> Client {
+        Client {
+            id: id,
+            shared: shared,
+            contexts: contexts,
+            store: 1,
+            rng: rng,
+            ops: vec![],
+            txns: vec![],
+        }
+    }
+
+    fn storage(&mut self) -> Option<(Storage, Context)> {
+        let storage = self.shared.storages.lock().unwrap().get(&self.store).cloned();
+        match storage {
+            Some(s) => Some((s, self.contexts[&self.store].clone())),
+            None => {
+                // The store is stopped.
+                self.switch_store(None);
+                None
+            }
+        }
+    }
+
+    fn switch_store(&mut self, e: Option<&StorageError>) {
+        if let Some(e) = e.and_then(region_error) {
+            if e.get_not_leader().has_leader() {
+                self.store = e.get_not_leader().get_leader().get_store_id();
+                return;
+            }
+        }
+        self.store = self.rng.gen_range(1, STORE_COUNT as u64 + 1);
+    }
+
+    fn run(&mut self) {
+        while !self.shared.stopped.load(Ordering::SeqCst) {
+            if self.rng.gen() {
+                self.raw_op();
+            } else {
+                self.txn();
+            }
+        }
+    }
+
+    fn raw_op(&mut self) {
+        let (storage, ctx) = match self.storage() {
+            Some(s) => s,
+            None => return,
+        };
+        let key = self.rng.gen_range(0, RAW_KEY_COUNT);
+        let invoke = self.shared.now();
+        let (op, complete) = if self.rng.gen() {
+            let value = self.shared.next_value();
+            let res = wait(|cb| storage.async_raw_put(ctx, raw_key(key), encode_value(value), cb));
+            let complete = match res {
+                Some(Ok(())) => Some(self.shared.now()),
+                Some(Err(e)) => {
+                    self.switch_store(Some(&e));
+                    if is_rejected(&e) {
+                        return;
+                    }
+                    None
+                }
+                None => {
+                    self.switch_store(None);
+                    None
+                }
+            };
+            (RegisterOp::Write(value), complete)
+        } else {
+            // The failed reads have no effect, they're not recorded.
+            match wait(|cb| storage.async_raw_get(ctx, raw_key(key), cb)) {
+                Some(Ok(value)) => (
+                    RegisterOp::Read(value.map(decode_value)),
+                    Some(self.shared.now()),
+                ),
+                Some(Err(e)) => return self.switch_store(Some(&e)),
+                None => return self.switch_store(None),
+            }
+        };
+        self.ops.push(Operation {
+            client: self.id,
+            key: key,
+            op: op,
+            invoke: invoke,
+            complete: complete,
+        });
+    }
+
+    // Resolves the lock of the transaction like TiDB does: cleans up the
+    // primary lock, then commits or rolls back the other locks by the result.
+    fn resolve_lock(&mut self, storage: &Storage, ctx: &Context, primary: Vec<u8>, ts: u64) {
+        let res = wait(|cb| storage.async_cleanup(ctx.clone(), make_key(&primary), ts, cb));
+        let commit_ts = match res {
+            Some(Ok(())) => None,
+            Some(Err(ref e)) if committed_ts(e).is_some() => committed_ts(e),
+            Some(Err(e)) => return self.switch_store(Some(&e)),
+            None => return self.switch_store(None),
+        };
+        match wait(|cb| storage.async_resolve_lock(ctx.clone(), ts, commit_ts, cb)) {
+            Some(Ok(())) => {}
+            Some(Err(e)) => self.switch_store(Some(&e)),
+            None => self.switch_store(None),
+        }
+    }
+
+    // Reads the key at the start ts, returns None if it fails.
+    fn read(
+        &mut self,
+        storage: &Storage,
+        ctx: &Context,
+        key: u64,
+        start_ts: u64,
+    ) -> Option<Option<u64>> {
+        for i in 0..MAX_RESOLVE_RETRY {
+            let res = wait(|cb| {
+                storage.async_get(ctx.clone(), make_key(&txn_key(key)), start_ts, cb)
+            });
+            let e = match res {
+                Some(Ok(value)) => return Some(value.map(decode_value)),
+                Some(Err(e)) => e,
+                None => {
+                    self.switch_store(None);
+                    return None;
+                }
+            };
+            match key_is_locked(&e) {
+                Some((primary, ts)) => {
+                    // Backoff before cleaning up the lock, the transaction may
+                    // be committing.
+                    thread::sleep(Duration::from_millis(10 * (i as u64 + 1)));
+                    self.resolve_lock(storage, ctx, primary, ts);
+                }
+                None => {
+                    self.switch_store(Some(&e));
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    fn txn(&mut self) {
+        let (storage, ctx) = match self.storage() {
+            Some(s) => s,
+            None => return,
+        };
+        let start_ts = self.shared.get_ts();
+        let mut txn = Txn {
+            client: self.id,
+            start_ts: start_ts,
+            commit_ts: None,
+            status: TxnStatus::Unknown,
+            reads: vec![],
+            writes: vec![],
+        };
+        for _ in 0..2 {
+            let key = self.rng.gen_range(0, TXN_KEY_COUNT);
+            match self.read(&storage, &ctx, key, start_ts) {
+                Some(value) => txn.reads.push((key, value)),
+                // Nothing is written, so the transaction can be dropped.
+                None => return,
+            }
+        }
+        let mut keys: Vec<u64> = (0..TXN_KEY_COUNT).collect();
+        self.rng.shuffle(&mut keys);
+        let write_count = self.rng.gen_range(1, 3);
+        for &key in &keys[..write_count] {
+            txn.writes.push((key, self.shared.next_value()));
+        }
+        let mutations: Vec<Mutation> = txn.writes
+            .iter()
+            .map(|&(k, v)| Mutation::Put((make_key(&txn_key(k)), encode_value(v))))
+            .collect();
+        let primary = txn_key(txn.writes[0].0);
+        let res = wait(|cb| {
+            let opts = Options::default();
+            storage.async_prewrite(ctx.clone(), mutations, primary, start_ts, opts, cb)
+        });
+        let write_keys: Vec<_> = txn.writes
+            .iter()
+            .map(|&(k, _)| make_key(&txn_key(k)))
+            .collect();
+        let prewritten = match res {
+            Some(Ok(ref results)) => results.iter().all(|r| r.is_ok()),
+            Some(Err(ref e)) => {
+                self.switch_store(Some(e));
+                false
+            }
+            None => {
+                // The transaction is never committed, the locks left may be
+                // rolled back by others.
+                self.switch_store(None);
+                self.txns.push(txn);
+                return;
+            }
+        };
+        if !prewritten {
+            match wait(|cb| storage.async_rollback(ctx.clone(), write_keys, start_ts, cb)) {
+                Some(Ok(())) => txn.status = TxnStatus::Aborted,
+                Some(Err(e)) => self.switch_store(Some(&e)),
+                None => self.switch_store(None),
+            }
+            self.txns.push(txn);
+            return;
+        }
+
+        let commit_ts = self.shared.get_ts();
+        txn.commit_ts = Some(commit_ts);
+        match wait(|cb| storage.async_commit(ctx.clone(), write_keys, start_ts, commit_ts, cb)) {
+            Some(Ok(())) => txn.status = TxnStatus::Committed,
+            Some(Err(e)) => self.switch_store(Some(&e)),
+            None => self.switch_store(None),
+        }
+        self.txns.push(txn);
+    }
+}
+
+#[derive(Debug)]
+enum Fault {
+    Partition(u64),
+    LossyNetwork,
+    TransferLeader(u64),
+    Restart(u64),
+}
+
+fn find_peer(region: &metapb::Region, store_id: u64) -> metapb::Peer {
+    region
+        .get_peers()
+        .iter()
+        .find(|p| p.get_store_id() == store_id)
+        .unwrap()
+        .clone()
+}
+
+// Polls until `cond` holds or the timeout elapses, returns whether it holds.
+fn wait_until<F: FnMut() -> bool>(timeout_ms: u64, mut cond: F) -> bool {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    loop {
+        if cond() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep_ms(10);
+    }
+}
+
+// Waits until the clients have run `FAULT_EVENTS` more events. The
+// operations may hang during a fault, so it gives up after a timeout.
+fn wait_client_events(shared: &Shared) {
+    let start = shared.clock.load(Ordering::SeqCst);
+    wait_until(FAULT_TIMEOUT_MS, || {
+        shared.clock.load(Ordering::SeqCst) >= start + FAULT_EVENTS
+    });
+}
+
+// Waits until all the stores agree on the leader of the region.
+fn wait_leader_agreed(cluster: &Cluster<ServerCluster>, region_id: u64) {
+    let agreed = wait_until(FAULT_TIMEOUT_MS, || {
+        let leaders: Vec<_> = (1..STORE_COUNT as u64 + 1)
+            .map(|id| cluster.query_leader(id, region_id))
+            .collect();
+        leaders[0].is_some() && leaders.iter().all(|l| *l == leaders[0])
+    });
+    if !agreed {
+        warn!("stores don't agree on the leader of region {}", region_id);
+    }
+}
+
+fn inject_fault(
+    cluster: &mut Cluster<ServerCluster>,
+    shared: &Shared,
+    region: &metapb::Region,
+    fault: &Fault,
+) {
+    match *fault {
+        Fault::Partition(store_id) => {
+            let others = (1..STORE_COUNT as u64 + 1)
+                .filter(|&id| id != store_id)
+                .collect();
+            cluster.partition(vec![store_id], others);
+            wait_client_events(shared);
+            cluster.clear_send_filters();
+            wait_leader_agreed(cluster, region.get_id());
+        }
+        Fault::LossyNetwork => {
+            cluster.add_send_filter(CloneFilterFactory(DropPacketFilter::new(30)));
+            wait_client_events(shared);
+            cluster.clear_send_filters();
+            wait_leader_agreed(cluster, region.get_id());
+        }
+        Fault::TransferLeader(store_id) => {
+            let epoch = region.get_region_epoch();
+            let req = new_admin_request(
+                region.get_id(),
+                epoch,
+                new_transfer_leader_cmd(find_peer(region, store_id)),
+            );
+            // Transferring leader may fail, it doesn't matter.
+            let _ = cluster.call_command_on_leader(req, Duration::from_secs(5));
+        }
+        Fault::Restart(store_id) => {
+            if let Some(mut storage) = shared.storages.lock().unwrap().remove(&store_id) {
+                storage.stop().unwrap();
+            }
+            cluster.stop_node(store_id);
+            wait_client_events(shared);
+            cluster.run_node(store_id);
+            let engine = cluster.sim.rl().storages[&store_id].clone();
+            shared
+                .storages
+                .lock()
+                .unwrap()
+                .insert(store_id, new_storage(engine));
+            wait_leader_agreed(cluster, region.get_id());
+        }
+    }
+}
+
+fn print_history<T: fmt::Display>(history: &[T]) -> String {
+    history
+        .iter()
+        .map(|h| format!("  {}", h))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Derives a new rng from `rng`, the constant word keeps the xorshift seed from
+// being all zeros.
+fn derive_rng<R: Rng>(rng: &mut R) -> XorShiftRng {
+    XorShiftRng::from_seed([rng.gen(), rng.gen(), rng.gen(), 0x9e37_79b9])
+}
+
+// Runs concurrent clients doing raw and transactional operations on a
+// cluster with faults injected, then checks the raw keys are linearizable,
+// and the transactions satisfy snapshot isolation.
+//
+// The faults and the operations of the clients are generated from one seed,
+// which is printed. Setting it in `SEED_ENV` replays the same choices, though
+// the interleaving of the clients still depends on the scheduling.
+#[test]
+fn test_storage_linearizability() {
+    let seed = new_seed(SEED_ENV, "test_storage_linearizability");
+    let mut rng = rng_from_seed(seed, 0);
+
+    let mut cluster = new_server_cluster(0, STORE_COUNT);
+    cluster.run();
+    // Make sure the leader has been elected.
+    assert_eq!(cluster.must_get(b""), None);
+    let region = cluster.get_region(b"");
+
+    let mut storages = HashMap::new();
+    let mut contexts = HashMap::new();
+    for peer in region.get_peers() {
+        let engine = cluster.sim.rl().storages[&peer.get_store_id()].clone();
+        storages.insert(peer.get_store_id(), new_storage(engine));
+        let mut ctx = Context::new();
+        ctx.set_region_id(region.get_id());
+        ctx.set_region_epoch(region.get_region_epoch().clone());
+        ctx.set_peer(peer.clone());
+        contexts.insert(peer.get_store_id(), ctx);
+    }
+    let shared = Arc::new(Shared {
+        storages: Mutex::new(storages),
+        clock: AtomicUsize::new(0),
+        oracle: AtomicUsize::new(1),
+        values: AtomicUsize::new(0),
+        stopped: AtomicBool::new(false),
+    });
+
+    let mut handles = vec![];
+    for id in 0..CLIENT_COUNT {
+        let (shared, contexts) = (shared.clone(), contexts.clone());
+        let client_rng = derive_rng(&mut rng);
+        handles.push(thread::spawn(move || {
+            let mut client = Client::new(id, shared, contexts, client_rng);
+            client.run();
+            (client.ops, client.txns)
+        }));
+    }
+
+    let mut faults = vec![];
+    for _ in 0..NEMESIS_ROUNDS {
+        wait_client_events(&shared);
+        let store_id = rng.gen_range(1, STORE_COUNT as u64 + 1);
+        let fault = match rng.gen_range(0, 4) {
+            0 => Fault::Partition(store_id),
+            1 => Fault::LossyNetwork,
+            2 => Fault::TransferLeader(store_id),
+            _ => Fault::Restart(store_id),
+        };
+        inject_fault(&mut cluster, &shared, &region, &fault);
+        faults.push(fault);
+    }
+    // Let the clients run a while after the faults are healed.
+    wait_client_events(&shared);
+    shared.stopped.store(true, Ordering::SeqCst);
+
+    let (mut ops, mut txns) = (vec![], vec![]);
+    for h in handles {
+        let (o, t) = h.join().unwrap();
+        ops.extend(o);
+        txns.extend(t);
+    }
+    assert!(!ops.is_empty() && !txns.is_empty());
+    if let Err(history) = check_registers(&ops) {
+        panic!(
+            "raw operations are not linearizable with seed {} and faults {:?}, \
+             minimized history:\n{}",
+            seed,
+            faults,
+            print_history(&history)
+        );
+    }
+    if let Err(anomaly) = check_snapshot_isolation(&txns) {
+        panic!(
+            "transactions violate snapshot isolation with seed {} and faults {:?}: {}\n{}",
+            seed,
+            faults,
+            anomaly.reason,
+            print_history(&anomaly.txns)
+        );
+    }
+    for (_, mut storage) in shared.storages.lock().unwrap().drain() {
+        storage.stop().unwrap();
+    }
+}
+
+fn op(client: usize, op: RegisterOp, invoke: u64, complete: Option<u64>) -> Operation {
+    Operation {
+        client: client,
+        key: 0,
+        op: op,
+        invoke: invoke,
+        complete: complete,
+    }
+}
+
+#[test]
+fn test_check_register() {
+    use self::RegisterOp::*;
+
+    // Concurrent writes may be linearized in any order.
+    let ops = vec![
+        op(0, Write(1), 0, Some(3)),
+        op(1, Write(2), 1, Some(4)),
+        op(2, Read(Some(1)), 5, Some(6)),
+    ];
+    assert!(check_register(&ops));
+
+    // A stale read after a write is completed.
+    let ops = vec![
+        op(0, Write(1), 0, Some(1)),
+        op(0, Write(2), 2, Some(3)),
+        op(1, Read(Some(1)), 4, Some(5)),
+    ];
+    assert!(!check_register(&ops));
+
+    // A write with an unknown result may take effect at any time later.
+    let ops = vec![
+        op(0, Write(1), 0, None),
+        op(1, Read(None), 1, Some(2)),
+        op(1, Read(Some(1)), 10, Some(11)),
+        op(2, Write(2), 12, Some(13)),
+        op(1, Read(Some(2)), 14, Some(15)),
+    ];
+    assert!(check_register(&ops));
+
+    // But it can't take effect before it's invoked.
+    let ops = vec![
+        op(1, Read(Some(1)), 0, Some(1)),
+        op(0, Write(1), 2, None),
+    ];
+    assert!(!check_register(&ops));
+
+    // The minimized history only contains the operations involved.
+    let mut ops = vec![op(0, Write(1), 0, Some(1)), op(0, Write(2), 2, Some(3))];
+    for i in 0..10 {
+        ops.push(op(1, Read(Some(2)), 4 + i * 2, Some(5 + i * 2)));
+    }
+    ops.push(op(2, Read(Some(1)), 30, Some(31)));
+    let minimized = minimize(ops);
+    let minimized: Vec<_> = minimized.into_iter().map(|o| o.op).collect();
+    assert_eq!(minimized, vec![Write(1), Write(2), Read(Some(1))]);
+}
+
+fn new_txn(start_ts: u64, commit_ts: Option<u64>, status: TxnStatus) -> Txn {
+    Txn {
+        client: 0,
+        start_ts: start_ts,
+        commit_ts: commit_ts,
+        status: status,
+        reads: vec![],
+        writes: vec![],
+    }
+}
+
+#[test]
+fn test_check_snapshot_isolation() {
+    let mut t1 = new_txn(1, Some(2), TxnStatus::Committed);
+    t1.writes = vec![(0, 10), (1, 11)];
+    let mut t2 = new_txn(3, Some(5), TxnStatus::Unknown);
+    t2.reads = vec![(0, Some(10)), (1, Some(11))];
+    t2.writes = vec![(0, 20)];
+    let mut t3 = new_txn(6, None, TxnStatus::Aborted);
+    t3.reads = vec![(0, Some(20)), (1, Some(11))];
+    t3.writes = vec![(1, 30)];
+    let mut t4 = new_txn(4, None, TxnStatus::Aborted);
+    t4.reads = vec![(0, Some(10))];
+    check_snapshot_isolation(&[t1.clone(), t2.clone(), t3.clone(), t4.clone()]).unwrap();
+
+    // Reads a stale value after t2 is known to be committed.
+    let mut t5 = new_txn(7, None, TxnStatus::Aborted);
+    t5.reads = vec![(0, Some(10))];
+    let txns = vec![t1.clone(), t2.clone(), t3.clone(), t5];
+    let anomaly = check_snapshot_isolation(&txns).unwrap_err();
+    assert_eq!(anomaly.txns.len(), 3);
+
+    // Reads a write of an aborted transaction.
+    let mut t6 = new_txn(8, None, TxnStatus::Aborted);
+    t6.reads = vec![(1, Some(30))];
+    let txns = vec![t1.clone(), t3.clone(), t6];
+    assert!(check_snapshot_isolation(&txns).is_err());
+
+    // Lost update.
+    let mut t7 = new_txn(2, Some(4), TxnStatus::Committed);
+    t7.writes = vec![(1, 40)];
+    let mut t8 = new_txn(3, Some(5), TxnStatus::Committed);
+    t8.writes = vec![(1, 50)];
+    assert!(check_snapshot_isolation(&[t7, t8]).is_err());
+}
@@ -11,15 +11,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rand::{self, Rng, ThreadRng};
+use rand::{self, Rng, SeedableRng, ThreadRng, XorShiftRng};
 use std::io::{self, Write};
 use std::env;
 use std::fmt::Arguments;
 use std::fs::File;
+use std::path::Path;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use tikv::util;
 use tikv::util::logger::{self, LogWriter};
+use tikv::util::security::SecurityConfig;
 
 
 /// A random generator of kv.
@@ -60,6 +63,34 @@ pub fn generate_random_kvs(n: usize, key_len: usize, value_len: usize) -> Vec<(V
     kv_generator.take(n).collect()
 }
 
+/// Returns the seed of a randomized test from the env var `env_var`, or from
+/// the current time if it's not set. The seed is printed, so a failing run
+/// can be reproduced by setting it.
+pub fn new_seed(env_var: &str, test: &str) -> u64 {
+    let seed = match env::var(env_var) {
+        Ok(seed) => seed.parse().expect("invalid seed"),
+        Err(_) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            now.as_secs() ^ now.subsec_nanos() as u64
+        }
+    };
+    println!("{} runs with {}={}", test, env_var, seed);
+    seed
+}
+
+/// Creates an rng of the seed, different streams give independent rngs. The
+/// seed is kept as is, so every seed leads to a distinct state, and the
+/// constant word keeps the xorshift seed from being all zeros.
+pub fn rng_from_seed(seed: u64, stream: u32) -> XorShiftRng {
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, stream, 0x9e37_79b9])
+}
+
+/// Creates the rng of a randomized test from the seed in the env var
+/// `env_var`, see `new_seed`.
+pub fn new_seeded_rng(env_var: &str, test: &str) -> XorShiftRng {
+    rng_from_seed(new_seed(env_var, test), 0)
+}
+
 /// A logger that add a test case tag before each line of log.
 struct CaseTraceLogger {
     f: Option<Mutex<File>>,
@@ -95,3 +126,16 @@ pub fn init_log() {
     // we don't mind set it multiple times.
     let _ = logger::init_log(CaseTraceLogger { f: writer }, level);
 }
+
+/// Returns the security config with the certificates in `tests/data`, which
+/// are generated by `tests/data/gen-certs.sh` and issued for "tikv-server".
+pub fn new_security_cfg() -> SecurityConfig {
+    let data_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let path = |name: &str| data_dir.join(name).to_str().unwrap().to_owned();
+    SecurityConfig {
+        ca_path: path("ca.pem"),
+        cert_path: path("cert.pem"),
+        key_path: path("key.pem"),
+        ..SecurityConfig::default()
+    }
+}
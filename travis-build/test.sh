@@ -39,11 +39,16 @@ if [[ `uname` == "Linux" ]]; then
     export EXTRA_CARGO_ARGS="-j 2"
 fi
 
+# The fail point tests are run by TEST_TARGET=failpoint_test.
+if [[ "$TEST_TARGET" = "" ]]; then
+    export TEST_TARGET=test
+fi
+
 if [[ "$SKIP_TESTS" != "true" ]]; then
-    make test 2>&1 | tee tests.out
+    make $TEST_TARGET 2>&1 | tee tests.out
 else
     export EXTRA_CARGO_ARGS="$EXTRA_CARGO_ARGS --no-run"
-    make test
+    make $TEST_TARGET
     exit $?
 fi
 status=$?